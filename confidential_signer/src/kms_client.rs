@@ -1,7 +1,7 @@
 use google_cloud_kms_v1::client::KeyManagementService;
 use google_cloud_auth::credentials::external_account;
 use serde::{Deserialize, Serialize};
-use signer_core::{EncryptionBackend, EncryptionBackendFactory};
+use signer_core::{CredentialsSchema, EncryptionBackend, EncryptionBackendFactory, cddl::Cddl};
 
 use crate::error;
 use strfmt::strfmt;
@@ -11,12 +11,28 @@ pub struct Config {
     pub confidential_file: String,
 }
 
-#[derive(Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Cddl)]
 pub struct Credentials {
     pub wip_provider_path: String,
     pub encryption_key_path: String,
 }
 
+impl Credentials {
+    pub fn new(
+        wip_provider_path: impl Into<String>,
+        encryption_key_path: impl Into<String>,
+    ) -> Self {
+        Self {
+            wip_provider_path: wip_provider_path.into(),
+            encryption_key_path: encryption_key_path.into(),
+        }
+    }
+}
+
+impl CredentialsSchema for Credentials {
+    const VERSION: u32 = 1;
+}
+
 pub struct ClientFactory {}
 
 impl ClientFactory {
@@ -13,3 +13,10 @@ pub type Server<R> = signer_core::rpc::server::Server<
 >;
 
 pub type Client = signer_core::rpc::client::Client<tokio::net::TcpStream, kms_client::Credentials>;
+
+/// `signer_core::prelude` extended with the Confidential Space specific types
+pub mod prelude {
+    pub use crate::kms_client::{Config, Credentials};
+    pub use crate::{Client, Server};
+    pub use signer_core::prelude::*;
+}
@@ -1,10 +1,14 @@
 use confidential_signer::{
     rand_core,
-    signer_core::{EncryptionBackend, EncryptionBackendFactory, rpc::server::Server},
+    signer_core::{
+        CredentialsSchema, EncryptionBackend, EncryptionBackendFactory,
+        rpc::{InsecureOptions, server::Server},
+    },
     tokio,
 };
 // use hyper_rustls::TlsAcceptor;
 use serde::{Deserialize, Serialize};
+use signer_core::cddl::Cddl;
 use std::{convert::Infallible, io, net::SocketAddr};
 
 pub struct App {}
@@ -43,9 +47,13 @@ impl EncryptionBackend for Passthrough {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Cddl)]
 struct DummyCredentials {} // serialized as empty object instead of null for unity
 
+impl CredentialsSchema for DummyCredentials {
+    const VERSION: u32 = 1;
+}
+
 struct PassthroughFactory;
 
 impl EncryptionBackendFactory for PassthroughFactory {
@@ -66,7 +74,14 @@ impl App {
 
             tokio::spawn(async move {
                 // Create server
-                let mut srv = Server::new(PassthroughFactory, rand_core::OsRng);
+                let mut srv = Server::new(PassthroughFactory, rand_core::OsRng)
+                    .with_insecure_options(InsecureOptions {
+                        passthrough_backend: true,
+                        import_unencrypted: true,
+                        export_wrapped: true,
+                        rewrap_key_id: true,
+                        ..Default::default()
+                    });
                 // Serve connection
                 if let Err(err) = srv.serve_connection(conn).await {
                     eprintln!("{}", err);
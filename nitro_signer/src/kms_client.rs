@@ -5,7 +5,7 @@ pub use aws_sdk_kms::types::{EncryptionAlgorithmSpec, KeyEncryptionMechanism};
 use aws_sdk_kms::{
     client::Client as KMSClient,
     config::{Credentials as AWSCredentials, Region, SharedCredentialsProvider},
-    error::SdkError,
+    error::{ProvideErrorMetadata, SdkError},
     types::RecipientInfo,
 };
 use cbc::cipher::{self, block_padding, BlockDecryptMut, IvSizeUser, KeyIvInit, KeySizeUser};
@@ -18,11 +18,15 @@ use const_oid::{
 };
 use rsa::{Oaep, RsaPrivateKey, RsaPublicKey};
 use serde::{Deserialize, Serialize};
-use signer_core::{EncryptionBackend, EncryptionBackendFactory};
+use signer_core::{
+    cddl::Cddl,
+    rpc::{CodedError, ErrorCode},
+    CredentialsSchema, EncryptionBackend, EncryptionBackendFactory,
+};
 use vsock::SocketAddr as VSockAddr;
 use zeroize::Zeroize;
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Cddl)]
 pub struct Credentials {
     pub access_key_id: String,
     pub secret_access_key: String,
@@ -31,6 +35,32 @@ pub struct Credentials {
     pub region: String,
 }
 
+impl Credentials {
+    pub fn new(
+        access_key_id: impl Into<String>,
+        secret_access_key: impl Into<String>,
+        encryption_key_id: impl Into<String>,
+        region: impl Into<String>,
+    ) -> Self {
+        Self {
+            access_key_id: access_key_id.into(),
+            secret_access_key: secret_access_key.into(),
+            session_token: None,
+            encryption_key_id: encryption_key_id.into(),
+            region: region.into(),
+        }
+    }
+
+    pub fn with_session_token(mut self, session_token: impl Into<String>) -> Self {
+        self.session_token = Some(session_token.into());
+        self
+    }
+}
+
+impl CredentialsSchema for Credentials {
+    const VERSION: u32 = 1;
+}
+
 pub trait Attester {
     type Error: std::error::Error + 'static;
     fn attest(&self, pk: &RsaPublicKey) -> Result<Vec<u8>, Self::Error>;
@@ -260,6 +290,8 @@ fn parse_enveloped_data<A>(src: &[u8]) -> Result<ParsedEnvelopedData, Error<A>>
 pub enum Error<A> {
     Attestation(A),
     Sdk(Box<dyn std::error::Error + Send + Sync + 'static>),
+    /// KMS can't be reached: connection failures and timeouts
+    Unavailable(Box<dyn std::error::Error + Send + Sync + 'static>),
     Ber(ale::Error),
     ContentType(ObjectIdentifier),
     Algorithm(ObjectIdentifier),
@@ -272,13 +304,41 @@ pub enum Error<A> {
     Unpad,
 }
 
+// hyper-rustls reports a host name unusable for SNI as a plain IO error
+fn is_invalid_dns_name(err: &(dyn std::error::Error + 'static)) -> bool {
+    let mut cur = Some(err);
+    while let Some(err) = cur {
+        if err
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|err| err.to_string() == "invalid dnsname")
+        {
+            return true;
+        }
+        cur = err.source();
+    }
+    false
+}
+
 impl<A, E, R> From<SdkError<E, R>> for Error<A>
 where
-    E: std::error::Error + Send + Sync + 'static,
+    E: std::error::Error + ProvideErrorMetadata + Send + Sync + 'static,
     R: std::fmt::Debug + Send + Sync + 'static,
 {
     fn from(value: SdkError<E, R>) -> Self {
-        Error::Sdk(Box::new(value))
+        match value {
+            SdkError::DispatchFailure(_) | SdkError::TimeoutError(_) => {
+                let code = if is_invalid_dns_name(&value) {
+                    ErrorCode::InvalidDnsName
+                } else {
+                    ErrorCode::BackendUnavailable
+                };
+                Error::Unavailable(Box::new(CodedError::new(code, value)))
+            }
+            _ if value.code() == Some("AccessDeniedException") => {
+                Error::Sdk(Box::new(CodedError::new(ErrorCode::KmsAccessDenied, value)))
+            }
+            _ => Error::Sdk(Box::new(value)),
+        }
     }
 }
 
@@ -308,6 +368,7 @@ where
         match self {
             Error::Attestation(_) => f.write_str("attestation error"),
             Error::Sdk(_) => f.write_str("SDK error"),
+            Error::Unavailable(_) => f.write_str("KMS unavailable"),
             Error::ZeroOutput => f.write_str("zero output"),
             Error::Ber(_) => f.write_str("BER error"),
             Error::ContentType(object_identifier) => {
@@ -334,6 +395,7 @@ where
         match self {
             Error::Attestation(error) => Some(error),
             Error::Sdk(error) => Some(error.as_ref()),
+            Error::Unavailable(error) => Some(error.as_ref()),
             Error::Ber(error) => Some(error),
             Error::Rsa(error) => Some(error),
             _ => None,
@@ -341,18 +403,13 @@ where
     }
 }
 
-impl<A> EncryptionBackend for Client<A>
-where
-    A: Attester + Send + Sync,
-{
-    type Error = Error<A::Error>;
-
-    async fn encrypt(&self, src: &[u8]) -> Result<Vec<u8>, Self::Error> {
+impl<A: Attester> Client<A> {
+    async fn encrypt_to(&self, key_id: &str, src: &[u8]) -> Result<Vec<u8>, Error<A::Error>> {
         let res = self
             .client
             .encrypt()
             .plaintext(src.into())
-            .set_key_id(Some(self.encryption_key_id.clone()))
+            .set_key_id(Some(key_id.into()))
             .send()
             .await?;
 
@@ -361,6 +418,25 @@ where
             None => Err(Error::ZeroOutput),
         }
     }
+}
+
+impl<A> EncryptionBackend for Client<A>
+where
+    A: Attester + Send + Sync,
+{
+    type Error = Error<A::Error>;
+
+    async fn encrypt(&self, src: &[u8]) -> Result<Vec<u8>, Self::Error> {
+        self.encrypt_to(&self.encryption_key_id, src).await
+    }
+
+    async fn encrypt_with_key(
+        &self,
+        key_id: &str,
+        src: &[u8],
+    ) -> Option<Result<Vec<u8>, Self::Error>> {
+        Some(self.encrypt_to(key_id, src).await)
+    }
 
     async fn decrypt(&self, src: &[u8]) -> Result<Vec<u8>, Self::Error> {
         let attestation_doc = match self
@@ -392,6 +468,10 @@ where
             None => Err(Error::ZeroOutput),
         }
     }
+
+    fn is_unavailable(err: &Self::Error) -> bool {
+        matches!(err, Error::Unavailable(_))
+    }
 }
 
 type Aes256Cbc = cbc::Decryptor<aes::Aes256>;
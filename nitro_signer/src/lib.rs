@@ -2,16 +2,26 @@ pub use aws_config;
 pub use rand_core;
 pub use rsa;
 pub use signer_core;
-use signer_core::EncryptedSigner;
+use signer_core::{
+    hedge::{Hedged, HedgedFactory},
+    EncryptedSigner,
+};
 pub use tokio;
 pub use vsock;
 
 pub mod kms_client;
 
 pub type Server<R, A> = signer_core::rpc::server::Server<
-    kms_client::ClientFactory<A>,
-    EncryptedSigner<kms_client::Client<A>>,
+    HedgedFactory<kms_client::ClientFactory<A>>,
+    EncryptedSigner<Hedged<kms_client::Client<A>>>,
     R,
 >;
 
 pub type Client = signer_core::rpc::client::Client<vsock::Stream, kms_client::Credentials>;
+
+/// `signer_core::prelude` extended with the AWS Nitro specific types
+pub mod prelude {
+    pub use crate::kms_client::{Config, Credentials};
+    pub use crate::{Client, Server};
+    pub use signer_core::prelude::*;
+}
@@ -1,15 +1,33 @@
 use crate::nsm::{self, SharedNSM};
+use crate::report::StartupReport;
 use nitro_signer::{
     aws_config,
     kms_client::{self, ClientFactory},
-    rsa, tokio, vsock, Server,
+    rsa,
+    signer_core::{
+        callback::{self, Callback},
+        crypto::KeychainNamespaces,
+        hedge::{HedgeConfig, HedgedFactory, Hedging},
+        noise,
+        rpc::{
+            events::EventBus, idempotency::IdempotencyCache, server::COMPILED_ALLOWLIST,
+            InsecureOptions,
+        },
+    },
+    tokio, vsock, Server,
 };
+use std::collections::HashMap;
 use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 pub struct App {
     priv_key: rsa::RsaPrivateKey,
     conf: Config,
     secm: SharedNSM,
+    entropy_seeded: usize,
+    insecure: InsecureOptions,
+    started: Instant,
 }
 
 #[derive(Debug)]
@@ -17,6 +35,7 @@ pub enum Error {
     NSM(nsm::Error),
     RSA(rsa::Error),
     IO(io::Error),
+    Callback(callback::Error),
 }
 
 impl From<nsm::Error> for Error {
@@ -37,12 +56,19 @@ impl From<io::Error> for Error {
     }
 }
 
+impl From<callback::Error> for Error {
+    fn from(value: callback::Error) -> Self {
+        Error::Callback(value)
+    }
+}
+
 impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Error::NSM(error) => write!(f, "NSM error: {}", error),
             Error::RSA(error) => write!(f, "RSA error: {}", error),
             Error::IO(error) => write!(f, "IO error: {}", error),
+            Error::Callback(error) => write!(f, "callback channel error: {}", error),
         }
     }
 }
@@ -51,6 +77,39 @@ impl std::error::Error for Error {}
 
 const RSA_BITS: usize = 2048;
 pub const DEFAULT_VSOCK_PORT: u32 = 2000;
+/// Parent instance
+pub const DEFAULT_CALLBACK_CID: u32 = 3;
+
+type CallbackChannel = Arc<tokio::sync::Mutex<callback::Channel<vsock::asio::Stream>>>;
+
+/// Host callback channel, see `signer_core::callback`
+pub struct CallbackConfig {
+    pub cid: Option<u32>,
+    pub port: u32,
+    /// Key shared with the host
+    pub key: Vec<u8>,
+}
+
+impl std::fmt::Debug for CallbackConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CallbackConfig")
+            .field("cid", &self.cid)
+            .field("port", &self.port)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Connections seeing the same imported keys
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KeychainScope {
+    /// Every connection has its own keys
+    #[default]
+    Connection,
+    /// All connections share the keys
+    Shared,
+    /// Connections from the same peer CID share the keys while different peers are isolated
+    Peer,
+}
 
 #[derive(Debug)]
 pub struct Config {
@@ -58,20 +117,65 @@ pub struct Config {
     pub proxy_cid: Option<u32>,
     pub endpoint: Option<String>,
     pub listen_port: Option<u32>,
+    /// Additionally accept connections encrypted with the Noise protocol on this port. The
+    /// channel key is generated at startup and reported as the public key of `Attest` documents
+    pub noise_port: Option<u32>,
+    /// Number of NSM random bytes fed into the kernel entropy pool at startup.
+    /// `Some(0)` disables seeding, `None` means `nsm::DEFAULT_ENTROPY_BYTE_SZ`
+    pub entropy_bytes: Option<usize>,
+    /// Issue a second KMS decrypt request if the first one takes longer than this
+    /// percentile of the recent latencies. `None` disables hedging
+    pub hedge_percentile: Option<f64>,
+    /// Interval of heartbeat frames sent while a request is being processed. `None` disables heartbeats
+    pub heartbeat_interval: Option<Duration>,
+    /// Accept `ImportUnencrypted`, `ImportWrapped` and `ImportMnemonic` requests. Reported as insecure
+    pub import_unencrypted: bool,
+    /// Accept `ExportWrapped` requests. Reported as insecure
+    pub export_wrapped: bool,
+    /// Accept `Rewrap` requests with a KMS key ID. Reported as insecure
+    pub rewrap_key_id: bool,
+    pub keychain_scope: KeychainScope,
+    /// Number of keys decrypted by `SignWith` kept in memory. `None` disables the cache
+    pub key_cache_size: Option<usize>,
+    /// Largest request frame accepted, `signer_core::rpc::server::DEFAULT_MAX_FRAME_SIZE` if not set
+    pub max_frame_size: Option<usize>,
+    /// Token required by `Reinitialize` requests. All requests are refused if not set
+    pub reinitialize_token: Option<String>,
+    /// Token `Shutdown` requests must pass to stop the signer. Without it only the connection
+    /// sending `Shutdown` is closed
+    pub shutdown_token: Option<String>,
+    /// Connect to the host callback channel at startup. Connections and the startup report
+    /// are pushed as audit records
+    pub callback: Option<CallbackConfig>,
 }
 
 impl App {
     pub fn init(conf: Config) -> Result<Self, Error> {
         let secm = nsm::NSM::open()?;
-        nsm::seed_rng(&secm, nsm::DEFAULT_ENTROPY_BYTE_SZ)?;
+        let entropy_bytes = conf.entropy_bytes.unwrap_or(nsm::DEFAULT_ENTROPY_BYTE_SZ);
+        if entropy_bytes != 0 {
+            nsm::seed_rng(&secm, entropy_bytes)?;
+        }
+
+        let insecure = InsecureOptions {
+            passthrough_backend: false,
+            import_unencrypted: conf.import_unencrypted,
+            export_wrapped: conf.export_wrapped,
+            rewrap_key_id: conf.rewrap_key_id,
+            // PCRs of an enclave started with the console attached are zeroed
+            debug: secm.describe_pcr(0)?.iter().all(|&b| b == 0),
+        };
 
-        let mut shared_sm = SharedNSM::new(secm);
+        let mut shared_sm = SharedNSM::new(secm).with_user_data(insecure.to_cbor());
         let priv_key = rsa::RsaPrivateKey::new(&mut shared_sm, RSA_BITS)?;
 
         Ok(Self {
             priv_key,
             conf,
             secm: shared_sm,
+            entropy_seeded: entropy_bytes,
+            insecure,
+            started: Instant::now(),
         })
     }
 
@@ -88,22 +192,199 @@ impl App {
             self.conf.listen_port.unwrap_or(DEFAULT_VSOCK_PORT),
         );
 
+        // shared by all connections
+        let hedging = self.conf.hedge_percentile.map(|percentile| {
+            Hedging::new(HedgeConfig {
+                percentile,
+                ..Default::default()
+            })
+        });
+
+        // keys outlive connections unless scoped to them
+        let keychains = KeychainNamespaces::new();
+        // a retried request comes from the same peer on a new connection
+        let mut idempotency_caches: HashMap<u32, Arc<IdempotencyCache>> = HashMap::new();
+        // subscribers see the events of all connections
+        let events = EventBus::default();
+
         let listener = vsock::asio::Listener::bind(&listen_addr)?;
+
+        // never leaves the enclave
+        let noise_key = Arc::new(noise::StaticKey::generate(&mut self.secm.clone()));
+        let secm = self.secm.clone().with_channel_key(noise_key.public_key());
+        let noise_addr = self
+            .conf
+            .noise_port
+            .map(|port| vsock::SocketAddr::new(vsock::VMADDR_CID_ANY, port));
+        let noise_listener = match &noise_addr {
+            Some(addr) => Some(vsock::asio::Listener::bind(addr)?),
+            None => None,
+        };
+
+        let mut features = Vec::new();
+        if hedging.is_some() {
+            features.push("kms_hedging");
+        }
+        if self.entropy_seeded != 0 {
+            features.push("entropy_seeding");
+        }
+        if self.conf.heartbeat_interval.is_some() {
+            features.push("heartbeat");
+        }
+        if cfg!(feature = "reproducible") {
+            features.push("reproducible");
+        }
+        if self.conf.callback.is_some() {
+            features.push("callback");
+        }
+        if self.conf.key_cache_size.is_some() {
+            features.push("key_cache");
+        }
+        match self.conf.keychain_scope {
+            KeychainScope::Connection => (),
+            KeychainScope::Shared => features.push("shared_keychain"),
+            KeychainScope::Peer => features.push("peer_keychain"),
+        }
+        if noise_listener.is_some() {
+            features.push("noise");
+        }
+        if COMPILED_ALLOWLIST.is_some_and(|s| !s.trim().is_empty()) {
+            features.push("request_allowlist");
+        }
+        let report = StartupReport::new(
+            &self.secm,
+            features,
+            [Some(listen_addr), noise_addr]
+                .iter()
+                .flatten()
+                .map(ToString::to_string)
+                .collect(),
+            self.entropy_seeded,
+            self.insecure,
+        )?;
+        println!("{}", report);
+
+        let callback = match &self.conf.callback {
+            Some(conf) => {
+                let addr =
+                    vsock::SocketAddr::new(conf.cid.unwrap_or(DEFAULT_CALLBACK_CID), conf.port);
+                let conn = vsock::asio::Stream::connect(&addr).await?;
+                let mut ch =
+                    callback::Channel::handshake(conn, &conf.key, &mut self.secm.clone()).await?;
+                println!("callback channel connected to {}", addr);
+                if let Ok(cb) = Callback::audit("startup", &report) {
+                    ch.call::<()>(&cb).await?;
+                }
+                Some(Arc::new(tokio::sync::Mutex::new(ch)))
+            }
+            None => None,
+        };
+
         loop {
-            let (conn, addr) = listener.accept().await?;
-            println!("incoming connection from {}", addr);
+            let ((conn, addr), encrypted) = tokio::select! {
+                res = listener.accept() => (res?, false),
+                res = async {
+                    match &noise_listener {
+                        Some(l) => l.accept().await,
+                        None => std::future::pending().await,
+                    }
+                } => (res?, true),
+            };
+            println!(
+                "incoming {}connection from {}",
+                if encrypted { "encrypted " } else { "" },
+                addr
+            );
+            if let Some(ch) = &callback {
+                tokio::spawn(audit(ch.clone(), "connection", addr.to_string()));
+            }
 
             let ccfg = client_conf.clone();
-            let secm = self.secm.clone();
+            let secm = secm.clone();
+            let noise_key = encrypted.then(|| noise_key.clone());
+            let hedging = hedging.clone();
+            let heartbeat_interval = self.conf.heartbeat_interval;
+            let key_cache_size = self.conf.key_cache_size;
+            let max_frame_size = self.conf.max_frame_size;
+            let reinitialize_token = self.conf.reinitialize_token.clone();
+            let shutdown_token = self.conf.shutdown_token.clone();
+            let insecure = self.insecure;
+            let started = self.started;
+            let callback = callback.clone();
+            let peer = addr.to_string();
+            let keychain = match self.conf.keychain_scope {
+                KeychainScope::Connection => None,
+                KeychainScope::Shared => Some(keychains.get("")),
+                KeychainScope::Peer => Some(keychains.get(&addr.cid().to_string())),
+            };
+            let idempotency_cache = idempotency_caches.entry(addr.cid()).or_default().clone();
+            let events = events.clone();
 
             tokio::spawn(async move {
+                let mut rng = secm.clone();
                 let cf = ClientFactory::new(ccfg, aws_config::load_from_env().await, secm.clone());
-                let mut srv = Server::new(cf, secm);
+                let mut srv = Server::new(HedgedFactory::new(cf, hedging.clone()), secm.clone())
+                    .with_attestation_source(Arc::new(secm))
+                    .with_idempotency_cache(idempotency_cache)
+                    .with_event_bus(events)
+                    .with_insecure_options(insecure)
+                    .with_start_time(started);
+                if let Some(interval) = heartbeat_interval {
+                    srv = srv.with_heartbeat_interval(interval);
+                }
+                if let Some(size) = key_cache_size {
+                    srv = srv.with_key_cache_size(size);
+                }
+                if let Some(size) = max_frame_size {
+                    srv = srv.with_max_frame_size(size);
+                }
+                if let Some(token) = reinitialize_token {
+                    srv = srv.with_reinitialize_token(token);
+                }
+                if let Some(token) = shutdown_token {
+                    srv = srv.with_shutdown_token(token);
+                }
+                if let Some(keychain) = keychain {
+                    srv = srv.with_shared_keychain(keychain);
+                }
 
-                if let Err(err) = srv.serve_connection(conn).await {
+                let res = match noise_key {
+                    Some(key) => match noise::accept(conn, &key, &mut rng).await {
+                        Ok((conn, _)) => srv.serve_connection(conn).await,
+                        Err(err) => {
+                            eprintln!("noise handshake with {} failed: {}", peer, err);
+                            return;
+                        }
+                    },
+                    None => srv.serve_connection(conn).await,
+                };
+                if let Err(err) = res {
                     eprintln!("{}", err);
                 }
+                if let Some(h) = hedging {
+                    println!("KMS hedging stats: {:?}", h.stats());
+                }
+                if srv.is_shutdown_authorized() {
+                    // retire the enclave once the host has the audit record
+                    println!("shutdown requested by {}", peer);
+                    if let Some(ch) = callback {
+                        audit(ch, "shutdown", peer).await;
+                    }
+                    std::process::exit(0);
+                } else if srv.is_shut_down() {
+                    println!("connection shut down by {}", peer);
+                }
             });
         }
     }
 }
+
+async fn audit(ch: CallbackChannel, event: &str, peer: String) {
+    let Ok(cb) = Callback::audit(event, &peer) else {
+        return;
+    };
+    // the signer keeps serving if the host is gone
+    if let Err(err) = ch.lock().await.call::<()>(&cb).await {
+        eprintln!("callback error: {}", err);
+    }
+}
@@ -1,8 +1,10 @@
 pub mod app;
 pub mod nsm;
+pub mod report;
 
 use nitro_signer::tokio;
 use std::env;
+use std::time::Duration;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
@@ -17,8 +19,79 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             .ok()
             .map(|s| s.parse().ok())
             .flatten(),
+        noise_port: env::var("NOISE_PORT")
+            .ok()
+            .map(|s| s.parse().ok())
+            .flatten(),
+        entropy_bytes: env::var("ENTROPY_BYTES")
+            .ok()
+            .map(|s| s.parse().ok())
+            .flatten(),
+        hedge_percentile: env::var("KMS_HEDGE_PERCENTILE")
+            .ok()
+            .map(|s| s.parse().ok())
+            .flatten(),
+        heartbeat_interval: env::var("HEARTBEAT_INTERVAL_MS")
+            .ok()
+            .map(|s| s.parse().ok().map(Duration::from_millis))
+            .flatten(),
+        import_unencrypted: env::var("IMPORT_UNENCRYPTED")
+            .ok()
+            .map(|s| s.parse().ok())
+            .flatten()
+            .unwrap_or(false),
+        export_wrapped: env::var("EXPORT_WRAPPED")
+            .ok()
+            .map(|s| s.parse().ok())
+            .flatten()
+            .unwrap_or(false),
+        rewrap_key_id: env::var("REWRAP_KEY_ID")
+            .ok()
+            .map(|s| s.parse().ok())
+            .flatten()
+            .unwrap_or(false),
+        key_cache_size: env::var("KEY_CACHE_SIZE")
+            .ok()
+            .map(|s| s.parse().ok())
+            .flatten(),
+        max_frame_size: env::var("MAX_FRAME_SIZE")
+            .ok()
+            .map(|s| s.parse().ok())
+            .flatten(),
+        // the Dockerfiles set empty values for unset build args
+        reinitialize_token: env::var("REINITIALIZE_TOKEN")
+            .ok()
+            .filter(|s| !s.is_empty()),
+        shutdown_token: env::var("SHUTDOWN_TOKEN").ok().filter(|s| !s.is_empty()),
+        keychain_scope: match env::var("KEYCHAIN_SCOPE").ok().as_deref() {
+            Some("shared") => app::KeychainScope::Shared,
+            Some("peer") => app::KeychainScope::Peer,
+            _ => app::KeychainScope::Connection,
+        },
+        // the Dockerfiles set empty values for unset build args
+        callback: match (
+            env::var("CALLBACK_PORT").ok().filter(|s| !s.is_empty()),
+            env::var("CALLBACK_KEY").ok().filter(|s| !s.is_empty()),
+        ) {
+            (Some(port), Some(key)) => Some(app::CallbackConfig {
+                cid: env::var("CALLBACK_CID")
+                    .ok()
+                    .map(|s| s.parse().ok())
+                    .flatten(),
+                port: port.parse()?,
+                key: parse_hex(&key).ok_or("CALLBACK_KEY must be hex encoded")?,
+            }),
+            _ => None,
+        },
     };
 
     let app = app::App::init(conf)?;
     app.run().await.map_err(Into::into)
 }
+
+fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
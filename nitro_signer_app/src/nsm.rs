@@ -8,6 +8,7 @@ use nitro_signer::{
         pkcs8::{spki, EncodePublicKey},
         rand_core,
     },
+    signer_core::{attestation::AttestationSource, noise},
 };
 use std::{
     alloc::{alloc, dealloc, Layout},
@@ -16,6 +17,7 @@ use std::{
     sync::Arc,
 };
 
+#[derive(Debug)]
 pub struct NSM(OwnedFd);
 
 impl NSM {
@@ -39,7 +41,16 @@ impl NSM {
             Some(key) => Some(key.to_public_key_der()?.into_vec()),
             None => None,
         };
+        self.attest_raw(user_data, nonce, pk)
+    }
 
+    /// Same as `attest` with the public key passed as is
+    pub fn attest_raw(
+        &self,
+        user_data: Option<&[u8]>,
+        nonce: Option<&[u8]>,
+        pk: Option<Vec<u8>>,
+    ) -> Result<Vec<u8>, Error> {
         let req = Request::Attestation {
             user_data: user_data.map(|v| Vec::from(v).into()),
             nonce: nonce.map(|v| Vec::from(v).into()),
@@ -53,6 +64,14 @@ impl NSM {
         }
     }
 
+    pub fn describe_pcr(&self, index: u16) -> Result<Vec<u8>, Error> {
+        match nsm_process_request(self.0.as_raw_fd(), Request::DescribePCR { index }) {
+            Response::DescribePCR { data, .. } => Ok(data),
+            Response::Error(error_code) => Err(Error::NSM(error_code)),
+            _ => Err(Error::ResponseType),
+        }
+    }
+
     pub fn get_random_vec(&self) -> Result<Vec<u8>, Error> {
         match nsm_process_request(self.0.as_raw_fd(), Request::GetRandom) {
             Response::GetRandom { random } => Ok(random),
@@ -113,12 +132,28 @@ impl std::error::Error for Error {
     }
 }
 
-#[derive(Clone)]
-pub struct SharedNSM(Arc<NSM>);
+#[derive(Debug, Clone)]
+pub struct SharedNSM(Arc<NSM>, Option<Arc<[u8]>>, Option<[u8; noise::KEY_SIZE]>);
 
 impl SharedNSM {
     pub fn new(nsm: NSM) -> Self {
-        Self(Arc::new(nsm))
+        Self(Arc::new(nsm), None, None)
+    }
+
+    /// Sets the Noise channel key reported as the public key of `Attest` documents
+    pub fn with_channel_key(mut self, key: [u8; noise::KEY_SIZE]) -> Self {
+        self.2 = Some(key);
+        self
+    }
+
+    /// Sets the user data embedded into attestation documents
+    pub fn with_user_data(mut self, user_data: Vec<u8>) -> Self {
+        self.1 = Some(user_data.into());
+        self
+    }
+
+    pub fn describe_pcr(&self, index: u16) -> Result<Vec<u8>, Error> {
+        self.0.describe_pcr(index)
     }
 }
 
@@ -155,7 +190,20 @@ impl CryptoRng for SharedNSM {}
 impl Attester for SharedNSM {
     type Error = Error;
     fn attest(&self, pk: &rsa::RsaPublicKey) -> Result<Vec<u8>, Self::Error> {
-        self.0.attest(None, None, Some(pk))
+        self.0.attest(self.1.as_deref(), None, Some(pk))
+    }
+}
+
+impl AttestationSource for SharedNSM {
+    fn attest(
+        &self,
+        nonce: &[u8],
+        user_data: &[u8],
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+        let opt = |v: &[u8]| if v.is_empty() { None } else { Some(v) };
+        Ok(self
+            .0
+            .attest_raw(opt(user_data), opt(nonce), self.2.map(Vec::from))?)
     }
 }
 
@@ -0,0 +1,53 @@
+use crate::nsm::{self, SharedNSM};
+use nitro_signer::prelude::InsecureOptions;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// PCRs reported at startup: enclave image, kernel, application and signing certificate
+pub const REPORTED_PCRS: &[u16] = &[0, 1, 2, 8];
+
+/// Startup report printed as a single JSON line so console capture tooling can check the enclave state
+#[derive(Debug, Serialize)]
+pub struct StartupReport {
+    pub name: &'static str,
+    pub version: &'static str,
+    pub features: Vec<&'static str>,
+    pub listen: Vec<String>,
+    /// Hex encoded PCR values
+    pub pcrs: BTreeMap<u16, String>,
+    /// Number of NSM random bytes fed into the kernel entropy pool
+    pub entropy_seeded: usize,
+    /// Insecure options also embedded into attestation documents
+    pub insecure: InsecureOptions,
+}
+
+impl StartupReport {
+    pub fn new(
+        secm: &SharedNSM,
+        features: Vec<&'static str>,
+        listen: Vec<String>,
+        entropy_seeded: usize,
+        insecure: InsecureOptions,
+    ) -> Result<Self, nsm::Error> {
+        let mut pcrs = BTreeMap::new();
+        for &index in REPORTED_PCRS {
+            let value = secm.describe_pcr(index)?;
+            pcrs.insert(index, value.iter().map(|b| format!("{:02x}", b)).collect());
+        }
+        Ok(Self {
+            name: env!("CARGO_PKG_NAME"),
+            version: env!("CARGO_PKG_VERSION"),
+            features,
+            listen,
+            pcrs,
+            entropy_seeded,
+            insecure,
+        })
+    }
+}
+
+impl std::fmt::Display for StartupReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&serde_json::to_string(self).map_err(|_| std::fmt::Error)?)
+    }
+}
@@ -1,9 +1,13 @@
 use nitro_signer::{
     rand_core,
-    signer_core::{rpc::server::Server, EncryptionBackend, EncryptionBackendFactory},
+    signer_core::{
+        rpc::{server::Server, InsecureOptions},
+        CredentialsSchema, EncryptionBackend, EncryptionBackendFactory,
+    },
     tokio,
 };
 use serde::{Deserialize, Serialize};
+use signer_core::cddl::Cddl;
 use std::{convert::Infallible, io, net::SocketAddr};
 
 pub struct App {}
@@ -42,9 +46,13 @@ impl EncryptionBackend for Passthrough {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Cddl)]
 struct DummyCredentials {} // serialized as empty object instead of null for unity
 
+impl CredentialsSchema for DummyCredentials {
+    const VERSION: u32 = 1;
+}
+
 struct PassthroughFactory;
 
 impl EncryptionBackendFactory for PassthroughFactory {
@@ -61,7 +69,14 @@ impl App {
         loop {
             let (conn, _) = listener.accept().await?;
             tokio::spawn(async move {
-                let mut srv = Server::new(PassthroughFactory, rand_core::OsRng);
+                let mut srv = Server::new(PassthroughFactory, rand_core::OsRng)
+                    .with_insecure_options(InsecureOptions {
+                        passthrough_backend: true,
+                        import_unencrypted: true,
+                        export_wrapped: true,
+                        rewrap_key_id: true,
+                        ..Default::default()
+                    });
 
                 if let Err(err) = srv.serve_connection(conn).await {
                     eprintln!("{}", err);
@@ -0,0 +1,114 @@
+//! Response framing: the former two buffer path serializing the payload and then copying it
+//! behind the length prefix against `rpc::encode_frame` writing the payload in place.
+//!
+//! Besides timings prints the number of allocations and copied bytes per frame
+//! for a fresh connection (empty buffers) and for a connection with warmed up buffers.
+
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use serde::Serialize;
+use signer_core::crypto::{KeyPair, KeyType, PrivateKey};
+use signer_core::rpc::{self, Result as RPCResult};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+/// Returns the number of bytes copied behind the prefix
+fn two_buffers<T: Serialize>(buf: &mut Vec<u8>, w_buf: &mut Vec<u8>, value: &T) -> usize {
+    buf.clear();
+    ciborium::into_writer(value, &mut *buf).unwrap();
+    let len = u32::try_from(buf.len()).unwrap().to_be_bytes();
+    w_buf.clear();
+    w_buf.extend_from_slice(&len);
+    w_buf.extend_from_slice(buf);
+    buf.len()
+}
+
+fn in_place<T: Serialize>(buf: &mut Vec<u8>, value: &T) -> usize {
+    rpc::encode_frame(buf, value).unwrap();
+    0
+}
+
+fn count<F: FnMut() -> usize>(mut f: F) -> (usize, usize) {
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    let copied = f();
+    (ALLOCATIONS.load(Ordering::Relaxed) - before, copied)
+}
+
+fn report<T: Serialize>(name: &str, value: &T) {
+    let (mut buf, mut w_buf) = (Vec::new(), Vec::new());
+    let (allocs, copied) = count(|| two_buffers(&mut buf, &mut w_buf, value));
+    println!("{name}/two_buffers: fresh {allocs} allocations, {copied} bytes copied");
+    let (allocs, copied) = count(|| two_buffers(&mut buf, &mut w_buf, value));
+    println!("{name}/two_buffers: warm {allocs} allocations, {copied} bytes copied");
+
+    let mut buf = Vec::new();
+    let (allocs, copied) = count(|| in_place(&mut buf, value));
+    println!("{name}/in_place: fresh {allocs} allocations, {copied} bytes copied");
+    let (allocs, copied) = count(|| in_place(&mut buf, value));
+    println!("{name}/in_place: warm {allocs} allocations, {copied} bytes copied");
+}
+
+fn bench_value<T: Serialize>(c: &mut Criterion, name: &str, value: &T) {
+    report(name, value);
+
+    let mut group = c.benchmark_group(name);
+    group.bench_function("two_buffers/warm", |b| {
+        let (mut buf, mut w_buf) = (Vec::new(), Vec::new());
+        b.iter(|| two_buffers(&mut buf, &mut w_buf, black_box(value)))
+    });
+    group.bench_function("in_place/warm", |b| {
+        let mut buf = Vec::new();
+        b.iter(|| in_place(&mut buf, black_box(value)))
+    });
+    group.bench_function("two_buffers/fresh", |b| {
+        b.iter_batched_ref(
+            || (Vec::new(), Vec::new()),
+            |(buf, w_buf)| two_buffers(buf, w_buf, black_box(value)),
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("in_place/fresh", |b| {
+        b.iter_batched_ref(
+            Vec::new,
+            |buf| in_place(buf, black_box(value)),
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+fn framing(c: &mut Criterion) {
+    // typical high QPS response
+    let sk = PrivateKey::generate(KeyType::Secp256k1, &mut rand_core::OsRng).unwrap();
+    let sig: RPCResult<_> = Ok(sk.try_sign(b"message", Default::default()).unwrap());
+    bench_value(c, "signature", &sig);
+
+    // large response
+    let describe: RPCResult<_> = Ok(rpc::describe::<()>());
+    bench_value(c, "describe", &describe);
+}
+
+criterion_group!(benches, framing);
+criterion_main!(benches);
@@ -0,0 +1,27 @@
+//! Attestation documents requested by the host over the RPC channel, see `Request::Attest`
+
+/// Source of attestation documents, e.g. the Nitro Secure Module
+pub trait AttestationSource: std::fmt::Debug + Send + Sync {
+    /// Returns the document binding the verifier's `nonce` and `user_data`. Empty values are
+    /// left out of the document
+    fn attest(
+        &self,
+        nonce: &[u8],
+        user_data: &[u8],
+    ) -> Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>>;
+}
+
+#[derive(Debug)]
+pub struct Error(pub Box<dyn std::error::Error + Send + Sync>);
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("attestation error")
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(self.0.as_ref())
+    }
+}
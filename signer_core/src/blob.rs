@@ -0,0 +1,151 @@
+//! Host side representation of encrypted key blobs
+//!
+//! A `BlobCodec` is applied to the output of the encryption backend before the blob is returned to the host
+//! and reverted before the blob is decrypted. It allows adding storage specific framing, integrity checks
+//! or compression without changing the encryption format.
+use subtle::ConstantTimeEq;
+
+pub trait BlobCodec: std::fmt::Debug + Send + Sync {
+    fn encode(&self, blob: Vec<u8>) -> Vec<u8>;
+    fn decode(&self, data: &[u8]) -> Result<Vec<u8>, Error>;
+}
+
+/// Compares blobs in constant time. Only the length is leaked
+pub fn ct_eq(a: &[u8], b: &[u8]) -> bool {
+    a.ct_eq(b).into()
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Magic,
+    Version(u8),
+    Length,
+    Checksum,
+    Codec(Box<dyn std::error::Error + Send + Sync>),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Magic => f.write_str("unknown blob format"),
+            Error::Version(v) => write!(f, "unsupported blob version {}", v),
+            Error::Length => f.write_str("invalid blob length"),
+            Error::Checksum => f.write_str("blob checksum mismatch"),
+            Error::Codec(_) => f.write_str("blob codec error"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Codec(err) => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// Passes blobs unchanged
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Raw;
+
+impl BlobCodec for Raw {
+    fn encode(&self, blob: Vec<u8>) -> Vec<u8> {
+        blob
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        Ok(data.into())
+    }
+}
+
+/// Prepends the header containing the format magic, version, payload length and CRC-32 of the payload:
+///
+/// ```text
+/// "ESB" | version: u8 | length: u32 BE | crc32: u32 BE | payload
+/// ```
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Framed;
+
+const MAGIC: &[u8; 3] = b"ESB";
+const VERSION: u8 = 1;
+const HEADER_SIZE: usize = 12;
+
+impl BlobCodec for Framed {
+    fn encode(&self, blob: Vec<u8>) -> Vec<u8> {
+        let mut out = Vec::with_capacity(HEADER_SIZE + blob.len());
+        out.extend_from_slice(MAGIC);
+        out.push(VERSION);
+        out.extend_from_slice(&u32::try_from(blob.len()).unwrap().to_be_bytes());
+        out.extend_from_slice(&crc32(&blob).to_be_bytes());
+        out.extend_from_slice(&blob);
+        out
+    }
+
+    fn decode(&self, data: &[u8]) -> Result<Vec<u8>, Error> {
+        if data.len() < HEADER_SIZE {
+            return Err(Error::Length);
+        }
+        if &data[..3] != MAGIC {
+            return Err(Error::Magic);
+        }
+        if data[3] != VERSION {
+            return Err(Error::Version(data[3]));
+        }
+        let len = u32::from_be_bytes(data[4..8].try_into().unwrap()) as usize;
+        let sum = u32::from_be_bytes(data[8..12].try_into().unwrap());
+        let payload = &data[HEADER_SIZE..];
+        if payload.len() != len {
+            return Err(Error::Length);
+        }
+        if crc32(payload) != sum {
+            return Err(Error::Checksum);
+        }
+        Ok(payload.into())
+    }
+}
+
+/// CRC-32/ISO-HDLC as used by zlib and PNG
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{crc32, ct_eq, BlobCodec, Error, Framed};
+
+    #[test]
+    fn framed() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+
+        let encoded = Framed.encode(b"blob".to_vec());
+        assert_eq!(Framed.decode(&encoded).unwrap(), b"blob");
+
+        let mut corrupted = encoded.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert!(matches!(Framed.decode(&corrupted), Err(Error::Checksum)));
+        assert!(matches!(
+            Framed.decode(&encoded[..encoded.len() - 1]),
+            Err(Error::Length)
+        ));
+        assert!(matches!(Framed.decode(b"blob"), Err(Error::Length)));
+    }
+
+    #[test]
+    fn blob_ct_eq() {
+        assert!(ct_eq(b"blob", b"blob"));
+        assert!(!ct_eq(b"blob", b"blub"));
+        assert!(!ct_eq(b"blob", b"blob2"));
+    }
+}
@@ -0,0 +1,390 @@
+//! Callback channel opened by the enclave to the host
+//!
+//! Carries flows initiated by the enclave (credential refresh, audit records, snapshot upload)
+//! independently of the client driven RPC connection. Frames use the same length prefix as the RPC
+//! protocol. In the handshake both peers prove the knowledge of the pre-shared key, every following
+//! frame is authenticated with the session key derived from it and both nonces.
+use crate::rpc::{self, codec};
+use crate::serde_helper::bytes;
+use crate::{TryFromCBOR, TryIntoCBOR};
+use hmac::{Hmac, Mac};
+use rand_core::CryptoRngCore;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::Sha256;
+use std::io;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use zeroize::Zeroizing;
+
+pub const PROTOCOL_VERSION: u32 = 1;
+pub const NONCE_SIZE: usize = 32;
+/// Frames longer than this are rejected before reading the payload
+pub const MAX_FRAME_SIZE: usize = 16 * 1024 * 1024;
+
+type HmacSha256 = Hmac<Sha256>;
+
+#[derive(Debug)]
+pub enum Error {
+    IO(io::Error),
+    Serialize(ciborium::ser::Error<io::Error>),
+    Deserialize(ciborium::de::Error<io::Error>),
+    /// The peer failed to prove the knowledge of the key or a frame MAC doesn't match
+    Authentication,
+    UnsupportedVersion(u32),
+    FrameTooLarge(usize),
+    /// A frame arrived out of order
+    Sequence {
+        expected: u64,
+        got: u64,
+    },
+    /// The host failed to handle the callback
+    Host(rpc::Error),
+}
+
+impl From<io::Error> for Error {
+    fn from(value: io::Error) -> Self {
+        Error::IO(value)
+    }
+}
+
+impl From<ciborium::ser::Error<io::Error>> for Error {
+    fn from(value: ciborium::ser::Error<io::Error>) -> Self {
+        Error::Serialize(value)
+    }
+}
+
+impl From<ciborium::de::Error<io::Error>> for Error {
+    fn from(value: ciborium::de::Error<io::Error>) -> Self {
+        Error::Deserialize(value)
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::IO(error) => write!(f, "IO error: {}", error),
+            Error::Serialize(error) => write!(f, "serialization error: {}", error),
+            Error::Deserialize(error) => write!(f, "deserialization error: {}", error),
+            Error::Authentication => f.write_str("callback channel authentication failed"),
+            Error::UnsupportedVersion(v) => {
+                write!(f, "unsupported callback protocol version: {}", v)
+            }
+            Error::FrameTooLarge(len) => write!(f, "callback frame is too large: {}", len),
+            Error::Sequence { expected, got } => write!(
+                f,
+                "unexpected callback frame sequence number {}, expected {}",
+                got, expected
+            ),
+            Error::Host(error) => write!(f, "host error: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Requests sent by the enclave
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Callback {
+    /// Asks the host for fresh encryption backend credentials
+    RefreshCredentials,
+    /// Audit record pushed by the enclave
+    Audit {
+        event: String,
+        details: ciborium::Value,
+    },
+    /// Opaque state snapshot to be stored by the host
+    Snapshot(#[serde(with = "bytes")] Vec<u8>),
+}
+
+impl Callback {
+    pub fn audit<D: Serialize>(event: &str, details: &D) -> Result<Self, ciborium::value::Error> {
+        Ok(Callback::Audit {
+            event: event.into(),
+            details: ciborium::Value::serialized(details)?,
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Hello {
+    version: u32,
+    #[serde(with = "bytes")]
+    nonce: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct HelloReply {
+    #[serde(with = "bytes")]
+    nonce: Vec<u8>,
+    /// Proves the knowledge of the key by the host
+    #[serde(with = "bytes")]
+    mac: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Frame {
+    seq: u64,
+    #[serde(with = "bytes")]
+    body: Vec<u8>,
+    #[serde(with = "bytes")]
+    mac: Vec<u8>,
+}
+
+// MAC labels keep host and enclave frames apart so neither can be reflected back
+const LABEL_HOST_PROOF: &[u8] = b"host proof";
+const LABEL_SESSION: &[u8] = b"session";
+const LABEL_ENCLAVE: &[u8] = b"enclave";
+const LABEL_HOST: &[u8] = b"host";
+
+fn mac(key: &[u8], parts: &[&[u8]]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).unwrap();
+    for p in parts {
+        mac.update(p);
+    }
+    mac
+}
+
+#[derive(Debug)]
+struct Session<T> {
+    sock: T,
+    key: Zeroizing<Vec<u8>>,
+    buf: Vec<u8>,
+}
+
+impl<T> Session<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    async fn write<V: Serialize>(sock: &mut T, buf: &mut Vec<u8>, v: &V) -> Result<(), Error> {
+        rpc::encode_frame(buf, v)?;
+        Ok(sock.write_all(buf).await?)
+    }
+
+    async fn read<V: DeserializeOwned>(sock: &mut T, buf: &mut Vec<u8>) -> Result<V, Error> {
+        let len = codec::read_len(sock).await?;
+        if len > MAX_FRAME_SIZE {
+            return Err(Error::FrameTooLarge(len));
+        }
+        codec::read_payload(sock, len, buf).await?;
+        Ok(V::try_from_cbor(buf)?)
+    }
+
+    fn new(sock: T, psk: &[u8], enclave_nonce: &[u8], host_nonce: &[u8]) -> Self {
+        Session {
+            sock,
+            key: Zeroizing::new(
+                mac(psk, &[LABEL_SESSION, enclave_nonce, host_nonce])
+                    .finalize()
+                    .into_bytes()
+                    .to_vec(),
+            ),
+            buf: Vec::new(),
+        }
+    }
+
+    async fn send<V: Serialize>(&mut self, label: &[u8], seq: u64, v: &V) -> Result<(), Error> {
+        let body = v.try_into_cbor()?;
+        let mac = mac(&self.key, &[label, &seq.to_be_bytes(), &body])
+            .finalize()
+            .into_bytes()
+            .to_vec();
+        Self::write(&mut self.sock, &mut self.buf, &Frame { seq, body, mac }).await
+    }
+
+    async fn recv<V: DeserializeOwned>(&mut self, label: &[u8], seq: u64) -> Result<V, Error> {
+        let frame: Frame = Self::read(&mut self.sock, &mut self.buf).await?;
+        mac(&self.key, &[label, &frame.seq.to_be_bytes(), &frame.body])
+            .verify_slice(&frame.mac)
+            .map_err(|_| Error::Authentication)?;
+        if frame.seq != seq {
+            return Err(Error::Sequence {
+                expected: seq,
+                got: frame.seq,
+            });
+        }
+        Ok(V::try_from_cbor(&frame.body)?)
+    }
+}
+
+/// Enclave side of the callback channel
+#[derive(Debug)]
+pub struct Channel<T> {
+    session: Session<T>,
+    seq: u64,
+}
+
+impl<T> Channel<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Performs the handshake over the connection to the host
+    pub async fn handshake<R: CryptoRngCore>(
+        mut sock: T,
+        psk: &[u8],
+        rng: &mut R,
+    ) -> Result<Self, Error> {
+        let mut buf = Vec::new();
+        let mut nonce = vec![0; NONCE_SIZE];
+        rng.fill_bytes(&mut nonce);
+        Session::write(
+            &mut sock,
+            &mut buf,
+            &Hello {
+                version: PROTOCOL_VERSION,
+                nonce: nonce.clone(),
+            },
+        )
+        .await?;
+        let reply: HelloReply = Session::read(&mut sock, &mut buf).await?;
+        mac(psk, &[LABEL_HOST_PROOF, &nonce, &reply.nonce])
+            .verify_slice(&reply.mac)
+            .map_err(|_| Error::Authentication)?;
+        Ok(Channel {
+            session: Session::new(sock, psk, &nonce, &reply.nonce),
+            seq: 0,
+        })
+    }
+
+    /// Sends the callback and waits for the host's response
+    pub async fn call<V: DeserializeOwned>(&mut self, callback: &Callback) -> Result<V, Error> {
+        let seq = self.seq;
+        self.seq += 1;
+        self.session.send(LABEL_ENCLAVE, seq, callback).await?;
+        self.session
+            .recv::<rpc::Result<V>>(LABEL_HOST, seq)
+            .await?
+            .map_err(Error::Host)
+    }
+}
+
+/// Host side of the callback channel
+#[derive(Debug)]
+pub struct HostChannel<T> {
+    session: Session<T>,
+    seq: u64,
+}
+
+impl<T> HostChannel<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Performs the handshake over the connection accepted from the enclave
+    pub async fn accept<R: CryptoRngCore>(
+        mut sock: T,
+        psk: &[u8],
+        rng: &mut R,
+    ) -> Result<Self, Error> {
+        let mut buf = Vec::new();
+        let hello: Hello = Session::read(&mut sock, &mut buf).await?;
+        if hello.version != PROTOCOL_VERSION {
+            return Err(Error::UnsupportedVersion(hello.version));
+        }
+        let mut nonce = vec![0; NONCE_SIZE];
+        rng.fill_bytes(&mut nonce);
+        let proof = mac(psk, &[LABEL_HOST_PROOF, &hello.nonce, &nonce])
+            .finalize()
+            .into_bytes()
+            .to_vec();
+        Session::write(
+            &mut sock,
+            &mut buf,
+            &HelloReply {
+                nonce: nonce.clone(),
+                mac: proof,
+            },
+        )
+        .await?;
+        Ok(HostChannel {
+            session: Session::new(sock, psk, &hello.nonce, &nonce),
+            seq: 0,
+        })
+    }
+
+    /// Waits for the next callback. `Ok(None)` is returned if the enclave closed the connection.
+    /// A frame of an enclave which doesn't know the key fails with `Error::Authentication`
+    pub async fn next(&mut self) -> Result<Option<Callback>, Error> {
+        match self.session.recv(LABEL_ENCLAVE, self.seq).await {
+            Err(Error::IO(err)) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(None),
+            res => res.map(Some),
+        }
+    }
+
+    /// Responds to the callback returned by `next`
+    pub async fn respond<V: Serialize>(&mut self, res: &rpc::Result<V>) -> Result<(), Error> {
+        let seq = self.seq;
+        self.seq += 1;
+        self.session.send(LABEL_HOST, seq, res).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Callback, Channel, Error, HostChannel};
+    use crate::rpc;
+    use tokio::net::UnixStream;
+
+    #[tokio::test]
+    async fn callback_channel() {
+        let (enclave_sock, host_sock) = UnixStream::pair().unwrap();
+        let psk = b"pre-shared key";
+
+        futures::join!(
+            async move {
+                let mut host = HostChannel::accept(host_sock, psk, &mut rand_core::OsRng)
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    host.next().await.unwrap(),
+                    Some(Callback::Snapshot(b"state".to_vec()))
+                );
+                host.respond(&rpc::Result::Ok(())).await.unwrap();
+                assert_eq!(
+                    host.next().await.unwrap(),
+                    Some(Callback::RefreshCredentials)
+                );
+                host.respond::<()>(&Err(rpc::Error {
+                    message: "no credentials".into(),
+                    source: None,
+                    code: None,
+                    trace_id: None,
+                }))
+                .await
+                .unwrap();
+                assert_eq!(host.next().await.unwrap(), None);
+            },
+            async move {
+                let mut ch = Channel::handshake(enclave_sock, psk, &mut rand_core::OsRng)
+                    .await
+                    .unwrap();
+                ch.call::<()>(&Callback::Snapshot(b"state".to_vec()))
+                    .await
+                    .unwrap();
+                let err = ch
+                    .call::<()>(&Callback::RefreshCredentials)
+                    .await
+                    .unwrap_err();
+                assert!(matches!(err, Error::Host(e) if e.message == "no credentials"));
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn callback_channel_wrong_key() {
+        let (enclave_sock, host_sock) = UnixStream::pair().unwrap();
+
+        futures::join!(
+            async move {
+                // the handshake is completed by the host, the enclave rejects its proof
+                let mut host = HostChannel::accept(host_sock, b"host key", &mut rand_core::OsRng)
+                    .await
+                    .unwrap();
+                assert!(host.next().await.unwrap().is_none());
+            },
+            async move {
+                let err = Channel::handshake(enclave_sock, b"enclave key", &mut rand_core::OsRng)
+                    .await
+                    .unwrap_err();
+                assert!(matches!(err, Error::Authentication));
+            }
+        );
+    }
+}
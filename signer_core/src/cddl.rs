@@ -0,0 +1,158 @@
+//! CDDL ([RFC 8610](https://www.rfc-editor.org/rfc/rfc8610)) description of the wire types
+use std::collections::HashMap;
+use std::fmt::Display;
+
+pub use signer_core_derive::Cddl;
+
+/// Types having a CDDL representation matching their serde encoding
+pub trait Cddl {
+    /// Returns the type expression, registering all rules it depends on in the schema
+    fn cddl_type(schema: &mut Schema) -> String;
+}
+
+/// Ordered set of CDDL rules
+#[derive(Debug, Default)]
+pub struct Schema {
+    rules: Vec<(String, Option<String>)>,
+    index: HashMap<String, usize>,
+}
+
+impl Schema {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers the rule if it's not defined yet and returns its name.
+    /// The name is reserved before `f` is called which allows recursive types.
+    pub fn define<F>(&mut self, name: &str, f: F) -> String
+    where
+        F: FnOnce(&mut Schema) -> String,
+    {
+        if !self.index.contains_key(name) {
+            self.index.insert(name.into(), self.rules.len());
+            self.rules.push((name.into(), None));
+            let def = f(self);
+            self.rules[self.index[name]].1 = Some(def);
+        }
+        name.into()
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.index.contains_key(name)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.index
+            .get(name)
+            .and_then(|i| self.rules[*i].1.as_deref())
+    }
+}
+
+impl Display for Schema {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (name, def) in &self.rules {
+            if let Some(def) = def {
+                writeln!(f, "{} = {}", name, def)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+macro_rules! impl_cddl {
+    ($t:expr => $($ty:ty),+) => {
+        $(
+            impl Cddl for $ty {
+                fn cddl_type(_: &mut Schema) -> String {
+                    String::from($t)
+                }
+            }
+        )+
+    };
+}
+
+impl_cddl!("uint" => u8, u16, u32, u64, usize);
+impl_cddl!("int" => i8, i16, i32, i64, isize);
+impl_cddl!("bool" => bool);
+impl_cddl!("tstr" => String, str);
+impl_cddl!("null" => ());
+
+impl<T: Cddl> Cddl for Option<T> {
+    fn cddl_type(schema: &mut Schema) -> String {
+        format!("{} / null", T::cddl_type(schema))
+    }
+}
+
+impl<T: Cddl> Cddl for Vec<T> {
+    fn cddl_type(schema: &mut Schema) -> String {
+        format!("[* {}]", T::cddl_type(schema))
+    }
+}
+
+impl<T: Cddl + ?Sized> Cddl for Box<T> {
+    fn cddl_type(schema: &mut Schema) -> String {
+        T::cddl_type(schema)
+    }
+}
+
+impl<K: Cddl, V: Cddl> Cddl for HashMap<K, V> {
+    fn cddl_type(schema: &mut Schema) -> String {
+        format!(
+            "{{ * {} => {} }}",
+            K::cddl_type(schema),
+            V::cddl_type(schema)
+        )
+    }
+}
+
+impl<K: Cddl, V: Cddl> Cddl for std::collections::BTreeMap<K, V> {
+    fn cddl_type(schema: &mut Schema) -> String {
+        format!(
+            "{{ * {} => {} }}",
+            K::cddl_type(schema),
+            V::cddl_type(schema)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Cddl, Schema};
+    use crate::serde_helper::bytes;
+    use serde::Serialize;
+
+    #[derive(Serialize, Cddl)]
+    struct Node {
+        #[serde(with = "bytes")]
+        data: Vec<u8>,
+        next: Option<Box<Node>>,
+    }
+
+    #[allow(dead_code)]
+    #[derive(Serialize, Cddl)]
+    enum Message<T> {
+        Empty,
+        Value(T),
+        Pair(u8, String),
+        Node { node: Node },
+    }
+
+    #[test]
+    fn derive() {
+        let mut schema = Schema::new();
+        assert_eq!(Message::<u32>::cddl_type(&mut schema), "Message");
+        assert_eq!(
+            schema.get("Message"),
+            Some("\"Empty\" / { Value: uint } / { Pair: [uint, tstr] } / { Node: { node: Node } }")
+        );
+        assert_eq!(
+            schema.get("Node"),
+            Some("{ data: bstr, next: Node / null }")
+        );
+        assert_eq!(
+            schema.to_string(),
+            "Message = \"Empty\" / { Value: uint } / { Pair: [uint, tstr] } / { Node: { node: Node } }\n\
+             Node = { data: bstr, next: Node / null }\n"
+        );
+    }
+}
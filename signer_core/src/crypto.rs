@@ -1,3 +1,7 @@
+use crate::cddl::{Cddl, Schema};
+use crate::lru::Lru;
+use crate::serde_helper::bytes;
+use crate::TryIntoCBOR;
 use blake2::{digest, Blake2b, Digest};
 use rand_core::CryptoRngCore;
 use serde::{Deserialize, Serialize};
@@ -5,10 +9,29 @@ use serde_repr::Deserialize_repr;
 use serde_repr::Serialize_repr;
 pub use signature::Error as SignatureError;
 use signature::{DigestSigner, Signer};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::time::{SystemTime, UNIX_EPOCH};
+use subtle::{Choice, ConstantTimeEq};
+use zeroize::{ZeroizeOnDrop, Zeroizing};
 
 pub mod bls;
+pub mod data_key;
 pub mod ecdsa;
+pub mod ecies;
+pub mod jwk;
+pub mod mnemonic;
+pub mod musig2;
+pub mod raw;
+pub mod seed;
+pub mod shamir;
+pub mod slip10;
+pub mod taproot;
+#[cfg(feature = "tezos")]
+pub mod tezos;
+pub mod wrap;
 
 use ecdsa::NistP256;
 use ecdsa::Secp256k1;
@@ -21,8 +44,17 @@ pub trait KeyPair {
     fn public_key(&self) -> Self::PublicKey;
     fn try_sign(&self, msg: &[u8], version: SigningVersion)
         -> Result<Self::Signature, Self::Error>;
+    /// Sign the already computed message digest of `DIGEST_SIZE` bytes skipping the Blake2b step
+    fn try_sign_prehashed(
+        &self,
+        digest: &[u8],
+        version: SigningVersion,
+    ) -> Result<Self::Signature, Self::Error>;
 }
 
+/// Size of the message digest accepted by `KeyPair::try_sign_prehashed`
+pub const DIGEST_SIZE: usize = 32;
+
 pub trait Random: Sized {
     type Error;
 
@@ -36,14 +68,58 @@ pub trait PossessionProver {
     fn try_prove(&self) -> Result<Self::Proof, Self::Error>;
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Key algorithm. Serialized by the variant name on the wire while the lower case names
+/// used by `Display` and `FromStr` are accepted too, e.g. in configuration files
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash, Cddl)]
 pub enum KeyType {
+    #[serde(alias = "secp256k1")]
     Secp256k1,
+    #[serde(alias = "p256")]
     NistP256,
+    #[serde(alias = "ed25519")]
     Ed25519,
+    #[serde(alias = "bls")]
     Bls,
 }
 
+impl KeyType {
+    pub const ALL: [KeyType; 4] = [
+        KeyType::Secp256k1,
+        KeyType::NistP256,
+        KeyType::Ed25519,
+        KeyType::Bls,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeyType::Secp256k1 => "secp256k1",
+            KeyType::NistP256 => "p256",
+            KeyType::Ed25519 => "ed25519",
+            KeyType::Bls => "bls",
+        }
+    }
+}
+
+impl std::fmt::Display for KeyType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl std::str::FromStr for KeyType {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "secp256k1" | "Secp256k1" => Ok(KeyType::Secp256k1),
+            "p256" | "NistP256" => Ok(KeyType::NistP256),
+            "ed25519" | "Ed25519" => Ok(KeyType::Ed25519),
+            "bls" | "Bls" => Ok(KeyType::Bls),
+            _ => Err(Error::UnknownKeyType(s.into())),
+        }
+    }
+}
+
 #[derive(Serialize_repr, Deserialize_repr, Debug, Clone, PartialEq, Eq)]
 #[repr(u8)]
 pub enum SigningVersion {
@@ -53,13 +129,204 @@ pub enum SigningVersion {
     Latest = 255,
 }
 
+impl Cddl for SigningVersion {
+    fn cddl_type(schema: &mut Schema) -> String {
+        schema.define("SigningVersion", |_| "0 / 1 / 2 / 255".into())
+    }
+}
+
 impl Default for SigningVersion {
     fn default() -> Self {
         SigningVersion::Latest
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// Message digest applied before signing
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Cddl)]
+pub enum DigestAlgorithm {
+    Blake2b256,
+    Sha256,
+    Sha512,
+    Keccak256,
+}
+
+impl DigestAlgorithm {
+    pub fn digest(&self, msg: &[u8]) -> Vec<u8> {
+        match self {
+            DigestAlgorithm::Blake2b256 => Blake2b256::digest(msg).to_vec(),
+            DigestAlgorithm::Sha256 => sha2::Sha256::digest(msg).to_vec(),
+            DigestAlgorithm::Sha512 => sha2::Sha512::digest(msg).to_vec(),
+            DigestAlgorithm::Keccak256 => sha3::Keccak256::digest(msg).to_vec(),
+        }
+    }
+
+    /// Returns the incremental hasher producing the same digest
+    pub fn hasher(&self) -> Hasher {
+        match self {
+            DigestAlgorithm::Blake2b256 => Hasher::Blake2b256(Blake2b256::new()),
+            DigestAlgorithm::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+            DigestAlgorithm::Sha512 => Hasher::Sha512(sha2::Sha512::new()),
+            DigestAlgorithm::Keccak256 => Hasher::Keccak256(sha3::Keccak256::new()),
+        }
+    }
+}
+
+/// Message digest computed over chunks of the message, see `DigestAlgorithm::hasher`
+#[derive(Debug, Clone)]
+pub enum Hasher {
+    Blake2b256(Blake2b256),
+    Sha256(sha2::Sha256),
+    Sha512(sha2::Sha512),
+    Keccak256(sha3::Keccak256),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Blake2b256(d) => d.update(data),
+            Hasher::Sha256(d) => d.update(data),
+            Hasher::Sha512(d) => d.update(data),
+            Hasher::Keccak256(d) => d.update(data),
+        }
+    }
+
+    pub fn finalize(self) -> Vec<u8> {
+        match self {
+            Hasher::Blake2b256(d) => d.finalize().to_vec(),
+            Hasher::Sha256(d) => d.finalize().to_vec(),
+            Hasher::Sha512(d) => d.finalize().to_vec(),
+            Hasher::Keccak256(d) => d.finalize().to_vec(),
+        }
+    }
+}
+
+/// Optional per request signing parameters
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, Cddl)]
+pub struct SignOptions {
+    /// Overrides the key type specific message digest (Blake2b256 for ECDSA and Ed25519).
+    /// Not supported by BLS which hashes the message to the curve itself
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<DigestAlgorithm>,
+    /// Mix fresh randomness into the RFC 6979 nonce (ECDSA only) to harden against fault attacks
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub hedged: bool,
+    /// Sign the EIP-191 personal message (`"\x19Ethereum Signed Message:\n" || len || message`).
+    /// Secp256k1 only, the digest defaults to Keccak256
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub eip191: bool,
+    /// Sign using the RFC 8032 Ed25519ph construction. Ed25519 only, can't be combined with `digest`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ed25519ph: Option<Ed25519ph>,
+    /// Sign with the explicit BLS scheme and domain separation tag instead of the ones implied
+    /// by the signing version. BLS only, can't be combined with `digest`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub bls: Option<bls::BlsOptions>,
+}
+
+/// Ed25519ph parameters. The message is prehashed with SHA-512
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, Cddl)]
+pub struct Ed25519ph {
+    /// Context string of up to 255 bytes
+    #[serde(default, with = "bytes")]
+    pub context: Vec<u8>,
+}
+
+impl SignOptions {
+    pub fn with_digest(mut self, digest: DigestAlgorithm) -> Self {
+        self.digest = Some(digest);
+        self
+    }
+
+    pub fn with_hedged(mut self, hedged: bool) -> Self {
+        self.hedged = hedged;
+        self
+    }
+
+    pub fn with_eip191(mut self, eip191: bool) -> Self {
+        self.eip191 = eip191;
+        self
+    }
+
+    pub fn with_ed25519ph(mut self, context: impl Into<Vec<u8>>) -> Self {
+        self.ed25519ph = Some(Ed25519ph {
+            context: context.into(),
+        });
+        self
+    }
+
+    pub fn with_bls(mut self, options: bls::BlsOptions) -> Self {
+        self.bls = Some(options);
+        self
+    }
+}
+
+/// Prepends the EIP-191 personal message prefix
+pub fn eip191_message(msg: &[u8]) -> Vec<u8> {
+    let mut res = format!("\x19Ethereum Signed Message:\n{}", msg.len()).into_bytes();
+    res.extend_from_slice(msg);
+    res
+}
+
+/// Verifies the aggregate BLS signature of the same message by all of `keys`.
+/// Returns `false` if the signature doesn't match
+pub fn verify_aggregate(
+    keys: &[PublicKey],
+    msg: &[u8],
+    signature: &Signature,
+    version: SigningVersion,
+) -> Result<bool, Error> {
+    let keys = keys
+        .iter()
+        .map(|pk| match pk {
+            PublicKey::Bls(pk) => Ok(pk.clone()),
+            _ => Err(Error::KeyTypeMismatch),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let signature = match signature {
+        Signature::Bls(sig) => sig,
+        _ => return Err(Error::KeyTypeMismatch),
+    };
+    match bls::verify_aggregate(&keys, msg, signature, version) {
+        Ok(()) => Ok(true),
+        Err(Error::Signature(_)) => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Verifies the Ed25519 signatures of the messages by the corresponding keys at once.
+/// The messages are prehashed with Blake2b256 like in `Verifier::verify`.
+/// Returns `false` if any of the signatures doesn't match without telling which one
+pub fn verify_batch<M: AsRef<[u8]>>(
+    keys: &[PublicKey],
+    messages: &[M],
+    signatures: &[Signature],
+) -> Result<bool, Error> {
+    if keys.len() != messages.len() || keys.len() != signatures.len() {
+        return Err(Error::BatchSizeMismatch);
+    }
+    let keys = keys
+        .iter()
+        .map(|pk| match pk {
+            PublicKey::Ed25519(pk) => Ok(*pk),
+            _ => Err(Error::KeyTypeMismatch),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let signatures = signatures
+        .iter()
+        .map(|sig| match sig {
+            Signature::Ed25519(sig) => Ok(*sig),
+            _ => Err(Error::KeyTypeMismatch),
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let digests: Vec<_> = messages
+        .iter()
+        .map(|msg| Blake2b256::digest(msg.as_ref()))
+        .collect();
+    let digests: Vec<&[u8]> = digests.iter().map(|d| &d[..]).collect();
+    Ok(ed25519_dalek::verify_batch(&digests, &signatures, &keys).is_ok())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Cddl)]
 pub enum Signature {
     Secp256k1(ecdsa::Signature<Secp256k1>),
     NistP256(ecdsa::Signature<NistP256>),
@@ -91,7 +358,7 @@ impl From<bls::Signature> for Signature {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Cddl)]
 pub enum ProofOfPossession {
     Bls(bls::ProofOfPossession),
 }
@@ -111,6 +378,26 @@ pub trait ProofVerifier<S> {
 }
 
 pub(crate) type Blake2b256 = Blake2b<digest::consts::U32>;
+pub(crate) type Blake2b160 = Blake2b<digest::consts::U20>;
+
+// ed25519 serializes signatures as a tuple of bytes
+impl Cddl for ed25519::Signature {
+    fn cddl_type(_: &mut Schema) -> String {
+        format!("[{0}*{0} uint]", ed25519::Signature::BYTE_SIZE)
+    }
+}
+
+impl Cddl for ed25519_dalek::SigningKey {
+    fn cddl_type(_: &mut Schema) -> String {
+        format!("bstr .size {}", ed25519_dalek::SECRET_KEY_LENGTH)
+    }
+}
+
+impl Cddl for ed25519_dalek::VerifyingKey {
+    fn cddl_type(_: &mut Schema) -> String {
+        format!("bstr .size {}", ed25519_dalek::PUBLIC_KEY_LENGTH)
+    }
+}
 
 impl KeyPair for ed25519_dalek::SigningKey {
     type PublicKey = ed25519_dalek::VerifyingKey;
@@ -129,9 +416,16 @@ impl KeyPair for ed25519_dalek::SigningKey {
         let d = Blake2b256::digest(msg);
         Ok(Signer::try_sign(self, &d)?)
     }
+    fn try_sign_prehashed(
+        &self,
+        digest: &[u8],
+        _version: SigningVersion,
+    ) -> Result<Self::Signature, Self::Error> {
+        Signer::try_sign(self, digest)
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, Cddl)]
 pub enum PrivateKey {
     Secp256k1(ecdsa::SigningKey<Secp256k1>),
     NistP256(ecdsa::SigningKey<NistP256>),
@@ -139,6 +433,36 @@ pub enum PrivateKey {
     Bls(bls::SigningKey),
 }
 
+// all variants scrub the key material on drop
+impl ZeroizeOnDrop for PrivateKey {}
+
+impl ConstantTimeEq for PrivateKey {
+    fn ct_eq(&self, other: &Self) -> Choice {
+        // the key type is not secret
+        match (self, other) {
+            (PrivateKey::Secp256k1(a), PrivateKey::Secp256k1(b)) => a.0.ct_eq(&b.0),
+            (PrivateKey::NistP256(a), PrivateKey::NistP256(b)) => a.0.ct_eq(&b.0),
+            (PrivateKey::Ed25519(a), PrivateKey::Ed25519(b)) => a.as_bytes().ct_eq(b.as_bytes()),
+            (PrivateKey::Bls(a), PrivateKey::Bls(b)) => {
+                let (a, b) = (
+                    Zeroizing::new(a.0.serialize()),
+                    Zeroizing::new(b.0.serialize()),
+                );
+                a[..].ct_eq(&b[..])
+            }
+            _ => Choice::from(0),
+        }
+    }
+}
+
+const _: () = {
+    const fn zeroize_on_drop<T: ZeroizeOnDrop>() {}
+    zeroize_on_drop::<ecdsa::SigningKey<Secp256k1>>();
+    zeroize_on_drop::<ecdsa::SigningKey<NistP256>>();
+    zeroize_on_drop::<ed25519_dalek::SigningKey>();
+    zeroize_on_drop::<bls::SigningKey>();
+};
+
 impl PrivateKey {
     pub fn generate<R: rand_core::CryptoRngCore>(t: KeyType, r: &mut R) -> Result<Self, Error> {
         match t {
@@ -150,6 +474,147 @@ impl PrivateKey {
                 .map_err(Into::into),
         }
     }
+
+    /// Regenerates the key until its public key satisfies the constraint.
+    /// Returns the key and the number of attempts made
+    pub fn generate_constrained<R: rand_core::CryptoRngCore>(
+        t: KeyType,
+        constraint: &KeyConstraint,
+        r: &mut R,
+    ) -> Result<(Self, u32), Error> {
+        let prefix = constraint.hash_prefix.to_ascii_lowercase();
+        if !prefix.bytes().all(|c| c.is_ascii_hexdigit()) {
+            return Err(Error::InvalidConstraint);
+        }
+        let max_attempts = constraint.max_attempts.min(MAX_GENERATE_ATTEMPTS);
+        for attempt in 1..=max_attempts {
+            let pk = Self::generate(t, r)?;
+            if pk.public_key().hash_hex().starts_with(&prefix) {
+                return Ok((pk, attempt));
+            }
+        }
+        Err(Error::ConstraintNotSatisfied(max_attempts))
+    }
+}
+
+/// Upper bound of `KeyConstraint::max_attempts`
+pub const MAX_GENERATE_ATTEMPTS: u32 = 1 << 20;
+
+/// Public key constraint for `PrivateKey::generate_constrained`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Cddl)]
+pub struct KeyConstraint {
+    /// Required hex prefix of `PublicKey::hash`
+    pub hash_prefix: String,
+    /// Give up after this many keys, capped by `MAX_GENERATE_ATTEMPTS`
+    pub max_attempts: u32,
+}
+
+impl KeyConstraint {
+    /// Creates the constraint allowing up to `MAX_GENERATE_ATTEMPTS` attempts
+    pub fn new(hash_prefix: impl Into<String>) -> Self {
+        Self {
+            hash_prefix: hash_prefix.into(),
+            max_attempts: MAX_GENERATE_ATTEMPTS,
+        }
+    }
+
+    pub fn with_max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts;
+        self
+    }
+}
+
+impl PrivateKey {
+    /// Rejects keys which can't be produced by a sane key generator. Zero and out of range
+    /// ECDSA and BLS scalars are already rejected during deserialization
+    pub fn check_weak(&self) -> Result<(), Error> {
+        match self {
+            PrivateKey::Secp256k1(_) | PrivateKey::NistP256(_) => Ok(()),
+            PrivateKey::Ed25519(val) => {
+                if val.as_bytes().iter().all(|b| *b == 0) || val.verifying_key().is_weak() {
+                    Err(Error::WeakKey)
+                } else {
+                    Ok(())
+                }
+            }
+            PrivateKey::Bls(val) => val.sk_to_pk().validate().or(Err(Error::WeakKey)),
+        }
+    }
+
+    pub fn try_sign_with_options<R: CryptoRngCore>(
+        &self,
+        msg: &[u8],
+        version: SigningVersion,
+        options: &SignOptions,
+        rng: &mut R,
+    ) -> Result<Signature, Error> {
+        if let Some(ph) = &options.ed25519ph {
+            return match self {
+                PrivateKey::Ed25519(val) if options.digest.is_none() => val
+                    .sign_prehashed(sha2::Sha512::new().chain_update(msg), Some(&ph.context))
+                    .map(Into::into)
+                    .map_err(Into::into),
+                _ => Err(Error::Ed25519phUnsupported),
+            };
+        }
+        if let Some(bls) = &options.bls {
+            return match self {
+                PrivateKey::Bls(val) if options.digest.is_none() => {
+                    val.try_sign_with_options(msg, bls).map(Into::into)
+                }
+                _ => Err(Error::BlsOptionsUnsupported),
+            };
+        }
+        let prefixed;
+        let (msg, digest) = if options.eip191 {
+            if !matches!(self, PrivateKey::Secp256k1(_)) {
+                return Err(Error::Eip191Unsupported);
+            }
+            prefixed = eip191_message(msg);
+            (
+                &prefixed[..],
+                Some(options.digest.unwrap_or(DigestAlgorithm::Keccak256)),
+            )
+        } else {
+            (msg, options.digest)
+        };
+        if options.hedged {
+            let hash = digest.unwrap_or(DigestAlgorithm::Blake2b256).digest(msg);
+            return match self {
+                PrivateKey::Secp256k1(val) => val
+                    .try_sign_prehashed_with_rng(&hash, rng)
+                    .map(Into::into)
+                    .map_err(Into::into),
+                PrivateKey::NistP256(val) => val
+                    .try_sign_prehashed_with_rng(&hash, rng)
+                    .map(Into::into)
+                    .map_err(Into::into),
+                _ => Err(Error::HedgingUnsupported),
+            };
+        }
+        match (digest, self) {
+            (None, _) => self.try_sign(msg, version),
+            (Some(_), PrivateKey::Bls(_)) => Err(Error::PrehashUnsupported),
+            (Some(alg), _) => self.try_sign_hash(&alg.digest(msg), version),
+        }
+    }
+
+    fn try_sign_hash(&self, hash: &[u8], version: SigningVersion) -> Result<Signature, Error> {
+        match self {
+            PrivateKey::Secp256k1(val) => val
+                .try_sign_prehashed(hash, version)
+                .map(Into::into)
+                .map_err(Into::into),
+            PrivateKey::NistP256(val) => val
+                .try_sign_prehashed(hash, version)
+                .map(Into::into)
+                .map_err(Into::into),
+            PrivateKey::Ed25519(val) => KeyPair::try_sign_prehashed(val, hash, version)
+                .map(Into::into)
+                .map_err(Into::into),
+            PrivateKey::Bls(val) => val.try_sign_prehashed(hash, version).map(Into::into),
+        }
+    }
 }
 
 impl From<ecdsa::SigningKey<Secp256k1>> for PrivateKey {
@@ -202,6 +667,17 @@ impl KeyPair for PrivateKey {
         }
     }
 
+    fn try_sign_prehashed(
+        &self,
+        digest: &[u8],
+        version: SigningVersion,
+    ) -> Result<Self::Signature, Self::Error> {
+        if digest.len() != DIGEST_SIZE {
+            return Err(Error::DigestSize(digest.len()));
+        }
+        self.try_sign_hash(digest, version)
+    }
+
     fn public_key(&self) -> Self::PublicKey {
         match self {
             PrivateKey::Secp256k1(val) => val.public_key().into(),
@@ -224,7 +700,7 @@ impl PossessionProver for PrivateKey {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, Cddl)]
 pub enum PublicKey {
     Secp256k1(ecdsa::VerifyingKey<Secp256k1>),
     NistP256(ecdsa::VerifyingKey<NistP256>),
@@ -256,6 +732,153 @@ impl From<bls::PublicKey> for PublicKey {
     }
 }
 
+/// Public key encodings for `PublicKey::export`
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Cddl)]
+pub enum PublicKeyFormat {
+    /// Compressed SEC1 point (ECDSA only)
+    Sec1Compressed,
+    /// Uncompressed SEC1 point (ECDSA only)
+    Sec1Uncompressed,
+    /// DER encoded SubjectPublicKeyInfo (ECDSA and Ed25519)
+    Spki,
+    /// Raw key bytes: compressed SEC1 point, 32 byte Ed25519 key or compressed 48 byte BLS key
+    Raw,
+    /// UTF-8 encoded JSON Web Key
+    Jwk,
+}
+
+impl PublicKey {
+    pub fn key_type(&self) -> KeyType {
+        match self {
+            PublicKey::Secp256k1(_) => KeyType::Secp256k1,
+            PublicKey::NistP256(_) => KeyType::NistP256,
+            PublicKey::Ed25519(_) => KeyType::Ed25519,
+            PublicKey::Bls(_) => KeyType::Bls,
+        }
+    }
+
+    /// Blake2b-160 hash of the raw public key (Tezos public key hash)
+    pub fn hash(&self) -> [u8; 20] {
+        let raw = match self {
+            PublicKey::Secp256k1(pk) => pk.to_sec1_bytes(true),
+            PublicKey::NistP256(pk) => pk.to_sec1_bytes(true),
+            PublicKey::Ed25519(pk) => pk.to_bytes().into(),
+            PublicKey::Bls(pk) => pk.to_bytes(),
+        };
+        Blake2b160::digest(raw).into()
+    }
+
+    /// Stable key identifier: SHA-256 of the CBOR encoded public key as returned by the signer
+    pub fn fingerprint(&self) -> Result<[u8; 32], Error> {
+        let buf = self.try_into_cbor().map_err(|_| Error::Serialize)?;
+        Ok(sha2::Sha256::digest(buf).into())
+    }
+
+    /// Verifies the signature produced by `Sign` with default options and the latest signing version.
+    /// Use `Verifier::verify` to pick the version explicitly
+    pub fn verify(&self, msg: &[u8], signature: &Signature) -> Result<(), Error> {
+        Verifier::verify(self, msg, signature, SigningVersion::Latest)
+    }
+
+    fn hash_hex(&self) -> String {
+        self.hash().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    pub fn export(&self, format: PublicKeyFormat) -> Result<Vec<u8>, Error> {
+        match (self, format) {
+            (PublicKey::Secp256k1(pk), PublicKeyFormat::Sec1Compressed | PublicKeyFormat::Raw) => {
+                Ok(pk.to_sec1_bytes(true))
+            }
+            (PublicKey::Secp256k1(pk), PublicKeyFormat::Sec1Uncompressed) => {
+                Ok(pk.to_sec1_bytes(false))
+            }
+            (PublicKey::Secp256k1(pk), PublicKeyFormat::Spki) => Ok(pk.to_spki_der()?),
+            (PublicKey::NistP256(pk), PublicKeyFormat::Sec1Compressed | PublicKeyFormat::Raw) => {
+                Ok(pk.to_sec1_bytes(true))
+            }
+            (PublicKey::NistP256(pk), PublicKeyFormat::Sec1Uncompressed) => {
+                Ok(pk.to_sec1_bytes(false))
+            }
+            (PublicKey::NistP256(pk), PublicKeyFormat::Spki) => Ok(pk.to_spki_der()?),
+            (PublicKey::Ed25519(pk), PublicKeyFormat::Raw) => Ok(pk.to_bytes().into()),
+            (PublicKey::Ed25519(pk), PublicKeyFormat::Spki) => {
+                Ok(spki::EncodePublicKey::to_public_key_der(pk)?.into_vec())
+            }
+            (PublicKey::Bls(pk), PublicKeyFormat::Raw) => Ok(pk.to_bytes()),
+            (_, PublicKeyFormat::Jwk) => Ok(self.to_jwk().to_json().into_bytes()),
+            _ => Err(Error::UnsupportedFormat),
+        }
+    }
+}
+
+impl Verifier<Signature> for PublicKey {
+    fn verify(
+        &self,
+        msg: &[u8],
+        signature: &Signature,
+        version: SigningVersion,
+    ) -> Result<(), Error> {
+        match (self, signature) {
+            (PublicKey::Secp256k1(pk), Signature::Secp256k1(sig)) => pk.verify(msg, sig, version),
+            (PublicKey::NistP256(pk), Signature::NistP256(sig)) => pk.verify(msg, sig, version),
+            // see KeyPair::try_sign for ed25519_dalek::SigningKey
+            (PublicKey::Ed25519(pk), Signature::Ed25519(sig)) => Ok(signature::Verifier::verify(
+                pk,
+                &Blake2b256::digest(msg),
+                sig,
+            )?),
+            (PublicKey::Bls(pk), Signature::Bls(sig)) => pk.verify(msg, sig, version),
+            _ => Err(Error::KeyTypeMismatch),
+        }
+    }
+}
+
+pub const DEFAULT_VERIFY_CACHE_SIZE: usize = 256;
+
+/// Remembers outcomes of recent signature verifications
+#[derive(Debug)]
+pub struct VerifyCache(Lru<[u8; 32], bool>);
+
+impl VerifyCache {
+    pub fn new(capacity: usize) -> Self {
+        VerifyCache(Lru::new(capacity))
+    }
+
+    /// Returns `Ok(false)` if the signature doesn't match and an error if the signature
+    /// can't be checked at all, e.g. if its type doesn't match the key type
+    pub fn verify(
+        &mut self,
+        public_key: &PublicKey,
+        msg: &[u8],
+        signature: &Signature,
+        version: SigningVersion,
+    ) -> Result<bool, Error> {
+        let mut d = Blake2b256::new();
+        d.update(public_key.try_into_cbor().map_err(|_| Error::Serialize)?);
+        d.update(signature.try_into_cbor().map_err(|_| Error::Serialize)?);
+        d.update([version.clone() as u8]);
+        d.update(Blake2b256::digest(msg));
+        let key: [u8; 32] = d.finalize().into();
+
+        if let Some(res) = self.0.get(&key) {
+            return Ok(*res);
+        }
+        let res = match Verifier::verify(public_key, msg, signature, version) {
+            Ok(()) => true,
+            Err(Error::Signature(_)) => false,
+            Err(err) => return Err(err),
+        };
+        self.0.insert(key, res);
+        Ok(res)
+    }
+}
+
+impl Default for VerifyCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_VERIFY_CACHE_SIZE)
+    }
+}
+
 #[derive(Debug)]
 pub enum Error {
     InvalidHandle,
@@ -263,6 +886,54 @@ pub enum Error {
     Bls(bls::Error),
     PopUnsupported,
     InvalidSigningVersion,
+    PrehashUnsupported,
+    DigestSize(usize),
+    KeyTypeMismatch,
+    Serialize,
+    HedgingUnsupported,
+    UnsupportedFormat,
+    Encoding(spki::Error),
+    InvalidConstraint,
+    ConstraintNotSatisfied(u32),
+    InvalidMnemonic,
+    /// Position of the word missing from the BIP39 English wordlist
+    UnknownMnemonicWord(usize),
+    MnemonicChecksum,
+    InvalidPath,
+    NonHardenedIndex,
+    DerivationUnsupported,
+    WeakKey,
+    PublicKeyOnly,
+    NotLoaded,
+    Eip191Unsupported,
+    UnknownKeyType(String),
+    EmptyKeySet,
+    InvalidThreshold,
+    InvalidShares,
+    Ed25519phUnsupported,
+    InvalidNonce,
+    InvalidPartialSignature(usize),
+    InvalidAggregateKey,
+    SignerNotInKeySet,
+    SignerCountMismatch,
+    InvalidSession,
+    TooManySessions,
+    InvalidTweak,
+    TweakUnsupported,
+    EciesUnsupported,
+    DecryptionFailed,
+    DataKey,
+    NotDataKey,
+    Rsa(rsa::Error),
+    BlsOptionsUnsupported,
+    InvalidDst,
+    BatchSizeMismatch,
+    InvalidSeed,
+    RawKeySize(usize),
+    InvalidHex,
+    MetadataTooLarge,
+    KeyExpired,
+    WrappingKey,
 }
 
 impl std::fmt::Display for Error {
@@ -273,6 +944,75 @@ impl std::fmt::Display for Error {
             Error::Bls(_) => f.write_str("BLST error"),
             Error::PopUnsupported => f.write_str("Proof of possession is not supported"),
             Error::InvalidSigningVersion => f.write_str("Invalid signing     version"),
+            Error::PrehashUnsupported => f.write_str("Prehashed signing is not supported"),
+            Error::DigestSize(sz) => write!(f, "Invalid digest size: {}", sz),
+            Error::KeyTypeMismatch => f.write_str("Key type mismatch"),
+            Error::Serialize => f.write_str("Serialization error"),
+            Error::HedgingUnsupported => f.write_str("Hedged signing is not supported"),
+            Error::UnsupportedFormat => f.write_str("Unsupported key format"),
+            Error::Encoding(_) => f.write_str("Key encoding error"),
+            Error::InvalidConstraint => f.write_str("Invalid key constraint"),
+            Error::InvalidMnemonic => f.write_str("Invalid mnemonic"),
+            Error::UnknownMnemonicWord(pos) => {
+                write!(f, "Unknown mnemonic word at position {}", pos)
+            }
+            Error::MnemonicChecksum => f.write_str("Invalid mnemonic checksum"),
+            Error::InvalidPath => f.write_str("Invalid derivation path"),
+            Error::NonHardenedIndex => f.write_str("Only hardened derivation is supported"),
+            Error::DerivationUnsupported => f.write_str("Key derivation is not supported"),
+            Error::WeakKey => f.write_str("Weak or invalid private key"),
+            Error::PublicKeyOnly => f.write_str("The handle refers to a public key"),
+            Error::NotLoaded => f.write_str("The key is not loaded"),
+            Error::Eip191Unsupported => {
+                f.write_str("EIP-191 messages can be signed with Secp256k1 keys only")
+            }
+            Error::EmptyKeySet => f.write_str("Empty public key set"),
+            Error::InvalidThreshold => {
+                f.write_str("The threshold must be between 1 and the number of shares")
+            }
+            Error::InvalidShares => f.write_str("Invalid or insufficient secret shares"),
+            Error::Ed25519phUnsupported => {
+                f.write_str("Ed25519ph requires an Ed25519 key and no custom digest")
+            }
+            Error::InvalidNonce => f.write_str("Invalid MuSig2 public nonce"),
+            Error::InvalidPartialSignature(i) => write!(f, "Invalid partial signature {}", i),
+            Error::InvalidAggregateKey => f.write_str("Invalid aggregate public key"),
+            Error::SignerNotInKeySet => f.write_str("The signing key is not in the key set"),
+            Error::SignerCountMismatch => {
+                f.write_str("The number of nonces or partial signatures doesn't match the key set")
+            }
+            Error::InvalidSession => f.write_str("Unknown or already used MuSig2 session"),
+            Error::TooManySessions => f.write_str("Too many pending MuSig2 sessions"),
+            Error::InvalidTweak => f.write_str("Invalid Taproot tweak"),
+            Error::EciesUnsupported => {
+                f.write_str("ECIES is supported by Secp256k1 and NIST P-256 keys only")
+            }
+            Error::DecryptionFailed => f.write_str("Decryption failed"),
+            Error::DataKey => f.write_str("The handle refers to a data key"),
+            Error::NotDataKey => f.write_str("The handle doesn't refer to a data key"),
+            Error::WrappingKey => {
+                f.write_str("The handle refers to a key used for unwrapping migrated keys")
+            }
+            Error::Rsa(_) => f.write_str("RSA error"),
+            Error::BlsOptionsUnsupported => {
+                f.write_str("BLS options require a BLS key and no custom digest")
+            }
+            Error::InvalidDst => f.write_str("The domain separation tag is too long"),
+            Error::InvalidSeed => f.write_str("Invalid master seed"),
+            Error::RawKeySize(sz) => write!(f, "Invalid raw private key size: {}", sz),
+            Error::InvalidHex => f.write_str("Invalid hex string"),
+            Error::MetadataTooLarge => f.write_str("Key metadata is too large"),
+            Error::KeyExpired => f.write_str("Key expired"),
+            Error::BatchSizeMismatch => {
+                f.write_str("The numbers of keys, messages and signatures don't match")
+            }
+            Error::TweakUnsupported => {
+                f.write_str("Taproot tweaking is supported by Secp256k1 keys only")
+            }
+            Error::UnknownKeyType(s) => write!(f, "Unknown key type: {}", s),
+            Error::ConstraintNotSatisfied(n) => {
+                write!(f, "Key constraint not satisfied after {} attempts", n)
+            }
         }
     }
 }
@@ -282,6 +1022,8 @@ impl std::error::Error for Error {
         match self {
             Error::Signature(e) => e.source(),
             Error::Bls(e) => Some(e),
+            Error::Encoding(e) => Some(e),
+            Error::Rsa(e) => Some(e),
             _ => None,
         }
     }
@@ -299,81 +1041,738 @@ impl From<bls::Error> for Error {
     }
 }
 
-pub struct Keychain {
-    keys: Vec<PrivateKey>,
+impl From<rsa::Error> for Error {
+    fn from(value: rsa::Error) -> Self {
+        Error::Rsa(value)
+    }
 }
 
-impl Keychain {
-    pub fn new() -> Self {
-        Keychain { keys: Vec::new() }
+impl From<spki::Error> for Error {
+    fn from(value: spki::Error) -> Self {
+        Error::Encoding(value)
     }
+}
 
-    pub fn import(&mut self, src: PrivateKey) -> usize {
-        self.keys.push(src);
-        self.keys.len() - 1
-    }
+enum KeychainEntry {
+    Private(PrivateKey),
+    Public(PublicKey),
+    /// Encrypted blob which is decrypted on first use
+    Deferred(Vec<u8>),
+    Data(data_key::DataKey),
+}
 
-    pub fn try_sign(
-        &self,
-        handle: usize,
-        msg: &[u8],
-        version: SigningVersion,
-    ) -> Result<Signature, Error> {
-        match self.keys.get(handle) {
-            Some(k) => Ok(k.try_sign(msg, version)?),
-            None => Err(Error::InvalidHandle),
+/// Kind of the keychain entry
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Cddl)]
+pub enum KeyKind {
+    Private,
+    Public,
+    /// Imported with `import_deferred` and not used yet
+    Deferred,
+    Data,
+}
+
+/// Keychain entry as reported by `Keychain::list`
+#[derive(Serialize, Deserialize, Debug, Clone, Cddl)]
+pub struct KeyInfo {
+    pub handle: KeyHandle,
+    pub kind: KeyKind,
+    /// Not known for deferred and data keys
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_type: Option<KeyType>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub public_key: Option<PublicKey>,
+    #[serde(default, skip_serializing_if = "KeyMetadata::is_empty")]
+    pub metadata: KeyMetadata,
+    /// Unix time in seconds after which the key is refused and dropped
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+}
+
+/// Usage of the keychain entry as reported by `Keychain::stats`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Cddl)]
+pub struct KeyStats {
+    pub handle: KeyHandle,
+    /// Number of signatures made including MuSig2 partial signatures
+    pub signatures: u64,
+    /// Unix time in seconds of the last signature
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_used: Option<u64>,
+}
+
+/// Current Unix time in seconds
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+// updated under the shared lock
+#[derive(Default)]
+struct UsageCounter {
+    signatures: AtomicU64,
+    /// Unix time in seconds, 0 if never used
+    last_used: AtomicU64,
+}
+
+/// Upper bound of the label size in bytes
+pub const MAX_LABEL_SIZE: usize = 64;
+/// Upper bound of the metadata entries count
+pub const MAX_METADATA_ENTRIES: usize = 16;
+/// Upper bound of the metadata key or value size in bytes
+pub const MAX_METADATA_VALUE_SIZE: usize = 256;
+
+/// Operator supplied description of the key, e.g. `baker-main`. Not interpreted by the signer
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, Cddl)]
+pub struct KeyMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl KeyMetadata {
+    pub fn new(label: impl Into<String>) -> Self {
+        KeyMetadata {
+            label: Some(label.into()),
+            metadata: BTreeMap::new(),
         }
     }
 
-    pub fn try_prove(&self, handle: usize) -> Result<ProofOfPossession, Error> {
-        match self.keys.get(handle) {
-            Some(k) => Ok(k.try_prove()?),
-            None => Err(Error::InvalidHandle),
-        }
+    pub fn with_entry(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.metadata.insert(key.into(), value.into());
+        self
     }
 
-    pub fn public_key(&self, handle: usize) -> Result<PublicKey, Error> {
-        match self.keys.get(handle) {
-            Some(k) => Ok(k.public_key()),
-            None => Err(Error::InvalidHandle),
+    pub fn is_empty(&self) -> bool {
+        self.label.is_none() && self.metadata.is_empty()
+    }
+
+    /// Checks the size limits
+    pub fn validate(&self) -> Result<(), Error> {
+        if self
+            .label
+            .as_ref()
+            .is_some_and(|l| l.len() > MAX_LABEL_SIZE)
+            || self.metadata.len() > MAX_METADATA_ENTRIES
+            || self.metadata.iter().any(|(k, v)| {
+                k.len() > MAX_METADATA_VALUE_SIZE || v.len() > MAX_METADATA_VALUE_SIZE
+            })
+        {
+            Err(Error::MetadataTooLarge)
+        } else {
+            Ok(())
         }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::{
-        Blake2b256, Digest, KeyType, Keychain, PrivateKey, PublicKey, Signature, SigningVersion,
-    };
-    use crate::{
-        crypto::{KeyPair, ProofOfPossession, ProofVerifier, Verifier},
-        macros::unwrap_as,
-        TryFromCBOR, TryIntoCBOR,
-    };
-    use signature::{DigestVerifier, Verifier as SigVerifier};
+/// Opaque identifier of a keychain entry. Handles are allocated sequentially by the keychain
+/// and never reused, so removing a key doesn't affect the handles of the others. They are
+/// scoped to the keychain, i.e. to the connection
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(transparent)]
+pub struct KeyHandle(u64);
 
-    macro_rules! impl_pk_serde_test {
-        ($name:ident, $ty:tt) => {
-            #[test]
-            fn $name() {
-                let pk = PrivateKey::generate(KeyType::$ty, &mut rand_core::OsRng).unwrap();
-                let ser_pk = pk.try_into_cbor().unwrap();
-                let de_pk = PrivateKey::try_from_cbor(&ser_pk).unwrap();
-                assert!(matches!(de_pk, PrivateKey::$ty(_)));
-            }
-        };
+impl Cddl for KeyHandle {
+    fn cddl_type(_: &mut Schema) -> String {
+        "uint".into()
     }
+}
 
-    impl_pk_serde_test!(serde_pk_nist_p256, NistP256);
-    impl_pk_serde_test!(serde_pk_secp256k1, Secp256k1);
-    impl_pk_serde_test!(serde_pk_ed25519, Ed25519);
-    impl_pk_serde_test!(serde_pk_bls, Bls);
+impl std::fmt::Display for KeyHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
 
-    macro_rules! impl_pubkey_serde_test {
-        ($name:ident, $ty:tt) => {
-            #[test]
-            fn $name() {
-                let pubkey = PrivateKey::generate(KeyType::$ty, &mut rand_core::OsRng)
+impl From<u64> for KeyHandle {
+    fn from(value: u64) -> Self {
+        KeyHandle(value)
+    }
+}
+
+impl From<KeyHandle> for u64 {
+    fn from(value: KeyHandle) -> Self {
+        value.0
+    }
+}
+
+/// Registry of the keys imported into the signer. Private, public-only and data keys share the handle space
+pub struct Keychain {
+    keys: BTreeMap<KeyHandle, KeychainEntry>,
+    metadata: BTreeMap<KeyHandle, KeyMetadata>,
+    usage: BTreeMap<KeyHandle, UsageCounter>,
+    expiry: BTreeMap<KeyHandle, u64>,
+    /// Entries dropped by `remove_expired`, reported as expired rather than invalid
+    expired: BTreeSet<KeyHandle>,
+    next_handle: u64,
+    musig2: Vec<Option<(KeyHandle, musig2::SignerSession)>>,
+    /// Keys which unwrapped a migrated key, never used for general ECIES decryption
+    wrapping: BTreeSet<KeyHandle>,
+    request_key: Option<Zeroizing<Vec<u8>>>,
+}
+
+/// Keychain shared by several signers, e.g. by all connections of the server, so the keys
+/// imported over one connection are available to the others. Handles are shared as well
+#[derive(Clone)]
+pub struct SharedKeychain(Arc<RwLock<Keychain>>);
+
+impl SharedKeychain {
+    pub fn new() -> Self {
+        Keychain::new().into()
+    }
+
+    // keychain operations don't panic halfway through a modification, so a poisoned lock is still usable
+    pub fn read(&self) -> RwLockReadGuard<'_, Keychain> {
+        self.0.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<'_, Keychain> {
+        self.0.write().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Debug for SharedKeychain {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SharedKeychain(..)")
+    }
+}
+
+impl Default for SharedKeychain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl From<Keychain> for SharedKeychain {
+    fn from(value: Keychain) -> Self {
+        SharedKeychain(Arc::new(RwLock::new(value)))
+    }
+}
+
+/// Shared keychains isolated from each other by namespace, e.g. one per tenant in a multi-tenant
+/// deployment. Handles of one namespace can't refer to the keys of another
+#[derive(Clone, Default)]
+pub struct KeychainNamespaces(Arc<Mutex<HashMap<String, SharedKeychain>>>);
+
+impl KeychainNamespaces {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the keychain of the namespace creating an empty one on first use
+    pub fn get(&self, namespace: &str) -> SharedKeychain {
+        self.0
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(namespace.into())
+            .or_default()
+            .clone()
+    }
+}
+
+impl Debug for KeychainNamespaces {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("KeychainNamespaces(..)")
+    }
+}
+
+/// Upper bound of MuSig2 sessions waiting for the second round
+pub const MAX_MUSIG2_SESSIONS: usize = 256;
+
+impl Keychain {
+    pub fn new() -> Self {
+        Keychain {
+            keys: BTreeMap::new(),
+            metadata: BTreeMap::new(),
+            usage: BTreeMap::new(),
+            expiry: BTreeMap::new(),
+            expired: BTreeSet::new(),
+            next_handle: 0,
+            musig2: Vec::new(),
+            wrapping: BTreeSet::new(),
+            request_key: None,
+        }
+    }
+
+    /// Key authenticating the requests of every connection using the keychain
+    pub fn request_key(&self) -> Option<&[u8]> {
+        self.request_key.as_ref().map(|key| &key[..])
+    }
+
+    pub fn set_request_key(&mut self, key: &[u8]) {
+        self.request_key = Some(Zeroizing::new(key.to_vec()));
+    }
+
+    fn insert(&mut self, entry: KeychainEntry) -> KeyHandle {
+        let handle = KeyHandle(self.next_handle);
+        self.next_handle += 1;
+        self.keys.insert(handle, entry);
+        self.usage.insert(handle, UsageCounter::default());
+        handle
+    }
+
+    pub fn import(&mut self, src: PrivateKey) -> KeyHandle {
+        self.insert(KeychainEntry::Private(src))
+    }
+
+    /// Imports the trusted public key without a secret part. The handle can't be used for signing
+    pub fn import_public_key(&mut self, src: PublicKey) -> KeyHandle {
+        self.insert(KeychainEntry::Public(src))
+    }
+
+    pub fn import_data_key(&mut self, src: data_key::DataKey) -> KeyHandle {
+        self.insert(KeychainEntry::Data(src))
+    }
+
+    pub fn data_key(&self, handle: KeyHandle) -> Result<&data_key::DataKey, Error> {
+        if self.is_expired(handle, unix_time()) {
+            return Err(Error::KeyExpired);
+        }
+        match self.keys.get(&handle) {
+            Some(KeychainEntry::Data(k)) => Ok(k),
+            Some(_) => Err(Error::NotDataKey),
+            None => Err(self.missing(handle)),
+        }
+    }
+
+    /// Registers the encrypted blob without decrypting it. See `Keychain::load`
+    pub fn import_deferred(&mut self, blob: Vec<u8>) -> KeyHandle {
+        self.insert(KeychainEntry::Deferred(blob))
+    }
+
+    /// Returns the encrypted blob if the key under the handle is not loaded yet
+    pub fn deferred(&self, handle: KeyHandle) -> Option<&[u8]> {
+        match self.keys.get(&handle) {
+            Some(KeychainEntry::Deferred(blob)) => Some(blob),
+            _ => None,
+        }
+    }
+
+    /// Replaces the deferred entry with the decrypted key
+    pub fn load(&mut self, handle: KeyHandle, src: PrivateKey) -> Result<(), Error> {
+        match self.keys.get_mut(&handle) {
+            Some(entry @ KeychainEntry::Deferred(_)) => {
+                *entry = KeychainEntry::Private(src);
+                Ok(())
+            }
+            Some(_) => Ok(()),
+            None => Err(Error::InvalidHandle),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    /// Returns the entries in the handle order
+    pub fn list(&self) -> Vec<KeyInfo> {
+        self.keys
+            .iter()
+            .map(|(handle, entry)| self.info(*handle, entry))
+            .collect()
+    }
+
+    fn info(&self, handle: KeyHandle, entry: &KeychainEntry) -> KeyInfo {
+        let (kind, public_key) = match entry {
+            KeychainEntry::Private(k) => (KeyKind::Private, Some(k.public_key())),
+            KeychainEntry::Public(k) => (KeyKind::Public, Some(k.clone())),
+            KeychainEntry::Deferred(_) => (KeyKind::Deferred, None),
+            KeychainEntry::Data(_) => (KeyKind::Data, None),
+        };
+        KeyInfo {
+            handle,
+            kind,
+            key_type: public_key.as_ref().map(PublicKey::key_type),
+            public_key,
+            metadata: self.metadata.get(&handle).cloned().unwrap_or_default(),
+            expires_at: self.expiry.get(&handle).copied(),
+        }
+    }
+
+    /// Attaches the metadata to the entry replacing the previous one
+    pub fn set_metadata(&mut self, handle: KeyHandle, metadata: KeyMetadata) -> Result<(), Error> {
+        metadata.validate()?;
+        if !self.keys.contains_key(&handle) {
+            return Err(self.missing(handle));
+        }
+        if metadata.is_empty() {
+            self.metadata.remove(&handle);
+        } else {
+            self.metadata.insert(handle, metadata);
+        }
+        Ok(())
+    }
+
+    /// Sets the Unix time in seconds after which the entry is refused and dropped. `None` removes the expiry
+    pub fn set_expiry(&mut self, handle: KeyHandle, expires_at: Option<u64>) -> Result<(), Error> {
+        if !self.keys.contains_key(&handle) {
+            return Err(self.missing(handle));
+        }
+        match expires_at {
+            Some(t) => self.expiry.insert(handle, t),
+            None => self.expiry.remove(&handle),
+        };
+        Ok(())
+    }
+
+    fn is_expired(&self, handle: KeyHandle, now: u64) -> bool {
+        self.expiry.get(&handle).is_some_and(|&t| t <= now)
+    }
+
+    /// Returns true if any entry has expired by `now` and awaits `remove_expired`
+    pub fn has_expired(&self, now: u64) -> bool {
+        self.expiry.values().any(|&t| t <= now)
+    }
+
+    /// Drops the entries expired by `now`. Their secrets are zeroized
+    pub fn remove_expired(&mut self, now: u64) {
+        let expired: Vec<KeyHandle> = self
+            .expiry
+            .iter()
+            .filter_map(|(handle, &t)| (t <= now).then_some(*handle))
+            .collect();
+        for handle in expired {
+            // the entry exists as the expiry is dropped together with it
+            let _ = self.remove(handle);
+            self.expired.insert(handle);
+        }
+    }
+
+    fn missing(&self, handle: KeyHandle) -> Error {
+        if self.expired.contains(&handle) {
+            Error::KeyExpired
+        } else {
+            Error::InvalidHandle
+        }
+    }
+
+    /// Returns the first entry with the label
+    pub fn find_label(&self, label: &str) -> Option<KeyInfo> {
+        let handle = self
+            .metadata
+            .iter()
+            .find_map(|(handle, m)| (m.label.as_deref() == Some(label)).then_some(*handle))?;
+        Some(self.info(handle, &self.keys[&handle]))
+    }
+
+    /// Drops the entry and closes the MuSig2 sessions using it. The handle is never reused
+    pub fn remove(&mut self, handle: KeyHandle) -> Result<(), Error> {
+        // the secrets are zeroized on drop
+        self.keys.remove(&handle).ok_or(Error::InvalidHandle)?;
+        self.metadata.remove(&handle);
+        self.usage.remove(&handle);
+        self.expiry.remove(&handle);
+        self.wrapping.remove(&handle);
+        for session in self.musig2.iter_mut() {
+            if matches!(session, Some((h, _)) if *h == handle) {
+                *session = None;
+            }
+        }
+        Ok(())
+    }
+
+    /// Drops all entries and MuSig2 sessions. Handles issued so far are never reused
+    pub fn clear(&mut self) {
+        // the secrets are zeroized on drop
+        self.keys.clear();
+        self.metadata.clear();
+        self.usage.clear();
+        self.expiry.clear();
+        self.expired.clear();
+        self.wrapping.clear();
+        self.musig2.iter_mut().for_each(|session| *session = None);
+    }
+
+    /// Returns the handle of the first loaded entry holding the private key.
+    /// All entries are compared in constant time
+    pub fn find_private_key(&self, private_key: &PrivateKey) -> Option<KeyHandle> {
+        let mut res = None;
+        for (handle, k) in self.keys.iter() {
+            if let KeychainEntry::Private(k) = k {
+                if bool::from(k.ct_eq(private_key)) && res.is_none() {
+                    res = Some(*handle);
+                }
+            }
+        }
+        res
+    }
+
+    /// Returns the first loaded entry holding the public key, with or without the private part
+    pub fn find_public_key(&self, public_key: &PublicKey) -> Option<KeyInfo> {
+        let hash = public_key.hash();
+        let (handle, entry) = self.keys.iter().find(|(_, k)| {
+            let pk = match k {
+                KeychainEntry::Private(k) => k.public_key(),
+                KeychainEntry::Public(k) => k.clone(),
+                KeychainEntry::Deferred(_) | KeychainEntry::Data(_) => return false,
+            };
+            std::mem::discriminant(&pk) == std::mem::discriminant(public_key) && pk.hash() == hash
+        })?;
+        Some(self.info(*handle, entry))
+    }
+
+    fn private_key(&self, handle: KeyHandle) -> Result<&PrivateKey, Error> {
+        if self.is_expired(handle, unix_time()) {
+            return Err(Error::KeyExpired);
+        }
+        match self.keys.get(&handle) {
+            Some(KeychainEntry::Private(k)) => Ok(k),
+            Some(KeychainEntry::Public(_)) => Err(Error::PublicKeyOnly),
+            Some(KeychainEntry::Deferred(_)) => Err(Error::NotLoaded),
+            Some(KeychainEntry::Data(_)) => Err(Error::DataKey),
+            None => Err(self.missing(handle)),
+        }
+    }
+
+    pub fn try_sign<R: CryptoRngCore>(
+        &self,
+        handle: KeyHandle,
+        msg: &[u8],
+        version: SigningVersion,
+        options: &SignOptions,
+        rng: &mut R,
+    ) -> Result<Signature, Error> {
+        let sig = self
+            .private_key(handle)?
+            .try_sign_with_options(msg, version, options, rng)?;
+        self.record_use(handle);
+        Ok(sig)
+    }
+
+    /// Signs every message with the same key and options, failing as a whole on the first error
+    pub fn try_sign_batch<M: AsRef<[u8]>, R: CryptoRngCore>(
+        &self,
+        handle: KeyHandle,
+        msgs: &[M],
+        version: SigningVersion,
+        options: &SignOptions,
+        rng: &mut R,
+    ) -> Result<Vec<Signature>, Error> {
+        let pk = self.private_key(handle)?;
+        let sigs = msgs
+            .iter()
+            .map(|msg| pk.try_sign_with_options(msg.as_ref(), version.clone(), options, rng))
+            .collect::<Result<Vec<_>, _>>()?;
+        for _ in 0..sigs.len() {
+            self.record_use(handle);
+        }
+        Ok(sigs)
+    }
+
+    pub fn try_sign_prehashed(
+        &self,
+        handle: KeyHandle,
+        digest: &[u8],
+        version: SigningVersion,
+    ) -> Result<Signature, Error> {
+        let sig = self
+            .private_key(handle)?
+            .try_sign_prehashed(digest, version)?;
+        self.record_use(handle);
+        Ok(sig)
+    }
+
+    /// Signs the message hashed by `hasher`. The signature is the same as `try_sign` with
+    /// the corresponding `digest` option would produce. Not supported by BLS keys
+    pub fn try_sign_hashed(
+        &self,
+        handle: KeyHandle,
+        hasher: Hasher,
+        version: SigningVersion,
+    ) -> Result<Signature, Error> {
+        let pk = self.private_key(handle)?;
+        if let PrivateKey::Bls(_) = pk {
+            return Err(Error::PrehashUnsupported);
+        }
+        let sig = pk.try_sign_hash(&hasher.finalize(), version)?;
+        self.record_use(handle);
+        Ok(sig)
+    }
+
+    fn record_use(&self, handle: KeyHandle) {
+        if let Some(usage) = self.usage.get(&handle) {
+            usage.signatures.fetch_add(1, Ordering::Relaxed);
+            usage.last_used.store(unix_time(), Ordering::Relaxed);
+        }
+    }
+
+    /// Returns the usage statistics of the entries in the handle order
+    pub fn stats(&self) -> Vec<KeyStats> {
+        self.usage
+            .iter()
+            .map(|(handle, usage)| KeyStats {
+                handle: *handle,
+                signatures: usage.signatures.load(Ordering::Relaxed),
+                last_used: Some(usage.last_used.load(Ordering::Relaxed)).filter(|&t| t != 0),
+            })
+            .collect()
+    }
+
+    pub fn derive_child(&self, handle: KeyHandle, path: &[u32]) -> Result<PrivateKey, Error> {
+        self.private_key(handle)?.derive_child(path)
+    }
+
+    pub fn try_prove(&self, handle: KeyHandle) -> Result<ProofOfPossession, Error> {
+        self.private_key(handle)?.try_prove()
+    }
+
+    pub fn tweak_taproot(
+        &self,
+        handle: KeyHandle,
+        merkle_root: &[u8],
+    ) -> Result<PrivateKey, Error> {
+        self.private_key(handle)?.tweak_taproot(merkle_root)
+    }
+
+    pub fn wrap<R: CryptoRngCore>(
+        &self,
+        handle: KeyHandle,
+        recipient: &wrap::WrappingKey,
+        r: &mut R,
+    ) -> Result<Vec<u8>, Error> {
+        self.private_key(handle)?.wrap(recipient, r)
+    }
+
+    /// Fails for the keys used by `unwrap_ecies`
+    pub fn ecies_decrypt(&self, handle: KeyHandle, src: &[u8]) -> Result<Vec<u8>, Error> {
+        if self.wrapping.contains(&handle) {
+            return Err(Error::WrappingKey);
+        }
+        self.private_key(handle)?.ecies_decrypt(src)
+    }
+
+    /// Decrypts the CBOR encoded key wrapped by `wrap` to the ECIES key under `handle` and
+    /// reserves the key for unwrapping
+    pub fn unwrap_ecies(
+        &mut self,
+        handle: KeyHandle,
+        src: &[u8],
+    ) -> Result<Zeroizing<Vec<u8>>, Error> {
+        let res = self.private_key(handle)?.unwrap_ecies(src)?;
+        self.wrapping.insert(handle);
+        Ok(res)
+    }
+
+    /// Splits the serialized private key into Shamir shares
+    pub fn split<R: CryptoRngCore>(
+        &self,
+        handle: KeyHandle,
+        threshold: u8,
+        shares: u8,
+        r: &mut R,
+    ) -> Result<Vec<shamir::Share>, Error> {
+        let secret = Zeroizing::new(
+            self.private_key(handle)?
+                .try_into_cbor()
+                .map_err(|_| Error::Serialize)?,
+        );
+        shamir::split(&secret, threshold, shares, r)
+    }
+
+    fn musig2_secret(&self, handle: KeyHandle) -> Result<&k256::Scalar, Error> {
+        match self.private_key(handle)? {
+            PrivateKey::Secp256k1(sk) => Ok(sk.as_nonzero_scalar()),
+            _ => Err(Error::KeyTypeMismatch),
+        }
+    }
+
+    /// Starts the MuSig2 signing session by the key set including the key under the handle.
+    /// Returns the session number and the signer's public nonce
+    pub fn musig2_nonce<R: CryptoRngCore>(
+        &mut self,
+        handle: KeyHandle,
+        public_keys: &[PublicKey],
+        msg: &[u8],
+        r: &mut R,
+    ) -> Result<(usize, &musig2::SignerSession), Error> {
+        let ctx = musig2::KeyAggContext::from_public_keys(public_keys)?;
+        let session = musig2::SignerSession::new(self.musig2_secret(handle)?, ctx, msg, r)?;
+        let index = match self.musig2.iter().position(Option::is_none) {
+            Some(i) => i,
+            None if self.musig2.len() < MAX_MUSIG2_SESSIONS => {
+                self.musig2.push(None);
+                self.musig2.len() - 1
+            }
+            None => return Err(Error::TooManySessions),
+        };
+        let (_, session) = self.musig2[index].insert((handle, session));
+        Ok((index, session))
+    }
+
+    /// Produces the partial signature. The session is closed even if signing fails
+    pub fn musig2_sign(
+        &mut self,
+        session: usize,
+        pub_nonces: &[Vec<u8>],
+    ) -> Result<[u8; musig2::PARTIAL_SIGNATURE_SIZE], Error> {
+        let (handle, session) = self
+            .musig2
+            .get_mut(session)
+            .and_then(Option::take)
+            .ok_or(Error::InvalidSession)?;
+        let sig = session.sign(
+            self.musig2_secret(handle)?,
+            &musig2::parse_pub_nonces(pub_nonces)?,
+        )?;
+        self.record_use(handle);
+        Ok(sig)
+    }
+
+    pub fn public_key(&self, handle: KeyHandle) -> Result<PublicKey, Error> {
+        match self.keys.get(&handle) {
+            Some(KeychainEntry::Private(k)) => Ok(k.public_key()),
+            Some(KeychainEntry::Public(k)) => Ok(k.clone()),
+            Some(KeychainEntry::Deferred(_)) => Err(Error::NotLoaded),
+            Some(KeychainEntry::Data(_)) => Err(Error::DataKey),
+            None => Err(self.missing(handle)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        data_key, eip191_message, unix_time, Blake2b256, Digest, DigestAlgorithm, Error,
+        KeyConstraint, KeyHandle, KeyKind, KeyMetadata, KeyStats, KeyType, Keychain,
+        KeychainNamespaces, PrivateKey, PublicKey, PublicKeyFormat, Secp256k1, SignOptions,
+        Signature, SigningVersion, VerifyCache, MAX_LABEL_SIZE,
+    };
+    use crate::{
+        crypto::{
+            bls::{BlsOptions, Scheme as BlsScheme},
+            KeyPair, ProofOfPossession, ProofVerifier, Verifier,
+        },
+        macros::unwrap_as,
+        TryFromCBOR, TryIntoCBOR,
+    };
+    use signature::{DigestVerifier, Verifier as SigVerifier};
+
+    macro_rules! impl_pk_serde_test {
+        ($name:ident, $ty:tt) => {
+            #[test]
+            fn $name() {
+                let pk = PrivateKey::generate(KeyType::$ty, &mut rand_core::OsRng).unwrap();
+                let ser_pk = pk.try_into_cbor().unwrap();
+                let de_pk = PrivateKey::try_from_cbor(&ser_pk).unwrap();
+                assert!(matches!(de_pk, PrivateKey::$ty(_)));
+            }
+        };
+    }
+
+    impl_pk_serde_test!(serde_pk_nist_p256, NistP256);
+    impl_pk_serde_test!(serde_pk_secp256k1, Secp256k1);
+    impl_pk_serde_test!(serde_pk_ed25519, Ed25519);
+    impl_pk_serde_test!(serde_pk_bls, Bls);
+
+    macro_rules! impl_pubkey_serde_test {
+        ($name:ident, $ty:tt) => {
+            #[test]
+            fn $name() {
+                let pubkey = PrivateKey::generate(KeyType::$ty, &mut rand_core::OsRng)
                     .unwrap()
                     .public_key();
                 let ser_pubkey = pubkey.try_into_cbor().unwrap();
@@ -416,7 +1815,13 @@ mod tests {
         let data = b"text";
         let sig = unwrap_as!(
             keychain
-                .try_sign(handle, data, SigningVersion::Latest)
+                .try_sign(
+                    handle,
+                    data,
+                    SigningVersion::Latest,
+                    &SignOptions::default(),
+                    &mut rand_core::OsRng
+                )
                 .unwrap(),
             Signature::Secp256k1
         );
@@ -437,7 +1842,13 @@ mod tests {
         let data = b"text";
         let sig = unwrap_as!(
             keychain
-                .try_sign(handle, data, SigningVersion::Latest)
+                .try_sign(
+                    handle,
+                    data,
+                    SigningVersion::Latest,
+                    &SignOptions::default(),
+                    &mut rand_core::OsRng
+                )
                 .unwrap(),
             Signature::NistP256
         );
@@ -458,7 +1869,13 @@ mod tests {
         let data = b"text";
         let sig = unwrap_as!(
             keychain
-                .try_sign(handle, data, SigningVersion::Latest)
+                .try_sign(
+                    handle,
+                    data,
+                    SigningVersion::Latest,
+                    &SignOptions::default(),
+                    &mut rand_core::OsRng
+                )
                 .unwrap(),
             Signature::Ed25519
         );
@@ -476,7 +1893,15 @@ mod tests {
 
         let data = b"text";
         let sig = unwrap_as!(
-            keychain.try_sign(handle, data, SigningVersion::V1).unwrap(),
+            keychain
+                .try_sign(
+                    handle,
+                    data,
+                    SigningVersion::V1,
+                    &SignOptions::default(),
+                    &mut rand_core::OsRng
+                )
+                .unwrap(),
             Signature::Bls
         );
         let pub_key = unwrap_as!(keychain.public_key(handle).unwrap(), PublicKey::Bls);
@@ -492,7 +1917,15 @@ mod tests {
 
         let data = b"text";
         let sig = unwrap_as!(
-            keychain.try_sign(handle, data, SigningVersion::V2).unwrap(),
+            keychain
+                .try_sign(
+                    handle,
+                    data,
+                    SigningVersion::V2,
+                    &SignOptions::default(),
+                    &mut rand_core::OsRng
+                )
+                .unwrap(),
             Signature::Bls
         );
         let pub_key = unwrap_as!(keychain.public_key(handle).unwrap(), PublicKey::Bls);
@@ -511,4 +1944,862 @@ mod tests {
 
         pub_key.verify_pop(&sig).unwrap();
     }
+
+    #[test]
+    fn keychain_remove() {
+        let mut keychain = Keychain::new();
+        let a = PrivateKey::generate(KeyType::Secp256k1, &mut rand_core::OsRng).unwrap();
+        let b = PrivateKey::generate(KeyType::Secp256k1, &mut rand_core::OsRng).unwrap();
+        let keys = [a.public_key(), b.public_key()];
+        let a = keychain.import(a);
+        let b = keychain.import(b);
+        let (session, _) = keychain
+            .musig2_nonce(a, &keys, b"text", &mut rand_core::OsRng)
+            .unwrap();
+
+        keychain.remove(a).unwrap();
+        assert!(matches!(keychain.public_key(a), Err(Error::InvalidHandle)));
+        assert!(matches!(keychain.remove(a), Err(Error::InvalidHandle)));
+        assert!(matches!(
+            keychain.musig2_sign(session, &[]),
+            Err(Error::InvalidSession)
+        ));
+        assert!(keychain.find_public_key(&keys[0]).is_none());
+
+        // the other handles stay valid and aren't reused
+        assert!(keychain.public_key(b).is_ok());
+        let c = keychain.import_public_key(keys[0].clone());
+        assert_eq!(u64::from(c), 2);
+    }
+
+    #[test]
+    fn keychain_list() {
+        let mut keychain = Keychain::new();
+        let pk = PrivateKey::generate(KeyType::Ed25519, &mut rand_core::OsRng).unwrap();
+        let pub_key = pk.public_key();
+        keychain.import(pk);
+        keychain.import_deferred(vec![1, 2, 3]);
+        let removed = keychain.import_public_key(pub_key.clone());
+        keychain.import_public_key(pub_key.clone());
+        keychain.import_data_key(data_key::DataKey::generate(&mut rand_core::OsRng));
+        keychain.remove(removed).unwrap();
+
+        let list = keychain.list();
+        assert_eq!(
+            list.iter()
+                .map(|k| (u64::from(k.handle), k.kind))
+                .collect::<Vec<_>>(),
+            [
+                (0, KeyKind::Private),
+                (1, KeyKind::Deferred),
+                (3, KeyKind::Public),
+                (4, KeyKind::Data)
+            ]
+        );
+        assert_eq!(list[0].key_type, Some(KeyType::Ed25519));
+        assert_eq!(list[0].public_key.as_ref().unwrap().hash(), pub_key.hash());
+        assert_eq!(list[1].key_type, None);
+        assert!(list[3].public_key.is_none());
+    }
+
+    #[test]
+    fn keychain_stats() {
+        let mut keychain = Keychain::new();
+        let a =
+            keychain.import(PrivateKey::generate(KeyType::Ed25519, &mut rand_core::OsRng).unwrap());
+        let b = keychain
+            .import(PrivateKey::generate(KeyType::Secp256k1, &mut rand_core::OsRng).unwrap());
+        for _ in 0..2 {
+            keychain
+                .try_sign(
+                    a,
+                    b"text",
+                    SigningVersion::Latest,
+                    &SignOptions::default(),
+                    &mut rand_core::OsRng,
+                )
+                .unwrap();
+        }
+        // failed operations are not counted
+        assert!(keychain
+            .try_sign_prehashed(b, &[0; 8], SigningVersion::Latest)
+            .is_err());
+
+        let stats = keychain.stats();
+        assert_eq!((stats[0].handle, stats[0].signatures), (a, 2));
+        assert!(stats[0].last_used.is_some());
+        assert_eq!(
+            stats[1],
+            KeyStats {
+                handle: b,
+                signatures: 0,
+                last_used: None
+            }
+        );
+        keychain.remove(a).unwrap();
+        assert_eq!(keychain.stats().len(), 1);
+    }
+
+    #[test]
+    fn keychain_namespaces() {
+        let namespaces = KeychainNamespaces::new();
+        let a = namespaces.get("16");
+        let handle = a
+            .write()
+            .import(PrivateKey::generate(KeyType::Ed25519, &mut rand_core::OsRng).unwrap());
+        assert!(namespaces.get("16").read().public_key(handle).is_ok());
+        assert!(matches!(
+            namespaces.get("17").read().public_key(handle),
+            Err(Error::InvalidHandle)
+        ));
+    }
+
+    #[test]
+    fn keychain_expiry() {
+        let mut keychain = Keychain::new();
+        let a =
+            keychain.import(PrivateKey::generate(KeyType::Ed25519, &mut rand_core::OsRng).unwrap());
+        let b = keychain.import_data_key(data_key::DataKey::generate(&mut rand_core::OsRng));
+        let now = unix_time();
+        keychain.set_expiry(a, Some(now - 1)).unwrap();
+        keychain.set_expiry(b, Some(now + 3600)).unwrap();
+        assert_eq!(keychain.list()[1].expires_at, Some(now + 3600));
+
+        // refused before being dropped
+        assert!(matches!(keychain.try_prove(a), Err(Error::KeyExpired)));
+        assert!(keychain.has_expired(now));
+        keychain.remove_expired(now);
+        assert!(!keychain.has_expired(now));
+        assert!(matches!(keychain.try_prove(a), Err(Error::KeyExpired)));
+        assert!(matches!(
+            keychain.set_expiry(a, None),
+            Err(Error::KeyExpired)
+        ));
+        assert_eq!(keychain.list().len(), 1);
+
+        assert!(keychain.data_key(b).is_ok());
+        keychain.set_expiry(b, None).unwrap();
+        keychain.remove_expired(now + 3600);
+        assert!(keychain.data_key(b).is_ok());
+    }
+
+    #[test]
+    fn keychain_metadata() {
+        let mut keychain = Keychain::new();
+        let a =
+            keychain.import(PrivateKey::generate(KeyType::Ed25519, &mut rand_core::OsRng).unwrap());
+        let b = keychain.import_data_key(data_key::DataKey::generate(&mut rand_core::OsRng));
+        let md = KeyMetadata::new("baker-main").with_entry("chain", "mainnet");
+        keychain.set_metadata(a, md.clone()).unwrap();
+        keychain
+            .set_metadata(b, KeyMetadata::new("payout"))
+            .unwrap();
+
+        let info = keychain.find_label("baker-main").unwrap();
+        assert_eq!((info.handle, info.kind), (a, KeyKind::Private));
+        assert_eq!(info.metadata, md);
+        assert_eq!(keychain.find_label("payout").unwrap().handle, b);
+        assert!(keychain.find_label("other").is_none());
+        assert_eq!(keychain.list()[0].metadata, md);
+
+        assert!(matches!(
+            keychain.set_metadata(a, KeyMetadata::new("x".repeat(MAX_LABEL_SIZE + 1))),
+            Err(Error::MetadataTooLarge)
+        ));
+        assert!(matches!(
+            keychain.set_metadata(KeyHandle(5), md),
+            Err(Error::InvalidHandle)
+        ));
+
+        keychain.set_metadata(a, KeyMetadata::default()).unwrap();
+        assert!(keychain.find_label("baker-main").is_none());
+        keychain.remove(b).unwrap();
+        assert!(keychain.find_label("payout").is_none());
+    }
+
+    #[test]
+    fn keychain_prehashed() {
+        let mut keychain = Keychain::new();
+        let data = b"text";
+        let digest = Blake2b256::digest(data);
+
+        let handle = keychain
+            .import(PrivateKey::generate(KeyType::Secp256k1, &mut rand_core::OsRng).unwrap());
+        let sig = unwrap_as!(
+            keychain
+                .try_sign_prehashed(handle, &digest, SigningVersion::Latest)
+                .unwrap(),
+            Signature::Secp256k1
+        );
+        let expected = unwrap_as!(
+            keychain
+                .try_sign(
+                    handle,
+                    data,
+                    SigningVersion::Latest,
+                    &SignOptions::default(),
+                    &mut rand_core::OsRng
+                )
+                .unwrap(),
+            Signature::Secp256k1
+        );
+        assert_eq!(*sig, *expected);
+
+        let handle =
+            keychain.import(PrivateKey::generate(KeyType::Ed25519, &mut rand_core::OsRng).unwrap());
+        let sig = unwrap_as!(
+            keychain
+                .try_sign_prehashed(handle, &digest, SigningVersion::Latest)
+                .unwrap(),
+            Signature::Ed25519
+        );
+        let pub_key = unwrap_as!(keychain.public_key(handle).unwrap(), PublicKey::Ed25519);
+        pub_key.verify(&digest, &sig).unwrap();
+
+        assert!(matches!(
+            keychain.try_sign_prehashed(handle, data, SigningVersion::Latest),
+            Err(Error::DigestSize(4))
+        ));
+
+        let handle =
+            keychain.import(PrivateKey::generate(KeyType::Bls, &mut rand_core::OsRng).unwrap());
+        assert!(matches!(
+            keychain.try_sign_prehashed(handle, &digest, SigningVersion::Latest),
+            Err(Error::PrehashUnsupported)
+        ));
+    }
+
+    #[test]
+    fn verify_cache() {
+        let mut cache = VerifyCache::new(4);
+        let data = b"text";
+        for t in [
+            KeyType::Secp256k1,
+            KeyType::NistP256,
+            KeyType::Ed25519,
+            KeyType::Bls,
+        ] {
+            let pk = PrivateKey::generate(t, &mut rand_core::OsRng).unwrap();
+            let pub_key = pk.public_key();
+            let sig = pk.try_sign(data, SigningVersion::Latest).unwrap();
+            for _ in 0..2 {
+                assert!(cache
+                    .verify(&pub_key, data, &sig, SigningVersion::Latest)
+                    .unwrap());
+                assert!(!cache
+                    .verify(&pub_key, b"other", &sig, SigningVersion::Latest)
+                    .unwrap());
+            }
+        }
+
+        let pub_key = PrivateKey::generate(KeyType::Ed25519, &mut rand_core::OsRng)
+            .unwrap()
+            .public_key();
+        let sig = PrivateKey::generate(KeyType::Secp256k1, &mut rand_core::OsRng)
+            .unwrap()
+            .try_sign(data, SigningVersion::Latest)
+            .unwrap();
+        assert!(matches!(
+            cache.verify(&pub_key, data, &sig, SigningVersion::Latest),
+            Err(Error::KeyTypeMismatch)
+        ));
+    }
+
+    #[test]
+    fn sign_options_digest() {
+        use signature::hazmat::PrehashVerifier;
+
+        let data = b"text";
+        for alg in [
+            DigestAlgorithm::Blake2b256,
+            DigestAlgorithm::Sha256,
+            DigestAlgorithm::Sha512,
+            DigestAlgorithm::Keccak256,
+        ] {
+            let options = SignOptions {
+                digest: Some(alg),
+                ..Default::default()
+            };
+            let hash = alg.digest(data);
+
+            let pk = PrivateKey::generate(KeyType::Secp256k1, &mut rand_core::OsRng).unwrap();
+            let sig = unwrap_as!(
+                pk.try_sign_with_options(
+                    data,
+                    SigningVersion::Latest,
+                    &options,
+                    &mut rand_core::OsRng
+                )
+                .unwrap(),
+                Signature::Secp256k1
+            );
+            unwrap_as!(pk.public_key(), PublicKey::Secp256k1)
+                .verify_prehash(&hash, &*sig)
+                .unwrap();
+
+            let pk = PrivateKey::generate(KeyType::Ed25519, &mut rand_core::OsRng).unwrap();
+            let sig = unwrap_as!(
+                pk.try_sign_with_options(
+                    data,
+                    SigningVersion::Latest,
+                    &options,
+                    &mut rand_core::OsRng
+                )
+                .unwrap(),
+                Signature::Ed25519
+            );
+            unwrap_as!(pk.public_key(), PublicKey::Ed25519)
+                .verify(&hash, &sig)
+                .unwrap();
+
+            let pk = PrivateKey::generate(KeyType::Bls, &mut rand_core::OsRng).unwrap();
+            assert!(matches!(
+                pk.try_sign_with_options(
+                    data,
+                    SigningVersion::Latest,
+                    &options,
+                    &mut rand_core::OsRng
+                ),
+                Err(Error::PrehashUnsupported)
+            ));
+        }
+    }
+
+    #[test]
+    fn sign_options_builder() {
+        let options = SignOptions::default()
+            .with_digest(DigestAlgorithm::Keccak256)
+            .with_eip191(true);
+        assert_eq!(
+            options,
+            SignOptions {
+                digest: Some(DigestAlgorithm::Keccak256),
+                hedged: false,
+                eip191: true,
+                ed25519ph: None,
+                bls: None,
+            }
+        );
+        assert_ne!(options, options.clone().with_hedged(true));
+        assert_eq!(
+            KeyConstraint::new("abc").with_max_attempts(10),
+            KeyConstraint {
+                hash_prefix: "abc".into(),
+                max_attempts: 10,
+            }
+        );
+    }
+
+    #[test]
+    fn key_type_string() {
+        use crate::{TryFromCBOR, TryIntoCBOR};
+
+        for t in [
+            KeyType::Secp256k1,
+            KeyType::NistP256,
+            KeyType::Ed25519,
+            KeyType::Bls,
+        ] {
+            assert_eq!(t.to_string().parse::<KeyType>().unwrap(), t);
+            assert_eq!(
+                KeyType::try_from_cbor(&t.to_string().try_into_cbor().unwrap()).unwrap(),
+                t
+            );
+        }
+        assert_eq!("p256".parse::<KeyType>().unwrap(), KeyType::NistP256);
+        assert_eq!("NistP256".parse::<KeyType>().unwrap(), KeyType::NistP256);
+        // wire representation is unchanged
+        assert_eq!(
+            KeyType::NistP256.try_into_cbor().unwrap(),
+            "NistP256".try_into_cbor().unwrap()
+        );
+        assert!(matches!(
+            "rsa".parse::<KeyType>(),
+            Err(Error::UnknownKeyType(s)) if s == "rsa"
+        ));
+    }
+
+    #[test]
+    fn sign_options_eip191() {
+        use signature::hazmat::PrehashVerifier;
+
+        let options = SignOptions {
+            eip191: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            eip191_message(b"hello"),
+            b"\x19Ethereum Signed Message:\n5hello".to_vec()
+        );
+        let hash = DigestAlgorithm::Keccak256.digest(&eip191_message(b"hello"));
+        assert_eq!(
+            hash.iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>(),
+            "50b2c43fd39106bafbba0da34fc430e1f91e3c96ea2acee2bc34119f92b37750"
+        );
+
+        let pk = PrivateKey::generate(KeyType::Secp256k1, &mut rand_core::OsRng).unwrap();
+        let sig = unwrap_as!(
+            pk.try_sign_with_options(
+                b"hello",
+                SigningVersion::Latest,
+                &options,
+                &mut rand_core::OsRng
+            )
+            .unwrap(),
+            Signature::Secp256k1
+        );
+        unwrap_as!(pk.public_key(), PublicKey::Secp256k1)
+            .verify_prehash(&hash, &*sig)
+            .unwrap();
+
+        let pk = PrivateKey::generate(KeyType::NistP256, &mut rand_core::OsRng).unwrap();
+        assert!(matches!(
+            pk.try_sign_with_options(
+                b"hello",
+                SigningVersion::Latest,
+                &options,
+                &mut rand_core::OsRng
+            ),
+            Err(Error::Eip191Unsupported)
+        ));
+    }
+
+    #[test]
+    fn sign_options_ed25519ph() {
+        fn hex(s: &str) -> Vec<u8> {
+            (0..s.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+                .collect()
+        }
+
+        // RFC 8032 section 7.3
+        let sk: [u8; 32] = hex("833fe62409237b9d62ec77587520911e9a759cec1d19755b7da901b96dca3d42")
+            .try_into()
+            .unwrap();
+        let pk = PrivateKey::from(ed25519_dalek::SigningKey::from_bytes(&sk));
+        let options = SignOptions::default().with_ed25519ph(b"");
+        let sig = unwrap_as!(
+            pk.try_sign_with_options(
+                b"abc",
+                SigningVersion::Latest,
+                &options,
+                &mut rand_core::OsRng
+            )
+            .unwrap(),
+            Signature::Ed25519
+        );
+        assert_eq!(
+            sig.to_bytes().to_vec(),
+            hex(concat!(
+                "98a70222f0b8121aa9d30f813d683f809e462b469c7ff87639499bb94e6dae41",
+                "31f85042463c2a355a2003d062adf5aaa10b8c61e636062aaad11c2a26083406"
+            ))
+        );
+
+        // context is bound to the signature
+        let options = SignOptions::default().with_ed25519ph(b"ctx");
+        let sig = unwrap_as!(
+            pk.try_sign_with_options(
+                b"abc",
+                SigningVersion::Latest,
+                &options,
+                &mut rand_core::OsRng
+            )
+            .unwrap(),
+            Signature::Ed25519
+        );
+        let vk = unwrap_as!(pk.public_key(), PublicKey::Ed25519);
+        let prehash = || sha2::Sha512::new().chain_update(b"abc");
+        vk.verify_prehashed(prehash(), Some(b"ctx"), &sig).unwrap();
+        assert!(vk.verify_prehashed(prehash(), Some(b"xyz"), &sig).is_err());
+
+        assert!(matches!(
+            pk.try_sign_with_options(
+                b"abc",
+                SigningVersion::Latest,
+                &options.clone().with_digest(DigestAlgorithm::Sha256),
+                &mut rand_core::OsRng
+            ),
+            Err(Error::Ed25519phUnsupported)
+        ));
+        assert!(matches!(
+            pk.try_sign_with_options(
+                b"abc",
+                SigningVersion::Latest,
+                &SignOptions::default().with_ed25519ph([0u8; 256]),
+                &mut rand_core::OsRng
+            ),
+            Err(Error::Signature(_))
+        ));
+
+        let pk = PrivateKey::generate(KeyType::Secp256k1, &mut rand_core::OsRng).unwrap();
+        assert!(matches!(
+            pk.try_sign_with_options(
+                b"abc",
+                SigningVersion::Latest,
+                &options,
+                &mut rand_core::OsRng
+            ),
+            Err(Error::Ed25519phUnsupported)
+        ));
+    }
+
+    #[test]
+    fn sign_options_bls() {
+        let pk = PrivateKey::generate(KeyType::Bls, &mut rand_core::OsRng).unwrap();
+        let pub_key = unwrap_as!(pk.public_key(), PublicKey::Bls);
+        let sign = |options: &BlsOptions| {
+            unwrap_as!(
+                pk.try_sign_with_options(
+                    b"abc",
+                    SigningVersion::Latest,
+                    &SignOptions::default().with_bls(options.clone()),
+                    &mut rand_core::OsRng
+                )
+                .unwrap(),
+                Signature::Bls
+            )
+        };
+
+        // the version defaults are reproduced
+        for version in [SigningVersion::V1, SigningVersion::V2] {
+            let sig = sign(&BlsOptions::from_version(version.clone()).unwrap());
+            pub_key.verify(b"abc", &sig, version).unwrap();
+        }
+
+        for scheme in [
+            BlsScheme::Basic,
+            BlsScheme::MessageAugmentation,
+            BlsScheme::ProofOfPossession,
+        ] {
+            let options = BlsOptions::new(scheme).with_dst(b"CUSTOM_DST_".as_slice());
+            let sig = sign(&options);
+            pub_key.verify_with_options(b"abc", &sig, &options).unwrap();
+            assert!(matches!(
+                pub_key.verify_with_options(b"abc", &sig, &BlsOptions::new(scheme)),
+                Err(Error::Signature(_))
+            ));
+        }
+        let sig = sign(&BlsOptions::new(BlsScheme::Basic));
+        assert!(matches!(
+            pub_key.verify_with_options(
+                b"abc",
+                &sig,
+                &BlsOptions::new(BlsScheme::MessageAugmentation)
+            ),
+            Err(Error::Signature(_))
+        ));
+
+        assert!(matches!(
+            pk.try_sign_with_options(
+                b"abc",
+                SigningVersion::Latest,
+                &SignOptions::default()
+                    .with_bls(BlsOptions::new(BlsScheme::Basic).with_dst([0u8; 256])),
+                &mut rand_core::OsRng
+            ),
+            Err(Error::InvalidDst)
+        ));
+        assert!(matches!(
+            pk.try_sign_with_options(
+                b"abc",
+                SigningVersion::Latest,
+                &SignOptions::default()
+                    .with_bls(BlsOptions::new(BlsScheme::Basic))
+                    .with_digest(DigestAlgorithm::Sha256),
+                &mut rand_core::OsRng
+            ),
+            Err(Error::BlsOptionsUnsupported)
+        ));
+        let pk = PrivateKey::generate(KeyType::Ed25519, &mut rand_core::OsRng).unwrap();
+        assert!(matches!(
+            pk.try_sign_with_options(
+                b"abc",
+                SigningVersion::Latest,
+                &SignOptions::default().with_bls(BlsOptions::new(BlsScheme::Basic)),
+                &mut rand_core::OsRng
+            ),
+            Err(Error::BlsOptionsUnsupported)
+        ));
+    }
+
+    #[test]
+    fn verify_batch() {
+        let keys: Vec<PrivateKey> = (0..4)
+            .map(|_| PrivateKey::generate(KeyType::Ed25519, &mut rand_core::OsRng).unwrap())
+            .collect();
+        let pks: Vec<PublicKey> = keys.iter().map(PrivateKey::public_key).collect();
+        let msgs: Vec<Vec<u8>> = (0..4u8).map(|i| vec![i; 10]).collect();
+        let mut sigs: Vec<Signature> = keys
+            .iter()
+            .zip(&msgs)
+            .map(|(k, m)| k.try_sign(m, SigningVersion::Latest).unwrap())
+            .collect();
+        assert!(super::verify_batch(&pks, &msgs, &sigs).unwrap());
+        assert!(super::verify_batch::<&[u8]>(&[], &[], &[]).unwrap());
+
+        sigs.swap(0, 1);
+        assert!(!super::verify_batch(&pks, &msgs, &sigs).unwrap());
+        assert!(matches!(
+            super::verify_batch(&pks[1..], &msgs, &sigs),
+            Err(Error::BatchSizeMismatch)
+        ));
+
+        let secp = PrivateKey::generate(KeyType::Secp256k1, &mut rand_core::OsRng).unwrap();
+        let sig = secp.try_sign(&msgs[0], SigningVersion::Latest).unwrap();
+        assert!(matches!(
+            super::verify_batch(&[secp.public_key()], &msgs[..1], &[sig]),
+            Err(Error::KeyTypeMismatch)
+        ));
+    }
+
+    #[test]
+    fn sign_options_hedged() {
+        let data = b"text";
+        let options = SignOptions {
+            hedged: true,
+            ..Default::default()
+        };
+
+        let pk = PrivateKey::generate(KeyType::NistP256, &mut rand_core::OsRng).unwrap();
+        let sig_a = pk
+            .try_sign_with_options(
+                data,
+                SigningVersion::Latest,
+                &options,
+                &mut rand_core::OsRng,
+            )
+            .unwrap();
+        let sig_b = pk
+            .try_sign_with_options(
+                data,
+                SigningVersion::Latest,
+                &options,
+                &mut rand_core::OsRng,
+            )
+            .unwrap();
+        let pub_key = pk.public_key();
+        pub_key.verify(data, &sig_a).unwrap();
+        pub_key.verify(data, &sig_b).unwrap();
+        // nonces are randomized
+        assert_ne!(
+            unwrap_as!(sig_a, Signature::NistP256).to_bytes(),
+            unwrap_as!(sig_b, Signature::NistP256).to_bytes()
+        );
+
+        let pk = PrivateKey::generate(KeyType::Ed25519, &mut rand_core::OsRng).unwrap();
+        assert!(matches!(
+            pk.try_sign_with_options(
+                data,
+                SigningVersion::Latest,
+                &options,
+                &mut rand_core::OsRng
+            ),
+            Err(Error::HedgingUnsupported)
+        ));
+    }
+
+    #[test]
+    fn public_key_export() {
+        use spki::DecodePublicKey;
+
+        let pk = PrivateKey::generate(KeyType::Secp256k1, &mut rand_core::OsRng)
+            .unwrap()
+            .public_key();
+        assert_eq!(
+            pk.export(PublicKeyFormat::Sec1Compressed).unwrap().len(),
+            33
+        );
+        assert_eq!(
+            pk.export(PublicKeyFormat::Sec1Uncompressed).unwrap().len(),
+            65
+        );
+        // uncompressed points are accepted on input
+        let uncompressed = ciborium::Value::Map(vec![(
+            "Secp256k1".into(),
+            ciborium::Value::Bytes(pk.export(PublicKeyFormat::Sec1Uncompressed).unwrap()),
+        )]);
+        let de_pk = PublicKey::try_from_cbor(&uncompressed.try_into_cbor().unwrap()).unwrap();
+        // and always returned compressed
+        assert_eq!(de_pk.try_into_cbor().unwrap(), pk.try_into_cbor().unwrap());
+        let der = pk.export(PublicKeyFormat::Spki).unwrap();
+        assert_eq!(
+            ::ecdsa::VerifyingKey::<Secp256k1>::from_public_key_der(&der).unwrap(),
+            unwrap_as!(pk, PublicKey::Secp256k1).0
+        );
+
+        let pk = PrivateKey::generate(KeyType::Ed25519, &mut rand_core::OsRng)
+            .unwrap()
+            .public_key();
+        let der = pk.export(PublicKeyFormat::Spki).unwrap();
+        let pk = unwrap_as!(pk, PublicKey::Ed25519);
+        assert_eq!(
+            ed25519_dalek::VerifyingKey::from_public_key_der(&der).unwrap(),
+            pk
+        );
+        assert_eq!(
+            PublicKey::Ed25519(pk).export(PublicKeyFormat::Raw).unwrap(),
+            pk.to_bytes()
+        );
+
+        let pk = PrivateKey::generate(KeyType::Bls, &mut rand_core::OsRng)
+            .unwrap()
+            .public_key();
+        assert_eq!(pk.export(PublicKeyFormat::Raw).unwrap().len(), 48);
+        assert!(matches!(
+            pk.export(PublicKeyFormat::Spki),
+            Err(Error::UnsupportedFormat)
+        ));
+    }
+
+    #[test]
+    fn generate_constrained() {
+        let constraint = KeyConstraint {
+            hash_prefix: "A".into(),
+            max_attempts: 1000,
+        };
+        let (pk, attempts) =
+            PrivateKey::generate_constrained(KeyType::Ed25519, &constraint, &mut rand_core::OsRng)
+                .unwrap();
+        assert!(attempts >= 1);
+        assert_eq!(pk.public_key().hash()[0] >> 4, 0xa);
+
+        let constraint = KeyConstraint {
+            hash_prefix: "0000000000".into(),
+            max_attempts: 3,
+        };
+        assert!(matches!(
+            PrivateKey::generate_constrained(KeyType::Ed25519, &constraint, &mut rand_core::OsRng),
+            Err(Error::ConstraintNotSatisfied(3))
+        ));
+
+        let constraint = KeyConstraint {
+            hash_prefix: "tz1".into(),
+            max_attempts: 3,
+        };
+        assert!(matches!(
+            PrivateKey::generate_constrained(KeyType::Ed25519, &constraint, &mut rand_core::OsRng),
+            Err(Error::InvalidConstraint)
+        ));
+    }
+
+    #[test]
+    fn weak_keys() {
+        fn key(t: &str, data: Vec<u8>) -> Vec<u8> {
+            ciborium::Value::Map(vec![(t.into(), ciborium::Value::Bytes(data))])
+                .try_into_cbor()
+                .unwrap()
+        }
+
+        for (t, data) in [
+            ("Secp256k1", vec![0; 32]),
+            ("Secp256k1", vec![0xff; 32]),
+            ("NistP256", vec![0; 32]),
+            ("NistP256", vec![0xff; 32]),
+            ("Bls", vec![0; 32]),
+            ("Bls", vec![0xff; 32]),
+        ] {
+            let err = PrivateKey::try_from_cbor(&key(t, data)).unwrap_err();
+            assert!(err.to_string().contains("Weak or invalid private key"));
+        }
+
+        let pk = PrivateKey::try_from_cbor(&key("Ed25519", vec![0; 32])).unwrap();
+        assert!(matches!(pk.check_weak(), Err(Error::WeakKey)));
+
+        for t in [KeyType::Secp256k1, KeyType::Ed25519, KeyType::Bls] {
+            let pk = PrivateKey::generate(t, &mut rand_core::OsRng).unwrap();
+            assert!(pk.check_weak().is_ok());
+        }
+    }
+
+    #[test]
+    fn keychain_public_key() {
+        let mut keychain = Keychain::new();
+        let pk = PrivateKey::generate(KeyType::Ed25519, &mut rand_core::OsRng).unwrap();
+        let private = keychain.import(pk.clone());
+        let public = keychain.import_public_key(pk.public_key());
+        assert_eq!(u64::from(public), u64::from(private) + 1);
+        assert!(matches!(
+            keychain.try_sign(
+                public,
+                b"text",
+                SigningVersion::Latest,
+                &SignOptions::default(),
+                &mut rand_core::OsRng
+            ),
+            Err(Error::PublicKeyOnly)
+        ));
+        assert_eq!(
+            keychain.public_key(public).unwrap().hash(),
+            pk.public_key().hash()
+        );
+        let info = keychain.find_public_key(&pk.public_key()).unwrap();
+        assert_eq!((info.handle, info.kind), (private, KeyKind::Private));
+
+        let other = PrivateKey::generate(KeyType::Ed25519, &mut rand_core::OsRng).unwrap();
+        assert!(keychain.find_public_key(&other.public_key()).is_none());
+    }
+
+    #[test]
+    fn fingerprint() {
+        let pk = PrivateKey::generate(KeyType::Secp256k1, &mut rand_core::OsRng).unwrap();
+        let fp = pk.public_key().fingerprint().unwrap();
+        let expected: [u8; 32] =
+            sha2::Sha256::digest(pk.public_key().try_into_cbor().unwrap()).into();
+        assert_eq!(fp, expected);
+
+        let other = PrivateKey::generate(KeyType::Secp256k1, &mut rand_core::OsRng).unwrap();
+        assert_ne!(fp, other.public_key().fingerprint().unwrap());
+    }
+
+    #[test]
+    fn public_key_verify() {
+        for t in [
+            KeyType::Secp256k1,
+            KeyType::NistP256,
+            KeyType::Ed25519,
+            KeyType::Bls,
+        ] {
+            let pk = PrivateKey::generate(t, &mut rand_core::OsRng).unwrap();
+            let sig = pk.try_sign(b"text", SigningVersion::Latest).unwrap();
+            pk.public_key().verify(b"text", &sig).unwrap();
+            assert!(matches!(
+                pk.public_key().verify(b"other", &sig),
+                Err(Error::Signature(_) | Error::Bls(_))
+            ));
+        }
+
+        let a = PrivateKey::generate(KeyType::Secp256k1, &mut rand_core::OsRng).unwrap();
+        let b = PrivateKey::generate(KeyType::Ed25519, &mut rand_core::OsRng).unwrap();
+        let sig = a.try_sign(b"text", SigningVersion::Latest).unwrap();
+        assert!(matches!(
+            b.public_key().verify(b"text", &sig),
+            Err(Error::KeyTypeMismatch)
+        ));
+    }
+
+    #[test]
+    fn private_key_ct_eq() {
+        use subtle::ConstantTimeEq;
+
+        let mut keychain = Keychain::new();
+        for t in [
+            KeyType::Secp256k1,
+            KeyType::NistP256,
+            KeyType::Ed25519,
+            KeyType::Bls,
+        ] {
+            let a = PrivateKey::generate(t, &mut rand_core::OsRng).unwrap();
+            let b = PrivateKey::generate(t, &mut rand_core::OsRng).unwrap();
+            assert!(bool::from(a.ct_eq(&a.clone())));
+            assert!(!bool::from(a.ct_eq(&b)));
+
+            let handle = keychain.import(a.clone());
+            assert_eq!(keychain.find_private_key(&a), Some(handle));
+            assert_eq!(keychain.find_private_key(&b), None);
+        }
+    }
 }
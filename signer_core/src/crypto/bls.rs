@@ -1,16 +1,18 @@
 use crate::{
+    cddl::{Cddl, Schema},
     crypto::{
         self, CryptoRngCore, Deserialize, KeyPair, PossessionProver, ProofVerifier, Random,
         Serialize, SigningVersion, Verifier,
     },
-    serde_helper,
+    serde_helper::{self, bytes},
 };
 use blst::min_pk;
 pub use blst::BLST_ERROR;
 use format_bytes::{format_bytes, DisplayBytes};
 use std::fmt::Display;
+use zeroize::{ZeroizeOnDrop, Zeroizing};
 
-#[derive(Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Cddl)]
 pub enum Scheme {
     Basic,
     MessageAugmentation,
@@ -58,6 +60,61 @@ impl Into<Vec<u8>> for CipherSuite {
     }
 }
 
+/// Longest domain separation tag allowed by RFC 9380
+pub const MAX_DST_SIZE: usize = 255;
+
+/// BLS signing parameters overriding the ones implied by the signing version, e.g. to match
+/// the ciphersuite expected by a particular chain
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Cddl)]
+pub struct BlsOptions {
+    /// `MessageAugmentation` prepends the compressed public key to the message
+    pub scheme: Scheme,
+    /// Custom domain separation tag. Defaults to the `BLS_SIG_BLS12381G2_XMD:SHA-256_SSWU_RO_`
+    /// ciphersuite of the scheme
+    #[serde(default, with = "bytes", skip_serializing_if = "Vec::is_empty")]
+    pub dst: Vec<u8>,
+}
+
+impl BlsOptions {
+    pub fn new(scheme: Scheme) -> Self {
+        BlsOptions {
+            scheme,
+            dst: Vec::new(),
+        }
+    }
+
+    pub fn with_dst(mut self, dst: impl Into<Vec<u8>>) -> Self {
+        self.dst = dst.into();
+        self
+    }
+
+    /// Parameters used by the signing version
+    pub fn from_version(version: SigningVersion) -> Result<Self, crypto::Error> {
+        match version {
+            SigningVersion::V0 => Err(crypto::Error::InvalidSigningVersion),
+            SigningVersion::V1 => Ok(Self::new(Scheme::MessageAugmentation)),
+            SigningVersion::V2 | SigningVersion::Latest => Ok(Self::new(Scheme::ProofOfPossession)),
+        }
+    }
+
+    fn dst(&self) -> Result<Vec<u8>, crypto::Error> {
+        if self.dst.is_empty() {
+            Ok(CipherSuite::Signature(2, self.scheme).into())
+        } else if self.dst.len() > MAX_DST_SIZE {
+            Err(crypto::Error::InvalidDst)
+        } else {
+            Ok(self.dst.clone())
+        }
+    }
+
+    fn aug(&self, pk: &min_pk::PublicKey) -> Vec<u8> {
+        match self.scheme {
+            Scheme::MessageAugmentation => pk.to_bytes().into(),
+            _ => Vec::new(),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Signature(min_pk::Signature);
 
@@ -68,6 +125,12 @@ impl core::ops::Deref for Signature {
     }
 }
 
+impl Cddl for Signature {
+    fn cddl_type(_: &mut Schema) -> String {
+        "bstr .size 96".into()
+    }
+}
+
 // use compressed form for serialization
 impl Serialize for Signature {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
@@ -101,6 +164,12 @@ impl core::ops::Deref for ProofOfPossession {
     }
 }
 
+impl Cddl for ProofOfPossession {
+    fn cddl_type(_: &mut Schema) -> String {
+        "bstr .size 96".into()
+    }
+}
+
 // use compressed form for serialization
 impl Serialize for ProofOfPossession {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
@@ -124,7 +193,7 @@ impl<'de> Deserialize<'de> for ProofOfPossession {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct PublicKey(min_pk::PublicKey);
 
 impl core::ops::Deref for PublicKey {
@@ -134,6 +203,17 @@ impl core::ops::Deref for PublicKey {
     }
 }
 
+impl PublicKey {
+    /// Compressed 48 byte form
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.0.compress().into()
+    }
+
+    pub fn from_bytes(src: &[u8]) -> Result<Self, Error> {
+        Ok(PublicKey(min_pk::PublicKey::uncompress(src)?))
+    }
+}
+
 impl Verifier<Signature> for PublicKey {
     fn verify(
         &self,
@@ -141,25 +221,23 @@ impl Verifier<Signature> for PublicKey {
         signature: &Signature,
         version: SigningVersion,
     ) -> Result<(), crypto::Error> {
-        let blst_error = match version {
-            SigningVersion::V0 => Err(crypto::Error::InvalidSigningVersion),
-            SigningVersion::V1 => {
-                let aug = self.to_bytes();
-                let cipher_suite: Vec<u8> =
-                    CipherSuite::Signature(2, Scheme::MessageAugmentation).into();
-                Ok(signature
-                    .0
-                    .verify(true, msg, &cipher_suite, &aug, self, true))
-            }
-            SigningVersion::V2 | SigningVersion::Latest => {
-                let cipher_suite: Vec<u8> =
-                    CipherSuite::Signature(2, Scheme::ProofOfPossession).into();
-                Ok(signature
-                    .0
-                    .verify(true, msg, &cipher_suite, &[], self, true))
-            }
-        }?;
-        match blst_error {
+        self.verify_with_options(msg, signature, &BlsOptions::from_version(version)?)
+    }
+}
+
+impl PublicKey {
+    /// Verifies the signature produced by `SigningKey::try_sign_with_options`
+    pub fn verify_with_options(
+        &self,
+        msg: &[u8],
+        signature: &Signature,
+        options: &BlsOptions,
+    ) -> Result<(), crypto::Error> {
+        let dst = options.dst()?;
+        match signature
+            .0
+            .verify(true, msg, &dst, &options.aug(self), self, true)
+        {
             blst::BLST_ERROR::BLST_SUCCESS => Ok(()),
             err => {
                 let b: Box<dyn std::error::Error + Send + Sync> = Box::new(Error::from(err));
@@ -169,6 +247,55 @@ impl Verifier<Signature> for PublicKey {
     }
 }
 
+/// Verifies the aggregate signature of the same message by all of `keys`.
+/// With the proof of possession scheme (V2) the proofs of all keys must be checked beforehand
+/// to rule out rogue key attacks
+pub fn verify_aggregate(
+    keys: &[PublicKey],
+    msg: &[u8],
+    signature: &Signature,
+    version: SigningVersion,
+) -> Result<(), crypto::Error> {
+    if keys.is_empty() {
+        return Err(crypto::Error::EmptyKeySet);
+    }
+    let blst_error = match version {
+        SigningVersion::V0 => Err(crypto::Error::InvalidSigningVersion),
+        SigningVersion::V1 => {
+            // augmented messages are distinct so the regular aggregate verification applies
+            let cipher_suite: Vec<u8> =
+                CipherSuite::Signature(2, Scheme::MessageAugmentation).into();
+            let msgs: Vec<Vec<u8>> = keys
+                .iter()
+                .map(|pk| [pk.to_bytes().as_slice(), msg].concat())
+                .collect();
+            let msgs: Vec<&[u8]> = msgs.iter().map(Vec::as_slice).collect();
+            let pks: Vec<&min_pk::PublicKey> = keys.iter().map(|pk| &pk.0).collect();
+            Ok(signature
+                .0
+                .aggregate_verify(true, &msgs, &cipher_suite, &pks, true))
+        }
+        SigningVersion::V2 | SigningVersion::Latest => {
+            // unlike aggregate_verify the keys aren't validated by blst
+            for pk in keys {
+                pk.0.validate().map_err(Error::from)?;
+            }
+            let cipher_suite: Vec<u8> = CipherSuite::Signature(2, Scheme::ProofOfPossession).into();
+            let pks: Vec<&min_pk::PublicKey> = keys.iter().map(|pk| &pk.0).collect();
+            Ok(signature
+                .0
+                .fast_aggregate_verify(true, msg, &cipher_suite, &pks))
+        }
+    }?;
+    match blst_error {
+        blst::BLST_ERROR::BLST_SUCCESS => Ok(()),
+        err => {
+            let b: Box<dyn std::error::Error + Send + Sync> = Box::new(Error::from(err));
+            Err(crypto::Error::Signature(b.into()))
+        }
+    }
+}
+
 impl ProofVerifier<ProofOfPossession> for PublicKey {
     fn verify_pop(&self, proof: &ProofOfPossession) -> Result<(), crypto::Error> {
         let cipher_suite: Vec<u8> =
@@ -186,6 +313,12 @@ impl ProofVerifier<ProofOfPossession> for PublicKey {
     }
 }
 
+impl Cddl for PublicKey {
+    fn cddl_type(_: &mut Schema) -> String {
+        "bstr .size 48".into()
+    }
+}
+
 // use compressed form for serialization
 impl Serialize for PublicKey {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
@@ -212,6 +345,9 @@ impl<'de> Deserialize<'de> for PublicKey {
 #[derive(Debug, Clone)]
 pub struct SigningKey(pub(crate) min_pk::SecretKey);
 
+// `min_pk::SecretKey` zeroizes itself on drop
+impl ZeroizeOnDrop for SigningKey {}
+
 impl core::ops::Deref for SigningKey {
     type Target = min_pk::SecretKey;
     fn deref(&self) -> &Self::Target {
@@ -222,9 +358,9 @@ impl core::ops::Deref for SigningKey {
 impl Random for SigningKey {
     type Error = Error;
     fn random<R: CryptoRngCore>(r: &mut R) -> Result<Self, Error> {
-        let mut ikm: [u8; 32] = [0; 32];
-        r.fill_bytes(&mut ikm);
-        Ok(SigningKey(min_pk::SecretKey::key_gen(&ikm, &[])?))
+        let mut ikm = Zeroizing::new([0u8; 32]);
+        r.fill_bytes(&mut ikm[..]);
+        Ok(SigningKey(min_pk::SecretKey::key_gen(&ikm[..], &[])?))
     }
 }
 
@@ -242,20 +378,32 @@ impl KeyPair for SigningKey {
         msg: &[u8],
         version: SigningVersion,
     ) -> Result<Self::Signature, Self::Error> {
-        match version {
-            SigningVersion::V0 => Err(crypto::Error::InvalidSigningVersion),
-            SigningVersion::V1 => {
-                let aug = self.sk_to_pk().to_bytes();
-                let cipher_suite: Vec<u8> =
-                    CipherSuite::Signature(2, Scheme::MessageAugmentation).into();
-                Ok(Signature(self.sign(msg, &cipher_suite, &aug)))
-            }
-            SigningVersion::V2 | SigningVersion::Latest => {
-                let cipher_suite: Vec<u8> =
-                    CipherSuite::Signature(2, Scheme::ProofOfPossession).into();
-                Ok(Signature(self.sign(msg, &cipher_suite, &[])))
-            }
-        }
+        self.try_sign_with_options(msg, &BlsOptions::from_version(version)?)
+    }
+
+    // BLS hashes the message to the curve itself
+    fn try_sign_prehashed(
+        &self,
+        _digest: &[u8],
+        _version: SigningVersion,
+    ) -> Result<Self::Signature, Self::Error> {
+        Err(crypto::Error::PrehashUnsupported)
+    }
+}
+
+impl SigningKey {
+    /// Signs the message with the explicitly chosen scheme and domain separation tag
+    pub fn try_sign_with_options(
+        &self,
+        msg: &[u8],
+        options: &BlsOptions,
+    ) -> Result<Signature, crypto::Error> {
+        let dst = options.dst()?;
+        Ok(Signature(self.sign(
+            msg,
+            &dst,
+            &options.aug(&self.sk_to_pk()),
+        )))
     }
 }
 
@@ -271,12 +419,18 @@ impl PossessionProver for SigningKey {
     }
 }
 
+impl Cddl for SigningKey {
+    fn cddl_type(_: &mut Schema) -> String {
+        "bstr .size 32".into()
+    }
+}
+
 impl Serialize for SigningKey {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
-        serializer.serialize_bytes(&self.0.serialize())
+        serializer.serialize_bytes(&Zeroizing::new(self.0.serialize())[..])
     }
 }
 
@@ -285,10 +439,12 @@ impl<'de> Deserialize<'de> for SigningKey {
     where
         D: serde::Deserializer<'de>,
     {
-        let bytes = deserializer.deserialize_bytes(serde_helper::ByteArrayVisitor::<32>::new())?;
-        match min_pk::SecretKey::deserialize(&bytes) {
+        let bytes = Zeroizing::new(
+            deserializer.deserialize_bytes(serde_helper::ByteArrayVisitor::<32>::new())?,
+        );
+        match min_pk::SecretKey::deserialize(&bytes[..]) {
             Ok(val) => Ok(SigningKey(val)),
-            Err(err) => Err(serde::de::Error::custom(Error::from(err))),
+            Err(_) => Err(serde::de::Error::custom(crypto::Error::WeakKey)),
         }
     }
 }
@@ -318,3 +474,55 @@ impl From<BLST_ERROR> for Error {
 }
 
 impl std::error::Error for Error {}
+
+#[cfg(test)]
+mod tests {
+    use super::{verify_aggregate, Signature, SigningKey};
+    use crate::crypto::{Error, KeyPair, Random, SigningVersion};
+    use blst::min_pk::AggregateSignature;
+
+    fn aggregate(keys: &[SigningKey], msg: &[u8], version: SigningVersion) -> Signature {
+        let sigs: Vec<Signature> = keys
+            .iter()
+            .map(|k| k.try_sign(msg, version.clone()).unwrap())
+            .collect();
+        let sigs: Vec<_> = sigs.iter().map(|s| &s.0).collect();
+        Signature(
+            AggregateSignature::aggregate(&sigs, true)
+                .unwrap()
+                .to_signature(),
+        )
+    }
+
+    #[test]
+    fn bls_verify_aggregate() {
+        let keys: Vec<SigningKey> = (0..4)
+            .map(|_| SigningKey::random(&mut rand_core::OsRng).unwrap())
+            .collect();
+        let pks: Vec<_> = keys.iter().map(KeyPair::public_key).collect();
+        let msg = b"block";
+
+        for version in [SigningVersion::V1, SigningVersion::V2] {
+            let sig = aggregate(&keys, msg, version.clone());
+            verify_aggregate(&pks, msg, &sig, version.clone()).unwrap();
+            assert!(matches!(
+                verify_aggregate(&pks, b"other", &sig, version.clone()),
+                Err(Error::Signature(_))
+            ));
+            // a missing signer
+            assert!(matches!(
+                verify_aggregate(&pks[1..], msg, &sig, version.clone()),
+                Err(Error::Signature(_))
+            ));
+        }
+        assert!(matches!(
+            verify_aggregate(
+                &[],
+                msg,
+                &aggregate(&keys, msg, SigningVersion::V2),
+                SigningVersion::V2
+            ),
+            Err(Error::EmptyKeySet)
+        ));
+    }
+}
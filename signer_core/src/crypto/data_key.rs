@@ -0,0 +1,121 @@
+//! Symmetric data keys for envelope encryption of host data
+//!
+//! The ciphertext is `nonce || AES-256-GCM(key, nonce, plaintext)` with a random 12 byte nonce.
+use crate::crypto::Error;
+use aes_gcm::{aead::Aead, aead::Payload, Aes256Gcm, KeyInit, Nonce};
+use rand_core::CryptoRngCore;
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+pub const KEY_SIZE: usize = 32;
+pub const NONCE_SIZE: usize = 12;
+
+mod key_bytes {
+    use super::KEY_SIZE;
+    use crate::serde_helper::ByteArrayVisitor;
+    use serde::{Deserializer, Serializer};
+    use zeroize::Zeroizing;
+
+    pub fn serialize<S: Serializer>(
+        value: &Zeroizing<[u8; KEY_SIZE]>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_bytes(&value[..])
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Zeroizing<[u8; KEY_SIZE]>, D::Error> {
+        Ok(Zeroizing::new(
+            deserializer.deserialize_bytes(ByteArrayVisitor::<KEY_SIZE>::new())?,
+        ))
+    }
+}
+
+/// Symmetric key stored in the keychain next to the signing keys. Encrypted the same way
+/// as private keys when leaving the signer
+#[derive(Clone, Serialize, Deserialize)]
+pub enum DataKey {
+    Aes256Gcm(#[serde(with = "key_bytes")] Zeroizing<[u8; KEY_SIZE]>),
+}
+
+impl std::fmt::Debug for DataKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DataKey::Aes256Gcm(_) => f.write_str("DataKey::Aes256Gcm(..)"),
+        }
+    }
+}
+
+impl DataKey {
+    pub fn generate<R: CryptoRngCore>(r: &mut R) -> Self {
+        let mut key = Zeroizing::new([0; KEY_SIZE]);
+        r.fill_bytes(&mut key[..]);
+        DataKey::Aes256Gcm(key)
+    }
+
+    fn cipher(&self) -> Aes256Gcm {
+        match self {
+            DataKey::Aes256Gcm(key) => Aes256Gcm::new(key.as_ref().into()),
+        }
+    }
+
+    /// Encrypts the plaintext. `aad` is authenticated but not encrypted
+    pub fn encrypt<R: CryptoRngCore>(&self, plaintext: &[u8], aad: &[u8], r: &mut R) -> Vec<u8> {
+        let mut out = vec![0; NONCE_SIZE];
+        r.fill_bytes(&mut out);
+        let ct = self
+            .cipher()
+            .encrypt(
+                Nonce::from_slice(&out),
+                Payload {
+                    msg: plaintext,
+                    aad,
+                },
+            )
+            .expect("message within AES-GCM size limit");
+        out.extend_from_slice(&ct);
+        out
+    }
+
+    /// Decrypts the ciphertext produced by `encrypt` with the same `aad`
+    pub fn decrypt(&self, ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>, Error> {
+        if ciphertext.len() < NONCE_SIZE {
+            return Err(Error::DecryptionFailed);
+        }
+        let (nonce, ct) = ciphertext.split_at(NONCE_SIZE);
+        self.cipher()
+            .decrypt(Nonce::from_slice(nonce), Payload { msg: ct, aad })
+            .map_err(|_| Error::DecryptionFailed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DataKey;
+    use crate::crypto::Error;
+    use crate::{TryFromCBOR, TryIntoCBOR};
+
+    #[test]
+    fn data_key() {
+        let key = DataKey::generate(&mut rand_core::OsRng);
+        let ct = key.encrypt(b"text", b"aad", &mut rand_core::OsRng);
+        assert_eq!(key.decrypt(&ct, b"aad").unwrap(), b"text");
+        assert!(matches!(
+            key.decrypt(&ct, b"other"),
+            Err(Error::DecryptionFailed)
+        ));
+        assert!(matches!(
+            key.decrypt(&ct[..8], b"aad"),
+            Err(Error::DecryptionFailed)
+        ));
+
+        let restored = DataKey::try_from_cbor(&key.try_into_cbor().unwrap()).unwrap();
+        assert_eq!(restored.decrypt(&ct, b"aad").unwrap(), b"text");
+        let other = DataKey::generate(&mut rand_core::OsRng);
+        assert!(matches!(
+            other.decrypt(&ct, b"aad"),
+            Err(Error::DecryptionFailed)
+        ));
+    }
+}
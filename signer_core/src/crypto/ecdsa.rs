@@ -1,7 +1,8 @@
 use crate::{
+    cddl::{Cddl, Schema},
     crypto::{
-        Blake2b256, CryptoRngCore, Deserialize, Digest, DigestSigner, KeyPair, Random, Serialize,
-        SigningVersion,
+        self, Blake2b256, CryptoRngCore, Deserialize, Digest, DigestSigner, KeyPair, Random,
+        Serialize, SigningVersion, Verifier,
     },
     serde_helper,
 };
@@ -9,14 +10,22 @@ use ecdsa::{hazmat::SignPrimitive, SignatureSize};
 use elliptic_curve::{
     ops::Invert,
     point::PointCompression,
-    sec1::{CompressedPointSize, FromEncodedPoint, ModulusSize, ToEncodedPoint},
+    sec1::{
+        CompressedPointSize, FromEncodedPoint, ModulusSize, ToEncodedPoint, UncompressedPointSize,
+    },
     AffinePoint, CurveArithmetic, FieldBytesSize, PrimeCurve, Scalar,
 };
 use generic_array::{typenum::Unsigned, ArrayLength};
 pub use k256::Secp256k1;
 pub use p256::NistP256;
+use signature::{
+    hazmat::{PrehashSigner, RandomizedPrehashSigner},
+    DigestVerifier,
+};
+use spki::EncodePublicKey;
 use std::convert::Infallible;
 use subtle::CtOption;
+use zeroize::ZeroizeOnDrop;
 
 #[derive(Debug, Clone)]
 pub struct Signature<C>(ecdsa::Signature<C>)
@@ -35,6 +44,16 @@ where
     }
 }
 
+impl<C> Cddl for Signature<C>
+where
+    C: PrimeCurve,
+    SignatureSize<C>: ArrayLength<u8>,
+{
+    fn cddl_type(_: &mut Schema) -> String {
+        format!("bstr .size {}", SignatureSize::<C>::USIZE)
+    }
+}
+
 impl<C> Serialize for Signature<C>
 where
     C: PrimeCurve,
@@ -73,6 +92,15 @@ where
     Scalar<C>: Invert<Output = CtOption<Scalar<C>>> + SignPrimitive<C>,
     SignatureSize<C>: ArrayLength<u8>;
 
+// `ecdsa::SigningKey` zeroizes itself on drop
+impl<C> ZeroizeOnDrop for SigningKey<C>
+where
+    C: PrimeCurve + CurveArithmetic,
+    Scalar<C>: Invert<Output = CtOption<Scalar<C>>> + SignPrimitive<C>,
+    SignatureSize<C>: ArrayLength<u8>,
+{
+}
+
 impl<C> Random for SigningKey<C>
 where
     C: PrimeCurve + CurveArithmetic,
@@ -97,6 +125,17 @@ where
     }
 }
 
+impl<C> Cddl for SigningKey<C>
+where
+    C: PrimeCurve + CurveArithmetic,
+    Scalar<C>: Invert<Output = CtOption<Scalar<C>>> + SignPrimitive<C>,
+    SignatureSize<C>: ArrayLength<u8>,
+{
+    fn cddl_type(_: &mut Schema) -> String {
+        format!("bstr .size {}", FieldBytesSize::<C>::USIZE)
+    }
+}
+
 impl<C> Serialize for SigningKey<C>
 where
     C: PrimeCurve + CurveArithmetic,
@@ -123,9 +162,10 @@ where
     {
         let bytes = deserializer
             .deserialize_bytes(serde_helper::BytesVisitor::new(FieldBytesSize::<C>::USIZE))?;
+        // the length is checked by the visitor so the only failure is a zero or out of range scalar
         match ecdsa::SigningKey::from_slice(&bytes) {
             Ok(val) => Ok(Self(val)),
-            Err(err) => Err(serde::de::Error::custom(err)),
+            Err(_) => Err(serde::de::Error::custom(crypto::Error::WeakKey)),
         }
     }
 }
@@ -135,7 +175,8 @@ where
     C: PrimeCurve + CurveArithmetic,
     Scalar<C>: Invert<Output = CtOption<Scalar<C>>> + SignPrimitive<C>,
     SignatureSize<C>: ArrayLength<u8>,
-    ecdsa::SigningKey<C>: DigestSigner<Blake2b256, ecdsa::Signature<C>>,
+    ecdsa::SigningKey<C>:
+        DigestSigner<Blake2b256, ecdsa::Signature<C>> + PrehashSigner<ecdsa::Signature<C>>,
 {
     type PublicKey = VerifyingKey<C>;
     type Signature = Signature<C>;
@@ -153,6 +194,30 @@ where
         d.update(msg);
         Ok(Signature(self.0.try_sign_digest(d)?))
     }
+    fn try_sign_prehashed(
+        &self,
+        digest: &[u8],
+        _version: SigningVersion,
+    ) -> Result<Self::Signature, Self::Error> {
+        Ok(Signature(self.0.sign_prehash(digest)?))
+    }
+}
+
+impl<C> SigningKey<C>
+where
+    C: PrimeCurve + CurveArithmetic,
+    Scalar<C>: Invert<Output = CtOption<Scalar<C>>> + SignPrimitive<C>,
+    SignatureSize<C>: ArrayLength<u8>,
+    ecdsa::SigningKey<C>: RandomizedPrehashSigner<ecdsa::Signature<C>>,
+{
+    /// Sign the digest using a RFC 6979 nonce mixed with additional randomness from `rng`
+    pub fn try_sign_prehashed_with_rng<R: CryptoRngCore>(
+        &self,
+        digest: &[u8],
+        rng: &mut R,
+    ) -> Result<Signature<C>, signature::Error> {
+        Ok(Signature(self.0.sign_prehash_with_rng(rng, digest)?))
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -170,6 +235,58 @@ where
     }
 }
 
+impl<C> Verifier<Signature<C>> for VerifyingKey<C>
+where
+    C: PrimeCurve + CurveArithmetic,
+    SignatureSize<C>: ArrayLength<u8>,
+    ecdsa::VerifyingKey<C>: DigestVerifier<Blake2b256, ecdsa::Signature<C>>,
+{
+    fn verify(
+        &self,
+        msg: &[u8],
+        signature: &Signature<C>,
+        _version: SigningVersion,
+    ) -> Result<(), crypto::Error> {
+        let mut d = Blake2b256::new();
+        d.update(msg);
+        Ok(self.0.verify_digest(d, &signature.0)?)
+    }
+}
+
+impl<C> VerifyingKey<C>
+where
+    C: PrimeCurve + CurveArithmetic + PointCompression,
+    FieldBytesSize<C>: ModulusSize,
+    AffinePoint<C>: FromEncodedPoint<C> + ToEncodedPoint<C>,
+{
+    pub fn to_sec1_bytes(&self, compress: bool) -> Vec<u8> {
+        self.0.to_encoded_point(compress).as_bytes().into()
+    }
+
+    /// DER encoded SubjectPublicKeyInfo
+    pub fn to_spki_der(&self) -> Result<Vec<u8>, spki::Error>
+    where
+        ecdsa::VerifyingKey<C>: EncodePublicKey,
+    {
+        Ok(self.0.to_public_key_der()?.into_vec())
+    }
+}
+
+impl<C> Cddl for VerifyingKey<C>
+where
+    C: PrimeCurve + CurveArithmetic,
+    FieldBytesSize<C>: ModulusSize,
+{
+    fn cddl_type(_: &mut Schema) -> String {
+        // uncompressed points are accepted on input
+        format!(
+            "bstr .size {} / bstr .size {}",
+            CompressedPointSize::<C>::USIZE,
+            UncompressedPointSize::<C>::USIZE
+        )
+    }
+}
+
 impl<C> Serialize for VerifyingKey<C>
 where
     C: PrimeCurve + CurveArithmetic + PointCompression,
@@ -194,8 +311,9 @@ where
     where
         D: serde::Deserializer<'de>,
     {
-        let bytes = deserializer.deserialize_bytes(serde_helper::BytesVisitor::new(
+        let bytes = deserializer.deserialize_bytes(serde_helper::BytesVisitor::either(
             CompressedPointSize::<C>::USIZE,
+            UncompressedPointSize::<C>::USIZE,
         ))?;
         match ecdsa::VerifyingKey::from_sec1_bytes(&bytes) {
             Ok(val) => Ok(Self(val)),
@@ -0,0 +1,175 @@
+//! ECIES over Secp256k1 and NIST P-256 keys.
+//!
+//! The ciphertext is `R || nonce || AES-256-GCM(k, nonce, plaintext)` where `R` is the compressed
+//! SEC1 ephemeral public key, `nonce` is 12 random bytes and `k` is HKDF-SHA256 of the X coordinate
+//! of the ECDH shared point with `R` as the salt and [`INFO`] as the info string. Keys wrapped by
+//! `ExportWrapped` use [`WRAP_INFO`] instead, so they can't be unwrapped by a general decryption.
+use crate::crypto::{
+    ecdsa::{NistP256, Secp256k1},
+    Error, PrivateKey, PublicKey,
+};
+use aes_gcm::{aead::Aead, Aes256Gcm, KeyInit, Nonce};
+use elliptic_curve::{
+    point::{AffineCoordinates, PointCompression},
+    sec1::{CompressedPointSize, FromEncodedPoint, ModulusSize, ToEncodedPoint},
+    AffinePoint, CurveArithmetic, FieldBytesSize, NonZeroScalar, ProjectivePoint,
+};
+use generic_array::typenum::Unsigned;
+use hkdf::Hkdf;
+use rand_core::CryptoRngCore;
+use sha2::Sha256;
+use zeroize::Zeroizing;
+
+/// HKDF info string
+pub const INFO: &[u8] = b"ECIES-HKDF-SHA256-AES256GCM";
+/// HKDF info string of wrapped private keys
+pub const WRAP_INFO: &[u8] = b"ECIES-HKDF-SHA256-AES256GCM key wrapping";
+pub const NONCE_SIZE: usize = 12;
+
+fn cipher<C>(
+    secret: &NonZeroScalar<C>,
+    point: &AffinePoint<C>,
+    ephemeral: &[u8],
+    info: &[u8],
+) -> Aes256Gcm
+where
+    C: CurveArithmetic,
+{
+    let shared = Zeroizing::new((ProjectivePoint::<C>::from(*point) * secret.as_ref()).into());
+    let x = Zeroizing::new(AffineCoordinates::x(&*shared as &AffinePoint<C>));
+    let mut key = Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha256>::new(Some(ephemeral), &x)
+        .expand(info, &mut key[..])
+        .expect("valid HKDF output length");
+    Aes256Gcm::new(key.as_ref().into())
+}
+
+fn encrypt<C, R>(
+    point: &AffinePoint<C>,
+    info: &[u8],
+    msg: &[u8],
+    r: &mut R,
+) -> Result<Vec<u8>, Error>
+where
+    C: CurveArithmetic + PointCompression,
+    FieldBytesSize<C>: ModulusSize,
+    AffinePoint<C>: FromEncodedPoint<C> + ToEncodedPoint<C>,
+    R: CryptoRngCore,
+{
+    let ephemeral = NonZeroScalar::<C>::random(r);
+    let mut out: Vec<u8> = elliptic_curve::PublicKey::<C>::from_secret_scalar(&ephemeral)
+        .to_encoded_point(true)
+        .as_bytes()
+        .into();
+    let cipher = cipher(&ephemeral, point, &out, info);
+    let mut nonce = [0u8; NONCE_SIZE];
+    r.fill_bytes(&mut nonce);
+    let ct = cipher
+        .encrypt(Nonce::from_slice(&nonce), msg)
+        .expect("message within AES-GCM size limit");
+    out.extend_from_slice(&nonce);
+    out.extend_from_slice(&ct);
+    Ok(out)
+}
+
+fn decrypt<C>(secret: &NonZeroScalar<C>, info: &[u8], src: &[u8]) -> Result<Vec<u8>, Error>
+where
+    C: CurveArithmetic,
+    FieldBytesSize<C>: ModulusSize,
+    AffinePoint<C>: FromEncodedPoint<C> + ToEncodedPoint<C>,
+{
+    let point_size = CompressedPointSize::<C>::USIZE;
+    if src.len() < point_size + NONCE_SIZE {
+        return Err(Error::DecryptionFailed);
+    }
+    let (ephemeral, rest) = src.split_at(point_size);
+    let (nonce, ct) = rest.split_at(NONCE_SIZE);
+    let point = elliptic_curve::PublicKey::<C>::from_sec1_bytes(ephemeral)
+        .map_err(|_| Error::DecryptionFailed)?;
+    cipher(secret, point.as_affine(), ephemeral, info)
+        .decrypt(Nonce::from_slice(nonce), ct)
+        .map_err(|_| Error::DecryptionFailed)
+}
+
+impl PublicKey {
+    /// Encrypts the message to the key
+    pub fn ecies_encrypt<R: CryptoRngCore>(&self, msg: &[u8], r: &mut R) -> Result<Vec<u8>, Error> {
+        self.ecies_encrypt_with(INFO, msg, r)
+    }
+
+    pub(crate) fn ecies_encrypt_with<R: CryptoRngCore>(
+        &self,
+        info: &[u8],
+        msg: &[u8],
+        r: &mut R,
+    ) -> Result<Vec<u8>, Error> {
+        match self {
+            PublicKey::Secp256k1(pk) => encrypt::<Secp256k1, R>(pk.as_affine(), info, msg, r),
+            PublicKey::NistP256(pk) => encrypt::<NistP256, R>(pk.as_affine(), info, msg, r),
+            _ => Err(Error::EciesUnsupported),
+        }
+    }
+}
+
+impl PrivateKey {
+    /// Decrypts the message encrypted to the public key
+    pub fn ecies_decrypt(&self, src: &[u8]) -> Result<Vec<u8>, Error> {
+        self.ecies_decrypt_with(INFO, src)
+    }
+
+    pub(crate) fn ecies_decrypt_with(&self, info: &[u8], src: &[u8]) -> Result<Vec<u8>, Error> {
+        match self {
+            PrivateKey::Secp256k1(sk) => decrypt(sk.as_nonzero_scalar(), info, src),
+            PrivateKey::NistP256(sk) => decrypt(sk.as_nonzero_scalar(), info, src),
+            _ => Err(Error::EciesUnsupported),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::{Error, KeyPair, KeyType, PrivateKey};
+
+    #[test]
+    fn ecies() {
+        for t in [KeyType::Secp256k1, KeyType::NistP256] {
+            let sk = PrivateKey::generate(t, &mut rand_core::OsRng).unwrap();
+            let ct = sk
+                .public_key()
+                .ecies_encrypt(b"text", &mut rand_core::OsRng)
+                .unwrap();
+            assert_eq!(sk.ecies_decrypt(&ct).unwrap(), b"text");
+
+            let mut tampered = ct.clone();
+            *tampered.last_mut().unwrap() ^= 1;
+            assert!(matches!(
+                sk.ecies_decrypt(&tampered),
+                Err(Error::DecryptionFailed)
+            ));
+            let other = PrivateKey::generate(t, &mut rand_core::OsRng).unwrap();
+            assert!(matches!(
+                other.ecies_decrypt(&ct),
+                Err(Error::DecryptionFailed)
+            ));
+            assert!(matches!(
+                sk.ecies_decrypt(&ct[..40]),
+                Err(Error::DecryptionFailed)
+            ));
+            // domain separation
+            assert!(matches!(
+                sk.ecies_decrypt_with(super::WRAP_INFO, &ct),
+                Err(Error::DecryptionFailed)
+            ));
+        }
+        let sk = PrivateKey::generate(KeyType::Ed25519, &mut rand_core::OsRng).unwrap();
+        assert!(matches!(
+            sk.public_key()
+                .ecies_encrypt(b"text", &mut rand_core::OsRng),
+            Err(Error::EciesUnsupported)
+        ));
+        assert!(matches!(
+            sk.ecies_decrypt(&[0; 64]),
+            Err(Error::EciesUnsupported)
+        ));
+    }
+}
@@ -0,0 +1,80 @@
+//! [RFC 7517](https://www.rfc-editor.org/rfc/rfc7517) JSON Web Key representation of public keys
+use crate::crypto::PublicKey;
+use base64ct::{Base64UrlUnpadded, Encoding};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Jwk {
+    pub kty: String,
+    pub crv: String,
+    pub x: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub y: Option<String>,
+}
+
+impl Jwk {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+fn ec(crv: &str, point: &[u8]) -> Jwk {
+    // uncompressed SEC1 point: 0x04 || x || y
+    let (x, y) = point[1..].split_at((point.len() - 1) / 2);
+    Jwk {
+        kty: "EC".into(),
+        crv: crv.into(),
+        x: Base64UrlUnpadded::encode_string(x),
+        y: Some(Base64UrlUnpadded::encode_string(y)),
+    }
+}
+
+fn okp(crv: &str, x: &[u8]) -> Jwk {
+    Jwk {
+        kty: "OKP".into(),
+        crv: crv.into(),
+        x: Base64UrlUnpadded::encode_string(x),
+        y: None,
+    }
+}
+
+impl PublicKey {
+    /// Ed25519 keys use `OKP` type from RFC 8037. BLS keys follow draft-ietf-cose-bls-key-representations
+    /// and are encoded as compressed G1 points
+    pub fn to_jwk(&self) -> Jwk {
+        match self {
+            PublicKey::Secp256k1(pk) => ec("secp256k1", &pk.to_sec1_bytes(false)),
+            PublicKey::NistP256(pk) => ec("P-256", &pk.to_sec1_bytes(false)),
+            PublicKey::Ed25519(pk) => okp("Ed25519", pk.as_bytes()),
+            PublicKey::Bls(pk) => okp("BLS12381G1", &pk.to_bytes()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::{KeyPair, KeyType, PrivateKey, PublicKeyFormat};
+    use base64ct::{Base64UrlUnpadded, Encoding};
+
+    #[test]
+    fn to_jwk() {
+        let pk = PrivateKey::generate(KeyType::NistP256, &mut rand_core::OsRng)
+            .unwrap()
+            .public_key();
+        let jwk = pk.to_jwk();
+        assert_eq!((jwk.kty.as_str(), jwk.crv.as_str()), ("EC", "P-256"));
+        let mut point = vec![4];
+        point.extend(Base64UrlUnpadded::decode_vec(&jwk.x).unwrap());
+        point.extend(Base64UrlUnpadded::decode_vec(jwk.y.as_ref().unwrap()).unwrap());
+        assert_eq!(point, pk.export(PublicKeyFormat::Sec1Uncompressed).unwrap());
+
+        let pk = PrivateKey::generate(KeyType::Ed25519, &mut rand_core::OsRng)
+            .unwrap()
+            .public_key();
+        let json = pk.export(PublicKeyFormat::Jwk).unwrap();
+        let v: serde_json::Value = serde_json::from_slice(&json).unwrap();
+        assert_eq!(v["kty"], "OKP");
+        assert_eq!(v["crv"], "Ed25519");
+        assert!(v.get("y").is_none());
+    }
+}
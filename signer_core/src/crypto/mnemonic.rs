@@ -0,0 +1,148 @@
+//! BIP39 mnemonic seed derivation
+//!
+//! The words are checked against the English wordlist and the checksum is verified.
+//! Only ASCII mnemonics and passphrases are accepted as they don't need Unicode normalization.
+use crate::crypto::{ecdsa, Error, KeyType, PrivateKey};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256, Sha512};
+use zeroize::Zeroizing;
+
+const PBKDF2_ROUNDS: u32 = 2048;
+const WORD_COUNTS: &[usize] = &[12, 15, 18, 21, 24];
+/// BIP39 English wordlist, sorted
+const WORDLIST: &str = include_str!("bip39_english.txt");
+
+/// Checks the words and the checksum: each word encodes 11 bits, the entropy is followed by
+/// the first `bits / 33` bits of its SHA-256
+fn verify(words: &[&str]) -> Result<(), Error> {
+    let wordlist: Vec<&str> = WORDLIST.lines().collect();
+    let bits = words.len() * 11;
+    let mut data = Zeroizing::new(vec![0u8; bits.div_ceil(8)]);
+    for (i, word) in words.iter().enumerate() {
+        let index = wordlist
+            .binary_search(word)
+            .map_err(|_| Error::UnknownMnemonicWord(i))?;
+        for b in 0..11 {
+            if (index >> (10 - b)) & 1 == 1 {
+                let pos = i * 11 + b;
+                data[pos / 8] |= 0x80 >> (pos % 8);
+            }
+        }
+    }
+    let checksum_bits = bits / 33;
+    let entropy = (bits - checksum_bits) / 8;
+    let hash = Sha256::digest(&data[..entropy]);
+    let mask = 0xffu8 << (8 - checksum_bits);
+    if data[entropy] & mask == hash[0] & mask {
+        Ok(())
+    } else {
+        Err(Error::MnemonicChecksum)
+    }
+}
+
+/// PBKDF2-HMAC-SHA512 producing a single 64 byte block
+fn pbkdf2(password: &[u8], salt: &[u8]) -> Zeroizing<[u8; 64]> {
+    let mac = Hmac::<Sha512>::new_from_slice(password).unwrap();
+    let mut u = Zeroizing::new([0u8; 64]);
+    let mut m = mac.clone();
+    m.update(salt);
+    m.update(&1u32.to_be_bytes());
+    u.copy_from_slice(&m.finalize().into_bytes());
+
+    let mut out = u.clone();
+    for _ in 1..PBKDF2_ROUNDS {
+        let mut m = mac.clone();
+        m.update(&*u);
+        u.copy_from_slice(&m.finalize().into_bytes());
+        out.iter_mut().zip(u.iter()).for_each(|(o, u)| *o ^= u);
+    }
+    out
+}
+
+/// Returns the 64 byte BIP39 seed
+pub fn seed(mnemonic: &str, passphrase: &str) -> Result<Zeroizing<[u8; 64]>, Error> {
+    let words: Vec<&str> = mnemonic.split_whitespace().collect();
+    if !WORD_COUNTS.contains(&words.len())
+        || !words
+            .iter()
+            .all(|w| w.bytes().all(|c| c.is_ascii_lowercase()))
+        || !passphrase.is_ascii()
+    {
+        return Err(Error::InvalidMnemonic);
+    }
+    verify(&words)?;
+    let normalized = Zeroizing::new(words.join(" "));
+    let salt = Zeroizing::new(format!("mnemonic{}", passphrase));
+    Ok(pbkdf2(normalized.as_bytes(), salt.as_bytes()))
+}
+
+impl PrivateKey {
+    /// Derives the key from the first 32 bytes of the BIP39 seed like Tezos wallets do
+    pub fn from_mnemonic(t: KeyType, mnemonic: &str, passphrase: &str) -> Result<Self, Error> {
+        let seed = seed(mnemonic, passphrase)?;
+        let secret = &seed[..32];
+        match t {
+            KeyType::Ed25519 => {
+                Ok(ed25519_dalek::SigningKey::from_bytes(secret.try_into().unwrap()).into())
+            }
+            KeyType::Secp256k1 => Ok(PrivateKey::Secp256k1(ecdsa::SigningKey(
+                ::ecdsa::SigningKey::from_slice(secret)?,
+            ))),
+            KeyType::NistP256 => Ok(PrivateKey::NistP256(ecdsa::SigningKey(
+                ::ecdsa::SigningKey::from_slice(secret)?,
+            ))),
+            KeyType::Bls => Err(Error::InvalidMnemonic),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::seed;
+    use crate::crypto::{Error, KeyType, PrivateKey};
+
+    const MNEMONIC: &str = "abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon abandon about";
+
+    #[test]
+    fn bip39_seed() {
+        let s = seed(MNEMONIC, "TREZOR").unwrap();
+        let hex: String = s.iter().map(|b| format!("{:02x}", b)).collect();
+        assert_eq!(
+            hex,
+            "c55257c360c07c72029aebc1b53c05ed0362ada38ead3e3e9efa3708e53495531f09a6987599d18264c1e1c92f2cf141630c7a3c4ab7c81b2f001698e7463b04"
+        );
+        assert!(matches!(
+            seed("abandon about", ""),
+            Err(Error::InvalidMnemonic)
+        ));
+        assert!(PrivateKey::from_mnemonic(KeyType::Secp256k1, MNEMONIC, "").is_ok());
+    }
+
+    #[test]
+    fn bip39_verify() {
+        // BIP39 test vectors of every length
+        for mnemonic in [
+            "legal winner thank year wave sausage worth useful legal winner thank yellow",
+            "letter advice cage absurd amount doctor acoustic avoid letter advice cage above",
+            "zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo zoo wrong",
+            "scheme spot photo card baby mountain device kick cradle pact join borrow",
+            "cat swing flag economy stadium alone churn speed unique patch report train",
+            "gravity machine north sort system female filter attitude volume fold club stay feature office ecology stable narrow fog",
+            "hamster diagram private dutch cause delay private meat slide toddler razor book happy fancy gospel tennis maple dilemma loan word shrug inflict delay length",
+            "void come effort suffer camp survey warrior heavy shoot primary clutch crush open amazing screen patrol group space point ten exist slush involve unfold",
+        ] {
+            assert!(seed(mnemonic, "").is_ok(), "{}", mnemonic);
+        }
+
+        let wrong_word = MNEMONIC.replace("about", "abbout");
+        assert!(matches!(
+            seed(&wrong_word, ""),
+            Err(Error::UnknownMnemonicWord(11))
+        ));
+        let bad_checksum = MNEMONIC.replace("about", "above");
+        assert!(matches!(
+            seed(&bad_checksum, ""),
+            Err(Error::MnemonicChecksum)
+        ));
+    }
+}
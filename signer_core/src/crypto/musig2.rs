@@ -0,0 +1,398 @@
+//! MuSig2 multi-signatures over secp256k1 (BIP 327) producing BIP 340 Schnorr signatures.
+//!
+//! Key tweaking is not supported. The message is signed as is, without pre-hashing.
+use crate::crypto::{Error, PublicKey};
+use k256::{
+    elliptic_curve::{
+        group::Group,
+        ops::Reduce,
+        point::AffineCoordinates,
+        sec1::{FromEncodedPoint, ToEncodedPoint},
+        PrimeField,
+    },
+    AffinePoint, EncodedPoint, FieldBytes, ProjectivePoint, Scalar, U256,
+};
+use rand_core::CryptoRngCore;
+use sha2::{Digest, Sha256};
+use zeroize::{Zeroize, Zeroizing};
+
+pub const PUB_NONCE_SIZE: usize = 66;
+pub const PARTIAL_SIGNATURE_SIZE: usize = 32;
+pub const SIGNATURE_SIZE: usize = 64;
+
+type PubNonce = [u8; PUB_NONCE_SIZE];
+type PartialSignature = [u8; PARTIAL_SIGNATURE_SIZE];
+
+/// BIP 340 tagged hash
+pub(crate) fn tagged_hash(tag: &str, parts: &[&[u8]]) -> FieldBytes {
+    let tag = Sha256::digest(tag.as_bytes());
+    let mut d = Sha256::new().chain_update(tag).chain_update(tag);
+    for p in parts {
+        d.update(p);
+    }
+    d.finalize()
+}
+
+fn hash_to_scalar(tag: &str, parts: &[&[u8]]) -> Scalar {
+    <Scalar as Reduce<U256>>::reduce_bytes(&tagged_hash(tag, parts))
+}
+
+fn has_even_y(p: &AffinePoint) -> bool {
+    !bool::from(p.y_is_odd())
+}
+
+fn cbytes(p: &AffinePoint) -> [u8; 33] {
+    p.to_encoded_point(true).as_bytes().try_into().unwrap()
+}
+
+// the point at infinity is encoded as zeros
+fn cbytes_ext(p: &ProjectivePoint) -> [u8; 33] {
+    if bool::from(p.is_identity()) {
+        [0; 33]
+    } else {
+        cbytes(&p.to_affine())
+    }
+}
+
+fn cpoint(b: &[u8]) -> Option<AffinePoint> {
+    if b.len() != 33 || (b[0] != 2 && b[0] != 3) {
+        return None;
+    }
+    let ep = EncodedPoint::from_bytes(b).ok()?;
+    Option::from(AffinePoint::from_encoded_point(&ep))
+}
+
+fn cpoint_ext(b: &[u8]) -> Option<ProjectivePoint> {
+    if b.iter().all(|x| *x == 0) {
+        Some(ProjectivePoint::IDENTITY)
+    } else {
+        cpoint(b).map(Into::into)
+    }
+}
+
+fn pub_nonce_points(n: &PubNonce) -> Option<(ProjectivePoint, ProjectivePoint)> {
+    Some((cpoint(&n[..33])?.into(), cpoint(&n[33..])?.into()))
+}
+
+/// Parses the public nonces received from the co-signers
+pub fn parse_pub_nonces(src: &[Vec<u8>]) -> Result<Vec<PubNonce>, Error> {
+    src.iter()
+        .map(|n| n[..].try_into().or(Err(Error::InvalidNonce)))
+        .collect()
+}
+
+/// Aggregated public key of the signers. The order of the keys matters
+pub struct KeyAggContext {
+    keys: Vec<[u8; 33]>,
+    list_hash: FieldBytes,
+    second_key: Option<[u8; 33]>,
+    q: AffinePoint,
+}
+
+impl KeyAggContext {
+    pub fn new(keys: &[AffinePoint]) -> Result<Self, Error> {
+        if keys.is_empty() {
+            return Err(Error::EmptyKeySet);
+        }
+        let keys: Vec<[u8; 33]> = keys.iter().map(cbytes).collect();
+        let list_hash = tagged_hash(
+            "KeyAgg list",
+            &keys.iter().map(|k| &k[..]).collect::<Vec<_>>(),
+        );
+        let second_key = keys.iter().find(|k| **k != keys[0]).copied();
+        let mut ctx = KeyAggContext {
+            keys,
+            list_hash,
+            second_key,
+            q: AffinePoint::IDENTITY,
+        };
+        let q = ctx.keys.iter().fold(ProjectivePoint::IDENTITY, |acc, k| {
+            acc + ProjectivePoint::from(cpoint(k).unwrap()) * ctx.coefficient(k)
+        });
+        if bool::from(q.is_identity()) {
+            return Err(Error::InvalidAggregateKey);
+        }
+        ctx.q = q.to_affine();
+        Ok(ctx)
+    }
+
+    /// Accepts Secp256k1 keys only
+    pub fn from_public_keys(keys: &[PublicKey]) -> Result<Self, Error> {
+        let keys = keys
+            .iter()
+            .map(|pk| match pk {
+                PublicKey::Secp256k1(pk) => Ok(*pk.as_affine()),
+                _ => Err(Error::KeyTypeMismatch),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Self::new(&keys)
+    }
+
+    fn coefficient(&self, pk: &[u8; 33]) -> Scalar {
+        if Some(pk) == self.second_key.as_ref() {
+            Scalar::ONE
+        } else {
+            hash_to_scalar("KeyAgg coefficient", &[&self.list_hash, pk])
+        }
+    }
+
+    fn contains(&self, pk: &[u8; 33]) -> bool {
+        self.keys.iter().any(|k| k == pk)
+    }
+
+    /// X-only aggregated key for BIP 340 verification
+    pub fn aggregate_key(&self) -> [u8; 32] {
+        self.q.x().into()
+    }
+}
+
+/// Sums the public nonces of all signers
+pub fn nonce_agg(pub_nonces: &[PubNonce]) -> Result<PubNonce, Error> {
+    let (r1, r2) = pub_nonces.iter().try_fold(
+        (ProjectivePoint::IDENTITY, ProjectivePoint::IDENTITY),
+        |(r1, r2), n| {
+            let (a, b) = pub_nonce_points(n).ok_or(Error::InvalidNonce)?;
+            Ok::<_, Error>((r1 + a, r2 + b))
+        },
+    )?;
+    let mut res = [0; PUB_NONCE_SIZE];
+    res[..33].copy_from_slice(&cbytes_ext(&r1));
+    res[33..].copy_from_slice(&cbytes_ext(&r2));
+    Ok(res)
+}
+
+struct SessionValues {
+    b: Scalar,
+    e: Scalar,
+    r: AffinePoint,
+}
+
+impl SessionValues {
+    fn new(ctx: &KeyAggContext, agg_nonce: &PubNonce, msg: &[u8]) -> Result<Self, Error> {
+        let q = ctx.aggregate_key();
+        let b = hash_to_scalar("MuSig/noncecoef", &[agg_nonce, &q, msg]);
+        let r1 = cpoint_ext(&agg_nonce[..33]).ok_or(Error::InvalidNonce)?;
+        let r2 = cpoint_ext(&agg_nonce[33..]).ok_or(Error::InvalidNonce)?;
+        let r = r1 + r2 * b;
+        let r = if bool::from(r.is_identity()) {
+            AffinePoint::GENERATOR
+        } else {
+            r.to_affine()
+        };
+        let e = hash_to_scalar("BIP0340/challenge", &[&r.x(), &q, msg]);
+        Ok(SessionValues { b, e, r })
+    }
+
+    fn signature(&self, s: &Scalar) -> [u8; SIGNATURE_SIZE] {
+        let mut res = [0; SIGNATURE_SIZE];
+        res[..32].copy_from_slice(&self.r.x());
+        res[32..].copy_from_slice(&s.to_bytes());
+        res
+    }
+}
+
+/// Signer state between the nonce exchange and the partial signature. The secret nonce
+/// is consumed by `sign` so it can't be reused
+pub struct SignerSession {
+    k1: Scalar,
+    k2: Scalar,
+    public_key: [u8; 33],
+    pub_nonce: PubNonce,
+    ctx: KeyAggContext,
+    msg: Vec<u8>,
+}
+
+impl Drop for SignerSession {
+    fn drop(&mut self) {
+        self.k1.zeroize();
+        self.k2.zeroize();
+    }
+}
+
+impl SignerSession {
+    /// Generates the secret nonce for signing `msg` by the aggregated key with `sk`
+    pub fn new<R: CryptoRngCore>(
+        sk: &Scalar,
+        ctx: KeyAggContext,
+        msg: &[u8],
+        r: &mut R,
+    ) -> Result<Self, Error> {
+        let public_key = cbytes(&(ProjectivePoint::GENERATOR * sk).to_affine());
+        if !ctx.contains(&public_key) {
+            return Err(Error::SignerNotInKeySet);
+        }
+        let mut rand = Zeroizing::new([0u8; 32]);
+        r.fill_bytes(&mut rand[..]);
+        let aux = tagged_hash("MuSig/aux", &[&rand[..]]);
+        let sk_bytes = Zeroizing::new(sk.to_bytes());
+        for (r, (s, a)) in rand.iter_mut().zip(sk_bytes.iter().zip(aux.iter())) {
+            *r = s ^ a;
+        }
+
+        let agg_pk = ctx.aggregate_key();
+        let msg_len = (msg.len() as u64).to_be_bytes();
+        let nonce = |i: u8| {
+            hash_to_scalar(
+                "MuSig/nonce",
+                &[
+                    &rand[..],
+                    &[33],
+                    &public_key,
+                    &[32],
+                    &agg_pk,
+                    &[1],
+                    &msg_len,
+                    msg,
+                    &[0; 4],
+                    &[i],
+                ],
+            )
+        };
+        let mut session = SignerSession {
+            k1: nonce(0),
+            k2: nonce(1),
+            public_key,
+            pub_nonce: [0; PUB_NONCE_SIZE],
+            ctx,
+            msg: msg.into(),
+        };
+        if bool::from(session.k1.is_zero() | session.k2.is_zero()) {
+            return Err(Error::InvalidNonce);
+        }
+        session.pub_nonce[..33].copy_from_slice(&cbytes(
+            &(ProjectivePoint::GENERATOR * session.k1).to_affine(),
+        ));
+        session.pub_nonce[33..].copy_from_slice(&cbytes(
+            &(ProjectivePoint::GENERATOR * session.k2).to_affine(),
+        ));
+        Ok(session)
+    }
+
+    pub fn pub_nonce(&self) -> &PubNonce {
+        &self.pub_nonce
+    }
+
+    pub fn aggregate_key(&self) -> [u8; 32] {
+        self.ctx.aggregate_key()
+    }
+
+    /// Produces the partial signature. `pub_nonces` must include the own public nonce
+    pub fn sign(self, sk: &Scalar, pub_nonces: &[PubNonce]) -> Result<PartialSignature, Error> {
+        if cbytes(&(ProjectivePoint::GENERATOR * sk).to_affine()) != self.public_key {
+            return Err(Error::SignerNotInKeySet);
+        }
+        if !pub_nonces.contains(&self.pub_nonce) {
+            return Err(Error::InvalidNonce);
+        }
+        let v = SessionValues::new(&self.ctx, &nonce_agg(pub_nonces)?, &self.msg)?;
+        let a = self.ctx.coefficient(&self.public_key);
+        let d = Zeroizing::new(if has_even_y(&self.ctx.q) { *sk } else { -*sk });
+        let (k1, k2) = if has_even_y(&v.r) {
+            (self.k1, self.k2)
+        } else {
+            (-self.k1, -self.k2)
+        };
+        let (k1, k2) = (Zeroizing::new(k1), Zeroizing::new(k2));
+        let s = *k1 + v.b * *k2 + v.e * a * *d;
+        Ok(s.to_bytes().into())
+    }
+}
+
+/// Checks the partial signatures against the signers' public nonces and combines them
+/// into the BIP 340 signature. `pub_nonces` and `partial_signatures` follow the order of the keys
+pub fn aggregate(
+    ctx: &KeyAggContext,
+    msg: &[u8],
+    pub_nonces: &[PubNonce],
+    partial_signatures: &[Vec<u8>],
+) -> Result<[u8; SIGNATURE_SIZE], Error> {
+    if pub_nonces.len() != ctx.keys.len() || partial_signatures.len() != ctx.keys.len() {
+        return Err(Error::SignerCountMismatch);
+    }
+    let v = SessionValues::new(ctx, &nonce_agg(pub_nonces)?, msg)?;
+    let mut s = Scalar::ZERO;
+    for (i, (psig, (pk, n))) in partial_signatures
+        .iter()
+        .zip(ctx.keys.iter().zip(pub_nonces.iter()))
+        .enumerate()
+    {
+        let s_i = <[u8; PARTIAL_SIGNATURE_SIZE]>::try_from(&psig[..])
+            .ok()
+            .and_then(|b| Option::<Scalar>::from(Scalar::from_repr(b.into())))
+            .ok_or(Error::InvalidPartialSignature(i))?;
+        let (r1, r2) = pub_nonce_points(n).ok_or(Error::InvalidNonce)?;
+        let re = r1 + r2 * v.b;
+        let re = if has_even_y(&v.r) { re } else { -re };
+        let g = if has_even_y(&ctx.q) {
+            Scalar::ONE
+        } else {
+            -Scalar::ONE
+        };
+        let p = ProjectivePoint::from(cpoint(pk).unwrap());
+        if ProjectivePoint::GENERATOR * s_i != re + p * (v.e * ctx.coefficient(pk) * g) {
+            return Err(Error::InvalidPartialSignature(i));
+        }
+        s += s_i;
+    }
+    Ok(v.signature(&s))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{aggregate, nonce_agg, KeyAggContext, SignerSession};
+    use crate::crypto::Error;
+    use k256::{elliptic_curve::Field, schnorr, ProjectivePoint, Scalar};
+
+    #[test]
+    fn musig2_sign_aggregate() {
+        let msg = b"message";
+        let sks: Vec<Scalar> = (0..3)
+            .map(|_| Scalar::random(&mut rand_core::OsRng))
+            .collect();
+        let pks: Vec<_> = sks
+            .iter()
+            .map(|sk| (ProjectivePoint::GENERATOR * sk).to_affine())
+            .collect();
+
+        let sessions: Vec<_> = sks
+            .iter()
+            .map(|sk| {
+                SignerSession::new(
+                    sk,
+                    KeyAggContext::new(&pks).unwrap(),
+                    msg,
+                    &mut rand_core::OsRng,
+                )
+                .unwrap()
+            })
+            .collect();
+        let agg_key = sessions[0].aggregate_key();
+        let nonces: Vec<_> = sessions.iter().map(|s| *s.pub_nonce()).collect();
+        let mut psigs: Vec<Vec<u8>> = sessions
+            .into_iter()
+            .zip(sks.iter())
+            .map(|(s, sk)| s.sign(sk, &nonces).unwrap().to_vec())
+            .collect();
+
+        let ctx = KeyAggContext::new(&pks).unwrap();
+        let sig = aggregate(&ctx, msg, &nonces, &psigs).unwrap();
+        let vk = schnorr::VerifyingKey::from_bytes(&agg_key).unwrap();
+        vk.verify_raw(msg, &schnorr::Signature::try_from(&sig[..]).unwrap())
+            .unwrap();
+
+        // a bad partial signature is attributed to its signer
+        psigs[1][31] ^= 1;
+        assert!(matches!(
+            aggregate(&ctx, msg, &nonces, &psigs),
+            Err(Error::InvalidPartialSignature(1))
+        ));
+        assert!(nonce_agg(&[[0; 66]]).is_err());
+
+        // outsiders can't sign
+        let sk = Scalar::random(&mut rand_core::OsRng);
+        assert!(matches!(
+            SignerSession::new(&sk, ctx, msg, &mut rand_core::OsRng),
+            Err(Error::SignerNotInKeySet)
+        ));
+    }
+}
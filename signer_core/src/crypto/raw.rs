@@ -0,0 +1,199 @@
+//! Private keys in the raw formats used by other signers and wallets
+//!
+//! ECDSA and BLS secrets are 32 byte big endian scalars, Ed25519 secrets are RFC 8032 seeds
+//! which are expanded and clamped by the signing code.
+use crate::cddl::{Cddl, Schema};
+use crate::crypto::{bls, ecdsa, Error, KeyType, PrivateKey};
+use crate::serde_helper::bytes;
+use blst::min_pk;
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+pub const SECRET_SIZE: usize = 32;
+
+fn decode_hex(src: &str) -> Option<Zeroizing<Vec<u8>>> {
+    let src = src.strip_prefix("0x").unwrap_or(src).as_bytes();
+    if !src.len().is_multiple_of(2) {
+        return None;
+    }
+    let nibble = |c: u8| (c as char).to_digit(16).map(|d| d as u8);
+    src.chunks(2)
+        .map(|p| Some(nibble(p[0])? << 4 | nibble(p[1])?))
+        .collect::<Option<Vec<u8>>>()
+        .map(Zeroizing::new)
+}
+
+impl PrivateKey {
+    /// Parses the raw secret of the given key type
+    pub fn from_raw(t: KeyType, secret: &[u8]) -> Result<Self, Error> {
+        if secret.len() != SECRET_SIZE {
+            return Err(Error::RawKeySize(secret.len()));
+        }
+        let pk = match t {
+            KeyType::Ed25519 => {
+                ed25519_dalek::SigningKey::from_bytes(secret.try_into().unwrap()).into()
+            }
+            KeyType::Secp256k1 => PrivateKey::Secp256k1(ecdsa::SigningKey(
+                ::ecdsa::SigningKey::from_slice(secret).or(Err(Error::WeakKey))?,
+            )),
+            KeyType::NistP256 => PrivateKey::NistP256(ecdsa::SigningKey(
+                ::ecdsa::SigningKey::from_slice(secret).or(Err(Error::WeakKey))?,
+            )),
+            KeyType::Bls => {
+                bls::SigningKey(min_pk::SecretKey::from_bytes(secret).or(Err(Error::WeakKey))?)
+                    .into()
+            }
+        };
+        pk.check_weak()?;
+        Ok(pk)
+    }
+
+    /// Parses the hex encoded raw secret, optionally `0x` prefixed
+    pub fn from_hex(t: KeyType, secret: &str) -> Result<Self, Error> {
+        let secret = decode_hex(secret).ok_or(Error::InvalidHex)?;
+        Self::from_raw(t, &secret)
+    }
+}
+
+/// Raw secret either as bytes or as a hex string
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum RawSecret {
+    Bytes(#[serde(with = "bytes")] Vec<u8>),
+    Hex(String),
+}
+
+impl Cddl for RawSecret {
+    fn cddl_type(_: &mut Schema) -> String {
+        "bstr / tstr".into()
+    }
+}
+
+/// Private key accepted by `ImportUnencrypted`: either the crate's own encoding or
+/// the raw secret with an explicit key type
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum UnencryptedKey {
+    Raw {
+        key_type: KeyType,
+        secret: RawSecret,
+    },
+    Encoded(PrivateKey),
+}
+
+impl Cddl for UnencryptedKey {
+    fn cddl_type(schema: &mut Schema) -> String {
+        let key_type = KeyType::cddl_type(schema);
+        let secret = RawSecret::cddl_type(schema);
+        let encoded = PrivateKey::cddl_type(schema);
+        schema.define("UnencryptedKey", |_| {
+            format!(
+                "{{ key_type: {}, secret: {} }} / {}",
+                key_type, secret, encoded
+            )
+        })
+    }
+}
+
+impl UnencryptedKey {
+    pub fn into_private_key(self) -> Result<PrivateKey, Error> {
+        match self {
+            UnencryptedKey::Raw {
+                key_type,
+                secret: RawSecret::Bytes(secret),
+            } => PrivateKey::from_raw(key_type, &Zeroizing::new(secret)),
+            UnencryptedKey::Raw {
+                key_type,
+                secret: RawSecret::Hex(secret),
+            } => PrivateKey::from_hex(key_type, &Zeroizing::new(secret)),
+            UnencryptedKey::Encoded(pk) => Ok(pk),
+        }
+    }
+}
+
+impl From<PrivateKey> for UnencryptedKey {
+    fn from(value: PrivateKey) -> Self {
+        UnencryptedKey::Encoded(value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RawSecret, UnencryptedKey};
+    use crate::crypto::{Error, KeyPair, KeyType, PrivateKey};
+    use crate::{TryFromCBOR, TryIntoCBOR};
+
+    const SECRET: &str = "0c28fca386c7a227600b2fe50b7cae11ec86d3bf1fbe471be89827e19d72aa1d";
+
+    #[test]
+    fn from_raw() {
+        for t in [
+            KeyType::Secp256k1,
+            KeyType::NistP256,
+            KeyType::Ed25519,
+            KeyType::Bls,
+        ] {
+            let a = PrivateKey::from_hex(t, SECRET).unwrap();
+            let b = PrivateKey::from_hex(t, &format!("0x{}", SECRET.to_uppercase())).unwrap();
+            assert_eq!(
+                a.public_key().fingerprint().unwrap(),
+                b.public_key().fingerprint().unwrap()
+            );
+            assert!(matches!(
+                PrivateKey::from_raw(t, &[0; 32]),
+                Err(Error::WeakKey)
+            ));
+        }
+        // above the group order
+        assert!(matches!(
+            PrivateKey::from_raw(KeyType::Secp256k1, &[0xff; 32]),
+            Err(Error::WeakKey)
+        ));
+        assert!(matches!(
+            PrivateKey::from_raw(KeyType::Bls, &[0xff; 32]),
+            Err(Error::WeakKey)
+        ));
+        assert!(matches!(
+            PrivateKey::from_raw(KeyType::Ed25519, &[1; 31]),
+            Err(Error::RawKeySize(31))
+        ));
+        assert!(matches!(
+            PrivateKey::from_hex(KeyType::Ed25519, &SECRET[1..]),
+            Err(Error::InvalidHex)
+        ));
+        assert!(matches!(
+            PrivateKey::from_hex(KeyType::Ed25519, &SECRET.replace('c', "g")),
+            Err(Error::InvalidHex)
+        ));
+    }
+
+    #[test]
+    fn unencrypted_key_serde() {
+        let pk = PrivateKey::from_hex(KeyType::Secp256k1, SECRET).unwrap();
+        let fp = pk.public_key().fingerprint().unwrap();
+
+        let keys = [
+            UnencryptedKey::Raw {
+                key_type: KeyType::Secp256k1,
+                secret: RawSecret::Hex(SECRET.into()),
+            },
+            UnencryptedKey::Raw {
+                key_type: KeyType::Secp256k1,
+                secret: RawSecret::Bytes(super::decode_hex(SECRET).unwrap().to_vec()),
+            },
+            pk.into(),
+        ];
+        for key in keys {
+            let decoded = UnencryptedKey::try_from_cbor(&key.try_into_cbor().unwrap()).unwrap();
+            assert_eq!(
+                decoded
+                    .into_private_key()
+                    .unwrap()
+                    .public_key()
+                    .fingerprint()
+                    .unwrap(),
+                fp
+            );
+        }
+    }
+}
@@ -0,0 +1,100 @@
+//! Deterministic key derivation from a sealed master seed
+//!
+//! The key material is HKDF-SHA256 of the seed with [`SALT`] as the salt and
+//! `key type || 0x00 || label || counter` as the info string. The one byte counter starts at 0
+//! and is only incremented while the output isn't a valid ECDSA scalar. BLS keys are produced
+//! by `KeyGen` of the BLS signature draft applied to the output.
+use crate::crypto::{bls, ecdsa, Error, KeyType, PrivateKey};
+use blst::min_pk;
+use hkdf::Hkdf;
+use rand_core::CryptoRngCore;
+use sha2::Sha256;
+use zeroize::Zeroizing;
+
+pub const SEED_SIZE: usize = 32;
+/// HKDF salt
+pub const SALT: &[u8] = b"enclave-signer key derivation";
+
+/// Returns a fresh master seed
+pub fn generate<R: CryptoRngCore>(r: &mut R) -> Zeroizing<[u8; SEED_SIZE]> {
+    let mut seed = Zeroizing::new([0; SEED_SIZE]);
+    r.fill_bytes(&mut seed[..]);
+    seed
+}
+
+fn expand(seed: &[u8], t: KeyType, label: &[u8], counter: u8) -> Zeroizing<[u8; 32]> {
+    let info = [t.as_str().as_bytes(), &[0], label, &[counter]].concat();
+    let mut okm = Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha256>::new(Some(SALT), seed)
+        .expand(&info, &mut okm[..])
+        .expect("valid HKDF output length");
+    okm
+}
+
+impl PrivateKey {
+    /// Derives the key of the given type from the master seed. The same seed, type and label
+    /// always produce the same key
+    pub fn from_seed(t: KeyType, seed: &[u8], label: &[u8]) -> Result<Self, Error> {
+        if seed.len() != SEED_SIZE {
+            return Err(Error::InvalidSeed);
+        }
+        for counter in 0..=u8::MAX {
+            let okm = expand(seed, t, label, counter);
+            let res = match t {
+                KeyType::Ed25519 => return Ok(ed25519_dalek::SigningKey::from_bytes(&okm).into()),
+                KeyType::Bls => {
+                    let sk = min_pk::SecretKey::key_gen(&okm[..], &[]).map_err(bls::Error::from)?;
+                    return Ok(bls::SigningKey(sk).into());
+                }
+                KeyType::Secp256k1 => ::ecdsa::SigningKey::from_slice(&okm[..])
+                    .map(|sk| PrivateKey::Secp256k1(ecdsa::SigningKey(sk))),
+                KeyType::NistP256 => ::ecdsa::SigningKey::from_slice(&okm[..])
+                    .map(|sk| PrivateKey::NistP256(ecdsa::SigningKey(sk))),
+            };
+            if let Ok(pk) = res {
+                return Ok(pk);
+            }
+        }
+        Err(Error::WeakKey)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::generate;
+    use crate::crypto::{Error, KeyPair, KeyType, PrivateKey};
+
+    #[test]
+    fn from_seed() {
+        let seed = generate(&mut rand_core::OsRng);
+        for t in [
+            KeyType::Secp256k1,
+            KeyType::NistP256,
+            KeyType::Ed25519,
+            KeyType::Bls,
+        ] {
+            let a = PrivateKey::from_seed(t, &seed[..], b"label").unwrap();
+            assert!(a.check_weak().is_ok());
+            assert_eq!(
+                a.public_key().fingerprint().unwrap(),
+                PrivateKey::from_seed(t, &seed[..], b"label")
+                    .unwrap()
+                    .public_key()
+                    .fingerprint()
+                    .unwrap()
+            );
+            assert_ne!(
+                a.public_key().fingerprint().unwrap(),
+                PrivateKey::from_seed(t, &seed[..], b"other")
+                    .unwrap()
+                    .public_key()
+                    .fingerprint()
+                    .unwrap()
+            );
+        }
+        assert!(matches!(
+            PrivateKey::from_seed(KeyType::Ed25519, &seed[..16], b"label"),
+            Err(Error::InvalidSeed)
+        ));
+    }
+}
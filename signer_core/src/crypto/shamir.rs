@@ -0,0 +1,161 @@
+//! Shamir secret sharing over GF(2^8) with the AES reduction polynomial, applied byte by byte.
+//!
+//! Field arithmetic avoids lookup tables and data dependent branches.
+use crate::crypto::Error;
+use crate::serde_helper::bytes;
+use rand_core::CryptoRngCore;
+use serde::{Deserialize, Serialize};
+use zeroize::{Zeroize, Zeroizing};
+
+/// Single share of a secret. `index` is the non zero evaluation point
+#[derive(Serialize, Deserialize)]
+pub struct Share {
+    pub index: u8,
+    pub threshold: u8,
+    #[serde(with = "bytes")]
+    pub data: Vec<u8>,
+}
+
+impl Drop for Share {
+    fn drop(&mut self) {
+        self.data.zeroize();
+    }
+}
+
+fn mul(mut a: u8, mut b: u8) -> u8 {
+    let mut r = 0;
+    for _ in 0..8 {
+        r ^= a & (b & 1).wrapping_neg();
+        a = (a << 1) ^ ((a >> 7).wrapping_neg() & 0x1b);
+        b >>= 1;
+    }
+    r
+}
+
+// a^254 = a^-1
+fn inv(a: u8) -> u8 {
+    let a2 = mul(a, a);
+    let a4 = mul(a2, a2);
+    let a8 = mul(a4, a4);
+    let a16 = mul(a8, a8);
+    let a32 = mul(a16, a16);
+    let a64 = mul(a32, a32);
+    let a128 = mul(a64, a64);
+    mul(mul(mul(a128, a64), mul(a32, a16)), mul(mul(a8, a4), a2))
+}
+
+/// Splits `secret` into `shares` shares any `threshold` of which restore it
+pub fn split<R: CryptoRngCore>(
+    secret: &[u8],
+    threshold: u8,
+    shares: u8,
+    r: &mut R,
+) -> Result<Vec<Share>, Error> {
+    if threshold == 0 || threshold > shares {
+        return Err(Error::InvalidThreshold);
+    }
+    let mut res: Vec<Share> = (1..=shares)
+        .map(|index| Share {
+            index,
+            threshold,
+            data: Vec::with_capacity(secret.len()),
+        })
+        .collect();
+
+    let mut coef = Zeroizing::new(vec![0u8; threshold as usize]);
+    for &b in secret {
+        coef[0] = b;
+        r.fill_bytes(&mut coef[1..]);
+        for share in res.iter_mut() {
+            // Horner's method
+            let y = coef
+                .iter()
+                .rev()
+                .fold(0, |acc, &c| mul(acc, share.index) ^ c);
+            share.data.push(y);
+        }
+    }
+    Ok(res)
+}
+
+/// Restores the secret from at least `threshold` distinct shares
+pub fn combine(shares: &[Share]) -> Result<Zeroizing<Vec<u8>>, Error> {
+    let first = shares.first().ok_or(Error::InvalidShares)?;
+    if first.threshold == 0 || shares.len() < first.threshold as usize {
+        return Err(Error::InvalidShares);
+    }
+    let shares = &shares[..first.threshold as usize];
+    for (i, s) in shares.iter().enumerate() {
+        if s.index == 0
+            || s.threshold != first.threshold
+            || s.data.len() != first.data.len()
+            || shares[..i].iter().any(|p| p.index == s.index)
+        {
+            return Err(Error::InvalidShares);
+        }
+    }
+
+    // Lagrange basis polynomials evaluated at zero
+    let basis: Vec<u8> = shares
+        .iter()
+        .map(|s| {
+            let (num, den) = shares
+                .iter()
+                .filter(|p| p.index != s.index)
+                .fold((1, 1), |(num, den), p| {
+                    (mul(num, p.index), mul(den, p.index ^ s.index))
+                });
+            mul(num, inv(den))
+        })
+        .collect();
+
+    let mut secret = Zeroizing::new(vec![0u8; first.data.len()]);
+    for (s, &l) in shares.iter().zip(basis.iter()) {
+        for (b, &y) in secret.iter_mut().zip(s.data.iter()) {
+            *b ^= mul(y, l);
+        }
+    }
+    Ok(secret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{combine, inv, mul, split};
+    use crate::crypto::Error;
+
+    #[test]
+    fn gf256() {
+        // FIPS 197 example
+        assert_eq!(mul(0x57, 0x83), 0xc1);
+        for a in 1..=255u8 {
+            assert_eq!(mul(a, inv(a)), 1);
+        }
+    }
+
+    #[test]
+    fn shamir_split_combine() {
+        let secret = b"0123456789abcdef0123456789abcdef";
+        let mut shares = split(secret, 3, 5, &mut rand_core::OsRng).unwrap();
+        assert_eq!(shares.len(), 5);
+        assert_eq!(&combine(&shares).unwrap()[..], secret);
+        assert_eq!(&combine(&shares[2..]).unwrap()[..], secret);
+
+        // any subset of the threshold size
+        shares.swap(0, 4);
+        assert_eq!(&combine(&shares[..3]).unwrap()[..], secret);
+        assert!(matches!(combine(&shares[..2]), Err(Error::InvalidShares)));
+
+        // duplicates
+        shares[1].index = shares[0].index;
+        assert!(matches!(combine(&shares), Err(Error::InvalidShares)));
+
+        assert!(matches!(
+            split(secret, 4, 3, &mut rand_core::OsRng),
+            Err(Error::InvalidThreshold)
+        ));
+        assert!(matches!(
+            split(secret, 0, 3, &mut rand_core::OsRng),
+            Err(Error::InvalidThreshold)
+        ));
+    }
+}
@@ -0,0 +1,191 @@
+//! [SLIP-10](https://github.com/satoshilabs/slips/blob/master/slip-0010.md) hardened key derivation
+//!
+//! The secret of the stored key is used as the SLIP-10 seed.
+use crate::crypto::{ecdsa, Error, NistP256, PrivateKey, Secp256k1};
+use elliptic_curve::{
+    ff::{Field, PrimeField},
+    CurveArithmetic, FieldBytes, Scalar,
+};
+use hmac::{Hmac, Mac};
+use sha2::Sha512;
+use zeroize::Zeroizing;
+
+pub const HARDENED: u32 = 1 << 31;
+
+const ED25519_KEY: &[u8] = b"ed25519 seed";
+const SECP256K1_KEY: &[u8] = b"Bitcoin seed";
+const NIST256P1_KEY: &[u8] = b"Nist256p1 seed";
+
+struct Node {
+    key: Zeroizing<[u8; 32]>,
+    chain_code: Zeroizing<[u8; 32]>,
+}
+
+impl Node {
+    fn from_digest(i: &[u8; 64]) -> Self {
+        let mut node = Node {
+            key: Zeroizing::new([0; 32]),
+            chain_code: Zeroizing::new([0; 32]),
+        };
+        node.key.copy_from_slice(&i[..32]);
+        node.chain_code.copy_from_slice(&i[32..]);
+        node
+    }
+}
+
+fn hmac(key: &[u8], parts: &[&[u8]]) -> Zeroizing<[u8; 64]> {
+    let mut mac = Hmac::<Sha512>::new_from_slice(key).unwrap();
+    for p in parts {
+        mac.update(p);
+    }
+    let mut out = Zeroizing::new([0; 64]);
+    out.copy_from_slice(&mac.finalize().into_bytes());
+    out
+}
+
+fn scalar<C: CurveArithmetic>(src: &[u8]) -> Option<Scalar<C>> {
+    Option::from(Scalar::<C>::from_repr(FieldBytes::<C>::clone_from_slice(
+        src,
+    )))
+    .filter(|s: &Scalar<C>| !bool::from(s.is_zero()))
+}
+
+fn master_ecdsa<C: CurveArithmetic>(curve_key: &[u8], seed: &[u8]) -> Node {
+    let mut i = hmac(curve_key, &[seed]);
+    while scalar::<C>(&i[..32]).is_none() {
+        i = hmac(curve_key, &[&i[..]]);
+    }
+    Node::from_digest(&i)
+}
+
+fn child_ecdsa<C: CurveArithmetic>(node: &Node, index: u32) -> Node {
+    let parent = scalar::<C>(&node.key[..]).unwrap();
+    let mut i = hmac(
+        &node.chain_code[..],
+        &[&[0], &node.key[..], &index.to_be_bytes()],
+    );
+    loop {
+        if let Some(k) = scalar::<C>(&i[..32]).map(|il| il + parent) {
+            if !bool::from(k.is_zero()) {
+                let mut child = Node::from_digest(&i);
+                child.key.copy_from_slice(&k.to_repr());
+                return child;
+            }
+        }
+        let ir = Zeroizing::new(i[32..].to_vec());
+        i = hmac(&node.chain_code[..], &[&[1], &ir, &index.to_be_bytes()]);
+    }
+}
+
+fn child_ed25519(node: &Node, index: u32) -> Node {
+    Node::from_digest(&hmac(
+        &node.chain_code[..],
+        &[&[0], &node.key[..], &index.to_be_bytes()],
+    ))
+}
+
+fn derive_ecdsa<C: CurveArithmetic>(curve_key: &[u8], seed: &[u8], path: &[u32]) -> Node {
+    path.iter()
+        .fold(master_ecdsa::<C>(curve_key, seed), |node, &index| {
+            child_ecdsa::<C>(&node, index)
+        })
+}
+
+fn derive_ed25519(seed: &[u8], path: &[u32]) -> Node {
+    path.iter().fold(
+        Node::from_digest(&hmac(ED25519_KEY, &[seed])),
+        |node, &index| child_ed25519(&node, index),
+    )
+}
+
+/// Parses `m/44'/1729'/0'` style path. All components must be hardened
+pub fn parse_path(src: &str) -> Result<Vec<u32>, Error> {
+    let mut parts = src.split('/');
+    if parts.next() != Some("m") {
+        return Err(Error::InvalidPath);
+    }
+    parts
+        .map(|p| {
+            let idx = p
+                .strip_suffix('\'')
+                .or_else(|| p.strip_suffix('h'))
+                .ok_or(Error::NonHardenedIndex)?;
+            match idx.parse::<u32>() {
+                Ok(v) if v < HARDENED => Ok(v | HARDENED),
+                _ => Err(Error::InvalidPath),
+            }
+        })
+        .collect()
+}
+
+impl PrivateKey {
+    /// Derives the hardened child key using the secret of `self` as the seed
+    pub fn derive_child(&self, path: &[u32]) -> Result<Self, Error> {
+        if path.iter().any(|&i| i < HARDENED) {
+            return Err(Error::NonHardenedIndex);
+        }
+        match self {
+            PrivateKey::Ed25519(sk) => {
+                let node = derive_ed25519(&sk.to_bytes(), path);
+                Ok(PrivateKey::Ed25519(ed25519_dalek::SigningKey::from_bytes(
+                    &node.key,
+                )))
+            }
+            PrivateKey::Secp256k1(sk) => {
+                let node = derive_ecdsa::<Secp256k1>(SECP256K1_KEY, &sk.to_bytes(), path);
+                Ok(PrivateKey::Secp256k1(ecdsa::SigningKey(
+                    ::ecdsa::SigningKey::from_slice(&node.key[..])?,
+                )))
+            }
+            PrivateKey::NistP256(sk) => {
+                let node = derive_ecdsa::<NistP256>(NIST256P1_KEY, &sk.to_bytes(), path);
+                Ok(PrivateKey::NistP256(ecdsa::SigningKey(
+                    ::ecdsa::SigningKey::from_slice(&node.key[..])?,
+                )))
+            }
+            PrivateKey::Bls(_) => Err(Error::DerivationUnsupported),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{derive_ecdsa, derive_ed25519, parse_path, HARDENED, SECP256K1_KEY};
+    use crate::crypto::{Error, Secp256k1};
+
+    const SEED: &[u8] = &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
+
+    fn hex(src: &[u8]) -> String {
+        src.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    // SLIP-10 test vector 1
+    #[test]
+    fn slip10_vectors() {
+        assert_eq!(
+            hex(&derive_ed25519(SEED, &[]).key[..]),
+            "2b4be7f19ee27bbf30c667b642d5f4aa69fd169872f8fc3059c08ebae2eb19e7"
+        );
+        assert_eq!(
+            hex(&derive_ed25519(SEED, &[HARDENED]).key[..]),
+            "68e0fe46dfb67e368c75379acec591dad19df3cde26e63b93a8e704f1dade7a3"
+        );
+        assert_eq!(
+            hex(&derive_ecdsa::<Secp256k1>(SECP256K1_KEY, SEED, &[HARDENED]).key[..]),
+            "edb2e14f9ee77d26dd93b4ecede8d16ed408ce149b6cd80b0715a2d911a0afea"
+        );
+    }
+
+    #[test]
+    fn path() {
+        assert_eq!(
+            parse_path("m/44'/1729h/0'").unwrap(),
+            vec![44 | HARDENED, 1729 | HARDENED, HARDENED]
+        );
+        assert!(matches!(
+            parse_path("m/44'/0"),
+            Err(Error::NonHardenedIndex)
+        ));
+        assert!(matches!(parse_path("44'"), Err(Error::InvalidPath)));
+    }
+}
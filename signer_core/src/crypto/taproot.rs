@@ -0,0 +1,107 @@
+//! [BIP 341](https://github.com/bitcoin/bips/blob/master/bip-0341.mediawiki) Taproot key tweaking
+use crate::crypto::{ecdsa, musig2::tagged_hash, Error, PrivateKey};
+use k256::{
+    elliptic_curve::{point::AffineCoordinates, PrimeField},
+    NonZeroScalar, ProjectivePoint, Scalar,
+};
+use zeroize::Zeroizing;
+
+/// Tweak committing the x-only internal key to the script tree `merkle_root`,
+/// which is empty for key path only outputs
+fn tweak(internal_key: &[u8], merkle_root: &[u8]) -> Result<Scalar, Error> {
+    if !merkle_root.is_empty() && merkle_root.len() != 32 {
+        return Err(Error::InvalidTweak);
+    }
+    Option::from(Scalar::from_repr(tagged_hash(
+        "TapTweak",
+        &[internal_key, merkle_root],
+    )))
+    .ok_or(Error::InvalidTweak)
+}
+
+impl PrivateKey {
+    /// Returns the tweaked key of the Taproot output committing to `merkle_root`
+    pub fn tweak_taproot(&self, merkle_root: &[u8]) -> Result<Self, Error> {
+        let sk = match self {
+            PrivateKey::Secp256k1(sk) => sk,
+            _ => return Err(Error::TweakUnsupported),
+        };
+        let p = (ProjectivePoint::GENERATOR * sk.as_nonzero_scalar().as_ref()).to_affine();
+        let d = Zeroizing::new(if bool::from(p.y_is_odd()) {
+            -*sk.as_nonzero_scalar().as_ref()
+        } else {
+            *sk.as_nonzero_scalar().as_ref()
+        });
+        let t = tweak(&p.x(), merkle_root)?;
+        let tweaked =
+            Option::<NonZeroScalar>::from(NonZeroScalar::new(*d + t)).ok_or(Error::InvalidTweak)?;
+        Ok(PrivateKey::Secp256k1(ecdsa::SigningKey(
+            ::ecdsa::SigningKey::from(tweaked),
+        )))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::tweak;
+    use crate::crypto::{Error, KeyPair, KeyType, PrivateKey, PublicKey};
+    use k256::{
+        elliptic_curve::{point::AffineCoordinates, sec1::FromEncodedPoint},
+        AffinePoint, EncodedPoint, ProjectivePoint,
+    };
+
+    fn hex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn lift_x(x: &[u8]) -> AffinePoint {
+        let mut b = vec![2];
+        b.extend_from_slice(x);
+        AffinePoint::from_encoded_point(&EncodedPoint::from_bytes(&b).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn taproot_tweak() {
+        // BIP 341 wallet test vectors, key path only output
+        let internal = hex("d6889cb081036e0faefa3a35157ad71086b123b2b144b649798b494c300a961d");
+        let t = tweak(&internal, &[]).unwrap();
+        assert_eq!(
+            t.to_bytes().to_vec(),
+            hex("b86e7be8f39bab32a6f2c0443abbc210f0edac0e2c53d501b36b64437d9c6c70")
+        );
+        let q =
+            (ProjectivePoint::from(lift_x(&internal)) + ProjectivePoint::GENERATOR * t).to_affine();
+        assert_eq!(
+            q.x().to_vec(),
+            hex("53a1f6e454df1aa2776a2814a721372d6258050de330b3c6d10ee8f4e0dda343")
+        );
+
+        // the tweaked private key matches the tweaked public key
+        let merkle_root = [7u8; 32];
+        let pk = PrivateKey::generate(KeyType::Secp256k1, &mut rand_core::OsRng).unwrap();
+        let internal = match pk.public_key() {
+            PublicKey::Secp256k1(p) => *p.as_affine(),
+            _ => unreachable!(),
+        };
+        let expected = (ProjectivePoint::from(lift_x(&internal.x()))
+            + ProjectivePoint::GENERATOR * tweak(&internal.x(), &merkle_root).unwrap())
+        .to_affine();
+        match pk.tweak_taproot(&merkle_root).unwrap().public_key() {
+            PublicKey::Secp256k1(p) => assert_eq!(p.as_affine().x(), expected.x()),
+            _ => unreachable!(),
+        }
+
+        assert!(matches!(
+            pk.tweak_taproot(&[0; 31]),
+            Err(Error::InvalidTweak)
+        ));
+        let pk = PrivateKey::generate(KeyType::NistP256, &mut rand_core::OsRng).unwrap();
+        assert!(matches!(
+            pk.tweak_taproot(&[]),
+            Err(Error::TweakUnsupported)
+        ));
+    }
+}
@@ -0,0 +1,217 @@
+//! Tezos base58check encodings of public keys, public key hashes and signatures
+use crate::crypto::{bls, ecdsa, PublicKey, Signature};
+use sha2::{Digest, Sha256};
+
+const TZ1: &[u8] = &[6, 161, 159];
+const TZ2: &[u8] = &[6, 161, 161];
+const TZ3: &[u8] = &[6, 161, 164];
+const TZ4: &[u8] = &[6, 161, 166];
+
+const EDPK: &[u8] = &[13, 15, 37, 217];
+const SPPK: &[u8] = &[3, 254, 226, 86];
+const P2PK: &[u8] = &[3, 178, 139, 127];
+const BLPK: &[u8] = &[6, 149, 135, 204];
+
+const EDSIG: &[u8] = &[9, 245, 205, 134, 18];
+const SPSIG: &[u8] = &[13, 115, 101, 19, 63];
+const P2SIG: &[u8] = &[54, 240, 44, 52];
+const BLSIG: &[u8] = &[40, 171, 64, 207];
+
+const ALPHABET: &[u8; 58] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Error {
+    InvalidCharacter,
+    Checksum,
+    Prefix,
+    Length,
+    InvalidKey,
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::InvalidCharacter => f.write_str("invalid base58 character"),
+            Error::Checksum => f.write_str("invalid base58check checksum"),
+            Error::Prefix => f.write_str("unexpected base58check prefix"),
+            Error::Length => f.write_str("invalid payload length"),
+            Error::InvalidKey => f.write_str("invalid key"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+fn checksum(data: &[u8]) -> [u8; 4] {
+    let d = Sha256::digest(Sha256::digest(data));
+    [d[0], d[1], d[2], d[3]]
+}
+
+fn base58_encode(src: &[u8]) -> String {
+    let zeros = src.iter().take_while(|&&b| b == 0).count();
+    // little endian base58 digits
+    let mut digits: Vec<u8> = Vec::with_capacity(src.len() * 138 / 100 + 1);
+    for &b in &src[zeros..] {
+        let mut carry = b as u32;
+        for d in digits.iter_mut() {
+            carry += (*d as u32) << 8;
+            *d = (carry % 58) as u8;
+            carry /= 58;
+        }
+        while carry != 0 {
+            digits.push((carry % 58) as u8);
+            carry /= 58;
+        }
+    }
+    std::iter::repeat_n(b'1', zeros)
+        .chain(digits.iter().rev().map(|&d| ALPHABET[d as usize]))
+        .map(char::from)
+        .collect()
+}
+
+fn base58_decode(src: &str) -> Result<Vec<u8>, Error> {
+    let zeros = src.bytes().take_while(|&c| c == b'1').count();
+    // little endian bytes
+    let mut bytes: Vec<u8> = Vec::with_capacity(src.len() * 733 / 1000 + 1);
+    for c in src.bytes().skip(zeros) {
+        let mut carry = match ALPHABET.iter().position(|&a| a == c) {
+            Some(v) => v as u32,
+            None => return Err(Error::InvalidCharacter),
+        };
+        for b in bytes.iter_mut() {
+            carry += (*b as u32) * 58;
+            *b = carry as u8;
+            carry >>= 8;
+        }
+        while carry != 0 {
+            bytes.push(carry as u8);
+            carry >>= 8;
+        }
+    }
+    Ok(std::iter::repeat_n(0, zeros)
+        .chain(bytes.into_iter().rev())
+        .collect())
+}
+
+/// Encodes the payload with the given prefix
+pub fn encode(prefix: &[u8], payload: &[u8]) -> String {
+    let mut buf = Vec::with_capacity(prefix.len() + payload.len() + 4);
+    buf.extend_from_slice(prefix);
+    buf.extend_from_slice(payload);
+    let sum = checksum(&buf);
+    buf.extend_from_slice(&sum);
+    base58_encode(&buf)
+}
+
+/// Decodes the string checking the checksum and the prefix. Returns the payload
+pub fn decode(prefix: &[u8], src: &str) -> Result<Vec<u8>, Error> {
+    let mut buf = base58_decode(src)?;
+    if buf.len() < 4 {
+        return Err(Error::Length);
+    }
+    let sum = buf.split_off(buf.len() - 4);
+    if checksum(&buf) != sum[..] {
+        return Err(Error::Checksum);
+    }
+    match buf.strip_prefix(prefix) {
+        Some(payload) => Ok(payload.into()),
+        None => Err(Error::Prefix),
+    }
+}
+
+impl PublicKey {
+    /// `edpk`, `sppk`, `p2pk` or `BLpk` encoded public key
+    pub fn to_base58(&self) -> String {
+        match self {
+            PublicKey::Ed25519(pk) => encode(EDPK, pk.as_bytes()),
+            PublicKey::Secp256k1(pk) => encode(SPPK, &pk.to_sec1_bytes(true)),
+            PublicKey::NistP256(pk) => encode(P2PK, &pk.to_sec1_bytes(true)),
+            PublicKey::Bls(pk) => encode(BLPK, &pk.to_bytes()),
+        }
+    }
+
+    /// `tz1`, `tz2`, `tz3` or `tz4` address
+    pub fn to_address(&self) -> String {
+        let prefix = match self {
+            PublicKey::Ed25519(_) => TZ1,
+            PublicKey::Secp256k1(_) => TZ2,
+            PublicKey::NistP256(_) => TZ3,
+            PublicKey::Bls(_) => TZ4,
+        };
+        encode(prefix, &self.hash())
+    }
+
+    pub fn from_base58(src: &str) -> Result<Self, Error> {
+        match src.get(..4) {
+            Some("edpk") => ed25519_dalek::VerifyingKey::try_from(decode(EDPK, src)?.as_slice())
+                .map(PublicKey::Ed25519)
+                .map_err(|_| Error::InvalidKey),
+            Some("sppk") => ::ecdsa::VerifyingKey::from_sec1_bytes(&decode(SPPK, src)?)
+                .map(|pk| PublicKey::Secp256k1(ecdsa::VerifyingKey(pk)))
+                .map_err(|_| Error::InvalidKey),
+            Some("p2pk") => ::ecdsa::VerifyingKey::from_sec1_bytes(&decode(P2PK, src)?)
+                .map(|pk| PublicKey::NistP256(ecdsa::VerifyingKey(pk)))
+                .map_err(|_| Error::InvalidKey),
+            Some("BLpk") => bls::PublicKey::from_bytes(&decode(BLPK, src)?)
+                .map(PublicKey::Bls)
+                .map_err(|_| Error::InvalidKey),
+            _ => Err(Error::Prefix),
+        }
+    }
+}
+
+impl Signature {
+    /// `edsig`, `spsig1`, `p2sig` or `BLsig` encoded signature
+    pub fn to_base58(&self) -> String {
+        match self {
+            Signature::Ed25519(sig) => encode(EDSIG, &sig.to_bytes()),
+            Signature::Secp256k1(sig) => encode(SPSIG, &sig.to_bytes()),
+            Signature::NistP256(sig) => encode(P2SIG, &sig.to_bytes()),
+            Signature::Bls(sig) => encode(BLSIG, &sig.compress()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode, encode, Error, TZ1};
+    use crate::crypto::{KeyPair, KeyType, PrivateKey, PublicKey, SigningVersion};
+
+    #[test]
+    fn bootstrap_account() {
+        let pk = PublicKey::from_base58("edpkuBknW28nW72KG6RoHtYW7p12T6GKc7nAbwYX5m8Wd9sDVC9yav")
+            .unwrap();
+        assert_eq!(pk.to_address(), "tz1KqTpEZ7Yob7QbPE4Hy4Wo8fHG8LhKxZSx");
+        assert_eq!(
+            pk.to_base58(),
+            "edpkuBknW28nW72KG6RoHtYW7p12T6GKc7nAbwYX5m8Wd9sDVC9yav"
+        );
+        assert_eq!(
+            decode(TZ1, "tz1KqTpEZ7Yob7QbPE4Hy4Wo8fHG8LhKxZSy"),
+            Err(Error::Checksum)
+        );
+        assert_eq!(decode(TZ1, &encode(TZ1, &[0; 20])).unwrap(), vec![0; 20]);
+    }
+
+    #[test]
+    fn prefixes() {
+        for (t, pk_prefix, addr_prefix, sig_prefix) in [
+            (KeyType::Ed25519, "edpk", "tz1", "edsig"),
+            (KeyType::Secp256k1, "sppk", "tz2", "spsig1"),
+            (KeyType::NistP256, "p2pk", "tz3", "p2sig"),
+            (KeyType::Bls, "BLpk", "tz4", "BLsig"),
+        ] {
+            let sk = PrivateKey::generate(t, &mut rand_core::OsRng).unwrap();
+            let pk = sk.public_key();
+            let encoded = pk.to_base58();
+            assert!(encoded.starts_with(pk_prefix));
+            assert_eq!(
+                PublicKey::from_base58(&encoded).unwrap().to_base58(),
+                encoded
+            );
+            assert!(pk.to_address().starts_with(addr_prefix));
+            let sig = sk.try_sign(b"text", SigningVersion::Latest).unwrap();
+            assert!(sig.to_base58().starts_with(sig_prefix));
+        }
+    }
+}
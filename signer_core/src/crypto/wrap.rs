@@ -0,0 +1,96 @@
+//! Export of private keys encrypted to a recipient public key
+use crate::crypto::{ecies, Error, PrivateKey, PublicKey};
+use crate::serde_helper::bytes;
+use crate::TryIntoCBOR;
+use rand_core::CryptoRngCore;
+use rsa::{pkcs8::DecodePublicKey, Oaep, RsaPublicKey};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use signer_core_derive::Cddl;
+use zeroize::Zeroizing;
+
+/// Public key of the recipient of an exported key, e.g. another enclave's attestation key
+#[derive(Debug, Clone, Serialize, Deserialize, Cddl)]
+pub enum WrappingKey {
+    /// DER encoded RSA SubjectPublicKeyInfo. The key is encrypted with RSA-OAEP-SHA256
+    Rsa(#[serde(with = "bytes")] Vec<u8>),
+    /// Secp256k1 or NIST P-256 key. See `crypto::ecies`, the key is encrypted with
+    /// `ecies::WRAP_INFO`
+    Ecies(PublicKey),
+}
+
+impl PrivateKey {
+    /// Encrypts the CBOR encoded key to the recipient
+    pub fn wrap<R: CryptoRngCore>(
+        &self,
+        recipient: &WrappingKey,
+        r: &mut R,
+    ) -> Result<Vec<u8>, Error> {
+        let src = Zeroizing::new(self.try_into_cbor().map_err(|_| Error::Serialize)?);
+        match recipient {
+            WrappingKey::Rsa(der) => Ok(RsaPublicKey::from_public_key_der(der)?.encrypt(
+                r,
+                Oaep::new::<Sha256>(),
+                &src,
+            )?),
+            WrappingKey::Ecies(pk) => pk.ecies_encrypt_with(ecies::WRAP_INFO, &src, r),
+        }
+    }
+
+    /// Decrypts the CBOR encoded key wrapped to this ECIES key
+    pub fn unwrap_ecies(&self, src: &[u8]) -> Result<Zeroizing<Vec<u8>>, Error> {
+        Ok(Zeroizing::new(
+            self.ecies_decrypt_with(ecies::WRAP_INFO, src)?,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::WrappingKey;
+    use crate::crypto::{Error, KeyPair, KeyType, PrivateKey};
+    use crate::TryFromCBOR;
+    use rsa::{pkcs8::EncodePublicKey, Oaep, RsaPrivateKey};
+    use sha2::Sha256;
+    use subtle::ConstantTimeEq;
+
+    #[test]
+    fn wrap() {
+        let key = PrivateKey::generate(KeyType::Ed25519, &mut rand_core::OsRng).unwrap();
+
+        let rsa_key = RsaPrivateKey::new(&mut rand_core::OsRng, 1024).unwrap();
+        let recipient = WrappingKey::Rsa(
+            rsa_key
+                .to_public_key()
+                .to_public_key_der()
+                .unwrap()
+                .into_vec(),
+        );
+        let wrapped = key.wrap(&recipient, &mut rand_core::OsRng).unwrap();
+        let unwrapped =
+            PrivateKey::try_from_cbor(&rsa_key.decrypt(Oaep::new::<Sha256>(), &wrapped).unwrap())
+                .unwrap();
+        assert!(bool::from(unwrapped.ct_eq(&key)));
+
+        let ecies_key = PrivateKey::generate(KeyType::NistP256, &mut rand_core::OsRng).unwrap();
+        let wrapped = key
+            .wrap(
+                &WrappingKey::Ecies(ecies_key.public_key()),
+                &mut rand_core::OsRng,
+            )
+            .unwrap();
+        let unwrapped =
+            PrivateKey::try_from_cbor(&ecies_key.unwrap_ecies(&wrapped).unwrap()).unwrap();
+        assert!(bool::from(unwrapped.ct_eq(&key)));
+        // not a plain ECIES ciphertext
+        assert!(matches!(
+            ecies_key.ecies_decrypt(&wrapped),
+            Err(Error::DecryptionFailed)
+        ));
+
+        assert!(matches!(
+            key.wrap(&WrappingKey::Rsa(vec![0; 16]), &mut rand_core::OsRng),
+            Err(Error::Encoding(_))
+        ));
+    }
+}
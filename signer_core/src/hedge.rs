@@ -0,0 +1,265 @@
+//! Hedged decryption requests
+//!
+//! If a decryption request takes longer than the configured percentile of the recently observed
+//! latencies a second identical request is issued and the result of whichever completes first is used.
+use crate::cddl::Cddl;
+use crate::{EncryptionBackend, EncryptionBackendFactory};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone)]
+pub struct HedgeConfig {
+    /// Latency percentile in (0, 1] after which the hedged request is issued
+    pub percentile: f64,
+    /// Number of recent latency samples to keep
+    pub window: usize,
+    /// Don't hedge until this many samples are collected
+    pub min_samples: usize,
+    pub min_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl Default for HedgeConfig {
+    fn default() -> Self {
+        HedgeConfig {
+            percentile: 0.95,
+            window: 128,
+            min_samples: 16,
+            min_delay: Duration::from_millis(10),
+            max_delay: Duration::from_secs(2),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    requests: AtomicU64,
+    hedged: AtomicU64,
+    hedge_wins: AtomicU64,
+}
+
+/// Hedging statistics snapshot
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize, Cddl)]
+pub struct HedgeStats {
+    /// Total number of decryption requests
+    pub requests: u64,
+    /// Number of requests for which a hedged request was issued
+    pub hedged: u64,
+    /// Number of requests completed by the hedged request first
+    pub hedge_wins: u64,
+    /// Current hedging threshold in milliseconds, if enough samples are collected
+    pub threshold_ms: Option<u64>,
+}
+
+#[derive(Debug)]
+struct Inner {
+    config: HedgeConfig,
+    samples: Mutex<VecDeque<Duration>>,
+    counters: Counters,
+}
+
+/// Latency history and statistics shared by all backends created by a factory
+#[derive(Debug, Clone)]
+pub struct Hedging(Arc<Inner>);
+
+impl Hedging {
+    pub fn new(config: HedgeConfig) -> Self {
+        Hedging(Arc::new(Inner {
+            samples: Mutex::new(VecDeque::with_capacity(config.window)),
+            config,
+            counters: Counters::default(),
+        }))
+    }
+
+    fn record(&self, latency: Duration) {
+        let mut samples = self.0.samples.lock().unwrap();
+        if samples.len() >= self.0.config.window {
+            samples.pop_front();
+        }
+        samples.push_back(latency);
+    }
+
+    fn threshold(&self) -> Option<Duration> {
+        let conf = &self.0.config;
+        let mut sorted: Vec<Duration> = {
+            let samples = self.0.samples.lock().unwrap();
+            if samples.len() < conf.min_samples {
+                return None;
+            }
+            samples.iter().cloned().collect()
+        };
+        if sorted.is_empty() {
+            return Some(conf.min_delay);
+        }
+        sorted.sort();
+        let idx =
+            ((sorted.len() as f64 * conf.percentile).ceil() as usize).clamp(1, sorted.len()) - 1;
+        Some(sorted[idx].clamp(conf.min_delay, conf.max_delay))
+    }
+
+    pub fn stats(&self) -> HedgeStats {
+        let c = &self.0.counters;
+        HedgeStats {
+            requests: c.requests.load(Ordering::Relaxed),
+            hedged: c.hedged.load(Ordering::Relaxed),
+            hedge_wins: c.hedge_wins.load(Ordering::Relaxed),
+            threshold_ms: self.threshold().map(|d| d.as_millis() as u64),
+        }
+    }
+}
+
+/// Encryption backend issuing hedged decryption requests
+pub struct Hedged<E> {
+    inner: E,
+    hedging: Option<Hedging>,
+}
+
+impl<E> Hedged<E> {
+    pub fn new(inner: E, hedging: Option<Hedging>) -> Self {
+        Self { inner, hedging }
+    }
+}
+
+impl<E> EncryptionBackend for Hedged<E>
+where
+    E: EncryptionBackend + Sync,
+{
+    type Error = E::Error;
+
+    async fn encrypt(&self, src: &[u8]) -> Result<Vec<u8>, Self::Error> {
+        self.inner.encrypt(src).await
+    }
+
+    fn is_unavailable(err: &Self::Error) -> bool {
+        E::is_unavailable(err)
+    }
+
+    async fn decrypt(&self, src: &[u8]) -> Result<Vec<u8>, Self::Error> {
+        let hedging = match &self.hedging {
+            Some(h) => h,
+            None => return self.inner.decrypt(src).await,
+        };
+        let counters = &hedging.0.counters;
+        counters.requests.fetch_add(1, Ordering::Relaxed);
+
+        let start = Instant::now();
+        let primary = self.inner.decrypt(src);
+        tokio::pin!(primary);
+
+        if let Some(delay) = hedging.threshold() {
+            tokio::select! {
+                res = &mut primary => {
+                    hedging.record(start.elapsed());
+                    return res;
+                }
+                _ = tokio::time::sleep(delay) => {}
+            }
+        } else {
+            let res = primary.await;
+            hedging.record(start.elapsed());
+            return res;
+        }
+
+        counters.hedged.fetch_add(1, Ordering::Relaxed);
+        let hedge_start = Instant::now();
+        let hedge = self.inner.decrypt(src);
+        tokio::pin!(hedge);
+
+        tokio::select! {
+            res = &mut primary => {
+                hedging.record(start.elapsed());
+                res
+            }
+            res = &mut hedge => {
+                hedging.record(hedge_start.elapsed());
+                counters.hedge_wins.fetch_add(1, Ordering::Relaxed);
+                res
+            }
+        }
+    }
+}
+
+/// Wraps backends produced by the inner factory into `Hedged`. Hedging is disabled if `hedging` is `None`
+pub struct HedgedFactory<F> {
+    inner: F,
+    hedging: Option<Hedging>,
+}
+
+impl<F> HedgedFactory<F> {
+    pub fn new(inner: F, hedging: Option<Hedging>) -> Self {
+        Self { inner, hedging }
+    }
+}
+
+impl<F> EncryptionBackendFactory for HedgedFactory<F>
+where
+    F: EncryptionBackendFactory,
+    F::Output: Sync,
+{
+    type Output = Hedged<F::Output>;
+    type Credentials = F::Credentials;
+
+    async fn try_new(
+        &self,
+        cred: Self::Credentials,
+    ) -> Result<Self::Output, <F::Output as EncryptionBackend>::Error> {
+        Ok(Hedged::new(
+            self.inner.try_new(cred).await?,
+            self.hedging.clone(),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HedgeConfig, HedgeStats, Hedged, Hedging};
+    use crate::tests::DummyErr;
+    use crate::EncryptionBackend;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    // the first call stalls
+    struct Stall(AtomicUsize);
+
+    impl EncryptionBackend for Stall {
+        type Error = DummyErr;
+
+        async fn encrypt(&self, src: &[u8]) -> Result<Vec<u8>, Self::Error> {
+            Ok(Vec::from(src))
+        }
+
+        async fn decrypt(&self, src: &[u8]) -> Result<Vec<u8>, Self::Error> {
+            let n = self.0.fetch_add(1, Ordering::SeqCst);
+            if n == 0 {
+                tokio::time::sleep(Duration::from_secs(10)).await;
+            }
+            Ok(vec![n as u8; src.len()])
+        }
+    }
+
+    #[tokio::test]
+    async fn hedged_decrypt() {
+        let hedging = Hedging::new(HedgeConfig {
+            min_samples: 0,
+            ..Default::default()
+        });
+        let backend = Hedged::new(Stall(AtomicUsize::new(0)), Some(hedging.clone()));
+
+        assert_eq!(backend.decrypt(b"x").await.unwrap(), vec![1]);
+        assert_eq!(backend.decrypt(b"x").await.unwrap(), vec![2]);
+        let stats = hedging.stats();
+        assert_eq!(
+            stats,
+            HedgeStats {
+                requests: 2,
+                hedged: 1,
+                hedge_wins: 1,
+                threshold_ms: stats.threshold_ms,
+            }
+        );
+        assert!(stats.threshold_ms.is_some());
+    }
+}
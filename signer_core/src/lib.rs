@@ -1,15 +1,35 @@
+use blob::BlobCodec;
 use crypto::{
-    KeyPair, KeyType, Keychain, PrivateKey, ProofOfPossession, PublicKey, Signature, SigningVersion,
+    data_key::DataKey, musig2, seed, shamir, wrap::WrappingKey, Hasher, KeyConstraint, KeyHandle,
+    KeyInfo, KeyMetadata, KeyPair, KeyStats, KeyType, PrivateKey, ProofOfPossession, PublicKey,
+    PublicKeyFormat, SharedKeychain, SignOptions, Signature, SigningVersion,
 };
+use lru::Lru;
 use rand_core::CryptoRngCore;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use zeroize::Zeroizing;
 
+// allows derived `Cddl` impls to refer to `::signer_core` from within the crate
+extern crate self as signer_core;
+
+pub mod attestation;
+pub mod blob;
+pub mod callback;
+pub mod cddl;
 pub mod crypto;
+pub mod hedge;
+pub(crate) mod lru;
+pub mod noise;
+pub mod prelude;
 pub mod rpc;
 pub(crate) mod serde_helper;
 
-use serde_helper::bytes;
+use cddl::Cddl;
+use serde_helper::{bytes, bytes_seq};
 
 trait TryIntoCBOR {
     type Error;
@@ -49,6 +69,17 @@ where
         ciborium::from_reader(src)
     }
 }
+/// Schema of the credentials passed by the host in `Initialize`, see `rpc::VersionedCredentials`
+pub trait CredentialsSchema {
+    /// Bump it whenever a field is added which older signers can't ignore
+    const VERSION: u32;
+}
+
+/// Backends which don't need any credentials
+impl CredentialsSchema for () {
+    const VERSION: u32 = 0;
+}
+
 pub trait EncryptionBackendFactory {
     type Output: EncryptionBackend;
     type Credentials;
@@ -64,23 +95,54 @@ pub trait EncryptionBackend: Sized {
 
     fn encrypt(&self, src: &[u8]) -> impl Future<Output = Result<Vec<u8>, Self::Error>> + Send;
     fn decrypt(&self, src: &[u8]) -> impl Future<Output = Result<Vec<u8>, Self::Error>> + Send;
+
+    /// Encrypts with the backend key `key_id` instead of the configured one, e.g. a rotated KMS key.
+    /// Returns `None` if the backend can't address keys by ID
+    fn encrypt_with_key(
+        &self,
+        _key_id: &str,
+        _src: &[u8],
+    ) -> impl Future<Output = Option<Result<Vec<u8>, Self::Error>>> + Send {
+        async { None }
+    }
+
+    /// Tells the backend being unreachable apart from the backend rejecting the request.
+    /// Such errors switch the signer into the degraded mode, see `BackendState`
+    fn is_unavailable(_err: &Self::Error) -> bool {
+        false
+    }
+}
+
+/// Availability of the encryption backend as seen by the last request using it.
+/// While the backend is unavailable keys already decrypted in memory keep signing
+/// and requests passing encrypted blobs fail with `Error::BackendUnavailable`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Cddl)]
+pub enum BackendState {
+    Available,
+    Unavailable,
 }
 
 #[derive(Debug)]
 pub enum Error<S: std::error::Error> {
     Encryption(S),
+    BackendUnavailable(S),
     Signer(crypto::Error),
     Serialize(ciborium::ser::Error<std::io::Error>),
     Deserialize(ciborium::de::Error<std::io::Error>),
+    Blob(blob::Error),
+    KeyIdUnsupported,
 }
 
 impl<S: std::error::Error> std::fmt::Display for Error<S> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Error::Encryption(_) => f.write_str("encryption error"),
+            Error::BackendUnavailable(_) => f.write_str("encryption backend unavailable"),
             Error::Signer(_) => f.write_str("signer error"),
             Error::Serialize(_) => f.write_str("serialization error"),
             Error::Deserialize(_) => f.write_str("deserialization error"),
+            Error::Blob(_) => f.write_str("key blob error"),
+            Error::KeyIdUnsupported => f.write_str("encryption backend doesn't support key IDs"),
         }
     }
 }
@@ -89,9 +151,12 @@ impl<S: std::error::Error + 'static> std::error::Error for Error<S> {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
             Error::Encryption(val) => Some(val),
+            Error::BackendUnavailable(val) => Some(val),
             Error::Signer(val) => Some(val),
             Error::Serialize(val) => Some(val),
             Error::Deserialize(val) => Some(val),
+            Error::Blob(val) => Some(val),
+            Error::KeyIdUnsupported => None,
         }
     }
 }
@@ -108,97 +173,542 @@ impl<S: std::error::Error> From<ciborium::ser::Error<std::io::Error>> for Error<
     }
 }
 
+impl<S: std::error::Error> From<blob::Error> for Error<S> {
+    fn from(value: blob::Error) -> Self {
+        Error::Blob(value)
+    }
+}
+
 impl<S: std::error::Error> From<crypto::Error> for Error<S> {
     fn from(value: crypto::Error) -> Self {
         Error::Signer(value)
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Cddl)]
 pub struct ImportResult {
     pub public_key: PublicKey,
-    pub handle: usize,
+    /// See `PublicKey::fingerprint`
+    #[serde(with = "bytes")]
+    pub fingerprint: Vec<u8>,
+    pub handle: KeyHandle,
+}
+
+#[derive(Debug, Serialize, Deserialize, Cddl)]
+pub struct ImportDeferredResult {
+    /// SHA-256 of the encrypted blob
+    #[serde(with = "bytes")]
+    pub blob_hash: Vec<u8>,
+    pub handle: KeyHandle,
+}
+
+#[derive(Debug, Serialize, Deserialize, Cddl)]
+pub struct ExportSharesResult {
+    pub public_key: PublicKey,
+    /// See `PublicKey::fingerprint`
+    #[serde(with = "bytes")]
+    pub fingerprint: Vec<u8>,
+    /// Encrypted shares
+    #[serde(with = "bytes_seq")]
+    #[cddl(type = "[* bstr]")]
+    pub shares: Vec<Vec<u8>>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Cddl)]
+pub struct MuSig2NonceResult {
+    /// Refers to the secret nonce kept by the signer until `MuSig2Sign`
+    pub session: usize,
+    #[serde(with = "bytes")]
+    pub pub_nonce: Vec<u8>,
+    /// X-only aggregated public key the final signature is verified with
+    #[serde(with = "bytes")]
+    pub aggregate_public_key: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Cddl)]
+pub struct MuSig2SignResult {
+    #[serde(with = "bytes")]
+    pub partial_signature: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Cddl)]
+pub struct ExportWrappedResult {
+    /// CBOR encoded private key encrypted to the recipient
+    #[serde(with = "bytes")]
+    pub wrapped_key: Vec<u8>,
+    pub public_key: PublicKey,
+}
+
+#[derive(Debug, Serialize, Deserialize, Cddl)]
+pub struct RewrapResult {
+    /// The same secret encrypted anew
+    #[serde(with = "bytes")]
+    pub key_data: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Cddl)]
+pub struct GenerateSeedResult {
+    #[serde(with = "bytes")]
+    pub encrypted_seed: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Cddl)]
+pub struct GenerateDataKeyResult {
+    #[serde(with = "bytes")]
+    pub encrypted_key: Vec<u8>,
+    pub handle: KeyHandle,
+}
+
+#[derive(Debug, Serialize, Deserialize, Cddl)]
+pub struct ImportDataKeyResult {
+    pub handle: KeyHandle,
+}
+
+#[derive(Debug, Serialize, Deserialize, Cddl)]
+pub struct EncryptDataResult {
+    #[serde(with = "bytes")]
+    pub ciphertext: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Cddl)]
+pub struct DecryptDataResult {
+    #[serde(with = "bytes")]
+    pub plaintext: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Cddl)]
+pub struct EciesDecryptResult {
+    #[serde(with = "bytes")]
+    pub plaintext: Vec<u8>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Cddl)]
+pub struct MuSig2AggregateResult {
+    /// BIP 340 Schnorr signature
+    #[serde(with = "bytes")]
+    pub signature: Vec<u8>,
+    #[serde(with = "bytes")]
+    pub aggregate_public_key: Vec<u8>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+impl MuSig2AggregateResult {
+    /// Checks the partial signatures of the signers and combines them. Nonces and
+    /// signatures follow the order of `public_keys`
+    pub fn new(
+        public_keys: &[PublicKey],
+        msg: &[u8],
+        pub_nonces: &[Vec<u8>],
+        partial_signatures: &[Vec<u8>],
+    ) -> Result<Self, crypto::Error> {
+        let ctx = musig2::KeyAggContext::from_public_keys(public_keys)?;
+        let pub_nonces = musig2::parse_pub_nonces(pub_nonces)?;
+        Ok(MuSig2AggregateResult {
+            signature: musig2::aggregate(&ctx, msg, &pub_nonces, partial_signatures)?.to_vec(),
+            aggregate_public_key: ctx.aggregate_key().to_vec(),
+        })
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Cddl)]
 pub struct GenerateResult {
     #[serde(with = "bytes")]
     pub encrypted_private_key: Vec<u8>,
     pub public_key: PublicKey,
+    /// See `PublicKey::fingerprint`
+    #[serde(with = "bytes")]
+    pub fingerprint: Vec<u8>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Cddl)]
 pub struct GenerateAndImportResult {
     #[serde(with = "bytes")]
     pub encrypted_private_key: Vec<u8>,
     pub public_key: PublicKey,
-    pub handle: usize,
+    /// See `PublicKey::fingerprint`
+    #[serde(with = "bytes")]
+    pub fingerprint: Vec<u8>,
+    pub handle: KeyHandle,
+}
+
+#[derive(Debug, Serialize, Deserialize, Cddl)]
+pub struct GenerateConstrainedResult {
+    #[serde(with = "bytes")]
+    pub encrypted_private_key: Vec<u8>,
+    pub public_key: PublicKey,
+    /// See `PublicKey::fingerprint`
+    #[serde(with = "bytes")]
+    pub fingerprint: Vec<u8>,
+    /// Set if the key was imported
+    pub handle: Option<KeyHandle>,
+    /// Number of generated candidate keys
+    pub attempts: u32,
+}
+
+#[derive(Debug, Serialize, Deserialize, Cddl)]
+pub struct ExportPublicKeyResult {
+    pub format: PublicKeyFormat,
+    #[serde(with = "bytes")]
+    pub data: Vec<u8>,
 }
 
+/// Number of candidate keys generated by `EncryptedSigner::generate_constrained` between progress reports
+const GENERATE_CHUNK: u32 = 4096;
+
 pub struct EncryptedSigner<E> {
-    keychain: Keychain,
+    keychain: SharedKeychain,
     enc: E,
+    codec: Arc<dyn BlobCodec>,
+    unavailable: AtomicBool,
+    /// Decrypted keys of recent `try_sign_with` blobs keyed by the blob hash
+    key_cache: Option<Mutex<Lru<[u8; 32], PrivateKey>>>,
 }
 
 impl<E: EncryptionBackend> EncryptedSigner<E> {
     pub fn new(enc: E) -> Self {
         Self {
-            keychain: Keychain::new(),
+            keychain: SharedKeychain::new(),
             enc,
+            codec: Arc::new(blob::Raw),
+            unavailable: AtomicBool::new(false),
+            key_cache: None,
         }
     }
 
-    pub fn try_sign(
+    pub fn backend_state(&self) -> BackendState {
+        if self.unavailable.load(Ordering::Relaxed) {
+            BackendState::Unavailable
+        } else {
+            BackendState::Available
+        }
+    }
+
+    // any response from the backend, including a rejection, means it's reachable
+    fn backend_result<T>(&self, res: Result<T, E::Error>) -> Result<T, Error<E::Error>> {
+        let unavailable = matches!(&res, Err(err) if E::is_unavailable(err));
+        self.unavailable.store(unavailable, Ordering::Relaxed);
+        match res {
+            Ok(val) => Ok(val),
+            Err(err) if unavailable => Err(Error::BackendUnavailable(err)),
+            Err(err) => Err(Error::Encryption(err)),
+        }
+    }
+
+    /// Replaces the session-local keychain with the shared one
+    pub fn with_keychain(mut self, keychain: SharedKeychain) -> Self {
+        self.keychain = keychain;
+        self
+    }
+
+    pub fn keychain(&self) -> &SharedKeychain {
+        &self.keychain
+    }
+
+    /// Keeps up to `capacity` keys decrypted by `try_sign_with` and `public_key_from` in memory,
+    /// so repeated requests with the same blob skip the backend round trip. Disabled by default
+    pub fn with_key_cache(mut self, capacity: usize) -> Self {
+        self.key_cache = Some(Mutex::new(Lru::new(capacity)));
+        self
+    }
+
+    /// Sets the codec applied to encrypted blobs exchanged with the host
+    pub fn with_codec(mut self, codec: Arc<dyn BlobCodec>) -> Self {
+        self.codec = codec;
+        self
+    }
+
+    pub async fn try_sign<R: CryptoRngCore>(
         &self,
-        handle: usize,
+        handle: KeyHandle,
         msg: &[u8],
         version: SigningVersion,
+        options: &SignOptions,
+        rng: &mut R,
+    ) -> Result<Signature, Error<E::Error>> {
+        self.load(handle).await?;
+        Ok(self
+            .keychain
+            .read()
+            .try_sign(handle, msg, version, options, rng)?)
+    }
+
+    pub async fn try_sign_batch<M: AsRef<[u8]>, R: CryptoRngCore>(
+        &self,
+        handle: KeyHandle,
+        msgs: &[M],
+        version: SigningVersion,
+        options: &SignOptions,
+        rng: &mut R,
+    ) -> Result<Vec<Signature>, Error<E::Error>> {
+        self.load(handle).await?;
+        Ok(self
+            .keychain
+            .read()
+            .try_sign_batch(handle, msgs, version, options, rng)?)
+    }
+
+    /// See `Keychain::try_sign_hashed`
+    pub async fn try_sign_hashed(
+        &self,
+        handle: KeyHandle,
+        hasher: Hasher,
+        version: SigningVersion,
     ) -> Result<Signature, Error<E::Error>> {
-        Ok(self.keychain.try_sign(handle, msg, version)?)
+        self.load(handle).await?;
+        Ok(self
+            .keychain
+            .read()
+            .try_sign_hashed(handle, hasher, version)?)
     }
 
-    pub fn public_key(&self, handle: usize) -> Result<PublicKey, Error<E::Error>> {
-        Ok(self.keychain.public_key(handle)?)
+    pub async fn try_sign_prehashed(
+        &self,
+        handle: KeyHandle,
+        digest: &[u8],
+        version: SigningVersion,
+    ) -> Result<Signature, Error<E::Error>> {
+        self.load(handle).await?;
+        Ok(self
+            .keychain
+            .read()
+            .try_sign_prehashed(handle, digest, version)?)
     }
 
-    pub fn try_prove(&self, handle: usize) -> Result<ProofOfPossession, Error<E::Error>> {
-        Ok(self.keychain.try_prove(handle)?)
+    pub async fn public_key(&self, handle: KeyHandle) -> Result<PublicKey, Error<E::Error>> {
+        self.load(handle).await?;
+        Ok(self.keychain.read().public_key(handle)?)
     }
 
-    async fn decrypt(&self, src: &[u8]) -> Result<PrivateKey, Error<E::Error>> {
-        match self.enc.decrypt(src).await {
-            Ok(decrypted) => Ok(PrivateKey::try_from_cbor(&decrypted[..])?),
-            Err(err) => return Err(Error::Encryption(err)),
+    pub async fn export_public_key(
+        &self,
+        handle: KeyHandle,
+        format: PublicKeyFormat,
+    ) -> Result<ExportPublicKeyResult, Error<E::Error>> {
+        Ok(ExportPublicKeyResult {
+            format,
+            data: self.public_key(handle).await?.export(format)?,
+        })
+    }
+
+    pub async fn try_prove(&self, handle: KeyHandle) -> Result<ProofOfPossession, Error<E::Error>> {
+        self.load(handle).await?;
+        Ok(self.keychain.read().try_prove(handle)?)
+    }
+
+    /// Starts the MuSig2 session signing `msg` by `public_keys` including the key under the handle
+    pub async fn musig2_nonce<R: CryptoRngCore>(
+        &self,
+        handle: KeyHandle,
+        public_keys: &[PublicKey],
+        msg: &[u8],
+        r: &mut R,
+    ) -> Result<MuSig2NonceResult, Error<E::Error>> {
+        self.load(handle).await?;
+        let mut keychain = self.keychain.write();
+        let (session, s) = keychain.musig2_nonce(handle, public_keys, msg, r)?;
+        Ok(MuSig2NonceResult {
+            session,
+            pub_nonce: s.pub_nonce().to_vec(),
+            aggregate_public_key: s.aggregate_key().to_vec(),
+        })
+    }
+
+    /// Returns the partial signature of the session. `pub_nonces` are the public nonces of all signers
+    pub fn musig2_sign(
+        &self,
+        session: usize,
+        pub_nonces: &[Vec<u8>],
+    ) -> Result<MuSig2SignResult, Error<E::Error>> {
+        Ok(MuSig2SignResult {
+            partial_signature: self
+                .keychain
+                .write()
+                .musig2_sign(session, pub_nonces)?
+                .to_vec(),
+        })
+    }
+
+    /// Decrypts the deferred key on first use and keeps it in memory afterwards
+    async fn load(&self, handle: KeyHandle) -> Result<(), Error<E::Error>> {
+        self.remove_expired();
+        let blob = self.keychain.read().deferred(handle).map(Vec::from);
+        if let Some(blob) = blob {
+            let pk = self.decrypt(&blob).await?;
+            self.keychain.write().load(handle, pk)?;
         }
+        Ok(())
     }
 
-    async fn encrypt(&self, pk: &PrivateKey) -> Result<Vec<u8>, Error<E::Error>> {
-        let buf = pk.try_into_cbor()?;
-        match self.enc.encrypt(&buf).await {
-            Ok(value) => Ok(value),
-            Err(err) => Err(Error::Encryption(err)),
+    // the write lock is only taken if there is something to drop
+    fn remove_expired(&self) {
+        let now = crypto::unix_time();
+        if self.keychain.read().has_expired(now) {
+            self.keychain.write().remove_expired(now);
         }
     }
 
-    pub async fn import(&mut self, key_data: &[u8]) -> Result<ImportResult, Error<E::Error>> {
+    async fn decrypt(&self, src: &[u8]) -> Result<PrivateKey, Error<E::Error>> {
+        let decrypted = self.open(src).await?;
+        let pk = PrivateKey::try_from_cbor(&decrypted[..])?;
+        pk.check_weak()?;
+        Ok(pk)
+    }
+
+    async fn encrypt(&self, pk: &PrivateKey) -> Result<Vec<u8>, Error<E::Error>> {
+        self.seal(&Zeroizing::new(pk.try_into_cbor()?)).await
+    }
+
+    /// Decrypts the blob received from the host
+    async fn open(&self, src: &[u8]) -> Result<Zeroizing<Vec<u8>>, Error<E::Error>> {
+        let src = self.codec.decode(src)?;
+        let res = self.enc.decrypt(&src).await;
+        Ok(Zeroizing::new(self.backend_result(res)?))
+    }
+
+    /// Encrypts the secret into the blob returned to the host
+    async fn seal(&self, src: &[u8]) -> Result<Vec<u8>, Error<E::Error>> {
+        let res = self.enc.encrypt(src).await;
+        Ok(self.codec.encode(self.backend_result(res)?))
+    }
+
+    pub async fn import(&self, key_data: &[u8]) -> Result<ImportResult, Error<E::Error>> {
         let pk = self.decrypt(key_data).await?;
         let p = pk.public_key();
         Ok(ImportResult {
+            fingerprint: p.fingerprint()?.into(),
+            public_key: p,
+            handle: self.keychain.write().import(pk),
+        })
+    }
+
+    /// Splits the key into `shares` Shamir shares any `threshold` of which restore it.
+    /// Every share is encrypted separately so the whole key never leaves the signer
+    pub async fn export_shares<R: CryptoRngCore>(
+        &self,
+        handle: KeyHandle,
+        threshold: u8,
+        shares: u8,
+        r: &mut R,
+    ) -> Result<ExportSharesResult, Error<E::Error>> {
+        self.load(handle).await?;
+        let public_key = self.keychain.read().public_key(handle)?;
+        let mut res = Vec::with_capacity(shares as usize);
+        let split = self.keychain.read().split(handle, threshold, shares, r)?;
+        for share in split {
+            res.push(self.seal(&Zeroizing::new(share.try_into_cbor()?)).await?);
+        }
+        Ok(ExportSharesResult {
+            fingerprint: public_key.fingerprint()?.into(),
+            public_key,
+            shares: res,
+        })
+    }
+
+    /// Restores the key from the encrypted shares returned by `export_shares` and imports it
+    pub async fn import_shares(&self, shares: &[Vec<u8>]) -> Result<ImportResult, Error<E::Error>> {
+        let mut decrypted = Vec::with_capacity(shares.len());
+        for share in shares {
+            decrypted.push(shamir::Share::try_from_cbor(&self.open(share).await?[..])?);
+        }
+        let secret = shamir::combine(&decrypted)?;
+        let pk = PrivateKey::try_from_cbor(&secret[..])?;
+        pk.check_weak()?;
+        let p = pk.public_key();
+        Ok(ImportResult {
+            fingerprint: p.fingerprint()?.into(),
             public_key: p,
-            handle: self.keychain.import(pk),
+            handle: self.keychain.write().import(pk),
         })
     }
 
+    /// Registers the encrypted key without decrypting it. The key is decrypted on first use
+    pub fn import_deferred(&self, key_data: &[u8]) -> ImportDeferredResult {
+        ImportDeferredResult {
+            blob_hash: Sha256::digest(key_data).to_vec(),
+            handle: self.keychain.write().import_deferred(key_data.into()),
+        }
+    }
+
+    /// Imports the trusted public key. The returned handle can be used for verification only
+    pub fn import_public_key(
+        &self,
+        public_key: PublicKey,
+    ) -> Result<ImportResult, Error<E::Error>> {
+        Ok(ImportResult {
+            fingerprint: public_key.fingerprint()?.into(),
+            public_key: public_key.clone(),
+            handle: self.keychain.write().import_public_key(public_key),
+        })
+    }
+
+    /// Lists the keys in the session-local storage
+    pub fn list_keys(&self) -> Vec<KeyInfo> {
+        self.remove_expired();
+        self.keychain.read().list()
+    }
+
+    /// Returns the number of keys in the session-local storage
+    pub fn key_count(&self) -> usize {
+        self.remove_expired();
+        self.keychain.read().len()
+    }
+
+    /// Returns the per-key usage statistics
+    pub fn key_stats(&self) -> Vec<KeyStats> {
+        self.remove_expired();
+        self.keychain.read().stats()
+    }
+
+    /// Attaches the label and metadata to the key in the session-local storage
+    pub fn set_metadata(
+        &self,
+        handle: KeyHandle,
+        metadata: KeyMetadata,
+    ) -> Result<(), Error<E::Error>> {
+        Ok(self.keychain.write().set_metadata(handle, metadata)?)
+    }
+
+    /// Makes the key expire in `ttl` seconds. Expired keys are refused and zeroized.
+    /// `None` removes the expiry
+    pub fn set_expiry(&self, handle: KeyHandle, ttl: Option<u64>) -> Result<(), Error<E::Error>> {
+        let expires_at = ttl.map(|ttl| crypto::unix_time().saturating_add(ttl));
+        Ok(self.keychain.write().set_expiry(handle, expires_at)?)
+    }
+
+    /// Looks the key up by its label
+    pub fn find_label(&self, label: &str) -> Option<KeyInfo> {
+        self.keychain.read().find_label(label)
+    }
+
+    /// Looks the key up by its public key, e.g. to recover the handle of a key pinned earlier
+    pub fn find_public_key(&self, public_key: &PublicKey) -> Option<KeyInfo> {
+        self.keychain.read().find_public_key(public_key)
+    }
+
+    /// Drops the key from the session-local storage. The handle becomes invalid
+    pub fn remove(&self, handle: KeyHandle) -> Result<(), Error<E::Error>> {
+        Ok(self.keychain.write().remove(handle)?)
+    }
+
+    /// Drops all keys of the keychain, shared or not, and the key cache
+    pub fn clear(&self) {
+        self.keychain.write().clear();
+        if let Some(cache) = &self.key_cache {
+            cache.lock().unwrap_or_else(PoisonError::into_inner).clear();
+        }
+    }
+
     pub async fn import_unencrypted(
-        &mut self,
+        &self,
         pk: PrivateKey,
     ) -> Result<GenerateAndImportResult, Error<E::Error>> {
+        pk.check_weak()?;
         let p = pk.public_key();
         let encrypted = self.encrypt(&pk).await?;
         Ok(GenerateAndImportResult {
             encrypted_private_key: encrypted,
+            fingerprint: p.fingerprint()?.into(),
             public_key: p,
-            handle: self.keychain.import(pk),
+            handle: self.keychain.write().import(pk),
         })
     }
 
@@ -212,12 +722,13 @@ impl<E: EncryptionBackend> EncryptedSigner<E> {
         let encrypted = self.encrypt(&pk).await?;
         Ok(GenerateResult {
             encrypted_private_key: encrypted,
+            fingerprint: p.fingerprint()?.into(),
             public_key: p,
         })
     }
 
     pub async fn generate_and_import<R: CryptoRngCore>(
-        &mut self,
+        &self,
         t: KeyType,
         r: &mut R,
     ) -> Result<GenerateAndImportResult, Error<E::Error>> {
@@ -226,22 +737,297 @@ impl<E: EncryptionBackend> EncryptedSigner<E> {
         let encrypted = self.encrypt(&pk).await?;
         Ok(GenerateAndImportResult {
             encrypted_private_key: encrypted,
+            fingerprint: p.fingerprint()?.into(),
             public_key: p,
-            handle: self.keychain.import(pk),
+            handle: self.keychain.write().import(pk),
+        })
+    }
+
+    /// Returns the stored key encrypted to the recipient instead of the encryption backend
+    pub async fn export_wrapped<R: CryptoRngCore>(
+        &self,
+        handle: KeyHandle,
+        recipient: &WrappingKey,
+        r: &mut R,
+    ) -> Result<ExportWrappedResult, Error<E::Error>> {
+        self.load(handle).await?;
+        Ok(ExportWrappedResult {
+            wrapped_key: self.keychain.read().wrap(handle, recipient, r)?,
+            public_key: self.keychain.read().public_key(handle)?,
         })
     }
 
-    pub async fn try_sign_with(
+    /// Imports the key exported by `export_wrapped` to the ECIES key stored under `handle`
+    /// and returns it encrypted by the local backend. `handle` can't be used with `ecies_decrypt`
+    /// from then on
+    pub async fn import_wrapped(
+        &self,
+        handle: KeyHandle,
+        wrapped_key: &[u8],
+    ) -> Result<GenerateAndImportResult, Error<E::Error>> {
+        self.load(handle).await?;
+        let decrypted = self.keychain.write().unwrap_ecies(handle, wrapped_key)?;
+        let pk = PrivateKey::try_from_cbor(&decrypted[..])?;
+        pk.check_weak()?;
+        let p = pk.public_key();
+        Ok(GenerateAndImportResult {
+            encrypted_private_key: self.encrypt(&pk).await?,
+            fingerprint: p.fingerprint()?.into(),
+            public_key: p,
+            handle: self.keychain.write().import(pk),
+        })
+    }
+
+    /// Generates the AES-256-GCM data key and returns it encrypted the same way as private keys
+    pub async fn generate_data_key<R: CryptoRngCore>(
+        &self,
+        r: &mut R,
+    ) -> Result<GenerateDataKeyResult, Error<E::Error>> {
+        let key = DataKey::generate(r);
+        Ok(GenerateDataKeyResult {
+            encrypted_key: self.seal(&Zeroizing::new(key.try_into_cbor()?)).await?,
+            handle: self.keychain.write().import_data_key(key),
+        })
+    }
+
+    pub async fn import_data_key(
+        &self,
+        src: &[u8],
+    ) -> Result<ImportDataKeyResult, Error<E::Error>> {
+        let decrypted = self.open(src).await?;
+        let key = DataKey::try_from_cbor(&decrypted[..])?;
+        Ok(ImportDataKeyResult {
+            handle: self.keychain.write().import_data_key(key),
+        })
+    }
+
+    pub fn encrypt_data<R: CryptoRngCore>(
+        &self,
+        handle: KeyHandle,
+        plaintext: &[u8],
+        aad: &[u8],
+        r: &mut R,
+    ) -> Result<EncryptDataResult, Error<E::Error>> {
+        Ok(EncryptDataResult {
+            ciphertext: self
+                .keychain
+                .read()
+                .data_key(handle)?
+                .encrypt(plaintext, aad, r),
+        })
+    }
+
+    pub fn decrypt_data(
+        &self,
+        handle: KeyHandle,
+        ciphertext: &[u8],
+        aad: &[u8],
+    ) -> Result<DecryptDataResult, Error<E::Error>> {
+        Ok(DecryptDataResult {
+            plaintext: self
+                .keychain
+                .read()
+                .data_key(handle)?
+                .decrypt(ciphertext, aad)?,
+        })
+    }
+
+    /// Derives the hardened SLIP-10 child of the stored key and returns it encrypted
+    pub async fn derive_child(
+        &self,
+        handle: KeyHandle,
+        path: &[u32],
+    ) -> Result<GenerateResult, Error<E::Error>> {
+        self.load(handle).await?;
+        let pk = self.keychain.read().derive_child(handle, path)?;
+        let p = pk.public_key();
+        Ok(GenerateResult {
+            encrypted_private_key: self.encrypt(&pk).await?,
+            fingerprint: p.fingerprint()?.into(),
+            public_key: p,
+        })
+    }
+
+    /// Tweaks the stored Secp256k1 key with the BIP 341 Taproot commitment to `merkle_root`
+    /// and returns it encrypted
+    pub async fn tweak_key(
+        &self,
+        handle: KeyHandle,
+        merkle_root: &[u8],
+    ) -> Result<GenerateResult, Error<E::Error>> {
+        self.load(handle).await?;
+        let pk = self.keychain.read().tweak_taproot(handle, merkle_root)?;
+        let p = pk.public_key();
+        Ok(GenerateResult {
+            encrypted_private_key: self.encrypt(&pk).await?,
+            fingerprint: p.fingerprint()?.into(),
+            public_key: p,
+        })
+    }
+
+    /// Decrypts the ECIES ciphertext addressed to the stored key. See `crypto::ecies`
+    pub async fn ecies_decrypt(
+        &self,
+        handle: KeyHandle,
+        ciphertext: &[u8],
+    ) -> Result<EciesDecryptResult, Error<E::Error>> {
+        self.load(handle).await?;
+        Ok(EciesDecryptResult {
+            plaintext: self.keychain.read().ecies_decrypt(handle, ciphertext)?,
+        })
+    }
+
+    /// Decrypts the blob and encrypts it again either with the configured backend key or
+    /// with `key_id`, e.g. to rotate KMS keys. The blob may hold any secret encrypted by the signer
+    pub async fn rewrap(
+        &self,
+        key_data: &[u8],
+        key_id: Option<&str>,
+    ) -> Result<RewrapResult, Error<E::Error>> {
+        let decrypted = self.open(key_data).await?;
+        let key_data = match key_id {
+            None => self.seal(&decrypted).await?,
+            Some(key_id) => {
+                let res = self
+                    .enc
+                    .encrypt_with_key(key_id, &decrypted)
+                    .await
+                    .ok_or(Error::KeyIdUnsupported)?;
+                self.codec.encode(self.backend_result(res)?)
+            }
+        };
+        Ok(RewrapResult { key_data })
+    }
+
+    /// Generates the master seed for `generate_from_seed` and returns it encrypted
+    pub async fn generate_seed<R: CryptoRngCore>(
+        &self,
+        r: &mut R,
+    ) -> Result<GenerateSeedResult, Error<E::Error>> {
+        Ok(GenerateSeedResult {
+            encrypted_seed: self.seal(&seed::generate(r)[..]).await?,
+        })
+    }
+
+    /// Derives the key from the encrypted master seed and the label and returns it encrypted.
+    /// The same seed and label always produce the same key so it can be regenerated if lost
+    pub async fn generate_from_seed(
+        &self,
+        t: KeyType,
+        encrypted_seed: &[u8],
+        label: &[u8],
+    ) -> Result<GenerateResult, Error<E::Error>> {
+        let seed = self.open(encrypted_seed).await?;
+        let pk = PrivateKey::from_seed(t, &seed, label)?;
+        let p = pk.public_key();
+        Ok(GenerateResult {
+            encrypted_private_key: self.encrypt(&pk).await?,
+            fingerprint: p.fingerprint()?.into(),
+            public_key: p,
+        })
+    }
+
+    /// Derives the key from the BIP39 mnemonic and returns it encrypted
+    pub async fn import_mnemonic(
+        &self,
+        t: KeyType,
+        mnemonic: &str,
+        passphrase: &str,
+    ) -> Result<GenerateResult, Error<E::Error>> {
+        let pk = PrivateKey::from_mnemonic(t, mnemonic, passphrase)?;
+        let p = pk.public_key();
+        Ok(GenerateResult {
+            encrypted_private_key: self.encrypt(&pk).await?,
+            fingerprint: p.fingerprint()?.into(),
+            public_key: p,
+        })
+    }
+
+    /// Generates the key satisfying the constraint. Rejected candidates never leave the signer.
+    /// `progress` is called with the number of attempts made so far and the maximum number of attempts
+    pub async fn generate_constrained<R: CryptoRngCore, P: Fn(u64, u64)>(
+        &self,
+        t: KeyType,
+        constraint: &KeyConstraint,
+        import: bool,
+        r: &mut R,
+        progress: P,
+    ) -> Result<GenerateConstrainedResult, Error<E::Error>> {
+        let max_attempts = constraint.max_attempts.min(crypto::MAX_GENERATE_ATTEMPTS);
+        let mut attempts = 0;
+        // search in chunks yielding in between so heartbeats and progress can be sent
+        let (pk, attempts) = loop {
+            let chunk = KeyConstraint {
+                hash_prefix: constraint.hash_prefix.clone(),
+                max_attempts: GENERATE_CHUNK.min(max_attempts - attempts),
+            };
+            match PrivateKey::generate_constrained(t, &chunk, r) {
+                Ok((pk, n)) => break (pk, attempts + n),
+                Err(crypto::Error::ConstraintNotSatisfied(n)) => {
+                    attempts += n;
+                    if attempts >= max_attempts {
+                        return Err(crypto::Error::ConstraintNotSatisfied(attempts).into());
+                    }
+                    progress(attempts.into(), max_attempts.into());
+                    tokio::task::yield_now().await;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        };
+        let p = pk.public_key();
+        let encrypted = self.encrypt(&pk).await?;
+        Ok(GenerateConstrainedResult {
+            encrypted_private_key: encrypted,
+            fingerprint: p.fingerprint()?.into(),
+            public_key: p,
+            handle: if import {
+                Some(self.keychain.write().import(pk))
+            } else {
+                None
+            },
+            attempts,
+        })
+    }
+
+    pub async fn try_sign_with<R: CryptoRngCore>(
         &self,
         key_data: &[u8],
         msg: &[u8],
         version: SigningVersion,
+        options: &SignOptions,
+        rng: &mut R,
     ) -> Result<Signature, Error<E::Error>> {
-        Ok(self.decrypt(key_data).await?.try_sign(msg, version)?)
+        Ok(self
+            .decrypt_cached(key_data)
+            .await?
+            .try_sign_with_options(msg, version, options, rng)?)
     }
 
     pub async fn public_key_from(&self, key_data: &[u8]) -> Result<PublicKey, Error<E::Error>> {
-        Ok(self.decrypt(key_data).await?.public_key())
+        Ok(self.decrypt_cached(key_data).await?.public_key())
+    }
+
+    /// Same as `decrypt` but looks the blob up in the key cache first
+    async fn decrypt_cached(&self, key_data: &[u8]) -> Result<PrivateKey, Error<E::Error>> {
+        let Some(cache) = &self.key_cache else {
+            return self.decrypt(key_data).await;
+        };
+        let hash: [u8; 32] = Sha256::digest(key_data).into();
+        // the lock is never held across the backend call
+        let cached = cache
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&hash)
+            .cloned();
+        if let Some(pk) = cached {
+            return Ok(pk);
+        }
+        let pk = self.decrypt(key_data).await?;
+        cache
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(hash, pk.clone());
+        Ok(pk)
     }
 }
 
@@ -275,12 +1061,17 @@ pub(crate) mod macros {
 
 #[cfg(test)]
 mod tests {
-    use crate::crypto::{Blake2b256, PublicKey, Signature, SigningVersion};
+    use crate::crypto::{Blake2b256, PublicKey, SignOptions, Signature, SigningVersion};
     use crate::macros::unwrap_as;
-    use crate::{EncryptedSigner, EncryptionBackend, EncryptionBackendFactory, KeyType};
+    use crate::{
+        BackendState, Cddl, CredentialsSchema, EncryptedSigner, EncryptionBackend,
+        EncryptionBackendFactory, Error, KeyType,
+    };
     use blake2::Digest;
     use serde::{Deserialize, Serialize};
     use signature::{DigestVerifier, Verifier};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
 
     pub(crate) struct PassthroughFactory;
 
@@ -297,8 +1088,13 @@ mod tests {
 
     #[derive(Debug)]
     pub(crate) struct Passthrough;
-    #[derive(Serialize, Deserialize, Debug)]
+    #[derive(Serialize, Deserialize, Debug, Cddl)]
     pub(crate) struct DummyCredentials {}
+
+    impl CredentialsSchema for DummyCredentials {
+        const VERSION: u32 = 1;
+    }
+
     #[derive(Debug)]
     pub(crate) struct DummyErr;
 
@@ -322,6 +1118,153 @@ mod tests {
         }
     }
 
+    /// Passthrough backend which can be switched off
+    #[derive(Debug, Clone, Default)]
+    pub(crate) struct Flaky(pub(crate) Arc<AtomicBool>);
+
+    impl Flaky {
+        fn check(&self) -> Result<(), DummyErr> {
+            if self.0.load(Ordering::Relaxed) {
+                Err(DummyErr)
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    impl EncryptionBackend for Flaky {
+        type Error = DummyErr;
+
+        async fn encrypt(&self, src: &[u8]) -> Result<Vec<u8>, Self::Error> {
+            self.check().map(|_| Vec::from(src))
+        }
+
+        async fn decrypt(&self, src: &[u8]) -> Result<Vec<u8>, Self::Error> {
+            self.check().map(|_| Vec::from(src))
+        }
+
+        fn is_unavailable(_err: &Self::Error) -> bool {
+            true
+        }
+    }
+
+    impl EncryptionBackendFactory for Flaky {
+        type Output = Flaky;
+        type Credentials = DummyCredentials;
+        fn try_new(
+            &self,
+            _cred: Self::Credentials,
+        ) -> impl std::future::Future<Output = Result<Self::Output, DummyErr>> {
+            let backend = self.clone();
+            async { Ok(backend) }
+        }
+    }
+
+    #[tokio::test]
+    async fn signer_key_cache() {
+        let backend = Flaky::default();
+        let signer = EncryptedSigner::new(backend.clone()).with_key_cache(1);
+        let a = signer
+            .generate(KeyType::Ed25519, &mut rand_core::OsRng)
+            .await
+            .unwrap();
+        let b = signer
+            .generate(KeyType::Ed25519, &mut rand_core::OsRng)
+            .await
+            .unwrap();
+        let sign = |key_data: Vec<u8>| {
+            let signer = &signer;
+            async move {
+                signer
+                    .try_sign_with(
+                        &key_data,
+                        b"text",
+                        SigningVersion::Latest,
+                        &SignOptions::default(),
+                        &mut rand_core::OsRng,
+                    )
+                    .await
+            }
+        };
+        sign(a.encrypted_private_key.clone()).await.unwrap();
+
+        // served from the cache while the backend is off
+        backend.0.store(true, Ordering::Relaxed);
+        sign(a.encrypted_private_key.clone()).await.unwrap();
+        assert_eq!(
+            signer
+                .public_key_from(&a.encrypted_private_key)
+                .await
+                .unwrap()
+                .hash(),
+            a.public_key.hash()
+        );
+        assert!(sign(b.encrypted_private_key.clone()).await.is_err());
+
+        // evicts `a`
+        backend.0.store(false, Ordering::Relaxed);
+        sign(b.encrypted_private_key.clone()).await.unwrap();
+        backend.0.store(true, Ordering::Relaxed);
+        assert!(sign(a.encrypted_private_key.clone()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn signer_rewrap() {
+        let signer = EncryptedSigner::new(Passthrough);
+        let res = signer
+            .generate(KeyType::Ed25519, &mut rand_core::OsRng)
+            .await
+            .unwrap();
+        let rewrapped = signer
+            .rewrap(&res.encrypted_private_key, None)
+            .await
+            .unwrap();
+        let imported = signer.import(&rewrapped.key_data).await.unwrap();
+        assert_eq!(
+            imported.public_key.fingerprint().unwrap(),
+            res.public_key.fingerprint().unwrap()
+        );
+        assert!(matches!(
+            signer
+                .rewrap(&res.encrypted_private_key, Some("alias/rotated"))
+                .await,
+            Err(Error::KeyIdUnsupported)
+        ));
+    }
+
+    #[tokio::test]
+    async fn signer_degraded() {
+        let backend = Flaky::default();
+        let signer = EncryptedSigner::new(backend.clone());
+        let res = signer
+            .generate_and_import(KeyType::Ed25519, &mut rand_core::OsRng)
+            .await
+            .unwrap();
+        assert_eq!(signer.backend_state(), BackendState::Available);
+
+        backend.0.store(true, Ordering::Relaxed);
+        assert!(matches!(
+            signer.import(&res.encrypted_private_key).await,
+            Err(Error::BackendUnavailable(_))
+        ));
+        assert_eq!(signer.backend_state(), BackendState::Unavailable);
+        // keys in memory keep signing
+        signer
+            .try_sign(
+                res.handle,
+                b"text",
+                SigningVersion::Latest,
+                &SignOptions::default(),
+                &mut rand_core::OsRng,
+            )
+            .await
+            .unwrap();
+
+        backend.0.store(false, Ordering::Relaxed);
+        signer.import(&res.encrypted_private_key).await.unwrap();
+        assert_eq!(signer.backend_state(), BackendState::Available);
+    }
+
     #[tokio::test]
     async fn signer_secp256k1() {
         let signer = EncryptedSigner::new(Passthrough);
@@ -333,7 +1276,13 @@ mod tests {
         let data = b"text";
         let sig = unwrap_as!(
             signer
-                .try_sign_with(&res.encrypted_private_key, data, SigningVersion::Latest)
+                .try_sign_with(
+                    &res.encrypted_private_key,
+                    data,
+                    SigningVersion::Latest,
+                    &SignOptions::default(),
+                    &mut rand_core::OsRng
+                )
                 .await
                 .unwrap(),
             Signature::Secp256k1
@@ -347,6 +1296,69 @@ mod tests {
             .unwrap();
     }
 
+    #[tokio::test]
+    async fn signer_shares() {
+        let signer = EncryptedSigner::new(Passthrough);
+        let res = signer
+            .generate_and_import(KeyType::Secp256k1, &mut rand_core::OsRng)
+            .await
+            .unwrap();
+        let exported = signer
+            .export_shares(res.handle, 2, 3, &mut rand_core::OsRng)
+            .await
+            .unwrap();
+        assert_eq!(exported.shares.len(), 3);
+        assert_eq!(exported.fingerprint, res.fingerprint);
+
+        let imported = signer.import_shares(&exported.shares[1..]).await.unwrap();
+        assert_eq!(imported.fingerprint, res.fingerprint);
+        assert!(matches!(
+            signer.import_shares(&exported.shares[..1]).await,
+            Err(Error::Signer(crate::crypto::Error::InvalidShares))
+        ));
+        assert!(matches!(
+            signer
+                .export_shares(res.handle, 4, 3, &mut rand_core::OsRng)
+                .await,
+            Err(Error::Signer(crate::crypto::Error::InvalidThreshold))
+        ));
+    }
+
+    #[tokio::test]
+    async fn signer_deferred() {
+        let signer = EncryptedSigner::new(Passthrough);
+        let res = signer
+            .generate(KeyType::Ed25519, &mut rand_core::OsRng)
+            .await
+            .unwrap();
+
+        let imported = signer.import_deferred(&res.encrypted_private_key);
+        assert_eq!(
+            imported.blob_hash,
+            sha2::Sha256::digest(&res.encrypted_private_key).to_vec()
+        );
+        let sig = unwrap_as!(
+            signer
+                .try_sign(
+                    imported.handle,
+                    b"text",
+                    SigningVersion::Latest,
+                    &SignOptions::default(),
+                    &mut rand_core::OsRng
+                )
+                .await
+                .unwrap(),
+            Signature::Ed25519
+        );
+        unwrap_as!(res.public_key, PublicKey::Ed25519)
+            .verify(&Blake2b256::digest(b"text"), &sig)
+            .unwrap();
+
+        // the blob is only checked on first use
+        let broken = signer.import_deferred(b"garbage");
+        assert!(signer.public_key(broken.handle).await.is_err());
+    }
+
     #[tokio::test]
     async fn signer_nist_p256() {
         let signer = EncryptedSigner::new(Passthrough);
@@ -358,7 +1370,13 @@ mod tests {
         let data = b"text";
         let sig = unwrap_as!(
             signer
-                .try_sign_with(&res.encrypted_private_key, data, SigningVersion::Latest)
+                .try_sign_with(
+                    &res.encrypted_private_key,
+                    data,
+                    SigningVersion::Latest,
+                    &SignOptions::default(),
+                    &mut rand_core::OsRng
+                )
                 .await
                 .unwrap(),
             Signature::NistP256
@@ -383,7 +1401,13 @@ mod tests {
         let data = b"text";
         let sig = unwrap_as!(
             signer
-                .try_sign_with(&res.encrypted_private_key, data, SigningVersion::Latest)
+                .try_sign_with(
+                    &res.encrypted_private_key,
+                    data,
+                    SigningVersion::Latest,
+                    &SignOptions::default(),
+                    &mut rand_core::OsRng
+                )
                 .await
                 .unwrap(),
             Signature::Ed25519
@@ -0,0 +1,97 @@
+use std::collections::{BTreeMap, HashMap};
+use std::hash::Hash;
+
+/// Bounded map evicting the least recently used entry
+#[derive(Debug)]
+pub struct Lru<K, V> {
+    capacity: usize,
+    tick: u64,
+    entries: HashMap<K, (V, u64)>,
+    order: BTreeMap<u64, K>,
+}
+
+impl<K, V> Lru<K, V>
+where
+    K: Hash + Eq + Clone,
+{
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            tick: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        let tick = self.next_tick();
+        match self.entries.get_mut(key) {
+            Some((v, t)) => {
+                let k = self.order.remove(t).unwrap();
+                self.order.insert(tick, k);
+                *t = tick;
+                Some(v)
+            }
+            None => None,
+        }
+    }
+
+    pub fn insert(&mut self, key: K, value: V) {
+        if self.capacity == 0 {
+            return;
+        }
+        let tick = self.next_tick();
+        if let Some((_, t)) = self.entries.insert(key.clone(), (value, tick)) {
+            self.order.remove(&t);
+        } else if self.entries.len() > self.capacity {
+            if let Some((_, oldest)) = self.order.pop_first() {
+                self.entries.remove(&oldest);
+            }
+        }
+        self.order.insert(tick, key);
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        let (v, t) = self.entries.remove(key)?;
+        self.order.remove(&t);
+        Some(v)
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Lru;
+
+    #[test]
+    fn eviction() {
+        let mut lru = Lru::new(2);
+        lru.insert(1, "a");
+        lru.insert(2, "b");
+        assert_eq!(lru.get(&1), Some(&"a"));
+        lru.insert(3, "c");
+        assert_eq!(lru.get(&2), None);
+        assert_eq!(lru.get(&1), Some(&"a"));
+        assert_eq!(lru.get(&3), Some(&"c"));
+        lru.insert(3, "d");
+        assert_eq!(lru.get(&3), Some(&"d"));
+        assert_eq!(lru.get(&1), Some(&"a"));
+        assert_eq!(lru.entries.len(), 2);
+        assert_eq!(lru.remove(&1), Some("a"));
+        assert_eq!(lru.remove(&1), None);
+        assert_eq!(lru.order.len(), 1);
+    }
+}
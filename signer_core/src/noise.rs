@@ -0,0 +1,463 @@
+//! Encrypted channel carrying the RPC connection
+//!
+//! The handshake is `Noise_XK_25519_AESGCM_SHA256`: the client knows the signer's static key in
+//! advance, normally taken from the `public_key` of an attestation document, and sends its own
+//! static key encrypted in the last handshake message. Handshake and transport messages use the
+//! same length prefix as RPC frames and are at most [`MAX_MESSAGE_SIZE`] bytes long. After the
+//! handshake both sides bridge the channel to an in-memory stream, so `Server` and `Client` run on
+//! top of it unchanged.
+use crate::rpc::codec;
+use aes_gcm::{aead::Aead, aead::Payload, Aes256Gcm, KeyInit, Nonce};
+use curve25519_dalek::montgomery::MontgomeryPoint;
+use hkdf::Hkdf;
+use rand_core::CryptoRngCore;
+use sha2::{Digest, Sha256};
+use std::io;
+use subtle::ConstantTimeEq;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use zeroize::Zeroizing;
+
+pub const PROTOCOL_NAME: &[u8] = b"Noise_XK_25519_AESGCM_SHA256";
+/// Mixed into the handshake hash
+pub const PROLOGUE: &[u8] = b"enclave-signer rpc";
+pub const KEY_SIZE: usize = 32;
+pub const MAX_MESSAGE_SIZE: usize = 65535;
+const TAG_SIZE: usize = 16;
+const HASH_SIZE: usize = 32;
+/// Buffer size of the in-memory stream between the channel and the protocol
+const DUPLEX_BUF_SIZE: usize = 64 << 10;
+
+#[derive(Debug)]
+pub enum Error {
+    IO(io::Error),
+    /// A message failed to decrypt or the peer's key is invalid
+    Authentication,
+    /// Handshake message of an unexpected length
+    InvalidMessage(usize),
+    MessageTooLarge(usize),
+}
+
+impl From<io::Error> for Error {
+    fn from(value: io::Error) -> Self {
+        Error::IO(value)
+    }
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::IO(error) => write!(f, "IO error: {}", error),
+            Error::Authentication => f.write_str("noise channel authentication failed"),
+            Error::InvalidMessage(len) => {
+                write!(f, "unexpected noise handshake message length: {}", len)
+            }
+            Error::MessageTooLarge(len) => write!(f, "noise message is too large: {}", len),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::IO(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+/// X25519 secret identifying a side of the channel
+#[derive(Clone)]
+pub struct StaticKey(Zeroizing<[u8; KEY_SIZE]>);
+
+impl std::fmt::Debug for StaticKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("StaticKey(..)")
+    }
+}
+
+impl StaticKey {
+    pub fn generate<R: CryptoRngCore>(r: &mut R) -> Self {
+        let mut key = Zeroizing::new([0; KEY_SIZE]);
+        r.fill_bytes(&mut key[..]);
+        StaticKey(key)
+    }
+
+    pub fn from_bytes(secret: [u8; KEY_SIZE]) -> Self {
+        StaticKey(Zeroizing::new(secret))
+    }
+
+    pub fn public_key(&self) -> [u8; KEY_SIZE] {
+        MontgomeryPoint::mul_base_clamped(*self.0).to_bytes()
+    }
+
+    fn dh(&self, public: &[u8; KEY_SIZE]) -> Result<Zeroizing<[u8; KEY_SIZE]>, Error> {
+        let shared = Zeroizing::new(MontgomeryPoint(*public).mul_clamped(*self.0).to_bytes());
+        // low order points give the all zero output
+        if bool::from(shared[..].ct_eq(&[0; KEY_SIZE])) {
+            Err(Error::Authentication)
+        } else {
+            Ok(shared)
+        }
+    }
+}
+
+#[derive(Default)]
+struct CipherState {
+    key: Option<Zeroizing<[u8; KEY_SIZE]>>,
+    n: u64,
+}
+
+impl CipherState {
+    fn new(key: Zeroizing<[u8; KEY_SIZE]>) -> Self {
+        CipherState {
+            key: Some(key),
+            n: 0,
+        }
+    }
+
+    fn next_nonce(&mut self) -> Result<[u8; 12], Error> {
+        let mut nonce = [0; 12];
+        nonce[4..].copy_from_slice(&self.n.to_be_bytes());
+        // 2^64 - 1 is reserved
+        self.n = self
+            .n
+            .checked_add(1)
+            .filter(|&n| n != u64::MAX)
+            .ok_or(Error::Authentication)?;
+        Ok(nonce)
+    }
+
+    fn encrypt(&mut self, ad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+        if self.key.is_none() {
+            return Ok(plaintext.into());
+        }
+        let nonce = self.next_nonce()?;
+        let key = self.key.as_ref().unwrap();
+        Aes256Gcm::new(key.as_ref().into())
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: plaintext,
+                    aad: ad,
+                },
+            )
+            .or(Err(Error::MessageTooLarge(plaintext.len())))
+    }
+
+    fn decrypt(&mut self, ad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+        if self.key.is_none() {
+            return Ok(ciphertext.into());
+        }
+        let nonce = self.next_nonce()?;
+        let key = self.key.as_ref().unwrap();
+        Aes256Gcm::new(key.as_ref().into())
+            .decrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: ad,
+                },
+            )
+            .or(Err(Error::Authentication))
+    }
+}
+
+struct SymmetricState {
+    ck: Zeroizing<[u8; HASH_SIZE]>,
+    h: [u8; HASH_SIZE],
+    cipher: CipherState,
+}
+
+impl SymmetricState {
+    fn new() -> Self {
+        let mut h = [0; HASH_SIZE];
+        h[..PROTOCOL_NAME.len()].copy_from_slice(PROTOCOL_NAME);
+        let mut st = SymmetricState {
+            ck: Zeroizing::new(h),
+            h,
+            cipher: CipherState::default(),
+        };
+        st.mix_hash(PROLOGUE);
+        st
+    }
+
+    fn mix_hash(&mut self, data: &[u8]) {
+        self.h = Sha256::new()
+            .chain_update(self.h)
+            .chain_update(data)
+            .finalize()
+            .into();
+    }
+
+    fn hkdf(&self, ikm: &[u8]) -> (Zeroizing<[u8; HASH_SIZE]>, Zeroizing<[u8; KEY_SIZE]>) {
+        let mut okm = Zeroizing::new([0; HASH_SIZE + KEY_SIZE]);
+        Hkdf::<Sha256>::new(Some(&self.ck[..]), ikm)
+            .expand(&[], &mut okm[..])
+            .expect("valid HKDF output length");
+        let mut a = Zeroizing::new([0; HASH_SIZE]);
+        let mut b = Zeroizing::new([0; KEY_SIZE]);
+        a.copy_from_slice(&okm[..HASH_SIZE]);
+        b.copy_from_slice(&okm[HASH_SIZE..]);
+        (a, b)
+    }
+
+    fn mix_key(&mut self, ikm: &[u8]) {
+        let (ck, key) = self.hkdf(ikm);
+        self.ck = ck;
+        self.cipher = CipherState::new(key);
+    }
+
+    fn encrypt_and_hash(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, Error> {
+        let ciphertext = self.cipher.encrypt(&self.h, plaintext)?;
+        self.mix_hash(&ciphertext);
+        Ok(ciphertext)
+    }
+
+    fn decrypt_and_hash(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, Error> {
+        let plaintext = self.cipher.decrypt(&self.h, ciphertext)?;
+        self.mix_hash(ciphertext);
+        Ok(plaintext)
+    }
+
+    /// Returns the initiator's and the responder's sending keys
+    fn split(&self) -> (CipherState, CipherState) {
+        let (a, b) = self.hkdf(&[]);
+        (CipherState::new(a), CipherState::new(b))
+    }
+}
+
+async fn write_message<T: AsyncWrite + Unpin>(wr: &mut T, msg: &[u8]) -> io::Result<()> {
+    codec::write_frame(wr, msg).await?;
+    wr.flush().await
+}
+
+async fn read_message<T: AsyncRead + Unpin>(rd: &mut T, buf: &mut Vec<u8>) -> Result<(), Error> {
+    let len = codec::read_len(rd).await?;
+    if len > MAX_MESSAGE_SIZE {
+        return Err(Error::MessageTooLarge(len));
+    }
+    Ok(codec::read_payload(rd, len, buf).await?)
+}
+
+async fn read_handshake<T: AsyncRead + Unpin>(rd: &mut T, len: usize) -> Result<Vec<u8>, Error> {
+    let mut buf = Vec::new();
+    read_message(rd, &mut buf).await?;
+    if buf.len() != len {
+        return Err(Error::InvalidMessage(buf.len()));
+    }
+    Ok(buf)
+}
+
+/// Encrypts the data read from the returned stream and decrypts the messages written to it
+fn bridge<T>(sock: T, mut send: CipherState, mut recv: CipherState) -> DuplexStream
+where
+    T: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (local, remote) = tokio::io::duplex(DUPLEX_BUF_SIZE);
+    let (mut local_rd, mut local_wr) = tokio::io::split(local);
+    let (mut sock_rd, mut sock_wr) = tokio::io::split(sock);
+    tokio::spawn(async move {
+        let mut buf = vec![0; MAX_MESSAGE_SIZE - TAG_SIZE];
+        while let Ok(n @ 1..) = local_rd.read(&mut buf).await {
+            let Ok(msg) = send.encrypt(&[], &buf[..n]) else {
+                break;
+            };
+            if write_message(&mut sock_wr, &msg).await.is_err() {
+                break;
+            }
+        }
+        let _ = sock_wr.shutdown().await;
+    });
+    tokio::spawn(async move {
+        let mut buf = Vec::new();
+        while read_message(&mut sock_rd, &mut buf).await.is_ok() {
+            // a forged message ends the connection
+            let Ok(data) = recv.decrypt(&[], &buf) else {
+                break;
+            };
+            if local_wr.write_all(&data).await.is_err() {
+                break;
+            }
+        }
+        let _ = local_wr.shutdown().await;
+    });
+    remote
+}
+
+/// Performs the initiator side of the handshake with the signer owning `remote`.
+/// The returned stream is used with `Client::new`
+pub async fn connect<T, R>(
+    mut sock: T,
+    key: &StaticKey,
+    remote: &[u8; KEY_SIZE],
+    r: &mut R,
+) -> Result<DuplexStream, Error>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    R: CryptoRngCore,
+{
+    let mut st = SymmetricState::new();
+    st.mix_hash(remote);
+
+    // -> e, es
+    let e = StaticKey::generate(r);
+    let mut msg = e.public_key().to_vec();
+    st.mix_hash(&msg);
+    st.mix_key(&e.dh(remote)?[..]);
+    msg.extend(st.encrypt_and_hash(&[])?);
+    write_message(&mut sock, &msg).await?;
+
+    // <- e, ee
+    let msg = read_handshake(&mut sock, KEY_SIZE + TAG_SIZE).await?;
+    let re: [u8; KEY_SIZE] = msg[..KEY_SIZE].try_into().unwrap();
+    st.mix_hash(&re);
+    st.mix_key(&e.dh(&re)?[..]);
+    st.decrypt_and_hash(&msg[KEY_SIZE..])?;
+
+    // -> s, se
+    let mut msg = st.encrypt_and_hash(&key.public_key())?;
+    st.mix_key(&key.dh(&re)?[..]);
+    msg.extend(st.encrypt_and_hash(&[])?);
+    write_message(&mut sock, &msg).await?;
+
+    let (send, recv) = st.split();
+    Ok(bridge(sock, send, recv))
+}
+
+/// Performs the responder side of the handshake. Returns the stream to be passed to
+/// `Server::serve_connection` and the client's static public key
+pub async fn accept<T, R>(
+    mut sock: T,
+    key: &StaticKey,
+    r: &mut R,
+) -> Result<(DuplexStream, [u8; KEY_SIZE]), Error>
+where
+    T: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    R: CryptoRngCore,
+{
+    let mut st = SymmetricState::new();
+    st.mix_hash(&key.public_key());
+
+    // -> e, es
+    let msg = read_handshake(&mut sock, KEY_SIZE + TAG_SIZE).await?;
+    let re: [u8; KEY_SIZE] = msg[..KEY_SIZE].try_into().unwrap();
+    st.mix_hash(&re);
+    st.mix_key(&key.dh(&re)?[..]);
+    st.decrypt_and_hash(&msg[KEY_SIZE..])?;
+
+    // <- e, ee
+    let e = StaticKey::generate(r);
+    let mut msg = e.public_key().to_vec();
+    st.mix_hash(&msg);
+    st.mix_key(&e.dh(&re)?[..]);
+    msg.extend(st.encrypt_and_hash(&[])?);
+    write_message(&mut sock, &msg).await?;
+
+    // -> s, se
+    let msg = read_handshake(&mut sock, KEY_SIZE + 2 * TAG_SIZE).await?;
+    let rs: [u8; KEY_SIZE] = st
+        .decrypt_and_hash(&msg[..KEY_SIZE + TAG_SIZE])?
+        .try_into()
+        .or(Err(Error::Authentication))?;
+    st.mix_key(&e.dh(&rs)?[..]);
+    st.decrypt_and_hash(&msg[KEY_SIZE + TAG_SIZE..])?;
+
+    let (recv, send) = st.split();
+    Ok((bridge(sock, send, recv), rs))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{accept, connect, Error, StaticKey};
+    use crate::crypto::{KeyType, SignOptions, SigningVersion};
+    use crate::rpc::{client::Client, server::Server, InsecureOptions};
+    use crate::tests::{DummyCredentials, PassthroughFactory};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use tokio::net::UnixStream;
+
+    #[tokio::test]
+    async fn noise_channel() {
+        let (srv_sock, client_sock) = UnixStream::pair().unwrap();
+        let server_key = StaticKey::generate(&mut rand_core::OsRng);
+        let client_key = StaticKey::generate(&mut rand_core::OsRng);
+        let server_pub = server_key.public_key();
+        let client_pub = client_key.public_key();
+
+        futures::join!(
+            async move {
+                let (sock, peer) = accept(srv_sock, &server_key, &mut rand_core::OsRng)
+                    .await
+                    .unwrap();
+                assert_eq!(peer, client_pub);
+                let mut server = Server::new(PassthroughFactory, rand_core::OsRng)
+                    .with_insecure_options(InsecureOptions {
+                        passthrough_backend: true,
+                        ..Default::default()
+                    });
+                server.serve_connection(sock).await.unwrap();
+            },
+            async move {
+                let sock = connect(client_sock, &client_key, &server_pub, &mut rand_core::OsRng)
+                    .await
+                    .unwrap();
+                let mut client: Client<DuplexStream, DummyCredentials> = Client::new(sock);
+                client.initialize(DummyCredentials {}).await.unwrap();
+                let key = client.generate_and_import(KeyType::Ed25519).await.unwrap();
+                let msg = b"text";
+                let sig = client
+                    .try_sign(
+                        key.handle,
+                        msg,
+                        SigningVersion::Latest,
+                        &SignOptions::default(),
+                    )
+                    .await
+                    .unwrap();
+                key.public_key.verify(msg, &sig).unwrap();
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn noise_stream() {
+        let (srv_sock, client_sock) = UnixStream::pair().unwrap();
+        let server_key = StaticKey::generate(&mut rand_core::OsRng);
+        let client_key = StaticKey::generate(&mut rand_core::OsRng);
+        let server_pub = server_key.public_key();
+        // spans several messages
+        let data: Vec<u8> = (0..200_000).map(|i| i as u8).collect();
+        let expected = data.clone();
+
+        let (mut r1, mut r2) = (rand_core::OsRng, rand_core::OsRng);
+        futures::join!(
+            async {
+                let (mut sock, _) = accept(srv_sock, &server_key, &mut r1).await.unwrap();
+                let mut buf = Vec::new();
+                sock.read_to_end(&mut buf).await.unwrap();
+                assert_eq!(buf, expected);
+            },
+            async {
+                let mut sock = connect(client_sock, &client_key, &server_pub, &mut r2)
+                    .await
+                    .unwrap();
+                sock.write_all(&data).await.unwrap();
+                sock.shutdown().await.unwrap();
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn noise_channel_wrong_key() {
+        let (srv_sock, client_sock) = UnixStream::pair().unwrap();
+        let server_key = StaticKey::generate(&mut rand_core::OsRng);
+        let other = StaticKey::generate(&mut rand_core::OsRng).public_key();
+        let client_key = StaticKey::generate(&mut rand_core::OsRng);
+
+        let (mut r1, mut r2) = (rand_core::OsRng, rand_core::OsRng);
+        let (srv, _) = futures::join!(
+            accept(srv_sock, &server_key, &mut r1),
+            connect(client_sock, &client_key, &other, &mut r2)
+        );
+        assert!(matches!(srv, Err(Error::Authentication)));
+    }
+}
@@ -0,0 +1,36 @@
+//! Stable paths for host integrators: `use signer_core::prelude::*`.
+//!
+//! The module layout of the crate may change between versions while the paths below
+//! are kept stable.
+pub use crate::callback::{
+    Callback, Channel as CallbackChannel, Error as CallbackError, HostChannel as CallbackHost,
+};
+pub use crate::crypto::{
+    bls::{BlsOptions, Scheme as BlsScheme},
+    data_key::DataKey,
+    raw::{RawSecret, UnencryptedKey},
+    wrap::WrappingKey,
+};
+pub use crate::crypto::{
+    DigestAlgorithm, Ed25519ph, Error as CryptoError, KeyConstraint, KeyHandle, KeyInfo, KeyKind,
+    KeyMetadata, KeyPair, KeyStats, KeyType, KeychainNamespaces, PrivateKey, ProofOfPossession,
+    PublicKey, PublicKeyFormat, SharedKeychain, SignOptions, Signature, SigningVersion, Verifier,
+};
+pub use crate::rpc::{
+    client::{Client, ClientBuilder, Error as ClientError, Interceptor, ReconnectPolicy},
+    connector::{Connector, TcpConnector, UnixConnector, VsockConnector},
+    events::{Event, EventBus},
+    key::Key,
+    pool::{ClientPool, Lease as ClientLease},
+    server::{Server, StateError, STATE_MACHINE_VERSION},
+    AttestResult, CodedError, Compression, Error as RPCError, ErrorCode, HelloResult, InfoResult,
+    InsecureOptions, Interim, NegotiateCompressionResult, PongResult, Progress, Request,
+};
+pub use crate::{
+    BackendState, DecryptDataResult, EciesDecryptResult, EncryptDataResult, EncryptedSigner,
+    EncryptionBackend, EncryptionBackendFactory, Error as SignerError, ExportPublicKeyResult,
+    ExportSharesResult, ExportWrappedResult, GenerateAndImportResult, GenerateConstrainedResult,
+    GenerateDataKeyResult, GenerateResult, GenerateSeedResult, ImportDataKeyResult,
+    ImportDeferredResult, ImportResult, MuSig2AggregateResult, MuSig2NonceResult, MuSig2SignResult,
+    RewrapResult,
+};
@@ -1,25 +1,70 @@
 use crate::{
-    crypto::{KeyType, PrivateKey, SigningVersion},
-    serde_helper::bytes,
+    cddl::{Cddl, Schema},
+    crypto::{
+        raw::UnencryptedKey, wrap::WrappingKey, DigestAlgorithm, KeyConstraint, KeyHandle, KeyInfo,
+        KeyMetadata, KeyStats, KeyType, ProofOfPossession, PublicKey, PublicKeyFormat, SignOptions,
+        Signature, SigningVersion,
+    },
+    serde_helper::{bytes, bytes_seq},
+    CredentialsSchema, TryIntoCBOR,
+};
+pub use crate::{
+    BackendState, DecryptDataResult, EciesDecryptResult, EncryptDataResult, ExportPublicKeyResult,
+    ExportSharesResult, ExportWrappedResult, GenerateAndImportResult, GenerateConstrainedResult,
+    GenerateDataKeyResult, GenerateResult, GenerateSeedResult, ImportDataKeyResult,
+    ImportDeferredResult, ImportResult, MuSig2AggregateResult, MuSig2NonceResult, MuSig2SignResult,
+    RewrapResult,
 };
-pub use crate::{GenerateAndImportResult, GenerateResult, ImportResult};
+use hmac::{Hmac, Mac};
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use zeroize::Zeroize;
 
 pub mod client;
+pub mod codec;
+pub mod connector;
+pub mod events;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod idempotency;
+pub mod key;
+pub mod limits;
+pub mod pool;
 pub mod server;
+pub mod vectors;
 
-#[derive(Debug, Serialize, Deserialize)]
+pub(crate) use codec::{begin_frame, finish_frame};
+pub use codec::{encode_frame, Compression, DecompressionError, FRAME_PREFIX_LEN, HEARTBEAT_FRAME};
+
+/// Starts a `TaggedResponse` frame in `buf`. The response itself is appended in place
+/// and the frame is finished by `finish_frame`
+pub(crate) fn begin_tagged_frame(
+    buf: &mut Vec<u8>,
+    id: u64,
+) -> std::result::Result<(), ciborium::ser::Error<std::io::Error>> {
+    begin_frame(buf);
+    // { "Tagged": { "id": id, "response": ...
+    buf.extend_from_slice(b"\xa1\x66Tagged\xa2\x62id");
+    id.try_into_writer(&mut *buf)?;
+    buf.extend_from_slice(b"\x68response");
+    Ok(())
+}
+
+#[derive(Debug, Serialize, Deserialize, Cddl)]
 pub enum Request<C> {
     Initialize(C),
     Import(#[serde(with = "bytes")] Vec<u8>),
-    ImportUnencrypted(PrivateKey),
+    ImportUnencrypted(UnencryptedKey),
     Generate(KeyType),
     GenerateAndImport(KeyType),
     Sign {
-        handle: usize,
+        handle: KeyHandle,
         #[serde(with = "bytes")]
         message: Vec<u8>,
         version: SigningVersion,
+        #[serde(default)]
+        options: SignOptions,
     },
     SignWith {
         #[serde(with = "bytes")]
@@ -27,29 +72,715 @@ pub enum Request<C> {
         #[serde(with = "bytes")]
         message: Vec<u8>,
         version: SigningVersion,
+        #[serde(default)]
+        options: SignOptions,
     },
-    PublicKey(usize),
+    PublicKey(KeyHandle),
     PublicKeyFrom(#[serde(with = "bytes")] Vec<u8>),
-    ProvePossession(usize),
+    ProvePossession(KeyHandle),
+    Describe,
+    SignDigest {
+        handle: KeyHandle,
+        #[serde(with = "bytes")]
+        digest: Vec<u8>,
+        version: SigningVersion,
+    },
+    Verify {
+        public_key: PublicKey,
+        #[serde(with = "bytes")]
+        message: Vec<u8>,
+        signature: Signature,
+        version: SigningVersion,
+    },
+    ExportPublicKey {
+        handle: KeyHandle,
+        format: PublicKeyFormat,
+    },
+    GenerateConstrained {
+        key_type: KeyType,
+        constraint: KeyConstraint,
+        import: bool,
+    },
+    ImportMnemonic {
+        key_type: KeyType,
+        mnemonic: String,
+        #[serde(default)]
+        passphrase: String,
+    },
+    DeriveChild {
+        handle: KeyHandle,
+        path: Vec<u32>,
+    },
+    ImportPublicKey(PublicKey),
+    VerifyWith {
+        handle: KeyHandle,
+        #[serde(with = "bytes")]
+        message: Vec<u8>,
+        signature: Signature,
+        version: SigningVersion,
+    },
+    ImportDeferred(#[serde(with = "bytes")] Vec<u8>),
+    Hello,
+    VerifyAggregate {
+        public_keys: Vec<PublicKey>,
+        #[serde(with = "bytes")]
+        message: Vec<u8>,
+        signature: Signature,
+        version: SigningVersion,
+    },
+    InitializeWith {
+        credentials: C,
+        #[serde(default, with = "bytes_seq")]
+        #[cddl(type = "[* bstr]")]
+        keys: Vec<Vec<u8>>,
+    },
+    ExportShares {
+        handle: KeyHandle,
+        threshold: u8,
+        shares: u8,
+    },
+    ImportShares(
+        #[serde(with = "bytes_seq")]
+        #[cddl(type = "[* bstr]")]
+        Vec<Vec<u8>>,
+    ),
+    MuSig2Nonce {
+        handle: KeyHandle,
+        public_keys: Vec<PublicKey>,
+        #[serde(with = "bytes")]
+        message: Vec<u8>,
+    },
+    MuSig2Sign {
+        session: usize,
+        #[serde(with = "bytes_seq")]
+        #[cddl(type = "[* bstr]")]
+        pub_nonces: Vec<Vec<u8>>,
+    },
+    MuSig2Aggregate {
+        public_keys: Vec<PublicKey>,
+        #[serde(with = "bytes")]
+        message: Vec<u8>,
+        #[serde(with = "bytes_seq")]
+        #[cddl(type = "[* bstr]")]
+        pub_nonces: Vec<Vec<u8>>,
+        #[serde(with = "bytes_seq")]
+        #[cddl(type = "[* bstr]")]
+        partial_signatures: Vec<Vec<u8>>,
+    },
+    TweakKey {
+        handle: KeyHandle,
+        #[serde(with = "bytes")]
+        tweak: Vec<u8>,
+    },
+    EciesDecrypt {
+        handle: KeyHandle,
+        #[serde(with = "bytes")]
+        ciphertext: Vec<u8>,
+    },
+    ExportWrapped {
+        handle: KeyHandle,
+        recipient: WrappingKey,
+    },
+    ImportWrapped {
+        handle: KeyHandle,
+        #[serde(with = "bytes")]
+        wrapped_key: Vec<u8>,
+    },
+    GenerateDataKey,
+    ImportDataKey(#[serde(with = "bytes")] Vec<u8>),
+    EncryptData {
+        handle: KeyHandle,
+        #[serde(with = "bytes")]
+        plaintext: Vec<u8>,
+        #[serde(default, with = "bytes")]
+        aad: Vec<u8>,
+    },
+    DecryptData {
+        handle: KeyHandle,
+        #[serde(with = "bytes")]
+        ciphertext: Vec<u8>,
+        #[serde(default, with = "bytes")]
+        aad: Vec<u8>,
+    },
+    VerifyBatch {
+        public_keys: Vec<PublicKey>,
+        #[serde(with = "bytes_seq")]
+        #[cddl(type = "[* bstr]")]
+        messages: Vec<Vec<u8>>,
+        signatures: Vec<Signature>,
+    },
+    GenerateSeed,
+    Remove(KeyHandle),
+    ListKeys,
+    GenerateFromSeed {
+        key_type: KeyType,
+        #[serde(with = "bytes")]
+        seed: Vec<u8>,
+        #[serde(default, with = "bytes")]
+        label: Vec<u8>,
+    },
+    SetMetadata {
+        handle: KeyHandle,
+        metadata: KeyMetadata,
+    },
+    FindLabel(String),
+    FindPublicKey(PublicKey),
+    KeyStats,
+    SetExpiry {
+        handle: KeyHandle,
+        /// Seconds from now, `null` removes the expiry
+        ttl: Option<u64>,
+    },
+    Rewrap {
+        #[serde(with = "bytes")]
+        key_data: Vec<u8>,
+        /// Backend key to encrypt with instead of the configured one
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        key_id: Option<String>,
+    },
+    /// Carries a request together with the client chosen `id` echoed back in
+    /// `TaggedResponse`. Tagged signing requests may be processed concurrently and
+    /// answered out of order
+    Tagged {
+        id: u64,
+        request: Box<Request<C>>,
+    },
+    BatchSign {
+        handle: KeyHandle,
+        #[serde(with = "bytes_seq")]
+        #[cddl(type = "[* bstr]")]
+        msgs: Vec<Vec<u8>>,
+        version: SigningVersion,
+        #[serde(default)]
+        options: SignOptions,
+    },
+    Ping {
+        /// Echoed back as is
+        #[serde(default, with = "bytes")]
+        payload: Vec<u8>,
+    },
+    /// Drops the keys of the connection and closes it once answered. With the server's shutdown
+    /// token the whole signer is shut down
+    Shutdown {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
+    },
+    /// Starts signing a message sent in chunks, hashed as they arrive
+    SignStreamBegin {
+        handle: KeyHandle,
+        version: SigningVersion,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        digest: Option<DigestAlgorithm>,
+    },
+    SignStreamChunk(#[serde(with = "bytes")] Vec<u8>),
+    SignStreamFinish,
+    GetInfo,
+    /// Replaces the encryption backend credentials keeping the keys
+    Reinitialize {
+        credentials: C,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        token: Option<String>,
+    },
+    /// Carries the CBOR encoded request authenticated with the key provisioned by `Initialize`,
+    /// see `request_mac`. Required for every request of a session initialized with the key
+    Authenticated {
+        seq: u64,
+        #[serde(with = "bytes")]
+        request: Vec<u8>,
+        #[serde(with = "bytes")]
+        mac: Vec<u8>,
+    },
+    /// Returns the attestation document of the enclave
+    Attest {
+        #[serde(default, with = "bytes")]
+        nonce: Vec<u8>,
+        #[serde(default, with = "bytes")]
+        user_data: Vec<u8>,
+    },
+    /// Picks the first algorithm of the list supported by the server. Responses with payloads
+    /// of at least `min_size` bytes are compressed from then on
+    NegotiateCompression {
+        algorithms: Vec<Compression>,
+        #[serde(default)]
+        min_size: u64,
+    },
+    /// Pushes `Interim::Event` notifications to the connection from then on
+    Subscribe,
+    /// Abandons the tagged request `id` still being processed. It's answered with the
+    /// `Cancelled` error instead of its result. Returns false if there is no such request
+    Cancel {
+        id: u64,
+    },
+    /// Carries `Generate` or `GenerateAndImport`. A retry with the same `key` returns the key
+    /// generated by the first request instead of a new one, see `idempotency::IdempotencyCache`
+    Idempotent {
+        #[serde(with = "bytes")]
+        key: Vec<u8>,
+        request: Box<Request<C>>,
+    },
+    /// Carries a request together with the caller's trace ID. The server prefixes its log
+    /// lines with the ID and sets `trace_id` of the error if the request fails. Wraps any
+    /// other envelope except `Authenticated`
+    Traced {
+        trace_id: String,
+        request: Box<Request<C>>,
+    },
+    /// Routes the request to the named session of the connection, which has its own
+    /// `Initialize` state, keys and `Authenticated` sequence. Wraps any other envelope
+    Session {
+        name: String,
+        request: Box<Request<C>>,
+    },
+}
+
+impl<C> Request<C> {
+    /// Returns the request type name as it appears on the wire
+    pub fn name(&self) -> &'static str {
+        match self {
+            Request::Initialize(_) => "Initialize",
+            Request::Import(_) => "Import",
+            Request::ImportUnencrypted(_) => "ImportUnencrypted",
+            Request::Generate(_) => "Generate",
+            Request::GenerateAndImport(_) => "GenerateAndImport",
+            Request::Sign { .. } => "Sign",
+            Request::SignWith { .. } => "SignWith",
+            Request::PublicKey(_) => "PublicKey",
+            Request::PublicKeyFrom(_) => "PublicKeyFrom",
+            Request::ProvePossession(_) => "ProvePossession",
+            Request::Describe => "Describe",
+            Request::SignDigest { .. } => "SignDigest",
+            Request::Verify { .. } => "Verify",
+            Request::ExportPublicKey { .. } => "ExportPublicKey",
+            Request::GenerateConstrained { .. } => "GenerateConstrained",
+            Request::ImportMnemonic { .. } => "ImportMnemonic",
+            Request::DeriveChild { .. } => "DeriveChild",
+            Request::ImportPublicKey(_) => "ImportPublicKey",
+            Request::VerifyWith { .. } => "VerifyWith",
+            Request::ImportDeferred(_) => "ImportDeferred",
+            Request::Hello => "Hello",
+            Request::VerifyAggregate { .. } => "VerifyAggregate",
+            Request::InitializeWith { .. } => "InitializeWith",
+            Request::ExportShares { .. } => "ExportShares",
+            Request::ImportShares(_) => "ImportShares",
+            Request::MuSig2Nonce { .. } => "MuSig2Nonce",
+            Request::MuSig2Sign { .. } => "MuSig2Sign",
+            Request::MuSig2Aggregate { .. } => "MuSig2Aggregate",
+            Request::TweakKey { .. } => "TweakKey",
+            Request::EciesDecrypt { .. } => "EciesDecrypt",
+            Request::ExportWrapped { .. } => "ExportWrapped",
+            Request::ImportWrapped { .. } => "ImportWrapped",
+            Request::GenerateDataKey => "GenerateDataKey",
+            Request::ImportDataKey(_) => "ImportDataKey",
+            Request::EncryptData { .. } => "EncryptData",
+            Request::DecryptData { .. } => "DecryptData",
+            Request::VerifyBatch { .. } => "VerifyBatch",
+            Request::GenerateSeed => "GenerateSeed",
+            Request::Remove(_) => "Remove",
+            Request::ListKeys => "ListKeys",
+            Request::GenerateFromSeed { .. } => "GenerateFromSeed",
+            Request::SetMetadata { .. } => "SetMetadata",
+            Request::FindLabel(_) => "FindLabel",
+            Request::FindPublicKey(_) => "FindPublicKey",
+            Request::KeyStats => "KeyStats",
+            Request::SetExpiry { .. } => "SetExpiry",
+            Request::Rewrap { .. } => "Rewrap",
+            Request::Tagged { .. } => "Tagged",
+            Request::BatchSign { .. } => "BatchSign",
+            Request::Ping { .. } => "Ping",
+            Request::Shutdown { .. } => "Shutdown",
+            Request::SignStreamBegin { .. } => "SignStreamBegin",
+            Request::SignStreamChunk(_) => "SignStreamChunk",
+            Request::SignStreamFinish => "SignStreamFinish",
+            Request::GetInfo => "GetInfo",
+            Request::Reinitialize { .. } => "Reinitialize",
+            Request::Attest { .. } => "Attest",
+            Request::NegotiateCompression { .. } => "NegotiateCompression",
+            Request::Idempotent { .. } => "Idempotent",
+            Request::Traced { .. } => "Traced",
+            Request::Cancel { .. } => "Cancel",
+            Request::Subscribe => "Subscribe",
+            Request::Authenticated { .. } => "Authenticated",
+            Request::Session { .. } => "Session",
+        }
+    }
+
+    /// Returns true if the request doesn't change the connection state and can be processed
+    /// alongside other such requests
+    pub fn is_concurrent(&self) -> bool {
+        matches!(
+            self,
+            Request::Sign { .. }
+                | Request::SignWith { .. }
+                | Request::SignDigest { .. }
+                | Request::BatchSign { .. }
+                | Request::PublicKey(_)
+                | Request::PublicKeyFrom(_)
+        )
+    }
+}
+
+/// Response to a `Tagged` request
+#[derive(Debug, Serialize, Deserialize)]
+pub enum TaggedResponse<T> {
+    Tagged { id: u64, response: Result<T> },
+}
+
+/// Returns the MAC of the request sent in `Request::Authenticated`:
+/// HMAC-SHA256 of the big endian `seq` followed by the encoded request
+pub fn request_mac(key: &[u8], seq: u64, request: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(&seq.to_be_bytes());
+    mac.update(request);
+    mac
+}
+
+/// Key authenticating the requests of the session
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RequestKey(#[serde(with = "bytes")] pub Vec<u8>);
+
+impl std::fmt::Debug for RequestKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("RequestKey(..)")
+    }
+}
+
+impl Drop for RequestKey {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+/// Credentials envelope sent in `Initialize` and `InitializeWith`. Hosts predating
+/// versioning don't send `version` which then defaults to 0. Fields unknown to the receiver
+/// are kept in `unknown` instead of failing the request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VersionedCredentials<C> {
+    #[serde(default)]
+    pub version: u32,
+    /// Makes the server accept only `Request::Authenticated` requests once initialized
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_key: Option<RequestKey>,
+    #[serde(flatten)]
+    pub credentials: C,
+    #[serde(flatten)]
+    pub unknown: BTreeMap<String, ciborium::Value>,
+}
+
+impl<C: CredentialsSchema> VersionedCredentials<C> {
+    pub fn new(credentials: C) -> Self {
+        VersionedCredentials {
+            version: C::VERSION,
+            request_key: None,
+            credentials,
+            unknown: BTreeMap::new(),
+        }
+    }
+}
+
+impl<C: Cddl> Cddl for VersionedCredentials<C> {
+    fn cddl_type(schema: &mut Schema) -> String {
+        let c = C::cddl_type(schema);
+        schema.define("VersionedCredentials", |_| {
+            format!(
+                "{{ ? version: uint, ? request_key: bstr, ~{}, * tstr => any }}",
+                c
+            )
+        })
+    }
+}
+
+/// Progress of a long running request
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Cddl)]
+pub struct Progress {
+    pub completed: u64,
+    pub total: u64,
+}
+
+/// Message sent by the server before the final response of a long running request.
+/// Can't be confused with `Response<T>` which is either `Ok` or `Err`
+#[derive(Debug, Clone, Serialize, Deserialize, Cddl)]
+pub enum Interim {
+    Progress(Progress),
+    /// Sent once the encryption backend availability changes
+    BackendState(BackendState),
+    /// Pushed at any time between responses after `Subscribe`
+    Event(events::Event),
+}
+
+/// Insecure options the server was started with. Any of them set means the instance
+/// must not be trusted with production keys
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, Cddl)]
+pub struct InsecureOptions {
+    /// Keys are "encrypted" by a backend which doesn't encrypt anything
+    pub passthrough_backend: bool,
+    /// `ImportUnencrypted`, `ImportWrapped` and `ImportMnemonic` requests are accepted
+    pub import_unencrypted: bool,
+    /// `ExportWrapped` requests are accepted. The recipient key isn't verified, so the host
+    /// can export any key to itself
+    #[serde(default)]
+    pub export_wrapped: bool,
+    /// `Rewrap` requests with `key_id` are accepted. The host can re-encrypt any key to
+    /// a backend key it controls and decrypt it outside of the signer
+    #[serde(default)]
+    pub rewrap_key_id: bool,
+    /// Debug output is available to the host, e.g. an enclave console
+    pub debug: bool,
+}
+
+impl InsecureOptions {
+    pub fn is_secure(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Returns the CBOR encoding of the options to be embedded into attestation documents
+    pub fn to_cbor(&self) -> Vec<u8> {
+        self.try_into_cbor()
+            .expect("serialization of a plain struct into a vector can't fail")
+    }
+}
+
+/// `Hello` response
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Cddl)]
+pub struct HelloResult {
+    pub version: String,
+    pub state_machine_version: u32,
+    pub insecure: InsecureOptions,
+    /// The only requests handled by the server, all if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub allowed_requests: Option<Vec<String>>,
+    /// The keys are stored in a keychain shared with other connections and outlive this one
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub shared_keychain: bool,
+}
+
+/// `Ping` response
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Cddl)]
+pub struct PongResult {
+    #[serde(with = "bytes")]
+    pub payload: Vec<u8>,
+    /// Seconds since the server start
+    pub uptime: u64,
+}
+
+/// `Attest` response
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Cddl)]
+pub struct AttestResult {
+    /// COSE_Sign1 signed attestation document
+    #[serde(with = "bytes")]
+    pub document: Vec<u8>,
+}
+
+/// `NegotiateCompression` response
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Cddl)]
+pub struct NegotiateCompressionResult {
+    /// `None` if none of the proposed algorithms is supported
+    pub algorithm: Option<Compression>,
+}
+
+/// `GetInfo` response
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Cddl)]
+pub struct InfoResult {
+    pub version: String,
+    pub state_machine_version: u32,
+    pub key_types: Vec<KeyType>,
+    pub initialized: bool,
+    /// Number of keys in the session storage, 0 before initialization
+    pub keys: u64,
+}
+
+/// Machine-readable category of an `Error` for callers to branch on instead of the message
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Cddl)]
+#[non_exhaustive]
+pub enum ErrorCode {
+    /// The request isn't allowed before `Initialize`
+    Uninitialized,
+    AlreadyInitialized,
+    /// The request is disabled by the server configuration or the allowlist
+    Disabled,
+    /// The request lacks the valid authentication token
+    Unauthorized,
+    /// The request is malformed or not valid in the current state
+    InvalidRequest,
+    FrameTooLarge,
+    /// The credentials schema version is newer than the server supports
+    UnsupportedCredentials,
+    InvalidHandle,
+    KeyExpired,
+    ConstraintNotSatisfied,
+    /// The operation isn't supported by the key type or the backend
+    Unsupported,
+    /// The encrypted key blob is damaged or in an unknown format
+    InvalidBlob,
+    /// Any other signing or key handling error
+    Signer,
+    /// The encryption backend can't be reached, see `BackendState`
+    BackendUnavailable,
+    KmsAccessDenied,
+    /// The KMS endpoint host name is invalid, usually because of a mistyped region
+    InvalidDnsName,
+    /// The request was abandoned by `Cancel`
+    Cancelled,
+    /// The connection has the maximum number of tagged requests in flight, the request may be
+    /// retried once one of them is answered
+    TooManyInFlight,
+    /// Code sent by a newer server
+    #[serde(other)]
+    Unknown,
+}
+
+impl ErrorCode {
+    /// Returns the code of the first error in the chain which has one
+    pub fn of(err: &(dyn std::error::Error + 'static)) -> Option<Self> {
+        let mut cur = Some(err);
+        while let Some(err) = cur {
+            if let Some(code) = Self::of_single(err) {
+                return Some(code);
+            }
+            cur = err.source();
+        }
+        None
+    }
+
+    fn of_single(err: &(dyn std::error::Error + 'static)) -> Option<Self> {
+        use crate::crypto::Error as CryptoError;
+        use server::{CredentialsVersionError, FrameTooLargeError, StateError};
+
+        if let Some(err) = err.downcast_ref::<CodedError>() {
+            Some(err.code)
+        } else if let Some(err) = err.downcast_ref::<StateError>() {
+            Some(match err {
+                StateError::Uninitialized { .. } => ErrorCode::Uninitialized,
+                StateError::Initialized => ErrorCode::AlreadyInitialized,
+                StateError::Disabled(_) => ErrorCode::Disabled,
+                StateError::NestedTagged
+                | StateError::MisplacedTraced
+                | StateError::InvalidTraceId
+                | StateError::MisplacedSession
+                | StateError::InvalidSessionName
+                | StateError::TooManySessions(_)
+                | StateError::NoSignStream
+                | StateError::NoRequestKey
+                | StateError::NotIdempotent(_)
+                | StateError::InvalidIdempotencyKey
+                | StateError::IdempotencyKeyReused => ErrorCode::InvalidRequest,
+                StateError::Unauthorized
+                | StateError::InvalidShutdownToken
+                | StateError::Unauthenticated => ErrorCode::Unauthorized,
+                StateError::Cancelled => ErrorCode::Cancelled,
+                StateError::TooManyInFlight(_) => ErrorCode::TooManyInFlight,
+            })
+        } else if let Some(err) = err.downcast_ref::<CryptoError>() {
+            Some(match err {
+                CryptoError::InvalidHandle => ErrorCode::InvalidHandle,
+                CryptoError::KeyExpired => ErrorCode::KeyExpired,
+                CryptoError::ConstraintNotSatisfied(_) => ErrorCode::ConstraintNotSatisfied,
+                CryptoError::PopUnsupported
+                | CryptoError::PrehashUnsupported
+                | CryptoError::HedgingUnsupported
+                | CryptoError::UnsupportedFormat
+                | CryptoError::DerivationUnsupported
+                | CryptoError::Eip191Unsupported
+                | CryptoError::Ed25519phUnsupported
+                | CryptoError::TweakUnsupported
+                | CryptoError::EciesUnsupported
+                | CryptoError::BlsOptionsUnsupported => ErrorCode::Unsupported,
+                _ => ErrorCode::Signer,
+            })
+        } else if err.is::<limits::Error>() {
+            Some(ErrorCode::InvalidRequest)
+        } else if let Some(err) = err.downcast_ref::<DecompressionError>() {
+            Some(match err {
+                DecompressionError::TooLarge(_) => ErrorCode::FrameTooLarge,
+                _ => ErrorCode::InvalidRequest,
+            })
+        } else if err.is::<FrameTooLargeError>() {
+            Some(ErrorCode::FrameTooLarge)
+        } else if err.is::<CredentialsVersionError>() {
+            Some(ErrorCode::UnsupportedCredentials)
+        } else if err.is::<crate::blob::Error>() {
+            Some(ErrorCode::InvalidBlob)
+        } else if err.is::<ciborium::de::Error<std::io::Error>>() {
+            Some(ErrorCode::InvalidRequest)
+        } else {
+            None
+        }
+    }
+}
+
+/// Attaches an `ErrorCode` to an error of another crate, e.g. the one of an encryption backend.
+/// Displayed as the wrapped error
+#[derive(Debug)]
+pub struct CodedError {
+    code: ErrorCode,
+    inner: Box<dyn std::error::Error + Send + Sync>,
+}
+
+impl CodedError {
+    pub fn new(code: ErrorCode, err: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Self {
+        Self {
+            code,
+            inner: err.into(),
+        }
+    }
+
+    pub fn code(&self) -> ErrorCode {
+        self.code
+    }
+}
+
+impl std::fmt::Display for CodedError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+impl std::error::Error for CodedError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.inner.source()
+    }
 }
 
 /// Wire-compatible error object
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, PartialEq, Cddl)]
 pub struct Error {
     pub message: String,
     pub source: Option<Box<Error>>,
+    /// Code of the first error in the chain which has one. Set on the outermost error only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<ErrorCode>,
+    /// Trace ID of the failed `Traced` request. Set on the outermost error only
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub trace_id: Option<String>,
 }
 
-impl<T: std::error::Error> From<T> for Error {
+impl<T: std::error::Error + 'static> From<T> for Error {
     fn from(value: T) -> Self {
+        Error {
+            code: ErrorCode::of(&value),
+            ..Self::from_chain(&value)
+        }
+    }
+}
+
+impl Error {
+    fn from_chain(value: &(dyn std::error::Error + 'static)) -> Self {
         Error {
             message: value.to_string(),
-            source: match value.source() {
-                Some(s) => Some(Box::new(Self::from(s))),
-                None => None,
-            },
+            source: value.source().map(|s| Box::new(Self::from_chain(s))),
+            code: None,
+            trace_id: None,
         }
     }
+
+    /// Iterates over the error and its sources, outermost first
+    pub fn chain(&self) -> impl Iterator<Item = &Error> {
+        std::iter::successors(Some(self), |err| err.source.as_deref())
+    }
+
+    /// Returns the innermost error of the chain, normally the one reported by a backend or a library
+    pub fn root_cause(&self) -> &Error {
+        self.chain().last().unwrap()
+    }
 }
 
 impl std::fmt::Display for Error {
@@ -63,18 +794,143 @@ impl std::fmt::Display for Error {
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// Returns the CDDL description of the protocol with `C` used as the credentials type
+pub fn describe<C: Cddl>() -> String {
+    let mut schema = Schema::new();
+    Request::<VersionedCredentials<C>>::cddl_type(&mut schema);
+    let err = Error::cddl_type(&mut schema);
+    Interim::cddl_type(&mut schema);
+    schema.define("Response<T>", |_| {
+        format!("{{ Ok: T }} / {{ Err: {} }}", err)
+    });
+    schema.define("TaggedResponse<T>", |_| {
+        "{ Tagged: { id: uint, response: Response<T> } }".into()
+    });
+
+    let responses = [
+        ("Initialize", <()>::cddl_type(&mut schema)),
+        ("Import", ImportResult::cddl_type(&mut schema)),
+        (
+            "ImportUnencrypted",
+            GenerateAndImportResult::cddl_type(&mut schema),
+        ),
+        ("Generate", GenerateResult::cddl_type(&mut schema)),
+        (
+            "GenerateAndImport",
+            GenerateAndImportResult::cddl_type(&mut schema),
+        ),
+        ("Sign", Signature::cddl_type(&mut schema)),
+        ("SignWith", Signature::cddl_type(&mut schema)),
+        ("PublicKey", PublicKey::cddl_type(&mut schema)),
+        ("PublicKeyFrom", PublicKey::cddl_type(&mut schema)),
+        ("ProvePossession", ProofOfPossession::cddl_type(&mut schema)),
+        ("Describe", String::cddl_type(&mut schema)),
+        ("SignDigest", Signature::cddl_type(&mut schema)),
+        ("Verify", bool::cddl_type(&mut schema)),
+        (
+            "ExportPublicKey",
+            ExportPublicKeyResult::cddl_type(&mut schema),
+        ),
+        (
+            "GenerateConstrained",
+            GenerateConstrainedResult::cddl_type(&mut schema),
+        ),
+        ("ImportMnemonic", GenerateResult::cddl_type(&mut schema)),
+        ("DeriveChild", GenerateResult::cddl_type(&mut schema)),
+        ("ImportPublicKey", ImportResult::cddl_type(&mut schema)),
+        ("VerifyWith", bool::cddl_type(&mut schema)),
+        (
+            "ImportDeferred",
+            ImportDeferredResult::cddl_type(&mut schema),
+        ),
+        ("Hello", HelloResult::cddl_type(&mut schema)),
+        ("VerifyAggregate", bool::cddl_type(&mut schema)),
+        (
+            "InitializeWith",
+            Vec::<ImportResult>::cddl_type(&mut schema),
+        ),
+        ("ExportShares", ExportSharesResult::cddl_type(&mut schema)),
+        ("ImportShares", ImportResult::cddl_type(&mut schema)),
+        ("MuSig2Nonce", MuSig2NonceResult::cddl_type(&mut schema)),
+        ("MuSig2Sign", MuSig2SignResult::cddl_type(&mut schema)),
+        (
+            "MuSig2Aggregate",
+            MuSig2AggregateResult::cddl_type(&mut schema),
+        ),
+        ("TweakKey", GenerateResult::cddl_type(&mut schema)),
+        ("EciesDecrypt", EciesDecryptResult::cddl_type(&mut schema)),
+        ("ExportWrapped", ExportWrappedResult::cddl_type(&mut schema)),
+        (
+            "ImportWrapped",
+            GenerateAndImportResult::cddl_type(&mut schema),
+        ),
+        (
+            "GenerateDataKey",
+            GenerateDataKeyResult::cddl_type(&mut schema),
+        ),
+        ("ImportDataKey", ImportDataKeyResult::cddl_type(&mut schema)),
+        ("EncryptData", EncryptDataResult::cddl_type(&mut schema)),
+        ("DecryptData", DecryptDataResult::cddl_type(&mut schema)),
+        ("VerifyBatch", bool::cddl_type(&mut schema)),
+        ("GenerateSeed", GenerateSeedResult::cddl_type(&mut schema)),
+        ("GenerateFromSeed", GenerateResult::cddl_type(&mut schema)),
+        ("Remove", <()>::cddl_type(&mut schema)),
+        ("ListKeys", Vec::<KeyInfo>::cddl_type(&mut schema)),
+        ("SetMetadata", <()>::cddl_type(&mut schema)),
+        ("FindLabel", Option::<KeyInfo>::cddl_type(&mut schema)),
+        ("FindPublicKey", Option::<KeyInfo>::cddl_type(&mut schema)),
+        ("KeyStats", Vec::<KeyStats>::cddl_type(&mut schema)),
+        ("SetExpiry", <()>::cddl_type(&mut schema)),
+        ("Rewrap", RewrapResult::cddl_type(&mut schema)),
+        ("BatchSign", Vec::<Signature>::cddl_type(&mut schema)),
+        ("Ping", PongResult::cddl_type(&mut schema)),
+        ("Shutdown", <()>::cddl_type(&mut schema)),
+        ("SignStreamBegin", <()>::cddl_type(&mut schema)),
+        ("SignStreamChunk", <()>::cddl_type(&mut schema)),
+        ("SignStreamFinish", Signature::cddl_type(&mut schema)),
+        ("GetInfo", InfoResult::cddl_type(&mut schema)),
+        ("Reinitialize", <()>::cddl_type(&mut schema)),
+        ("Attest", AttestResult::cddl_type(&mut schema)),
+        (
+            "NegotiateCompression",
+            NegotiateCompressionResult::cddl_type(&mut schema),
+        ),
+        ("Cancel", bool::cddl_type(&mut schema)),
+        ("Subscribe", <()>::cddl_type(&mut schema)),
+    ];
+    for (req, res) in responses {
+        schema.define(&format!("{}Response", req), |_| {
+            format!("Response<{}>", res)
+        });
+    }
+    schema.to_string()
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::crypto::{Blake2b256, KeyType, PublicKey, Signature, SigningVersion};
+    use crate::attestation::AttestationSource;
+    use crate::crypto::{
+        wrap::WrappingKey, Blake2b256, DigestAlgorithm, KeyHandle, KeyKind, KeyMetadata, KeyType,
+        KeychainNamespaces, PrivateKey, PublicKey, SharedKeychain, SignOptions, Signature,
+        SigningVersion,
+    };
     use crate::rpc::{
         client::{Client, Error as ClientError},
-        server::Server,
-        Error,
+        describe,
+        server::{Server, STATE_MACHINE_VERSION},
+        CodedError, Error, ErrorCode, InsecureOptions, Request, VersionedCredentials,
     };
     use crate::tests::{DummyCredentials, Passthrough, PassthroughFactory};
-    use crate::{macros::unwrap_as, EncryptedSigner};
+    use crate::{
+        crypto::KeyPair, macros::unwrap_as, CredentialsSchema, EncryptedSigner, TryFromCBOR,
+        TryIntoCBOR,
+    };
     use blake2::Digest;
+    use hmac::Mac;
+    use serde::Serialize;
     use signature::DigestVerifier;
+    use std::sync::Arc;
+    use std::time::Duration;
     use tokio::net::UnixStream;
 
     #[tokio::test]
@@ -96,7 +952,12 @@ mod tests {
                 let data = b"text";
                 let sig = unwrap_as!(
                     client
-                        .try_sign_with(&res.encrypted_private_key, data, SigningVersion::Latest)
+                        .try_sign_with(
+                            &res.encrypted_private_key,
+                            data,
+                            SigningVersion::Latest,
+                            &SignOptions::default()
+                        )
                         .await
                         .unwrap(),
                     Signature::Secp256k1
@@ -126,11 +987,2261 @@ mod tests {
                 assert_eq!(
                     unwrap_as!(err, ClientError::RPC),
                     Error {
-                        message: "uninitialized".into(),
-                        source: None
+                        message: "uninitialized: Generate is not allowed before Initialize, \
+                                  allowed requests are Initialize, Describe, Verify, Hello, \
+                                  VerifyAggregate, InitializeWith, MuSig2Aggregate, VerifyBatch, \
+                                  Ping, GetInfo, Attest, NegotiateCompression, Subscribe (state machine version 13). \
+                                  Was the session re-initialized after reconnecting?"
+                            .into(),
+                        source: None,
+                        code: Some(ErrorCode::Uninitialized),
+                        trace_id: None,
                     }
                 );
             }
         );
     }
+
+    #[tokio::test]
+    async fn rpc_error_codes() {
+        let (srv_sock, client_sock) = UnixStream::pair().unwrap();
+        let mut server: Server<PassthroughFactory, EncryptedSigner<Passthrough>, rand_core::OsRng> =
+            Server::new(PassthroughFactory, rand_core::OsRng);
+
+        let mut client: Client<UnixStream, DummyCredentials> = Client::new(client_sock);
+
+        futures::join!(
+            async move {
+                server.serve_connection(srv_sock).await.unwrap();
+            },
+            async move {
+                client.initialize(DummyCredentials {}).await.unwrap();
+                let err = client.initialize(DummyCredentials {}).await.unwrap_err();
+                assert_eq!(err.code(), Some(ErrorCode::AlreadyInitialized));
+
+                let err = client
+                    .try_sign(
+                        KeyHandle::from(42),
+                        b"text",
+                        SigningVersion::Latest,
+                        &SignOptions::default(),
+                    )
+                    .await
+                    .unwrap_err();
+                let err = unwrap_as!(err, ClientError::RPC);
+                assert_eq!(err.code, Some(ErrorCode::InvalidHandle));
+                // only the outermost error carries the code
+                assert_eq!(err.source.unwrap().code, None);
+            }
+        );
+    }
+
+    #[derive(Debug)]
+    struct EchoAttestation;
+
+    impl AttestationSource for EchoAttestation {
+        fn attest(
+            &self,
+            nonce: &[u8],
+            user_data: &[u8],
+        ) -> std::result::Result<Vec<u8>, Box<dyn std::error::Error + Send + Sync>> {
+            if nonce.is_empty() {
+                return Err("nonce required".into());
+            }
+            Ok([nonce, user_data].concat())
+        }
+    }
+
+    #[tokio::test]
+    async fn rpc_attest() {
+        let (srv_sock, client_sock) = UnixStream::pair().unwrap();
+        let mut server: Server<PassthroughFactory, EncryptedSigner<Passthrough>, rand_core::OsRng> =
+            Server::new(PassthroughFactory, rand_core::OsRng)
+                .with_attestation_source(Arc::new(EchoAttestation));
+
+        let mut client: Client<UnixStream, DummyCredentials> = Client::new(client_sock);
+
+        futures::join!(
+            async move {
+                server.serve_connection(srv_sock).await.unwrap();
+            },
+            async move {
+                // available before initialization
+                let res = client.attest(b"nonce", b"data").await.unwrap();
+                assert_eq!(res.document, b"noncedata");
+                let err = client.attest(b"", b"data").await.unwrap_err();
+                assert_eq!(
+                    unwrap_as!(err, ClientError::RPC).to_string(),
+                    "attestation error: nonce required"
+                );
+            }
+        );
+
+        let (srv_sock, client_sock) = UnixStream::pair().unwrap();
+        let mut server: Server<PassthroughFactory, EncryptedSigner<Passthrough>, rand_core::OsRng> =
+            Server::new(PassthroughFactory, rand_core::OsRng);
+        let mut client: Client<UnixStream, DummyCredentials> = Client::new(client_sock);
+
+        futures::join!(
+            async move {
+                server.serve_connection(srv_sock).await.unwrap();
+            },
+            async move {
+                let err = client.attest(b"nonce", b"").await.unwrap_err();
+                assert_eq!(err.code(), Some(ErrorCode::Disabled));
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn rpc_reinitialize() {
+        let (srv_sock, client_sock) = UnixStream::pair().unwrap();
+        let mut server: Server<PassthroughFactory, EncryptedSigner<Passthrough>, rand_core::OsRng> =
+            Server::new(PassthroughFactory, rand_core::OsRng).with_reinitialize_token("secret");
+
+        let mut client: Client<UnixStream, DummyCredentials> = Client::new(client_sock);
+
+        futures::join!(
+            async move {
+                server.serve_connection(srv_sock).await.unwrap();
+            },
+            async move {
+                let err = client
+                    .reinitialize(DummyCredentials {}, Some("secret"))
+                    .await
+                    .unwrap_err();
+                assert_eq!(err.code(), Some(ErrorCode::Uninitialized));
+
+                client.initialize(DummyCredentials {}).await.unwrap();
+                let key = client.generate_and_import(KeyType::Ed25519).await.unwrap();
+                for token in [None, Some("wrong")] {
+                    let err = client
+                        .reinitialize(DummyCredentials {}, token)
+                        .await
+                        .unwrap_err();
+                    assert_eq!(err.code(), Some(ErrorCode::Unauthorized));
+                }
+                client
+                    .reinitialize(DummyCredentials {}, Some("secret"))
+                    .await
+                    .unwrap();
+
+                // the keys survive
+                let sig = client
+                    .try_sign(
+                        key.handle,
+                        b"text",
+                        SigningVersion::Latest,
+                        &SignOptions::default(),
+                    )
+                    .await
+                    .unwrap();
+                key.public_key.verify(b"text", &sig).unwrap();
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn rpc_authenticated() {
+        let (srv_sock, client_sock) = UnixStream::pair().unwrap();
+        let mut server: Server<PassthroughFactory, EncryptedSigner<Passthrough>, rand_core::OsRng> =
+            Server::new(PassthroughFactory, rand_core::OsRng).with_reinitialize_token("secret");
+        let mut client: Client<UnixStream, DummyCredentials> =
+            Client::new(client_sock).with_request_key(b"request key");
+
+        futures::join!(
+            async move {
+                server.serve_connection(srv_sock).await.unwrap();
+            },
+            async move {
+                client.initialize(DummyCredentials {}).await.unwrap();
+                let key = client.generate_and_import(KeyType::Ed25519).await.unwrap();
+                client
+                    .reinitialize(DummyCredentials {}, Some("secret"))
+                    .await
+                    .unwrap();
+                let sig = client
+                    .try_sign(
+                        key.handle,
+                        b"text",
+                        SigningVersion::Latest,
+                        &SignOptions::default(),
+                    )
+                    .await
+                    .unwrap();
+                key.public_key.verify(b"text", &sig).unwrap();
+            }
+        );
+
+        // a peer without the key
+        let (srv_sock, mut sock) = UnixStream::pair().unwrap();
+        let mut server: Server<PassthroughFactory, EncryptedSigner<Passthrough>, rand_core::OsRng> =
+            Server::new(PassthroughFactory, rand_core::OsRng);
+
+        futures::join!(
+            async move {
+                server.serve_connection(srv_sock).await.unwrap();
+            },
+            async move {
+                async fn exchange(
+                    sock: &mut UnixStream,
+                    req: &Request<VersionedCredentials<DummyCredentials>>,
+                ) -> Option<ErrorCode> {
+                    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+                    let mut buf = Vec::new();
+                    super::encode_frame(&mut buf, req).unwrap();
+                    sock.write_all(&buf).await.unwrap();
+                    let mut len = [0; 4];
+                    sock.read_exact(&mut len).await.unwrap();
+                    buf.resize(u32::from_be_bytes(len) as usize, 0);
+                    sock.read_exact(&mut buf).await.unwrap();
+                    super::Result::<ciborium::Value>::try_from_cbor(&buf)
+                        .unwrap()
+                        .err()
+                        .map(|err| err.code.unwrap())
+                }
+                let authenticated = |seq: u64, key: &[u8]| {
+                    let request = Request::<VersionedCredentials<DummyCredentials>>::Ping {
+                        payload: b"payload".to_vec(),
+                    }
+                    .try_into_cbor()
+                    .unwrap();
+                    let mac = super::request_mac(key, seq, &request)
+                        .finalize()
+                        .into_bytes()
+                        .to_vec();
+                    Request::Authenticated { seq, request, mac }
+                };
+
+                assert_eq!(
+                    exchange(&mut sock, &authenticated(1, b"request key")).await,
+                    Some(ErrorCode::InvalidRequest)
+                );
+                let mut cred = VersionedCredentials::new(DummyCredentials {});
+                cred.request_key = Some(super::RequestKey(b"request key".to_vec()));
+                assert_eq!(exchange(&mut sock, &Request::Initialize(cred)).await, None);
+
+                assert_eq!(
+                    exchange(
+                        &mut sock,
+                        &Request::Ping {
+                            payload: b"payload".to_vec()
+                        }
+                    )
+                    .await,
+                    Some(ErrorCode::Unauthorized)
+                );
+                assert_eq!(
+                    exchange(&mut sock, &authenticated(1, b"other key")).await,
+                    Some(ErrorCode::Unauthorized)
+                );
+                assert_eq!(
+                    exchange(&mut sock, &authenticated(1, b"request key")).await,
+                    None
+                );
+                // replay
+                assert_eq!(
+                    exchange(&mut sock, &authenticated(1, b"request key")).await,
+                    Some(ErrorCode::Unauthorized)
+                );
+            }
+        );
+
+        // the key set by one connection applies to all connections sharing the keychain
+        let keychain = SharedKeychain::new();
+        let serve = |sock: UnixStream| {
+            let mut server: Server<
+                PassthroughFactory,
+                EncryptedSigner<Passthrough>,
+                rand_core::OsRng,
+            > = Server::new(PassthroughFactory, rand_core::OsRng)
+                .with_shared_keychain(keychain.clone());
+            tokio::spawn(async move { server.serve_connection(sock).await.unwrap() })
+        };
+        let connect = |key: Option<&[u8]>| {
+            let (srv_sock, client_sock) = UnixStream::pair().unwrap();
+            serve(srv_sock);
+            let client = Client::<UnixStream, DummyCredentials>::new(client_sock);
+            match key {
+                Some(key) => client.with_request_key(key),
+                None => client,
+            }
+        };
+
+        let mut earlier = connect(None);
+        earlier.initialize(DummyCredentials {}).await.unwrap();
+        let mut owner = connect(Some(b"request key"));
+        owner.initialize(DummyCredentials {}).await.unwrap();
+        let key = owner.generate_and_import(KeyType::Ed25519).await.unwrap();
+
+        let err = earlier.public_key(key.handle).await.unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::Unauthorized));
+        for key in [None, Some(&b"other key"[..])] {
+            let mut client = connect(key);
+            let err = client.initialize(DummyCredentials {}).await.unwrap_err();
+            assert_eq!(err.code(), Some(ErrorCode::Unauthorized));
+        }
+        let mut client = connect(Some(b"request key"));
+        client.initialize(DummyCredentials {}).await.unwrap();
+        client.public_key(key.handle).await.unwrap();
+    }
+
+    #[test]
+    fn error_code() {
+        let err: crate::Error<CodedError> = crate::Error::Encryption(CodedError::new(
+            ErrorCode::KmsAccessDenied,
+            std::io::Error::other("access denied"),
+        ));
+        let err = Error::from(err);
+        assert_eq!(err.code, Some(ErrorCode::KmsAccessDenied));
+        assert_eq!(err.to_string(), "encryption error: access denied");
+        assert_eq!(
+            err.chain().map(|e| e.message.as_str()).collect::<Vec<_>>(),
+            ["encryption error", "access denied"]
+        );
+        assert_eq!(err.root_cause().message, "access denied");
+
+        assert_eq!(
+            Error::from(std::io::Error::other("io"))
+                .try_into_cbor()
+                .unwrap(),
+            Error {
+                message: "io".into(),
+                source: None,
+                code: None,
+                trace_id: None,
+            }
+            .try_into_cbor()
+            .unwrap()
+        );
+        // codes added by newer servers
+        #[derive(Serialize)]
+        struct NewerError {
+            message: String,
+            source: Option<Box<Error>>,
+            code: String,
+        }
+        let err = Error::try_from_cbor(
+            &NewerError {
+                message: "m".into(),
+                source: None,
+                code: "Future".into(),
+            }
+            .try_into_cbor()
+            .unwrap(),
+        )
+        .unwrap();
+        assert_eq!(err.code, Some(ErrorCode::Unknown));
+    }
+
+    #[derive(Serialize)]
+    struct NewerCredentials<const V: u32> {
+        extra: String,
+    }
+
+    impl<const V: u32> CredentialsSchema for NewerCredentials<V> {
+        const VERSION: u32 = V;
+    }
+
+    #[test]
+    fn versioned_credentials() {
+        let src = VersionedCredentials::new(NewerCredentials::<2> {
+            extra: "value".into(),
+        })
+        .try_into_cbor()
+        .unwrap();
+        let cred = VersionedCredentials::<DummyCredentials>::try_from_cbor(&src).unwrap();
+        assert_eq!(cred.version, 2);
+        assert_eq!(
+            cred.unknown.get("extra"),
+            Some(&ciborium::Value::Text("value".into()))
+        );
+        // unknown fields survive re-encoding
+        let cred =
+            VersionedCredentials::<DummyCredentials>::try_from_cbor(&cred.try_into_cbor().unwrap())
+                .unwrap();
+        assert_eq!(cred.unknown.len(), 1);
+
+        // hosts predating versioning, an empty map
+        let cred = VersionedCredentials::<DummyCredentials>::try_from_cbor(&[0xa0]).unwrap();
+        assert_eq!(cred.version, 0);
+        assert!(cred.unknown.is_empty());
+
+        let cred = VersionedCredentials::<()>::try_from_cbor(
+            &VersionedCredentials::new(()).try_into_cbor().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(cred.version, 0);
+    }
+
+    #[tokio::test]
+    async fn rpc_credentials_version() {
+        let (srv_sock, client_sock) = UnixStream::pair().unwrap();
+        let mut server: Server<PassthroughFactory, EncryptedSigner<Passthrough>, rand_core::OsRng> =
+            Server::new(PassthroughFactory, rand_core::OsRng);
+
+        let mut newer: Client<UnixStream, NewerCredentials<2>> = Client::new(client_sock);
+
+        futures::join!(
+            async move {
+                server.serve_connection(srv_sock).await.unwrap();
+            },
+            async move {
+                let err = newer
+                    .initialize(NewerCredentials {
+                        extra: "value".into(),
+                    })
+                    .await
+                    .unwrap_err();
+                assert_eq!(
+                    unwrap_as!(err, ClientError::RPC).message,
+                    "credentials version too new: 2, the signer supports up to 1"
+                );
+            }
+        );
+
+        let (srv_sock, client_sock) = UnixStream::pair().unwrap();
+        let mut server: Server<PassthroughFactory, EncryptedSigner<Passthrough>, rand_core::OsRng> =
+            Server::new(PassthroughFactory, rand_core::OsRng);
+
+        // fields unknown to the signer are ignored
+        let mut same: Client<UnixStream, NewerCredentials<1>> = Client::new(client_sock);
+
+        futures::join!(
+            async move {
+                server.serve_connection(srv_sock).await.unwrap();
+            },
+            async move {
+                same.initialize(NewerCredentials {
+                    extra: "value".into(),
+                })
+                .await
+                .unwrap();
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn rpc_hello() {
+        let (srv_sock, client_sock) = UnixStream::pair().unwrap();
+        let mut server: Server<PassthroughFactory, EncryptedSigner<Passthrough>, rand_core::OsRng> =
+            Server::new(PassthroughFactory, rand_core::OsRng).with_insecure_options(
+                InsecureOptions {
+                    passthrough_backend: true,
+                    ..Default::default()
+                },
+            );
+
+        let mut client: Client<UnixStream, DummyCredentials> = Client::new(client_sock);
+
+        futures::join!(
+            async move {
+                server.serve_connection(srv_sock).await.unwrap();
+            },
+            async move {
+                // available before initialization
+                let hello = client.hello().await.unwrap();
+                assert_eq!(hello.state_machine_version, STATE_MACHINE_VERSION);
+                assert_eq!(
+                    hello.insecure,
+                    InsecureOptions {
+                        passthrough_backend: true,
+                        import_unencrypted: false,
+                        export_wrapped: false,
+                        rewrap_key_id: false,
+                        debug: false
+                    }
+                );
+                assert!(!hello.insecure.is_secure());
+                assert_eq!(hello.allowed_requests, None);
+
+                let pong = client.ping(b"payload").await.unwrap();
+                assert_eq!(pong.payload, b"payload");
+                assert_eq!(pong.uptime, 0);
+
+                let info = client.get_info().await.unwrap();
+                assert_eq!(info.state_machine_version, STATE_MACHINE_VERSION);
+                assert_eq!(info.key_types, KeyType::ALL);
+                assert!(!info.initialized);
+                assert_eq!(info.keys, 0);
+
+                client.initialize(DummyCredentials {}).await.unwrap();
+                let generated = client.generate_and_import(KeyType::Ed25519).await.unwrap();
+                let info = client.get_info().await.unwrap();
+                assert!(info.initialized);
+                assert_eq!(info.keys, 1);
+                let key = PrivateKey::generate(KeyType::Ed25519, &mut rand_core::OsRng).unwrap();
+                let err = client.import_unencrypted(&key).await.unwrap_err();
+                assert_eq!(
+                    unwrap_as!(err, ClientError::RPC).message,
+                    "ImportUnencrypted is disabled"
+                );
+                let err = client
+                    .import_mnemonic(KeyType::Ed25519, "abandon abandon about", "")
+                    .await
+                    .unwrap_err();
+                assert_eq!(
+                    unwrap_as!(err, ClientError::RPC).message,
+                    "ImportMnemonic is disabled"
+                );
+                let err = client
+                    .import_wrapped(generated.handle, b"wrapped key")
+                    .await
+                    .unwrap_err();
+                assert_eq!(
+                    unwrap_as!(err, ClientError::RPC).message,
+                    "ImportWrapped is disabled"
+                );
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn rpc_allowed_requests() {
+        let (srv_sock, client_sock) = UnixStream::pair().unwrap();
+        let mut server: Server<PassthroughFactory, EncryptedSigner<Passthrough>, rand_core::OsRng> =
+            Server::new(PassthroughFactory, rand_core::OsRng).with_allowed_requests(&[
+                "Initialize",
+                "Hello",
+                "Import",
+                "Sign",
+            ]);
+
+        let mut client: Client<UnixStream, DummyCredentials> = Client::new(client_sock);
+
+        futures::join!(
+            async move {
+                server.serve_connection(srv_sock).await.unwrap();
+            },
+            async move {
+                let hello = client.hello().await.unwrap();
+                assert_eq!(
+                    hello.allowed_requests.unwrap(),
+                    ["Initialize", "Hello", "Import", "Sign"]
+                );
+                // rejected before the state check
+                let err = client.generate(KeyType::Ed25519).await.unwrap_err();
+                assert_eq!(
+                    unwrap_as!(err, ClientError::RPC).message,
+                    "Generate is disabled"
+                );
+
+                client.initialize(DummyCredentials {}).await.unwrap();
+                let key = PrivateKey::generate(KeyType::Ed25519, &mut rand_core::OsRng).unwrap();
+                let res = client.import(&key.try_into_cbor().unwrap()).await.unwrap();
+                client
+                    .try_sign(
+                        res.handle,
+                        b"text",
+                        SigningVersion::Latest,
+                        &SignOptions::default(),
+                    )
+                    .await
+                    .unwrap();
+                let err = client.import_unencrypted(&key).await.unwrap_err();
+                assert_eq!(
+                    unwrap_as!(err, ClientError::RPC).message,
+                    "ImportUnencrypted is disabled"
+                );
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn rpc_initialize_with() {
+        let (srv_sock, client_sock) = UnixStream::pair().unwrap();
+        let mut server: Server<PassthroughFactory, EncryptedSigner<Passthrough>, rand_core::OsRng> =
+            Server::new(PassthroughFactory, rand_core::OsRng);
+
+        let mut client: Client<UnixStream, DummyCredentials> = Client::new(client_sock);
+
+        futures::join!(
+            async move {
+                server.serve_connection(srv_sock).await.unwrap();
+            },
+            async move {
+                let keys = [
+                    PrivateKey::generate(KeyType::Ed25519, &mut rand_core::OsRng).unwrap(),
+                    PrivateKey::generate(KeyType::Secp256k1, &mut rand_core::OsRng).unwrap(),
+                ];
+                // the passthrough backend "encrypts" into CBOR
+                let mut blobs: Vec<Vec<u8>> =
+                    keys.iter().map(|k| k.try_into_cbor().unwrap()).collect();
+                blobs.push(b"garbage".to_vec());
+
+                // all or nothing
+                client
+                    .initialize_with(DummyCredentials {}, &blobs)
+                    .await
+                    .unwrap_err();
+                let err = client.generate(KeyType::Secp256k1).await.unwrap_err();
+                assert!(unwrap_as!(err, ClientError::RPC)
+                    .message
+                    .starts_with("uninitialized"));
+
+                let res = client
+                    .initialize_with(DummyCredentials {}, &blobs[..2])
+                    .await
+                    .unwrap();
+                assert_eq!(res.len(), 2);
+                for (i, (res, key)) in res.iter().zip(keys.iter()).enumerate() {
+                    assert_eq!(u64::from(res.handle), i as u64);
+                    assert_eq!(
+                        res.public_key.try_into_cbor().unwrap(),
+                        key.public_key().try_into_cbor().unwrap()
+                    );
+                }
+                client
+                    .try_sign(
+                        res[1].handle,
+                        b"text",
+                        SigningVersion::Latest,
+                        &SignOptions::default(),
+                    )
+                    .await
+                    .unwrap();
+                let err = client
+                    .initialize_with(DummyCredentials {}, &[])
+                    .await
+                    .unwrap_err();
+                assert_eq!(
+                    unwrap_as!(err, ClientError::RPC).message,
+                    "already initialized"
+                );
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn rpc_musig2() {
+        let (srv_sock, client_sock) = UnixStream::pair().unwrap();
+        let mut server: Server<PassthroughFactory, EncryptedSigner<Passthrough>, rand_core::OsRng> =
+            Server::new(PassthroughFactory, rand_core::OsRng);
+
+        let mut client: Client<UnixStream, DummyCredentials> = Client::new(client_sock);
+
+        futures::join!(
+            async move {
+                server.serve_connection(srv_sock).await.unwrap();
+            },
+            async move {
+                client.initialize(DummyCredentials {}).await.unwrap();
+                let mut public_keys = Vec::new();
+                for _ in 0..2 {
+                    let res = client
+                        .generate_and_import(KeyType::Secp256k1)
+                        .await
+                        .unwrap();
+                    public_keys.push(res.public_key);
+                }
+                let msg = b"text";
+
+                let mut sessions = Vec::new();
+                for handle in 0..2u64 {
+                    sessions.push(
+                        client
+                            .musig2_nonce(handle.into(), &public_keys, msg)
+                            .await
+                            .unwrap(),
+                    );
+                }
+                assert_eq!(
+                    sessions[0].aggregate_public_key,
+                    sessions[1].aggregate_public_key
+                );
+                let nonces: Vec<Vec<u8>> = sessions.iter().map(|s| s.pub_nonce.clone()).collect();
+                let mut psigs = Vec::new();
+                for s in &sessions {
+                    psigs.push(
+                        client
+                            .musig2_sign(s.session, &nonces)
+                            .await
+                            .unwrap()
+                            .partial_signature,
+                    );
+                }
+                // single use
+                let err = client
+                    .musig2_sign(sessions[0].session, &nonces)
+                    .await
+                    .unwrap_err();
+                assert!(unwrap_as!(err, ClientError::RPC)
+                    .to_string()
+                    .ends_with("Unknown or already used MuSig2 session"));
+
+                let res = client
+                    .musig2_aggregate(&public_keys, msg, &nonces, &psigs)
+                    .await
+                    .unwrap();
+                assert_eq!(res.aggregate_public_key, sessions[0].aggregate_public_key);
+                k256::schnorr::VerifyingKey::from_bytes(&res.aggregate_public_key)
+                    .unwrap()
+                    .verify_raw(
+                        msg,
+                        &k256::schnorr::Signature::try_from(&res.signature[..]).unwrap(),
+                    )
+                    .unwrap();
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn rpc_tweak_key() {
+        let (srv_sock, client_sock) = UnixStream::pair().unwrap();
+        let mut server: Server<PassthroughFactory, EncryptedSigner<Passthrough>, rand_core::OsRng> =
+            Server::new(PassthroughFactory, rand_core::OsRng);
+
+        let mut client: Client<UnixStream, DummyCredentials> = Client::new(client_sock);
+
+        futures::join!(
+            async move {
+                server.serve_connection(srv_sock).await.unwrap();
+            },
+            async move {
+                client.initialize(DummyCredentials {}).await.unwrap();
+                let internal = client
+                    .generate_and_import(KeyType::Secp256k1)
+                    .await
+                    .unwrap();
+                let res = client.tweak_key(internal.handle, &[]).await.unwrap();
+                assert!(matches!(res.public_key, PublicKey::Secp256k1(_)));
+                assert_ne!(
+                    res.public_key.try_into_cbor().unwrap(),
+                    internal.public_key.try_into_cbor().unwrap()
+                );
+                // the tweaked key is usable
+                let imported = client.import(&res.encrypted_private_key).await.unwrap();
+                client
+                    .try_sign(
+                        imported.handle,
+                        b"text",
+                        SigningVersion::Latest,
+                        &SignOptions::default(),
+                    )
+                    .await
+                    .unwrap();
+
+                let err = client
+                    .tweak_key(internal.handle, &[0; 16])
+                    .await
+                    .unwrap_err();
+                assert!(unwrap_as!(err, ClientError::RPC)
+                    .to_string()
+                    .ends_with("Invalid Taproot tweak"));
+
+                let ed = client.generate_and_import(KeyType::Ed25519).await.unwrap();
+                let err = client.tweak_key(ed.handle, &[]).await.unwrap_err();
+                assert!(unwrap_as!(err, ClientError::RPC)
+                    .to_string()
+                    .ends_with("Taproot tweaking is supported by Secp256k1 keys only"));
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn rpc_verify_batch() {
+        let (srv_sock, client_sock) = UnixStream::pair().unwrap();
+        let mut server: Server<PassthroughFactory, EncryptedSigner<Passthrough>, rand_core::OsRng> =
+            Server::new(PassthroughFactory, rand_core::OsRng);
+
+        let mut client: Client<UnixStream, DummyCredentials> = Client::new(client_sock);
+
+        let keys: Vec<PrivateKey> = (0..3)
+            .map(|_| PrivateKey::generate(KeyType::Ed25519, &mut rand_core::OsRng).unwrap())
+            .collect();
+        let pks: Vec<PublicKey> = keys.iter().map(PrivateKey::public_key).collect();
+        let msgs: Vec<Vec<u8>> = vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()];
+        let sigs: Vec<Signature> = keys
+            .iter()
+            .zip(&msgs)
+            .map(|(k, m)| k.try_sign(m, SigningVersion::Latest).unwrap())
+            .collect();
+
+        futures::join!(
+            async move {
+                server.serve_connection(srv_sock).await.unwrap();
+            },
+            async move {
+                // accepted before Initialize
+                assert!(client.verify_batch(&pks, &msgs, &sigs).await.unwrap());
+                let mut sigs = sigs;
+                sigs.reverse();
+                assert!(!client.verify_batch(&pks, &msgs, &sigs).await.unwrap());
+
+                let err = client
+                    .verify_batch(&pks[1..], &msgs, &sigs)
+                    .await
+                    .unwrap_err();
+                assert!(unwrap_as!(err, ClientError::RPC)
+                    .to_string()
+                    .ends_with("The numbers of keys, messages and signatures don't match"));
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn rpc_generate_from_seed() {
+        let (srv_sock, client_sock) = UnixStream::pair().unwrap();
+        let mut server: Server<PassthroughFactory, EncryptedSigner<Passthrough>, rand_core::OsRng> =
+            Server::new(PassthroughFactory, rand_core::OsRng);
+
+        let mut client: Client<UnixStream, DummyCredentials> = Client::new(client_sock);
+
+        futures::join!(
+            async move {
+                server.serve_connection(srv_sock).await.unwrap();
+            },
+            async move {
+                client.initialize(DummyCredentials {}).await.unwrap();
+                let seed = client.generate_seed().await.unwrap().encrypted_seed;
+                let a = client
+                    .generate_from_seed(KeyType::Secp256k1, &seed, b"wallet/0")
+                    .await
+                    .unwrap();
+                let b = client
+                    .generate_from_seed(KeyType::Secp256k1, &seed, b"wallet/0")
+                    .await
+                    .unwrap();
+                assert_eq!(a.fingerprint, b.fingerprint);
+                let c = client
+                    .generate_from_seed(KeyType::Secp256k1, &seed, b"wallet/1")
+                    .await
+                    .unwrap();
+                assert_ne!(a.fingerprint, c.fingerprint);
+
+                let err = client
+                    .generate_from_seed(KeyType::Secp256k1, &seed[1..], b"wallet/0")
+                    .await
+                    .unwrap_err();
+                assert!(unwrap_as!(err, ClientError::RPC)
+                    .to_string()
+                    .ends_with("Invalid master seed"));
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn rpc_import_raw() {
+        let (srv_sock, client_sock) = UnixStream::pair().unwrap();
+        let mut server: Server<PassthroughFactory, EncryptedSigner<Passthrough>, rand_core::OsRng> =
+            Server::new(PassthroughFactory, rand_core::OsRng).with_insecure_options(
+                InsecureOptions {
+                    import_unencrypted: true,
+                    ..Default::default()
+                },
+            );
+
+        let mut client: Client<UnixStream, DummyCredentials> = Client::new(client_sock);
+
+        futures::join!(
+            async move {
+                server.serve_connection(srv_sock).await.unwrap();
+            },
+            async move {
+                client.initialize(DummyCredentials {}).await.unwrap();
+                let secret = [7u8; 32];
+                let res = client.import_raw(KeyType::Ed25519, &secret).await.unwrap();
+                let expected = PrivateKey::from_raw(KeyType::Ed25519, &secret).unwrap();
+                assert_eq!(
+                    res.fingerprint,
+                    expected.public_key().fingerprint().unwrap()
+                );
+
+                let err = client
+                    .import_raw(KeyType::Secp256k1, &secret[1..])
+                    .await
+                    .unwrap_err();
+                assert!(unwrap_as!(err, ClientError::RPC)
+                    .to_string()
+                    .ends_with("Invalid raw private key size: 31"));
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn rpc_ecies_decrypt() {
+        let (srv_sock, client_sock) = UnixStream::pair().unwrap();
+        let mut server: Server<PassthroughFactory, EncryptedSigner<Passthrough>, rand_core::OsRng> =
+            Server::new(PassthroughFactory, rand_core::OsRng);
+
+        let mut client: Client<UnixStream, DummyCredentials> = Client::new(client_sock);
+
+        futures::join!(
+            async move {
+                server.serve_connection(srv_sock).await.unwrap();
+            },
+            async move {
+                client.initialize(DummyCredentials {}).await.unwrap();
+                let key = client.generate_and_import(KeyType::NistP256).await.unwrap();
+                let ct = key
+                    .public_key
+                    .ecies_encrypt(b"text", &mut rand_core::OsRng)
+                    .unwrap();
+                let res = client.ecies_decrypt(key.handle, &ct).await.unwrap();
+                assert_eq!(res.plaintext, b"text");
+
+                let err = client
+                    .ecies_decrypt(key.handle, &ct[..ct.len() - 1])
+                    .await
+                    .unwrap_err();
+                assert!(unwrap_as!(err, ClientError::RPC)
+                    .to_string()
+                    .ends_with("Decryption failed"));
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn rpc_export_wrapped() {
+        let (srv_sock, client_sock) = UnixStream::pair().unwrap();
+        let mut server: Server<PassthroughFactory, EncryptedSigner<Passthrough>, rand_core::OsRng> =
+            Server::new(PassthroughFactory, rand_core::OsRng).with_insecure_options(
+                InsecureOptions {
+                    export_wrapped: true,
+                    ..Default::default()
+                },
+            );
+        let mut client: Client<UnixStream, DummyCredentials> = Client::new(client_sock);
+
+        // recipient signer accepting the keys of unauthenticated senders
+        let (dst_srv_sock, dst_client_sock) = UnixStream::pair().unwrap();
+        let mut dst_server: Server<
+            PassthroughFactory,
+            EncryptedSigner<Passthrough>,
+            rand_core::OsRng,
+        > = Server::new(PassthroughFactory, rand_core::OsRng).with_insecure_options(
+            InsecureOptions {
+                import_unencrypted: true,
+                ..Default::default()
+            },
+        );
+        // and a secure one
+        let (secure_srv_sock, secure_client_sock) = UnixStream::pair().unwrap();
+        let mut secure_server: Server<
+            PassthroughFactory,
+            EncryptedSigner<Passthrough>,
+            rand_core::OsRng,
+        > = Server::new(PassthroughFactory, rand_core::OsRng)
+            .with_insecure_options(InsecureOptions::default());
+        let mut secure: Client<UnixStream, DummyCredentials> = Client::new(secure_client_sock);
+        let mut dst: Client<UnixStream, DummyCredentials> = Client::new(dst_client_sock);
+
+        futures::join!(
+            async move {
+                server.serve_connection(srv_sock).await.unwrap();
+            },
+            async move {
+                dst_server.serve_connection(dst_srv_sock).await.unwrap();
+            },
+            async move {
+                secure_server
+                    .serve_connection(secure_srv_sock)
+                    .await
+                    .unwrap();
+            },
+            async move {
+                client.initialize(DummyCredentials {}).await.unwrap();
+                dst.initialize(DummyCredentials {}).await.unwrap();
+                secure.initialize(DummyCredentials {}).await.unwrap();
+
+                let key = client
+                    .generate_and_import(KeyType::Secp256k1)
+                    .await
+                    .unwrap();
+                let recipient = dst.generate_and_import(KeyType::NistP256).await.unwrap();
+                let exported = client
+                    .export_wrapped(
+                        key.handle,
+                        &WrappingKey::Ecies(recipient.public_key.clone()),
+                    )
+                    .await
+                    .unwrap();
+                let imported = dst
+                    .import_wrapped(recipient.handle, &exported.wrapped_key)
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    imported.public_key.try_into_cbor().unwrap(),
+                    key.public_key.try_into_cbor().unwrap()
+                );
+                // the wrapped key isn't a plain ECIES ciphertext
+                let other = dst.generate_and_import(KeyType::NistP256).await.unwrap();
+                let exported = client
+                    .export_wrapped(key.handle, &WrappingKey::Ecies(other.public_key))
+                    .await
+                    .unwrap();
+                let err = dst
+                    .ecies_decrypt(other.handle, &exported.wrapped_key)
+                    .await
+                    .unwrap_err();
+                assert_eq!(
+                    unwrap_as!(err, ClientError::RPC).root_cause().message,
+                    "Decryption failed"
+                );
+                // and the recipient key is reserved for unwrapping
+                let ct = recipient
+                    .public_key
+                    .ecies_encrypt(b"text", &mut rand_core::OsRng)
+                    .unwrap();
+                let err = dst.ecies_decrypt(recipient.handle, &ct).await.unwrap_err();
+                assert_eq!(
+                    unwrap_as!(err, ClientError::RPC).root_cause().message,
+                    "The handle refers to a key used for unwrapping migrated keys"
+                );
+
+                let recipient = secure.generate_and_import(KeyType::NistP256).await.unwrap();
+                let exported = client
+                    .export_wrapped(key.handle, &WrappingKey::Ecies(recipient.public_key))
+                    .await
+                    .unwrap();
+                let err = secure
+                    .import_wrapped(recipient.handle, &exported.wrapped_key)
+                    .await
+                    .unwrap_err();
+                assert_eq!(err.code(), Some(ErrorCode::Disabled));
+
+                let err = dst
+                    .export_wrapped(imported.handle, &WrappingKey::Ecies(key.public_key))
+                    .await
+                    .unwrap_err();
+                assert_eq!(
+                    unwrap_as!(err, ClientError::RPC).message,
+                    "ExportWrapped is disabled"
+                );
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn rpc_data_key() {
+        let (srv_sock, client_sock) = UnixStream::pair().unwrap();
+        let mut server: Server<PassthroughFactory, EncryptedSigner<Passthrough>, rand_core::OsRng> =
+            Server::new(PassthroughFactory, rand_core::OsRng);
+
+        let mut client: Client<UnixStream, DummyCredentials> = Client::new(client_sock);
+
+        futures::join!(
+            async move {
+                server.serve_connection(srv_sock).await.unwrap();
+            },
+            async move {
+                client.initialize(DummyCredentials {}).await.unwrap();
+                let key = client.generate_data_key().await.unwrap();
+                let ct = client
+                    .encrypt_data(key.handle, b"text", b"aad")
+                    .await
+                    .unwrap()
+                    .ciphertext;
+
+                // the stored blob restores the same key
+                let imported = client.import_data_key(&key.encrypted_key).await.unwrap();
+                assert_ne!(imported.handle, key.handle);
+                let res = client
+                    .decrypt_data(imported.handle, &ct, b"aad")
+                    .await
+                    .unwrap();
+                assert_eq!(res.plaintext, b"text");
+
+                let err = client.decrypt_data(key.handle, &ct, b"").await.unwrap_err();
+                assert!(unwrap_as!(err, ClientError::RPC)
+                    .to_string()
+                    .ends_with("Decryption failed"));
+
+                // data keys can't sign and signing keys can't encrypt
+                let err = client
+                    .try_sign(
+                        key.handle,
+                        b"text",
+                        SigningVersion::Latest,
+                        &SignOptions::default(),
+                    )
+                    .await
+                    .unwrap_err();
+                assert!(unwrap_as!(err, ClientError::RPC)
+                    .to_string()
+                    .ends_with("The handle refers to a data key"));
+                let signing = client.generate_and_import(KeyType::Ed25519).await.unwrap();
+                let err = client
+                    .encrypt_data(signing.handle, b"text", b"")
+                    .await
+                    .unwrap_err();
+                assert!(unwrap_as!(err, ClientError::RPC)
+                    .to_string()
+                    .ends_with("The handle doesn't refer to a data key"));
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn rpc_rewrap() {
+        let (srv_sock, client_sock) = UnixStream::pair().unwrap();
+        let mut server: Server<PassthroughFactory, EncryptedSigner<Passthrough>, rand_core::OsRng> =
+            Server::new(PassthroughFactory, rand_core::OsRng);
+        let mut client: Client<UnixStream, DummyCredentials> = Client::new(client_sock);
+
+        futures::join!(
+            async move {
+                server.serve_connection(srv_sock).await.unwrap();
+            },
+            async move {
+                client.initialize(DummyCredentials {}).await.unwrap();
+                let key = client.generate(KeyType::Secp256k1).await.unwrap();
+                let rewrapped = client
+                    .rewrap(&key.encrypted_private_key, None)
+                    .await
+                    .unwrap();
+                let imported = client.import(&rewrapped.key_data).await.unwrap();
+                assert_eq!(imported.fingerprint, key.fingerprint);
+
+                let err = client
+                    .rewrap(&key.encrypted_private_key, Some("alias/rotated"))
+                    .await
+                    .unwrap_err();
+                assert!(unwrap_as!(err, ClientError::RPC)
+                    .to_string()
+                    .ends_with("Rewrap with key_id is disabled"));
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn rpc_shared_keychain() {
+        let keychain = SharedKeychain::new();
+        let mut clients = Vec::new();
+        for _ in 0..2 {
+            let (srv_sock, client_sock) = UnixStream::pair().unwrap();
+            let mut server: Server<
+                PassthroughFactory,
+                EncryptedSigner<Passthrough>,
+                rand_core::OsRng,
+            > = Server::new(PassthroughFactory, rand_core::OsRng)
+                .with_shared_keychain(keychain.clone());
+            tokio::spawn(async move { server.serve_connection(srv_sock).await.unwrap() });
+
+            let mut client: Client<UnixStream, DummyCredentials> = Client::new(client_sock);
+            client.initialize(DummyCredentials {}).await.unwrap();
+            clients.push(client);
+        }
+
+        let res = clients[0]
+            .generate_and_import(KeyType::Secp256k1)
+            .await
+            .unwrap();
+        let sig = clients[1]
+            .try_sign(
+                res.handle,
+                b"text",
+                SigningVersion::Latest,
+                &SignOptions::default(),
+            )
+            .await
+            .unwrap();
+        assert!(clients[0]
+            .verify(&res.public_key, b"text", &sig, SigningVersion::Latest)
+            .await
+            .unwrap());
+        let stats = clients[0].key_stats().await.unwrap();
+        assert_eq!((stats[0].handle, stats[0].signatures), (res.handle, 1));
+
+        // the trusted key imported by one connection is found by the other
+        let trusted = PrivateKey::generate(KeyType::Ed25519, &mut rand_core::OsRng)
+            .unwrap()
+            .public_key();
+        let imported = clients[0].import_public_key(&trusted).await.unwrap();
+        let info = clients[1].find_public_key(&trusted).await.unwrap().unwrap();
+        assert_eq!((info.handle, info.kind), (imported.handle, KeyKind::Public));
+        let info = clients[1]
+            .find_public_key(&res.public_key)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!((info.handle, info.kind), (res.handle, KeyKind::Private));
+        clients[1].remove(imported.handle).await.unwrap();
+        assert!(clients[0]
+            .find_public_key(&trusted)
+            .await
+            .unwrap()
+            .is_none());
+
+        assert!(clients[1].hello().await.unwrap().shared_keychain);
+        clients[1].remove(res.handle).await.unwrap();
+        assert!(clients[0].public_key(res.handle).await.is_err());
+        assert!(keychain.read().list().is_empty());
+    }
+
+    #[tokio::test]
+    async fn rpc_sessions() {
+        let namespaces = KeychainNamespaces::new();
+        let serve = |sock: UnixStream| {
+            let mut server: Server<
+                PassthroughFactory,
+                EncryptedSigner<Passthrough>,
+                rand_core::OsRng,
+            > = Server::new(PassthroughFactory, rand_core::OsRng)
+                .with_session_keychains(namespaces.clone());
+            tokio::spawn(async move { server.serve_connection(sock).await.unwrap() });
+        };
+
+        let (srv_sock, client_sock) = UnixStream::pair().unwrap();
+        serve(srv_sock);
+        let mut client: Client<UnixStream, DummyCredentials> =
+            Client::new(client_sock).with_request_key(b"request key");
+        client.initialize(DummyCredentials {}).await.unwrap();
+        let own = client.generate_and_import(KeyType::Ed25519).await.unwrap();
+
+        // each session has its own keys and sequence of authenticated requests
+        let mut keys = Vec::new();
+        for name in ["a", "b"] {
+            let mut session = client.session(name);
+            let err = session.list_keys().await.unwrap_err();
+            assert_eq!(err.code(), Some(ErrorCode::Uninitialized));
+            session.initialize(DummyCredentials {}).await.unwrap();
+            keys.push(session.generate_and_import(KeyType::Ed25519).await.unwrap());
+            assert!(session.hello().await.unwrap().shared_keychain);
+        }
+        for (name, key) in [("a", &keys[0]), ("b", &keys[1])] {
+            let mut session = client.session(name);
+            let list = session.list_keys().await.unwrap();
+            assert_eq!(list.len(), 1);
+            assert_eq!(list[0].handle, key.handle);
+        }
+        let list = client.list_keys().await.unwrap();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].handle, own.handle);
+        assert!(!client.hello().await.unwrap().shared_keychain);
+
+        // named sessions share the keychain of the namespace across connections
+        let (srv_sock, client_sock) = UnixStream::pair().unwrap();
+        serve(srv_sock);
+        let mut other: Client<UnixStream, DummyCredentials> =
+            Client::new(client_sock).with_request_key(b"request key");
+        let mut session = other.session("a");
+        session.initialize(DummyCredentials {}).await.unwrap();
+        let sig = session
+            .try_sign(
+                keys[0].handle,
+                b"text",
+                SigningVersion::Latest,
+                &SignOptions::default(),
+            )
+            .await
+            .unwrap();
+        keys[0].public_key.verify(b"text", &sig).unwrap();
+        drop(session);
+        assert_eq!(namespaces.get("b").read().list().len(), 1);
+
+        // invalid names and Session wrapped into other envelopes
+        let (srv_sock, mut sock) = UnixStream::pair().unwrap();
+        serve(srv_sock);
+        let session = |name: &str, request| Request::Session {
+            name: name.into(),
+            request: Box::new(request),
+        };
+        for req in [
+            session("", Request::Hello),
+            session("tenant a", Request::Hello),
+            session(&"a".repeat(129), Request::Hello),
+            session("a", session("b", Request::Hello)),
+        ] {
+            send_raw(&mut sock, req).await;
+            let err = recv_raw::<super::Result<ciborium::Value>>(&mut sock)
+                .await
+                .unwrap_err();
+            assert_eq!(err.code, Some(ErrorCode::InvalidRequest));
+        }
+        let tagged = Request::Tagged {
+            id: 1,
+            request: Box::new(session("a", Request::Hello)),
+        };
+        send_raw(&mut sock, tagged).await;
+        let super::TaggedResponse::Tagged { response, .. } =
+            recv_raw::<super::TaggedResponse<ciborium::Value>>(&mut sock).await;
+        assert_eq!(response.unwrap_err().code, Some(ErrorCode::InvalidRequest));
+        // "a" entered by the requests above
+        for n in 1..64 {
+            send_raw(&mut sock, session(&n.to_string(), Request::Hello)).await;
+            recv_raw::<super::Result<ciborium::Value>>(&mut sock)
+                .await
+                .unwrap();
+        }
+        send_raw(&mut sock, session("64", Request::Hello)).await;
+        let err = recv_raw::<super::Result<ciborium::Value>>(&mut sock)
+            .await
+            .unwrap_err();
+        assert_eq!(err.code, Some(ErrorCode::InvalidRequest));
+    }
+
+    #[tokio::test]
+    async fn rpc_shutdown() {
+        let keychain = SharedKeychain::new();
+        let serve = |sock: UnixStream, keychain: &SharedKeychain| {
+            let mut server: Server<
+                PassthroughFactory,
+                EncryptedSigner<Passthrough>,
+                rand_core::OsRng,
+            > = Server::new(PassthroughFactory, rand_core::OsRng)
+                .with_shared_keychain(keychain.clone())
+                .with_shutdown_token("secret");
+            tokio::spawn(async move {
+                server.serve_connection(sock).await.unwrap();
+                server
+            })
+        };
+
+        // rejected before Initialize, the connection is still served
+        let (srv_sock, client_sock) = UnixStream::pair().unwrap();
+        let task = serve(srv_sock, &keychain);
+        let mut client: Client<UnixStream, DummyCredentials> = Client::new(client_sock);
+        let err = client.shutdown(None).await.unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::Uninitialized));
+        client.ping(b"").await.unwrap();
+        drop(client);
+        assert!(!task.await.unwrap().is_shut_down());
+
+        // rejected unless authenticated once the session has the request key
+        let (srv_sock, mut sock) = UnixStream::pair().unwrap();
+        let task = serve(srv_sock, &SharedKeychain::new());
+        let mut cred = VersionedCredentials::new(DummyCredentials {});
+        cred.request_key = Some(super::RequestKey(b"request key".to_vec()));
+        send_raw(&mut sock, Request::Initialize(cred)).await;
+        recv_raw::<super::Result<()>>(&mut sock).await.unwrap();
+        send_raw(&mut sock, Request::Shutdown { token: None }).await;
+        let err = recv_raw::<super::Result<()>>(&mut sock).await.unwrap_err();
+        assert_eq!(err.code, Some(ErrorCode::Unauthorized));
+        send_raw(&mut sock, Request::Hello).await;
+        let err = recv_raw::<super::Result<()>>(&mut sock).await.unwrap_err();
+        assert_eq!(err.code, Some(ErrorCode::Unauthorized));
+        drop(sock);
+        assert!(!task.await.unwrap().is_shut_down());
+
+        // without the token only the connection is closed, the shared keys stay
+        let (srv_sock, client_sock) = UnixStream::pair().unwrap();
+        let task = serve(srv_sock, &keychain);
+        let mut client: Client<UnixStream, DummyCredentials> = Client::new(client_sock);
+        client.initialize(DummyCredentials {}).await.unwrap();
+        client.generate_and_import(KeyType::Ed25519).await.unwrap();
+        client.shutdown(None).await.unwrap();
+        let server = task.await.unwrap();
+        assert!(server.is_shut_down() && !server.is_shutdown_authorized());
+        assert_eq!(keychain.read().list().len(), 1);
+        assert!(matches!(client.hello().await, Err(ClientError::IO(_))));
+
+        let (srv_sock, client_sock) = UnixStream::pair().unwrap();
+        let task = serve(srv_sock, &keychain);
+        let mut client: Client<UnixStream, DummyCredentials> = Client::new(client_sock);
+        client.initialize(DummyCredentials {}).await.unwrap();
+        let err = client.shutdown(Some("wrong")).await.unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::Unauthorized));
+        assert_eq!(keychain.read().list().len(), 1);
+        client.shutdown(Some("secret")).await.unwrap();
+
+        // the connection is closed without waiting for the client
+        let server = task.await.unwrap();
+        assert!(server.is_shut_down() && server.is_shutdown_authorized());
+        assert!(keychain.read().list().is_empty());
+        assert!(matches!(client.hello().await, Err(ClientError::IO(_))));
+    }
+
+    #[tokio::test]
+    async fn rpc_sign_stream() {
+        let (srv_sock, client_sock) = UnixStream::pair().unwrap();
+        let mut server: Server<PassthroughFactory, EncryptedSigner<Passthrough>, rand_core::OsRng> =
+            Server::new(PassthroughFactory, rand_core::OsRng);
+
+        let mut client: Client<UnixStream, DummyCredentials> = Client::new(client_sock);
+
+        futures::join!(
+            async move {
+                server.serve_connection(srv_sock).await.unwrap();
+            },
+            async move {
+                client.initialize(DummyCredentials {}).await.unwrap();
+                let err = client.sign_stream_chunk(b"text").await.unwrap_err();
+                assert_eq!(
+                    unwrap_as!(err, ClientError::RPC).message,
+                    "no SignStreamBegin in progress"
+                );
+
+                let key = client.generate_and_import(KeyType::Ed25519).await.unwrap();
+                client
+                    .sign_stream_begin(key.handle, SigningVersion::Latest, None)
+                    .await
+                    .unwrap();
+                for chunk in [&b"long "[..], b"message"] {
+                    client.sign_stream_chunk(chunk).await.unwrap();
+                }
+                let sig = client.sign_stream_finish().await.unwrap();
+                key.public_key.verify(b"long message", &sig).unwrap();
+                assert!(client.sign_stream_finish().await.is_err());
+
+                // deterministic ECDSA signatures match the ones of Sign
+                let key = client
+                    .generate_and_import(KeyType::Secp256k1)
+                    .await
+                    .unwrap();
+                client
+                    .sign_stream_begin(
+                        key.handle,
+                        SigningVersion::Latest,
+                        Some(DigestAlgorithm::Sha256),
+                    )
+                    .await
+                    .unwrap();
+                client.sign_stream_chunk(b"long ").await.unwrap();
+                client.sign_stream_chunk(b"message").await.unwrap();
+                assert_eq!(
+                    client
+                        .sign_stream_finish()
+                        .await
+                        .unwrap()
+                        .try_into_cbor()
+                        .unwrap(),
+                    client
+                        .try_sign(
+                            key.handle,
+                            b"long message",
+                            SigningVersion::Latest,
+                            &SignOptions {
+                                digest: Some(DigestAlgorithm::Sha256),
+                                ..Default::default()
+                            },
+                        )
+                        .await
+                        .unwrap()
+                        .try_into_cbor()
+                        .unwrap()
+                );
+
+                let key = client.generate_and_import(KeyType::Bls).await.unwrap();
+                let err = client
+                    .sign_stream_begin(key.handle, SigningVersion::Latest, None)
+                    .await
+                    .unwrap_err();
+                assert_eq!(
+                    unwrap_as!(err, ClientError::RPC).message,
+                    "Prehashed signing is not supported"
+                );
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn rpc_batch_sign() {
+        let (srv_sock, client_sock) = UnixStream::pair().unwrap();
+        let mut server: Server<PassthroughFactory, EncryptedSigner<Passthrough>, rand_core::OsRng> =
+            Server::new(PassthroughFactory, rand_core::OsRng);
+
+        let mut client: Client<UnixStream, DummyCredentials> = Client::new(client_sock);
+
+        futures::join!(
+            async move {
+                server.serve_connection(srv_sock).await.unwrap();
+            },
+            async move {
+                client.initialize(DummyCredentials {}).await.unwrap();
+                let key = client.generate_and_import(KeyType::Bls).await.unwrap();
+                let msgs = [&b"block"[..], b"preattestation", b"attestation"];
+                let sigs = client
+                    .try_sign_batch(
+                        key.handle,
+                        &msgs,
+                        SigningVersion::Latest,
+                        &SignOptions::default(),
+                    )
+                    .await
+                    .unwrap();
+                assert_eq!(sigs.len(), msgs.len());
+                for (msg, sig) in msgs.iter().zip(&sigs) {
+                    key.public_key.verify(msg, sig).unwrap();
+                }
+                assert_eq!(client.key_stats().await.unwrap()[0].signatures, 3);
+
+                let empty: [&[u8]; 0] = [];
+                assert!(client
+                    .try_sign_batch(
+                        key.handle,
+                        &empty,
+                        SigningVersion::Latest,
+                        &SignOptions::default()
+                    )
+                    .await
+                    .unwrap()
+                    .is_empty());
+
+                let err = client
+                    .try_sign_batch(
+                        100.into(),
+                        &msgs,
+                        SigningVersion::Latest,
+                        &SignOptions::default(),
+                    )
+                    .await
+                    .unwrap_err();
+                assert!(unwrap_as!(err, ClientError::RPC)
+                    .to_string()
+                    .ends_with("invalid handle"));
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn rpc_remove_and_list() {
+        let (srv_sock, client_sock) = UnixStream::pair().unwrap();
+        let mut server: Server<PassthroughFactory, EncryptedSigner<Passthrough>, rand_core::OsRng> =
+            Server::new(PassthroughFactory, rand_core::OsRng);
+
+        let mut client: Client<UnixStream, DummyCredentials> = Client::new(client_sock);
+
+        futures::join!(
+            async move {
+                server.serve_connection(srv_sock).await.unwrap();
+            },
+            async move {
+                client.initialize(DummyCredentials {}).await.unwrap();
+                let a = client.generate_and_import(KeyType::Ed25519).await.unwrap();
+                let b = client.generate_and_import(KeyType::Ed25519).await.unwrap();
+                client.remove(a.handle).await.unwrap();
+
+                let err = client.public_key(a.handle).await.unwrap_err();
+                assert!(unwrap_as!(err, ClientError::RPC)
+                    .to_string()
+                    .ends_with("invalid handle"));
+                assert!(client.remove(a.handle).await.is_err());
+                assert_eq!(
+                    client.public_key(b.handle).await.unwrap().hash(),
+                    b.public_key.hash()
+                );
+
+                let list = client.list_keys().await.unwrap();
+                assert_eq!(list.len(), 1);
+                assert_eq!(list[0].handle, b.handle);
+                assert_eq!(list[0].kind, KeyKind::Private);
+                assert_eq!(list[0].key_type, Some(KeyType::Ed25519));
+                assert_eq!(
+                    list[0].public_key.as_ref().unwrap().hash(),
+                    b.public_key.hash()
+                );
+
+                client
+                    .set_expiry(b.handle, Some(Duration::ZERO))
+                    .await
+                    .unwrap();
+                let err = client.public_key(b.handle).await.unwrap_err();
+                assert!(unwrap_as!(err, ClientError::RPC)
+                    .to_string()
+                    .ends_with("Key expired"));
+                assert!(client.list_keys().await.unwrap().is_empty());
+
+                let b = client.generate_and_import(KeyType::Ed25519).await.unwrap();
+                let md = KeyMetadata::new("baker-main").with_entry("chain", "mainnet");
+                client.set_metadata(b.handle, md.clone()).await.unwrap();
+                let info = client.find_label("baker-main").await.unwrap().unwrap();
+                assert_eq!(info.handle, b.handle);
+                assert_eq!(info.metadata, md);
+                assert!(client.find_label("payout").await.unwrap().is_none());
+                assert_eq!(client.list_keys().await.unwrap()[0].metadata, md);
+                let err = client
+                    .set_metadata(a.handle, KeyMetadata::new("payout"))
+                    .await
+                    .unwrap_err();
+                assert!(unwrap_as!(err, ClientError::RPC)
+                    .to_string()
+                    .ends_with("invalid handle"));
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn rpc_describe() {
+        let (srv_sock, client_sock) = UnixStream::pair().unwrap();
+        let mut server: Server<PassthroughFactory, EncryptedSigner<Passthrough>, rand_core::OsRng> =
+            Server::new(PassthroughFactory, rand_core::OsRng);
+
+        let mut client: Client<UnixStream, DummyCredentials> = Client::new(client_sock);
+
+        futures::join!(
+            async move {
+                server.serve_connection(srv_sock).await.unwrap();
+            },
+            async move {
+                // available before initialization
+                let schema = client.describe().await.unwrap();
+                assert_eq!(schema, describe::<DummyCredentials>());
+                assert!(schema.contains("DummyCredentials = { }\n"));
+                assert!(schema.contains("SignResponse = Response<Signature>\n"));
+                assert!(schema.contains(
+                    "Signature = { Secp256k1: bstr .size 64 } / { NistP256: bstr .size 64 } / \
+                     { Ed25519: [64*64 uint] } / { Bls: bstr .size 96 }\n"
+                ));
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn rpc_idempotent() {
+        use super::idempotency::IdempotencyCache;
+
+        let cache = Arc::new(IdempotencyCache::default());
+        let (srv_sock, client_sock) = UnixStream::pair().unwrap();
+        let mut server: Server<PassthroughFactory, EncryptedSigner<Passthrough>, rand_core::OsRng> =
+            Server::new(PassthroughFactory, rand_core::OsRng).with_idempotency_cache(cache.clone());
+        let mut client: Client<UnixStream, DummyCredentials> = Client::new(client_sock);
+
+        let first = futures::join!(
+            async move {
+                server.serve_connection(srv_sock).await.unwrap();
+            },
+            async move {
+                client.initialize(DummyCredentials {}).await.unwrap();
+                let key = client
+                    .generate_and_import_idempotent(KeyType::Ed25519, b"key 1")
+                    .await
+                    .unwrap();
+                let retry = client
+                    .generate_and_import_idempotent(KeyType::Ed25519, b"key 1")
+                    .await
+                    .unwrap();
+                assert_eq!(retry.handle, key.handle);
+                assert_eq!(retry.fingerprint, key.fingerprint);
+                assert_eq!(retry.encrypted_private_key, key.encrypted_private_key);
+                assert_eq!(client.list_keys().await.unwrap().len(), 1);
+
+                let a = client
+                    .generate_idempotent(KeyType::Secp256k1, b"key 2")
+                    .await
+                    .unwrap();
+                let b = client
+                    .generate_idempotent(KeyType::Secp256k1, b"key 2")
+                    .await
+                    .unwrap();
+                assert_eq!(a.encrypted_private_key, b.encrypted_private_key);
+                let c = client
+                    .generate_idempotent(KeyType::Secp256k1, b"key 3")
+                    .await
+                    .unwrap();
+                assert_ne!(a.encrypted_private_key, c.encrypted_private_key);
+
+                for (t, key) in [
+                    (KeyType::Ed25519, &b"key 2"[..]),
+                    (KeyType::Secp256k1, b""),
+                    (KeyType::Secp256k1, &[0; 65]),
+                ] {
+                    let err = client.generate_idempotent(t, key).await.unwrap_err();
+                    assert_eq!(err.code(), Some(ErrorCode::InvalidRequest));
+                }
+                key
+            }
+        )
+        .1;
+
+        // retried on another connection sharing the cache
+        let (srv_sock, client_sock) = UnixStream::pair().unwrap();
+        let mut server: Server<PassthroughFactory, EncryptedSigner<Passthrough>, rand_core::OsRng> =
+            Server::new(PassthroughFactory, rand_core::OsRng).with_idempotency_cache(cache);
+        let mut client: Client<UnixStream, DummyCredentials> = Client::new(client_sock);
+
+        futures::join!(
+            async move {
+                server.serve_connection(srv_sock).await.unwrap();
+            },
+            async move {
+                client.initialize(DummyCredentials {}).await.unwrap();
+                client.generate_and_import(KeyType::Ed25519).await.unwrap();
+                client.generate_and_import(KeyType::Ed25519).await.unwrap();
+                let key = client
+                    .generate_and_import_idempotent(KeyType::Ed25519, b"key 1")
+                    .await
+                    .unwrap();
+                assert_eq!(key.fingerprint, first.fingerprint);
+                assert_ne!(key.handle, first.handle);
+                let sig = client
+                    .try_sign(
+                        key.handle,
+                        b"text",
+                        SigningVersion::Latest,
+                        &SignOptions::default(),
+                    )
+                    .await
+                    .unwrap();
+                first.public_key.verify(b"text", &sig).unwrap();
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn rpc_compression() {
+        use super::{codec, Compression, NegotiateCompressionResult, PongResult};
+        use tokio::io::AsyncWriteExt;
+
+        let (srv_sock, client_sock) = UnixStream::pair().unwrap();
+        let mut server: Server<PassthroughFactory, EncryptedSigner<Passthrough>, rand_core::OsRng> =
+            Server::new(PassthroughFactory, rand_core::OsRng);
+        let mut client: Client<UnixStream, DummyCredentials> = Client::new(client_sock);
+
+        futures::join!(
+            async move {
+                server.serve_connection(srv_sock).await.unwrap();
+            },
+            async move {
+                assert_eq!(
+                    client.negotiate_compression(1000).await.unwrap(),
+                    Some(Compression::Zstd)
+                );
+                let payload = vec![7; 4000];
+                assert_eq!(client.ping(&payload).await.unwrap().payload, payload);
+                assert_eq!(client.ping(b"short").await.unwrap().payload, b"short");
+            }
+        );
+
+        let (srv_sock, mut sock) = UnixStream::pair().unwrap();
+        let mut server: Server<PassthroughFactory, EncryptedSigner<Passthrough>, rand_core::OsRng> =
+            Server::new(PassthroughFactory, rand_core::OsRng).with_max_frame_size(3000);
+
+        futures::join!(
+            async move {
+                server.serve_connection(srv_sock).await.unwrap();
+            },
+            async move {
+                send_raw(
+                    &mut sock,
+                    Request::NegotiateCompression {
+                        algorithms: vec![Compression::Unknown],
+                        min_size: 100,
+                    },
+                )
+                .await;
+                let res = recv_raw::<super::Result<NegotiateCompressionResult>>(&mut sock).await;
+                assert_eq!(res.unwrap().algorithm, None);
+                // malformed
+                let mut buf = Vec::new();
+                super::encode_frame(
+                    &mut buf,
+                    &ciborium::Value::Map(vec![("Zstd".into(), ciborium::Value::Bytes(vec![0]))]),
+                )
+                .unwrap();
+                sock.write_all(&buf).await.unwrap();
+                let err = recv_raw::<super::Result<()>>(&mut sock).await.unwrap_err();
+                assert_eq!(err.code, Some(ErrorCode::InvalidRequest));
+
+                send_raw(
+                    &mut sock,
+                    Request::NegotiateCompression {
+                        algorithms: vec![Compression::Unknown, Compression::Deflate],
+                        min_size: 100,
+                    },
+                )
+                .await;
+                let res = recv_raw::<super::Result<NegotiateCompressionResult>>(&mut sock).await;
+                assert_eq!(res.unwrap().algorithm, Some(Compression::Deflate));
+
+                let ping = |len| {
+                    let mut buf = Vec::new();
+                    super::encode_frame(
+                        &mut buf,
+                        &Request::<VersionedCredentials<DummyCredentials>>::Ping {
+                            payload: vec![1; len],
+                        },
+                    )
+                    .unwrap();
+                    codec::compress_frame(&mut buf, Compression::Deflate, 0).unwrap();
+                    assert!(buf.len() < 100);
+                    buf
+                };
+                sock.write_all(&ping(2000)).await.unwrap();
+                let mut buf = Vec::new();
+                codec::read_frame(&mut sock, &mut buf, usize::MAX)
+                    .await
+                    .unwrap();
+                assert!(buf.len() < 100);
+                let payload = codec::decompress(&buf, usize::MAX).unwrap();
+                let pong = super::Result::<PongResult>::try_from_cbor(&payload)
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(pong.payload, vec![1; 2000]);
+
+                // the frame size limit applies to the decompressed payload
+                sock.write_all(&ping(4000)).await.unwrap();
+                let err = recv_raw::<super::Result<PongResult>>(&mut sock)
+                    .await
+                    .unwrap_err();
+                assert_eq!(err.code, Some(ErrorCode::FrameTooLarge));
+            }
+        );
+    }
+
+    async fn send_raw(
+        sock: &mut UnixStream,
+        req: super::Request<VersionedCredentials<DummyCredentials>>,
+    ) {
+        use tokio::io::AsyncWriteExt;
+        let mut buf = Vec::new();
+        super::encode_frame(&mut buf, &req).unwrap();
+        sock.write_all(&buf).await.unwrap();
+    }
+
+    async fn recv_raw<T: serde::de::DeserializeOwned>(sock: &mut UnixStream) -> T {
+        let mut buf = Vec::new();
+        loop {
+            super::codec::read_frame(sock, &mut buf, usize::MAX)
+                .await
+                .unwrap();
+            // skip heartbeats
+            if !buf.is_empty() {
+                return T::try_from_cbor(&buf).unwrap();
+            }
+        }
+    }
+
+    /// Passthrough backend holding every decryption until released
+    #[derive(Debug, Clone, Default)]
+    struct Gated(std::sync::Arc<tokio::sync::Notify>);
+
+    impl crate::EncryptionBackend for Gated {
+        type Error = crate::tests::DummyErr;
+
+        async fn encrypt(&self, src: &[u8]) -> Result<Vec<u8>, Self::Error> {
+            Ok(Vec::from(src))
+        }
+
+        async fn decrypt(&self, src: &[u8]) -> Result<Vec<u8>, Self::Error> {
+            self.0.notified().await;
+            Ok(Vec::from(src))
+        }
+    }
+
+    impl crate::EncryptionBackendFactory for Gated {
+        type Output = Gated;
+        type Credentials = DummyCredentials;
+        fn try_new(
+            &self,
+            _cred: Self::Credentials,
+        ) -> impl std::future::Future<Output = Result<Self::Output, crate::tests::DummyErr>>
+        {
+            let backend = self.clone();
+            async { Ok(backend) }
+        }
+    }
+
+    #[tokio::test]
+    async fn rpc_tagged() {
+        use super::{Request, TaggedResponse};
+
+        let (srv_sock, mut sock) = UnixStream::pair().unwrap();
+        let gate = Gated::default();
+        let mut server: Server<Gated, EncryptedSigner<Gated>, rand_core::OsRng> =
+            Server::new(gate.clone(), rand_core::OsRng);
+
+        futures::join!(
+            async move {
+                server.serve_connection(srv_sock).await.unwrap();
+            },
+            async move {
+                send_raw(
+                    &mut sock,
+                    Request::Initialize(VersionedCredentials::new(DummyCredentials {})),
+                )
+                .await;
+                recv_raw::<super::Result<()>>(&mut sock).await.unwrap();
+                send_raw(&mut sock, Request::GenerateAndImport(KeyType::Ed25519)).await;
+                let key = recv_raw::<super::Result<crate::GenerateAndImportResult>>(&mut sock)
+                    .await
+                    .unwrap();
+
+                let sign = |id, req| Request::Tagged {
+                    id,
+                    request: Box::new(req),
+                };
+                // held by the backend until released
+                send_raw(
+                    &mut sock,
+                    sign(
+                        1,
+                        Request::SignWith {
+                            encrypted_private_key: key.encrypted_private_key.clone(),
+                            message: b"slow".to_vec(),
+                            version: SigningVersion::Latest,
+                            options: SignOptions::default(),
+                        },
+                    ),
+                )
+                .await;
+                send_raw(
+                    &mut sock,
+                    sign(
+                        2,
+                        Request::Sign {
+                            handle: key.handle,
+                            message: b"fast".to_vec(),
+                            version: SigningVersion::Latest,
+                            options: SignOptions::default(),
+                        },
+                    ),
+                )
+                .await;
+                let TaggedResponse::Tagged { id, response } =
+                    recv_raw::<TaggedResponse<Signature>>(&mut sock).await;
+                assert_eq!(id, 2);
+                key.public_key.verify(b"fast", &response.unwrap()).unwrap();
+
+                gate.0.notify_one();
+                let TaggedResponse::Tagged { id, response } =
+                    recv_raw::<TaggedResponse<Signature>>(&mut sock).await;
+                assert_eq!(id, 1);
+                key.public_key.verify(b"slow", &response.unwrap()).unwrap();
+
+                // other requests are answered in order, tagged or not
+                send_raw(&mut sock, sign(3, Request::ListKeys)).await;
+                let TaggedResponse::Tagged { id, response } =
+                    recv_raw::<TaggedResponse<Vec<crate::crypto::KeyInfo>>>(&mut sock).await;
+                assert_eq!(id, 3);
+                assert_eq!(response.unwrap().len(), 1);
+
+                send_raw(&mut sock, sign(4, sign(5, Request::ListKeys))).await;
+                let TaggedResponse::Tagged { id, response } =
+                    recv_raw::<TaggedResponse<()>>(&mut sock).await;
+                assert_eq!(id, 4);
+                assert_eq!(
+                    response.unwrap_err().to_string(),
+                    "Tagged requests can't be nested"
+                );
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn rpc_events() {
+        use super::events::{Event, EventBus};
+
+        let bus = EventBus::default();
+        let (srv_sock, client_sock) = UnixStream::pair().unwrap();
+        let mut monitor_server: Server<
+            PassthroughFactory,
+            EncryptedSigner<Passthrough>,
+            rand_core::OsRng,
+        > = Server::new(PassthroughFactory, rand_core::OsRng).with_event_bus(bus.clone());
+        let mut monitor: Client<UnixStream, DummyCredentials> = Client::new(client_sock);
+
+        let (srv_sock2, client_sock) = UnixStream::pair().unwrap();
+        let mut server: Server<PassthroughFactory, EncryptedSigner<Passthrough>, rand_core::OsRng> =
+            Server::new(PassthroughFactory, rand_core::OsRng)
+                .with_event_bus(bus)
+                .with_allowed_requests(&["Initialize", "GenerateAndImport"]);
+        let mut client: Client<UnixStream, DummyCredentials> = Client::new(client_sock);
+
+        futures::join!(
+            async move {
+                monitor_server.serve_connection(srv_sock).await.unwrap();
+            },
+            async move {
+                server.serve_connection(srv_sock2).await.unwrap();
+            },
+            async move {
+                monitor.subscribe().await.unwrap();
+
+                client.initialize(DummyCredentials {}).await.unwrap();
+                client.generate_and_import(KeyType::Ed25519).await.unwrap();
+                let err = client.list_keys().await.unwrap_err();
+                assert_eq!(err.code(), Some(ErrorCode::Disabled));
+                drop(client);
+
+                assert_eq!(
+                    monitor.next_event().await.unwrap(),
+                    Event::KeychainChanged { keys: 0 }
+                );
+                assert_eq!(
+                    monitor.next_event().await.unwrap(),
+                    Event::KeychainChanged { keys: 1 }
+                );
+                assert_eq!(
+                    monitor.next_event().await.unwrap(),
+                    Event::PolicyViolation {
+                        request: "ListKeys".into(),
+                        code: ErrorCode::Disabled
+                    }
+                );
+                // events are interleaved with responses
+                let pong = monitor.ping(b"payload").await.unwrap();
+                assert_eq!(pong.payload, b"payload");
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn rpc_cancel() {
+        use super::{Request, TaggedResponse};
+
+        let (srv_sock, mut sock) = UnixStream::pair().unwrap();
+        let gate = Gated::default();
+        let mut server: Server<Gated, EncryptedSigner<Gated>, rand_core::OsRng> =
+            Server::new(gate.clone(), rand_core::OsRng);
+
+        futures::join!(
+            async move {
+                server.serve_connection(srv_sock).await.unwrap();
+            },
+            async move {
+                send_raw(
+                    &mut sock,
+                    Request::Initialize(VersionedCredentials::new(DummyCredentials {})),
+                )
+                .await;
+                recv_raw::<super::Result<()>>(&mut sock).await.unwrap();
+                send_raw(&mut sock, Request::GenerateAndImport(KeyType::Ed25519)).await;
+                let key = recv_raw::<super::Result<crate::GenerateAndImportResult>>(&mut sock)
+                    .await
+                    .unwrap();
+
+                // held by the backend forever
+                send_raw(
+                    &mut sock,
+                    Request::Tagged {
+                        id: 1,
+                        request: Box::new(Request::SignWith {
+                            encrypted_private_key: key.encrypted_private_key.clone(),
+                            message: b"slow".to_vec(),
+                            version: SigningVersion::Latest,
+                            options: SignOptions::default(),
+                        }),
+                    },
+                )
+                .await;
+                send_raw(&mut sock, Request::Cancel { id: 2 }).await;
+                assert!(!recv_raw::<super::Result<bool>>(&mut sock).await.unwrap());
+                send_raw(&mut sock, Request::Cancel { id: 1 }).await;
+                assert!(recv_raw::<super::Result<bool>>(&mut sock).await.unwrap());
+                let TaggedResponse::Tagged { id, response } =
+                    recv_raw::<TaggedResponse<Signature>>(&mut sock).await;
+                assert_eq!(id, 1);
+                assert_eq!(response.unwrap_err().code, Some(ErrorCode::Cancelled));
+
+                // the connection stays usable
+                send_raw(&mut sock, Request::ListKeys).await;
+                let keys = recv_raw::<super::Result<Vec<crate::crypto::KeyInfo>>>(&mut sock)
+                    .await
+                    .unwrap();
+                assert_eq!(keys.len(), 1);
+                send_raw(&mut sock, Request::Cancel { id: 1 }).await;
+                assert!(!recv_raw::<super::Result<bool>>(&mut sock).await.unwrap());
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn rpc_too_many_in_flight() {
+        use super::{Request, TaggedResponse};
+
+        let (srv_sock, mut sock) = UnixStream::pair().unwrap();
+        let gate = Gated::default();
+        let mut server: Server<Gated, EncryptedSigner<Gated>, rand_core::OsRng> =
+            Server::new(gate.clone(), rand_core::OsRng).with_max_concurrent_requests(2);
+
+        futures::join!(
+            async move {
+                server.serve_connection(srv_sock).await.unwrap();
+            },
+            async move {
+                send_raw(
+                    &mut sock,
+                    Request::Initialize(VersionedCredentials::new(DummyCredentials {})),
+                )
+                .await;
+                recv_raw::<super::Result<()>>(&mut sock).await.unwrap();
+                send_raw(&mut sock, Request::GenerateAndImport(KeyType::Ed25519)).await;
+                let key = recv_raw::<super::Result<crate::GenerateAndImportResult>>(&mut sock)
+                    .await
+                    .unwrap();
+
+                let sign_with = |id| Request::Tagged {
+                    id,
+                    request: Box::new(Request::SignWith {
+                        encrypted_private_key: key.encrypted_private_key.clone(),
+                        message: b"slow".to_vec(),
+                        version: SigningVersion::Latest,
+                        options: SignOptions::default(),
+                    }),
+                };
+                // held by the backend until released
+                for id in 1..=3 {
+                    send_raw(&mut sock, sign_with(id)).await;
+                }
+                let TaggedResponse::Tagged { id, response } =
+                    recv_raw::<TaggedResponse<Signature>>(&mut sock).await;
+                assert_eq!(id, 3);
+                let err = response.unwrap_err();
+                assert_eq!(err.code, Some(ErrorCode::TooManyInFlight));
+                assert_eq!(err.message, "too many requests in flight, the limit is 2");
+
+                for _ in 0..2 {
+                    gate.0.notify_one();
+                    let TaggedResponse::Tagged { response, .. } =
+                        recv_raw::<TaggedResponse<Signature>>(&mut sock).await;
+                    key.public_key.verify(b"slow", &response.unwrap()).unwrap();
+                }
+                send_raw(
+                    &mut sock,
+                    Request::Tagged {
+                        id: 4,
+                        request: Box::new(Request::Sign {
+                            handle: key.handle,
+                            message: b"text".to_vec(),
+                            version: SigningVersion::Latest,
+                            options: SignOptions::default(),
+                        }),
+                    },
+                )
+                .await;
+                let TaggedResponse::Tagged { id, response } =
+                    recv_raw::<TaggedResponse<Signature>>(&mut sock).await;
+                assert_eq!(id, 4);
+                key.public_key.verify(b"text", &response.unwrap()).unwrap();
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn rpc_traced() {
+        use super::{Request, TaggedResponse};
+
+        let (srv_sock, client_sock) = UnixStream::pair().unwrap();
+        let mut server: Server<PassthroughFactory, EncryptedSigner<Passthrough>, rand_core::OsRng> =
+            Server::new(PassthroughFactory, rand_core::OsRng);
+        let mut client: Client<UnixStream, DummyCredentials> =
+            Client::new(client_sock).with_request_key(b"request key");
+
+        futures::join!(
+            async move {
+                server.serve_connection(srv_sock).await.unwrap();
+            },
+            async move {
+                client.set_trace_id(Some("trace-1".into()));
+                client.initialize(DummyCredentials {}).await.unwrap();
+                let key = client.generate_and_import(KeyType::Ed25519).await.unwrap();
+                client
+                    .try_sign(
+                        key.handle,
+                        b"text",
+                        SigningVersion::Latest,
+                        &SignOptions::default(),
+                    )
+                    .await
+                    .unwrap();
+
+                let err = client
+                    .try_sign(
+                        KeyHandle::from(100),
+                        b"text",
+                        SigningVersion::Latest,
+                        &SignOptions::default(),
+                    )
+                    .await;
+                let err = unwrap_as!(err.unwrap_err(), ClientError::RPC);
+                assert_eq!(err.trace_id.as_deref(), Some("trace-1"));
+                assert_eq!(err.code, Some(ErrorCode::InvalidHandle));
+
+                client.set_trace_id(None);
+                let err = client
+                    .try_sign(
+                        KeyHandle::from(100),
+                        b"text",
+                        SigningVersion::Latest,
+                        &SignOptions::default(),
+                    )
+                    .await;
+                assert_eq!(
+                    unwrap_as!(err.unwrap_err(), ClientError::RPC).trace_id,
+                    None
+                );
+
+                client.set_trace_id(Some("trace\n2".into()));
+                let err = client.public_key(key.handle).await.unwrap_err();
+                assert_eq!(err.code(), Some(ErrorCode::InvalidRequest));
+            }
+        );
+
+        // concurrent tagged requests
+        let (srv_sock, mut sock) = UnixStream::pair().unwrap();
+        let mut server: Server<PassthroughFactory, EncryptedSigner<Passthrough>, rand_core::OsRng> =
+            Server::new(PassthroughFactory, rand_core::OsRng);
+        futures::join!(
+            async move {
+                server.serve_connection(srv_sock).await.unwrap();
+            },
+            async move {
+                send_raw(
+                    &mut sock,
+                    Request::Initialize(VersionedCredentials::new(DummyCredentials {})),
+                )
+                .await;
+                recv_raw::<super::Result<()>>(&mut sock).await.unwrap();
+                send_raw(
+                    &mut sock,
+                    Request::Traced {
+                        trace_id: "trace-3".into(),
+                        request: Box::new(Request::Tagged {
+                            id: 1,
+                            request: Box::new(Request::Sign {
+                                handle: KeyHandle::from(100),
+                                message: b"text".to_vec(),
+                                version: SigningVersion::Latest,
+                                options: SignOptions::default(),
+                            }),
+                        }),
+                    },
+                )
+                .await;
+                let TaggedResponse::Tagged { id, response } =
+                    recv_raw::<TaggedResponse<Signature>>(&mut sock).await;
+                assert_eq!(id, 1);
+                assert_eq!(response.unwrap_err().trace_id.as_deref(), Some("trace-3"));
+
+                // only the outermost envelope
+                send_raw(
+                    &mut sock,
+                    Request::Tagged {
+                        id: 2,
+                        request: Box::new(Request::Traced {
+                            trace_id: "trace-4".into(),
+                            request: Box::new(Request::GetInfo),
+                        }),
+                    },
+                )
+                .await;
+                let TaggedResponse::Tagged { response, .. } =
+                    recv_raw::<TaggedResponse<()>>(&mut sock).await;
+                let err = response.unwrap_err();
+                assert_eq!(err.code, Some(ErrorCode::InvalidRequest));
+                assert_eq!(err.trace_id, None);
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn rpc_frame_too_large() {
+        use tokio::io::AsyncWriteExt;
+
+        let (srv_sock, mut sock) = UnixStream::pair().unwrap();
+        let mut server: Server<PassthroughFactory, EncryptedSigner<Passthrough>, rand_core::OsRng> =
+            Server::new(PassthroughFactory, rand_core::OsRng).with_max_frame_size(64);
+
+        futures::join!(
+            async move {
+                server.serve_connection(srv_sock).await.unwrap();
+            },
+            async move {
+                sock.write_all(&1000u32.to_be_bytes()).await.unwrap();
+                sock.write_all(&[0; 1000]).await.unwrap();
+                let err = recv_raw::<super::Result<()>>(&mut sock).await.unwrap_err();
+                assert_eq!(
+                    err.to_string(),
+                    "frame too large: 1000 bytes, the limit is 64"
+                );
+
+                // the oversized frame is skipped
+                send_raw(&mut sock, super::Request::Hello).await;
+                recv_raw::<super::Result<super::HelloResult>>(&mut sock)
+                    .await
+                    .unwrap();
+            }
+        );
+    }
 }
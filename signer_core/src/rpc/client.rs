@@ -1,12 +1,32 @@
-use crate::crypto::{KeyType, PrivateKey, PublicKey, Signature, SigningVersion};
+use crate::crypto::{
+    raw::{RawSecret, UnencryptedKey},
+    wrap::WrappingKey,
+    DigestAlgorithm, KeyConstraint, KeyHandle, KeyInfo, KeyMetadata, KeyStats, KeyType, PrivateKey,
+    PublicKey, PublicKeyFormat, SignOptions, Signature, SigningVersion,
+};
+use crate::lru::Lru;
+use crate::rpc::connector::{Connector, TcpConnector, UnixConnector, VsockConnector};
 use crate::rpc::{
-    Error as RPCError, GenerateAndImportResult, GenerateResult, ImportResult, Request,
-    Result as RPCResult,
+    self, codec, events::Event, AttestResult, BackendState, Compression, DecryptDataResult,
+    EciesDecryptResult, EncryptDataResult, Error as RPCError, ErrorCode, ExportPublicKeyResult,
+    ExportSharesResult, ExportWrappedResult, GenerateAndImportResult, GenerateConstrainedResult,
+    GenerateDataKeyResult, GenerateResult, GenerateSeedResult, HelloResult, ImportDataKeyResult,
+    ImportDeferredResult, ImportResult, InfoResult, Interim, MuSig2AggregateResult,
+    MuSig2NonceResult, MuSig2SignResult, NegotiateCompressionResult, PongResult, Progress, Request,
+    RequestKey, Result as RPCResult, RewrapResult, VersionedCredentials,
 };
-use crate::{TryFromCBOR, TryIntoCBOR};
+use crate::{CredentialsSchema, TryFromCBOR, TryIntoCBOR};
+use hmac::Mac;
+use rand_core::{OsRng, RngCore};
 use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::future::Future;
 use std::marker::PhantomData;
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use std::ops::{Deref, DerefMut};
+use std::pin::Pin;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWriteExt};
 
 #[derive(Debug)]
 pub enum Error {
@@ -14,6 +34,8 @@ pub enum Error {
     RPC(RPCError),
     Serialize(ciborium::ser::Error<std::io::Error>),
     Deserialize(ciborium::de::Error<std::io::Error>),
+    /// No response within the request timeout, see `Client::with_request_timeout`
+    Timeout(Duration),
 }
 
 impl From<std::io::Error> for Error {
@@ -47,76 +69,923 @@ impl std::fmt::Display for Error {
             Error::RPC(error) => write!(f, "RPC error: {}", error),
             Error::Serialize(error) => write!(f, "serialization error: {}", error),
             Error::Deserialize(error) => write!(f, "deserialization error: {}", error),
+            Error::Timeout(timeout) => write!(f, "no response within {:?}", timeout),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::IO(error) => Some(error),
+            Error::Serialize(error) => Some(error),
+            Error::Deserialize(error) => Some(error),
+            // see `Error::rpc` for the chain of the server side error
+            Error::RPC(_) | Error::Timeout(_) => None,
+        }
+    }
+}
+
+impl Error {
+    /// Returns the code of the error reported by the server
+    pub fn code(&self) -> Option<ErrorCode> {
+        match self {
+            Error::RPC(error) => error.code,
+            _ => None,
+        }
+    }
+
+    /// Returns the error reported by the server along with its sources, see `RPCError::chain`
+    pub fn rpc(&self) -> Option<&RPCError> {
+        match self {
+            Error::RPC(error) => Some(error),
+            _ => None,
+        }
+    }
+}
+
+type ConnectFn<T> =
+    Box<dyn FnMut() -> Pin<Box<dyn Future<Output = std::io::Result<T>> + Send>> + Send>;
+
+/// Reconnection attempts of a client created by `Client::with_reconnect`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    /// Attempts to connect and restore the session before the IO error is returned
+    pub max_attempts: u32,
+    /// Delay after the first failed attempt, doubled after every next one
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl ReconnectPolicy {
+    /// Delay after `failed` attempts, randomized between a half of the nominal value and the
+    /// value itself so that clients restarted together don't reconnect in lockstep
+    fn delay(&self, failed: u32) -> Duration {
+        let nominal = self
+            .initial_delay
+            .saturating_mul(1 << failed.saturating_sub(1).min(31))
+            .min(self.max_delay);
+        let half = nominal / 2;
+        half + half.mul_f64(f64::from(OsRng.next_u32()) / f64::from(u32::MAX))
+    }
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        ReconnectPolicy {
+            max_attempts: 5,
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(5),
         }
     }
 }
 
-impl std::error::Error for Error {}
+/// Requests establishing the server side session state, replayed after reconnection
+#[derive(Default)]
+struct Session {
+    initialize: Option<Vec<u8>>,
+    /// Keys passed to `InitializeWith`, kept to rebuild the request on `Reinitialize`
+    initialize_keys: Vec<Vec<u8>>,
+    /// `Initialize` without the keys of `InitializeWith`, replayed if the keychain is shared
+    initialize_shared: Option<Vec<u8>>,
+    imports: Vec<Vec<u8>>,
+}
 
 pub struct Client<T, C> {
     socket: T,
     buf: Vec<u8>,
     w_buf: Vec<u8>,
+    connect: Option<ConnectFn<T>>,
+    reconnect_policy: ReconnectPolicy,
+    session: Session,
+    heartbeat_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+    /// A request timed out, so the connection is out of sync
+    timed_out: bool,
+    listeners: Listeners,
+    request_key: Option<RequestKey>,
+    /// Sequence number of the last `Authenticated` request, set once the server has the key
+    seq: Option<u64>,
+    /// Negotiated algorithm and the minimum size of the compressed request payloads
+    compression: Option<(Compression, usize)>,
+    trace_id: Option<String>,
+    max_frame_size: usize,
+    public_keys: Option<PublicKeyCache>,
+    interceptors: Vec<Box<dyn Interceptor>>,
+    /// Name of the session the requests are sent to, `None` for the one of the connection
+    session_name: Option<String>,
+    /// Other sessions by name
+    sessions: HashMap<Option<String>, SessionState>,
     _phantom: PhantomData<C>,
 }
 
-impl<T, C> Client<T, C>
+/// Client side state of a session other than the current one, swapped in by `Client::session`
+struct SessionState {
+    replay: Session,
+    seq: Option<u64>,
+    /// Public keys cached by handle, handles are scoped to the session
+    public_keys: Option<Lru<KeyHandle, PublicKey>>,
+}
+
+/// Client sending the requests to a named session of the connection, see `Client::session`.
+/// The previous session is the current one again once dropped
+pub struct SessionClient<'a, T, C> {
+    client: &'a mut Client<T, C>,
+    previous: Option<String>,
+}
+
+impl<T, C> Deref for SessionClient<'_, T, C> {
+    type Target = Client<T, C>;
+
+    fn deref(&self) -> &Self::Target {
+        self.client
+    }
+}
+
+impl<T, C> DerefMut for SessionClient<'_, T, C> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.client
+    }
+}
+
+impl<T, C> Drop for SessionClient<'_, T, C> {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        self.client.switch_session(previous);
+    }
+}
+
+impl<T, C> Client<T, C> {
+    /// Makes the named session the current one swapping the state of the sessions
+    fn switch_session(&mut self, name: Option<String>) {
+        if name == self.session_name {
+            return;
+        }
+        let state = self.sessions.remove(&name).unwrap_or_else(|| SessionState {
+            replay: Session::default(),
+            seq: None,
+            public_keys: self
+                .public_keys
+                .as_ref()
+                .map(|cache| Lru::new(cache.handles.capacity())),
+        });
+        let public_keys = match (&mut self.public_keys, state.public_keys) {
+            (Some(cache), Some(handles)) => Some(std::mem::replace(&mut cache.handles, handles)),
+            _ => None,
+        };
+        let current = SessionState {
+            replay: std::mem::replace(&mut self.session, state.replay),
+            seq: std::mem::replace(&mut self.seq, state.seq),
+            public_keys,
+        };
+        self.sessions
+            .insert(std::mem::replace(&mut self.session_name, name), current);
+    }
+}
+
+/// Hooks into the requests sent by the client, e.g. for logging, metrics or wrapping the
+/// requests into an envelope. Requests replayed after reconnection bypass the interceptors
+pub trait Interceptor: Send {
+    /// Called with the encoded request before it's sent. `payload` may be replaced
+    fn on_request(&mut self, name: &'static str, payload: &mut Vec<u8>) {
+        let _ = (name, payload);
+    }
+
+    /// Called with the encoded response, which may still be an error reported by the server,
+    /// or with the error which prevented receiving it
+    fn on_response(&mut self, name: &'static str, response: Result<&[u8], &Error>) {
+        let _ = (name, response);
+    }
+}
+
+/// Public keys returned by the server, see `Client::with_public_key_cache`
+struct PublicKeyCache {
+    handles: Lru<KeyHandle, PublicKey>,
+    blobs: Lru<Vec<u8>, PublicKey>,
+}
+
+type ProgressFn = Box<dyn FnMut(Progress) + Send>;
+type BackendStateFn = Box<dyn FnMut(BackendState) + Send>;
+type EventFn = Box<dyn FnMut(Event) + Send>;
+
+/// Callbacks receiving `Interim` frames
+#[derive(Default)]
+struct Listeners {
+    progress: Option<ProgressFn>,
+    backend_state: Option<BackendStateFn>,
+    event: Option<EventFn>,
+}
+
+/// Reads the next frame other than a heartbeat into `buf` decompressing it if needed.
+/// Payloads longer than `max` fail with `std::io::ErrorKind::InvalidData`
+async fn read_message<T>(
+    socket: &mut T,
+    buf: &mut Vec<u8>,
+    timeout: Option<Duration>,
+    max: usize,
+) -> std::io::Result<()>
+where
+    T: AsyncRead + Unpin,
+{
+    loop {
+        let len = match timeout {
+            Some(t) => match tokio::time::timeout(t, codec::read_len(socket)).await {
+                Ok(res) => res?,
+                Err(_) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::TimedOut,
+                        "no response or heartbeat from the peer",
+                    ))
+                }
+            },
+            None => codec::read_len(socket).await?,
+        };
+        // skip heartbeats
+        if len == 0 {
+            continue;
+        }
+        if len > max {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("frame too large: {} bytes, the limit is {}", len, max),
+            ));
+        }
+        codec::read_payload(socket, len, buf).await?;
+        let payload = match codec::decompress(buf, max)
+            .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))?
+        {
+            Cow::Owned(payload) => Some(payload),
+            Cow::Borrowed(_) => None,
+        };
+        if let Some(payload) = payload {
+            *buf = payload;
+        }
+        return Ok(());
+    }
+}
+
+/// Read and write deadlines of a single socket operation
+#[derive(Clone, Copy)]
+struct Deadlines {
+    read: Option<Duration>,
+    write: Option<Duration>,
+}
+
+async fn exchange<T>(
+    socket: &mut T,
+    frame: &[u8],
+    buf: &mut Vec<u8>,
+    deadlines: Deadlines,
+    max: usize,
+    mut listeners: Option<&mut Listeners>,
+) -> std::io::Result<()>
+where
+    T: AsyncRead + AsyncWriteExt + Unpin,
+{
+    match deadlines.write {
+        Some(t) => match tokio::time::timeout(t, socket.write_all(frame)).await {
+            Ok(res) => res?,
+            Err(_) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::TimedOut,
+                    "the peer doesn't accept the request",
+                ))
+            }
+        },
+        None => socket.write_all(frame).await?,
+    }
+    let timeout = deadlines.read;
+
+    loop {
+        read_message(socket, buf, timeout, max).await?;
+
+        match Interim::try_from_cbor(buf) {
+            Ok(Interim::Progress(p)) => {
+                if let Some(f) = listeners.as_mut().and_then(|l| l.progress.as_mut()) {
+                    f(p)
+                }
+            }
+            Ok(Interim::BackendState(state)) => {
+                if let Some(f) = listeners.as_mut().and_then(|l| l.backend_state.as_mut()) {
+                    f(state)
+                }
+            }
+            Ok(Interim::Event(event)) => {
+                if let Some(f) = listeners.as_mut().and_then(|l| l.event.as_mut()) {
+                    f(event)
+                }
+            }
+            Err(_) => return Ok(()),
+        }
+    }
+}
+
+/// `Request::Traced` borrowing the wrapped request
+#[derive(Serialize)]
+enum TracedRequest<'a, C> {
+    Traced {
+        trace_id: &'a str,
+        request: &'a Request<VersionedCredentials<C>>,
+    },
+}
+
+/// `Request::Session` borrowing the wrapped request
+#[derive(Serialize)]
+enum SessionRequest<'a, R> {
+    Session { name: &'a str, request: &'a R },
+}
+
+fn frame<R: Serialize>(req: &R) -> Result<Vec<u8>, Error> {
+    let mut buf = Vec::new();
+    rpc::encode_frame(&mut buf, req)?;
+    Ok(buf)
+}
+
+/// Encodes the frame wrapping the request into `Session` if the session is named
+fn session_frame<R: Serialize>(session: Option<&str>, req: &R) -> Result<Vec<u8>, Error> {
+    match session {
+        Some(name) => frame(&SessionRequest::Session { name, request: req }),
+        None => frame(req),
+    }
+}
+
+/// Replays the requests establishing the session on a new connection
+async fn replay<T, C>(
+    socket: &mut T,
+    buf: &mut Vec<u8>,
+    deadlines: Deadlines,
+    max: usize,
+    name: Option<&str>,
+    session: &Session,
+) -> Result<(), Error>
 where
     T: AsyncRead + AsyncWriteExt + Unpin,
     C: Serialize,
+{
+    // the keys of a shared keychain survive the connection, importing them again would
+    // duplicate them under new handles and a replayed `Remove` would fail
+    let shared = if session.imports.is_empty() && session.initialize_keys.is_empty() {
+        false
+    } else {
+        let hello = session_frame(name, &Request::<VersionedCredentials<C>>::Hello)?;
+        exchange(socket, &hello, buf, deadlines, max, None).await?;
+        matches!(
+            RPCResult::<HelloResult>::try_from_cbor(buf)?,
+            Ok(HelloResult {
+                shared_keychain: true,
+                ..
+            })
+        )
+    };
+    let (initialize, imports) = match shared {
+        true => (
+            session
+                .initialize_shared
+                .as_ref()
+                .or(session.initialize.as_ref()),
+            &[][..],
+        ),
+        false => (session.initialize.as_ref(), &session.imports[..]),
+    };
+    for frame in initialize.into_iter().chain(imports.iter()) {
+        exchange(socket, frame, buf, deadlines, max, None).await?;
+        RPCResult::<ciborium::Value>::try_from_cbor(buf)??;
+    }
+    Ok(())
+}
+
+impl<T, C> Client<T, C>
+where
+    T: AsyncRead + AsyncWriteExt + Unpin,
+    C: Serialize + CredentialsSchema,
 {
     pub fn new(sock: T) -> Self {
         Self {
             socket: sock,
             buf: Vec::new(),
             w_buf: Vec::new(),
+            connect: None,
+            reconnect_policy: ReconnectPolicy::default(),
+            session: Session::default(),
+            heartbeat_timeout: None,
+            write_timeout: None,
+            request_timeout: None,
+            timed_out: false,
+            listeners: Listeners::default(),
+            request_key: None,
+            seq: None,
+            compression: None,
+            trace_id: None,
+            // responses are trusted, the size is not limited by default
+            max_frame_size: usize::MAX,
+            public_keys: None,
+            interceptors: Vec::new(),
+            session_name: None,
+            sessions: HashMap::new(),
             _phantom: PhantomData,
         }
     }
 
-    async fn round_trip<R>(&mut self, req: Request<C>) -> Result<R, Error>
+    /// Returns the client sending the requests to the named session of the connection until
+    /// dropped. Each session has its own `Initialize` state, keys and handles on the server,
+    /// e.g. one per tenant of a host service. The sessions are replayed after reconnecting.
+    /// See `Request::Session`
+    pub fn session(&mut self, name: &str) -> SessionClient<'_, T, C> {
+        let previous = self.session_name.clone();
+        self.switch_session(Some(name.into()));
+        SessionClient {
+            client: self,
+            previous,
+        }
+    }
+
+    /// Passes `key` to the server at `Initialize` and authenticates all following requests with it,
+    /// see `Request::Authenticated`
+    pub fn with_request_key(mut self, key: &[u8]) -> Self {
+        self.request_key = Some(RequestKey(key.into()));
+        self
+    }
+
+    /// Fails the request with `std::io::ErrorKind::TimedOut` if neither the response
+    /// nor a heartbeat frame arrives within `timeout`. The server must be configured to send heartbeats
+    /// more often, see `Server::with_heartbeat_interval`
+    pub fn with_heartbeat_timeout(mut self, timeout: Duration) -> Self {
+        self.heartbeat_timeout = Some(timeout);
+        self
+    }
+
+    /// Fails the request with `std::io::ErrorKind::TimedOut` if the peer doesn't accept it
+    /// within `timeout`, e.g. because it stopped reading
+    pub fn with_write_timeout(mut self, timeout: Duration) -> Self {
+        self.write_timeout = Some(timeout);
+        self
+    }
+
+    /// Changes the heartbeat timeout, see `with_heartbeat_timeout`. It's the deadline of every
+    /// read, so a tool talking to a dead signer fails instead of hanging
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.heartbeat_timeout = timeout;
+    }
+
+    /// Changes the write timeout, see `with_write_timeout`
+    pub fn set_write_timeout(&mut self, timeout: Option<Duration>) {
+        self.write_timeout = timeout;
+    }
+
+    fn deadlines(&self) -> Deadlines {
+        Deadlines {
+            read: self.heartbeat_timeout,
+            write: self.write_timeout,
+        }
+    }
+
+    /// Adds the interceptor. Requests pass the interceptors in the order they were added and
+    /// responses in the reverse one
+    pub fn with_interceptor<I: Interceptor + 'static>(mut self, interceptor: I) -> Self {
+        self.interceptors.push(Box::new(interceptor));
+        self
+    }
+
+    /// Sets the callback receiving progress reports of long running requests
+    pub fn with_progress<F: FnMut(Progress) + Send + 'static>(mut self, f: F) -> Self {
+        self.listeners.progress = Some(Box::new(f));
+        self
+    }
+
+    /// Sets the callback notified when the server's encryption backend becomes unavailable or recovers
+    pub fn with_backend_state<F: FnMut(BackendState) + Send + 'static>(mut self, f: F) -> Self {
+        self.listeners.backend_state = Some(Box::new(f));
+        self
+    }
+
+    /// Sets the callback receiving the events pushed after `subscribe` while waiting for a response
+    pub fn with_events<F: FnMut(Event) + Send + 'static>(mut self, f: F) -> Self {
+        self.listeners.event = Some(Box::new(f));
+        self
+    }
+
+    /// Creates a client which reconnects using `connect` if the connection is dropped.
+    /// After reconnection the session is re-established by replaying `Initialize` and all key imports
+    /// so the key handles stay valid. If `Hello` reports a shared keychain the keys outlived the connection,
+    /// so only `Initialize` is replayed. The interrupted request is then retried unless it generates a new key,
+    /// in which case the original IO error is returned. Requests wrapped into `Idempotent` are retried.
+    /// Failed attempts to reconnect are repeated with backoff, see `with_reconnect_policy`
+    pub fn with_reconnect<F, Fut>(sock: T, mut connect: F) -> Self
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = std::io::Result<T>> + Send + 'static,
+    {
+        let mut client = Self::new(sock);
+        client.connect = Some(Box::new(move || Box::pin(connect())));
+        client
+    }
+
+    /// Fails requests with `Error::Timeout` if the response doesn't arrive within `timeout`,
+    /// heartbeats notwithstanding. The connection is abandoned then: a client created by
+    /// `with_reconnect` reconnects on the next request while others fail with
+    /// `std::io::ErrorKind::NotConnected`
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    /// Changes the request timeout for the following requests, e.g. to wait longer for a
+    /// single slow one
+    pub fn set_request_timeout(&mut self, timeout: Option<Duration>) {
+        self.request_timeout = timeout;
+    }
+
+    /// Replaces the default reconnection policy of a client created by `with_reconnect`
+    pub fn with_reconnect_policy(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect_policy = policy;
+        self
+    }
+
+    /// Keeps up to `capacity` public keys per handle and per encrypted key so that repeated
+    /// `public_key` and `public_key_from` calls don't reach the server. Entries of removed keys
+    /// are dropped, see also `invalidate_public_key`
+    pub fn with_public_key_cache(mut self, capacity: usize) -> Self {
+        self.public_keys = Some(PublicKeyCache {
+            handles: Lru::new(capacity),
+            blobs: Lru::new(capacity),
+        });
+        self
+    }
+
+    /// Drops the cached public key of the handle
+    pub fn invalidate_public_key(&mut self, handle: KeyHandle) {
+        if let Some(cache) = &mut self.public_keys {
+            cache.handles.remove(&handle);
+        }
+    }
+
+    /// Drops all cached public keys
+    pub fn clear_public_key_cache(&mut self) {
+        if let Some(cache) = &mut self.public_keys {
+            cache.handles.clear();
+            cache.blobs.clear();
+        }
+    }
+
+    /// Fails requests with `std::io::ErrorKind::InvalidData` if the response payload is
+    /// longer than `size` bytes, decompressed or not
+    pub fn with_max_frame_size(mut self, size: usize) -> Self {
+        self.max_frame_size = size;
+        self
+    }
+
+    /// Wraps the following requests into `Traced` with `trace_id`, or stops doing so if `None`.
+    /// The server logs and the errors of the failed requests then carry the ID
+    pub fn set_trace_id(&mut self, trace_id: Option<String>) {
+        self.trace_id = trace_id;
+    }
+
+    /// Restores the session repeating the attempts failed with IO errors
+    async fn reconnect(&mut self) -> Result<(), Error> {
+        let mut failed = 0;
+        loop {
+            match self.restore_session().await {
+                Err(Error::IO(_)) if failed + 1 < self.reconnect_policy.max_attempts => {
+                    failed += 1;
+                    tokio::time::sleep(self.reconnect_policy.delay(failed)).await;
+                }
+                res => return res,
+            }
+        }
+    }
+
+    async fn restore_session(&mut self) -> Result<(), Error> {
+        let connect = match &mut self.connect {
+            Some(connect) => connect,
+            None => return Ok(()),
+        };
+        self.socket = connect().await?;
+
+        let deadlines = self.deadlines();
+        let sessions = std::iter::once((&self.session_name, &self.session)).chain(
+            self.sessions
+                .iter()
+                .map(|(name, state)| (name, &state.replay)),
+        );
+        for (name, session) in sessions {
+            replay::<T, C>(
+                &mut self.socket,
+                &mut self.buf,
+                deadlines,
+                self.max_frame_size,
+                name.as_deref(),
+                session,
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    // remember the last sent request as a part of the session
+    fn record(&mut self, req: Option<Request<VersionedCredentials<C>>>) -> Result<(), Error> {
+        if self.connect.is_none() {
+            return Ok(());
+        }
+        let frame = match req {
+            Some(req) => self.encode(&req)?,
+            None => self.w_buf.clone(),
+        };
+        match self.session.initialize {
+            None => self.session.initialize = Some(frame),
+            Some(_) => self.session.imports.push(frame),
+        }
+        Ok(())
+    }
+
+    /// Encodes the request frame wrapping the request into `Traced` if the trace ID is set and
+    /// then into `Authenticated` once the server has the key
+    fn encode(&mut self, req: &Request<VersionedCredentials<C>>) -> Result<Vec<u8>, Error> {
+        let traced = self
+            .trace_id
+            .as_deref()
+            .map(|trace_id| TracedRequest::Traced {
+                trace_id,
+                request: req,
+            });
+        let session = self.session_name.as_deref();
+        let (Some(key), Some(seq)) = (&self.request_key, &mut self.seq) else {
+            return match &traced {
+                Some(traced) => session_frame(session, traced),
+                None => session_frame(session, req),
+            };
+        };
+        *seq += 1;
+        let request = match &traced {
+            Some(traced) => traced.try_into_cbor()?,
+            None => req.try_into_cbor()?,
+        };
+        let mac = rpc::request_mac(&key.0, *seq, &request)
+            .finalize()
+            .into_bytes()
+            .to_vec();
+        session_frame(
+            session,
+            &Request::<VersionedCredentials<C>>::Authenticated {
+                seq: *seq,
+                request,
+                mac,
+            },
+        )
+    }
+
+    async fn round_trip<R>(&mut self, req: Request<VersionedCredentials<C>>) -> Result<R, Error>
     where
         R: TryFromCBOR,
-        Request<C>: TryIntoCBOR,
+        Request<VersionedCredentials<C>>: TryIntoCBOR,
         RPCResult<R>: TryFromCBOR,
-        Error:
-            From<<Request<C> as TryIntoCBOR>::Error> + From<<RPCResult<R> as TryFromCBOR>::Error>,
+        Error: From<<Request<VersionedCredentials<C>> as TryIntoCBOR>::Error>
+            + From<<RPCResult<R> as TryFromCBOR>::Error>,
     {
-        self.buf.clear();
-        req.try_into_writer(&mut self.buf)?;
-        let len = u32::try_from(self.buf.len()).unwrap().to_be_bytes();
-
-        self.w_buf.clear();
-        self.w_buf.extend_from_slice(&len);
-        self.w_buf.extend_from_slice(&self.buf);
-        self.socket.write_all(&self.w_buf).await?;
+        if self.timed_out {
+            // the response of the timed out request may still arrive
+            if self.connect.is_none() {
+                return Err(std::io::Error::from(std::io::ErrorKind::NotConnected).into());
+            }
+            self.reconnect().await?;
+            self.timed_out = false;
+        }
 
-        let mut len_buf: [u8; 4] = [0; 4];
-        self.socket.read_exact(&mut len_buf).await?;
-        let len = u32::from_be_bytes(len_buf);
+        let name = req.name();
+        self.w_buf = self.encode(&req)?;
+        if !self.interceptors.is_empty() {
+            let mut payload = self.w_buf.split_off(codec::FRAME_PREFIX_LEN);
+            for interceptor in &mut self.interceptors {
+                interceptor.on_request(name, &mut payload);
+            }
+            codec::begin_frame(&mut self.w_buf);
+            self.w_buf.extend_from_slice(&payload);
+            codec::finish_frame(&mut self.w_buf);
+        }
+        if let Some((algorithm, min_size)) = self.compression {
+            codec::compress_frame(&mut self.w_buf, algorithm, min_size)?;
+        }
 
-        self.buf.resize(len as usize, 0);
-        self.socket.read_exact(&mut self.buf).await?;
+        let sent = match self.request_timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, self.send(&req)).await {
+                Ok(res) => res,
+                Err(_) => {
+                    self.timed_out = true;
+                    Err(Error::Timeout(timeout))
+                }
+            },
+            None => self.send(&req).await,
+        };
+        for interceptor in self.interceptors.iter_mut().rev() {
+            interceptor.on_response(name, sent.as_ref().map(|_| self.buf.as_slice()));
+        }
+        sent?;
 
         let res = RPCResult::<R>::try_from_cbor(&self.buf)?;
         Ok(res?)
     }
 
+    /// Sends the request frame in `w_buf` and reads the response into `buf`, retrying the
+    /// request after reconnecting if possible
+    async fn send(&mut self, req: &Request<VersionedCredentials<C>>) -> Result<(), Error> {
+        let deadlines = self.deadlines();
+        if let Err(err) = exchange(
+            &mut self.socket,
+            &self.w_buf,
+            &mut self.buf,
+            deadlines,
+            self.max_frame_size,
+            Some(&mut self.listeners),
+        )
+        .await
+        {
+            if self.connect.is_none() {
+                return Err(err.into());
+            }
+            self.reconnect().await?;
+            match req {
+                // don't silently replace the key which may have been already generated
+                Request::Generate(_)
+                | Request::GenerateAndImport(_)
+                | Request::GenerateConstrained { .. } => return Err(err.into()),
+                _ => {
+                    exchange(
+                        &mut self.socket,
+                        &self.w_buf,
+                        &mut self.buf,
+                        deadlines,
+                        self.max_frame_size,
+                        Some(&mut self.listeners),
+                    )
+                    .await?
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn credentials(&self, cred: C) -> VersionedCredentials<C> {
+        VersionedCredentials {
+            request_key: self.request_key.clone(),
+            ..VersionedCredentials::new(cred)
+        }
+    }
+
     pub async fn initialize(&mut self, cred: C) -> Result<(), Error> {
-        self.round_trip::<()>(Request::Initialize(cred)).await
+        let credentials = self.credentials(cred);
+        self.round_trip::<()>(Request::Initialize(credentials))
+            .await?;
+        self.seq = self.request_key.as_ref().map(|_| 0);
+        self.record(None)
+    }
+
+    /// Initializes the session and imports all keys in a single request. Either all keys
+    /// are imported or the session stays uninitialized. The results are in the order of `keys`,
+    /// use their handles as a shared keychain may already hold other keys
+    pub async fn initialize_with(
+        &mut self,
+        cred: C,
+        keys: &[Vec<u8>],
+    ) -> Result<Vec<ImportResult>, Error> {
+        let shared = match self.connect {
+            Some(_) => Some(session_frame(
+                self.session_name.as_deref(),
+                &Request::Initialize(VersionedCredentials {
+                    version: C::VERSION,
+                    request_key: self.request_key.clone(),
+                    credentials: &cred,
+                    unknown: Default::default(),
+                }),
+            )?),
+            None => None,
+        };
+        let credentials = self.credentials(cred);
+        let res = self
+            .round_trip::<Vec<ImportResult>>(Request::InitializeWith {
+                credentials,
+                keys: keys.into(),
+            })
+            .await?;
+        self.seq = self.request_key.as_ref().map(|_| 0);
+        self.record(None)?;
+        if self.connect.is_some() {
+            self.session.initialize_keys = keys.into();
+            self.session.initialize_shared = shared;
+        }
+        Ok(res)
+    }
+
+    /// Replaces the encryption backend credentials of the initialized session keeping the keys,
+    /// e.g. to refresh expiring credentials. `token` must match the one the server is configured
+    /// with, servers without a token refuse the request. The new credentials are used when the session is replayed after reconnecting
+    pub async fn reinitialize(&mut self, cred: C, token: Option<&str>) -> Result<(), Error> {
+        let replay = match self.connect {
+            Some(_) => {
+                let credentials = || VersionedCredentials {
+                    version: C::VERSION,
+                    request_key: self.request_key.clone(),
+                    credentials: &cred,
+                    unknown: Default::default(),
+                };
+                let keys = self.session.initialize_keys.clone();
+                let session = self.session_name.as_deref();
+                let initialize = session_frame(session, &Request::Initialize(credentials()))?;
+                Some(if keys.is_empty() {
+                    (initialize, None)
+                } else {
+                    let credentials = credentials();
+                    let frame =
+                        session_frame(session, &Request::InitializeWith { credentials, keys })?;
+                    (frame, Some(initialize))
+                })
+            }
+            None => None,
+        };
+        self.round_trip::<()>(Request::Reinitialize {
+            credentials: VersionedCredentials::new(cred),
+            token: token.map(Into::into),
+        })
+        .await?;
+        if let Some((initialize, shared)) = replay {
+            self.session.initialize = Some(initialize);
+            self.session.initialize_shared = shared;
+        }
+        Ok(())
     }
 
     pub async fn import(&mut self, key_data: &[u8]) -> Result<ImportResult, Error> {
-        self.round_trip::<ImportResult>(Request::Import(key_data.into()))
-            .await
+        let res = self
+            .round_trip::<ImportResult>(Request::Import(key_data.into()))
+            .await?;
+        self.record(None)?;
+        Ok(res)
+    }
+
+    /// Splits the key into encrypted Shamir shares any `threshold` of which restore it
+    pub async fn export_shares(
+        &mut self,
+        handle: KeyHandle,
+        threshold: u8,
+        shares: u8,
+    ) -> Result<ExportSharesResult, Error> {
+        self.round_trip::<ExportSharesResult>(Request::ExportShares {
+            handle,
+            threshold,
+            shares,
+        })
+        .await
+    }
+
+    /// Restores the key from the encrypted shares and imports it
+    pub async fn import_shares(&mut self, shares: &[Vec<u8>]) -> Result<ImportResult, Error> {
+        let res = self
+            .round_trip::<ImportResult>(Request::ImportShares(shares.into()))
+            .await?;
+        self.record(None)?;
+        Ok(res)
+    }
+
+    /// Registers the encrypted key without decrypting it. The key is decrypted by the signer on first use
+    pub async fn import_deferred(
+        &mut self,
+        key_data: &[u8],
+    ) -> Result<ImportDeferredResult, Error> {
+        let res = self
+            .round_trip::<ImportDeferredResult>(Request::ImportDeferred(key_data.into()))
+            .await?;
+        self.record(None)?;
+        Ok(res)
     }
 
     pub async fn import_unencrypted(
         &mut self,
         private_key: &PrivateKey,
     ) -> Result<GenerateAndImportResult, Error> {
-        self.round_trip::<GenerateAndImportResult>(Request::ImportUnencrypted(private_key.clone()))
-            .await
+        let res = self
+            .round_trip::<GenerateAndImportResult>(Request::ImportUnencrypted(
+                private_key.clone().into(),
+            ))
+            .await?;
+        self.record(Some(Request::Import(res.encrypted_private_key.clone())))?;
+        Ok(res)
+    }
+
+    /// Imports the raw secret, e.g. exported by another signer. See `crypto::raw`
+    pub async fn import_raw(
+        &mut self,
+        key_type: KeyType,
+        secret: &[u8],
+    ) -> Result<GenerateAndImportResult, Error> {
+        let res = self
+            .round_trip::<GenerateAndImportResult>(Request::ImportUnencrypted(
+                UnencryptedKey::Raw {
+                    key_type,
+                    secret: RawSecret::Bytes(secret.into()),
+                },
+            ))
+            .await?;
+        self.record(Some(Request::Import(res.encrypted_private_key.clone())))?;
+        Ok(res)
     }
 
     pub async fn generate(&mut self, t: KeyType) -> Result<GenerateResult, Error> {
@@ -128,50 +997,1374 @@ where
         &mut self,
         t: KeyType,
     ) -> Result<GenerateAndImportResult, Error> {
-        self.round_trip::<GenerateAndImportResult>(Request::GenerateAndImport(t))
-            .await
+        let res = self
+            .round_trip::<GenerateAndImportResult>(Request::GenerateAndImport(t))
+            .await?;
+        self.record(Some(Request::Import(res.encrypted_private_key.clone())))?;
+        Ok(res)
     }
 
-    pub async fn try_sign(
+    /// Same as `generate` but a request repeated with the same `key` returns the previously
+    /// generated key. Retried after reconnecting unlike `generate`
+    pub async fn generate_idempotent(
         &mut self,
-        handle: usize,
-        msg: &[u8],
-        version: SigningVersion,
-    ) -> Result<Signature, Error> {
-        self.round_trip::<Signature>(Request::Sign {
-            handle: handle,
-            message: msg.into(),
-            version,
+        t: KeyType,
+        key: &[u8],
+    ) -> Result<GenerateResult, Error> {
+        self.round_trip::<GenerateResult>(Request::Idempotent {
+            key: key.into(),
+            request: Box::new(Request::Generate(t)),
         })
         .await
     }
 
-    pub async fn try_sign_with(
+    /// Same as `generate_and_import` but a request repeated with the same `key` returns the
+    /// previously generated key, imported again if needed. Retried after reconnecting unlike
+    /// `generate_and_import`
+    pub async fn generate_and_import_idempotent(
         &mut self,
-        key_data: &[u8],
-        msg: &[u8],
-        version: SigningVersion,
-    ) -> Result<Signature, Error> {
-        self.round_trip::<Signature>(Request::SignWith {
-            encrypted_private_key: key_data.into(),
-            message: msg.into(),
-            version,
+        t: KeyType,
+        key: &[u8],
+    ) -> Result<GenerateAndImportResult, Error> {
+        let res = self
+            .round_trip::<GenerateAndImportResult>(Request::Idempotent {
+                key: key.into(),
+                request: Box::new(Request::GenerateAndImport(t)),
+            })
+            .await?;
+        self.record(Some(Request::Import(res.encrypted_private_key.clone())))?;
+        Ok(res)
+    }
+
+    /// Derives the hardened SLIP-10 child of the key stored under the handle.
+    /// See `crypto::slip10::parse_path`
+    pub async fn derive_child(
+        &mut self,
+        handle: KeyHandle,
+        path: &[u32],
+    ) -> Result<GenerateResult, Error> {
+        self.round_trip::<GenerateResult>(Request::DeriveChild {
+            handle,
+            path: path.into(),
         })
         .await
     }
 
-    pub async fn public_key(&mut self, handle: usize) -> Result<PublicKey, Error> {
-        self.round_trip::<PublicKey>(Request::PublicKey(handle))
-            .await
+    /// Tweaks the Secp256k1 key stored under the handle into the Taproot output key committing
+    /// to `merkle_root` (empty for key path only outputs)
+    pub async fn tweak_key(
+        &mut self,
+        handle: KeyHandle,
+        merkle_root: &[u8],
+    ) -> Result<GenerateResult, Error> {
+        self.round_trip::<GenerateResult>(Request::TweakKey {
+            handle,
+            tweak: merkle_root.into(),
+        })
+        .await
     }
 
-    pub async fn public_key_from(&mut self, key_data: &[u8]) -> Result<PublicKey, Error> {
-        self.round_trip::<PublicKey>(Request::PublicKeyFrom(key_data.into()))
+    /// Exports the key stored under the handle encrypted to the recipient. Rejected unless
+    /// enabled in the signer's insecure options
+    pub async fn export_wrapped(
+        &mut self,
+        handle: KeyHandle,
+        recipient: &WrappingKey,
+    ) -> Result<ExportWrappedResult, Error> {
+        self.round_trip::<ExportWrappedResult>(Request::ExportWrapped {
+            handle,
+            recipient: recipient.clone(),
+        })
+        .await
+    }
+
+    /// Imports the key exported by another signer to the ECIES key stored under the handle
+    /// and returns it encrypted by this signer's backend
+    pub async fn import_wrapped(
+        &mut self,
+        handle: KeyHandle,
+        wrapped_key: &[u8],
+    ) -> Result<GenerateAndImportResult, Error> {
+        let res = self
+            .round_trip::<GenerateAndImportResult>(Request::ImportWrapped {
+                handle,
+                wrapped_key: wrapped_key.into(),
+            })
+            .await?;
+        self.record(Some(Request::Import(res.encrypted_private_key.clone())))?;
+        Ok(res)
+    }
+
+    /// Generates the master seed inside the signer and returns it encrypted
+    pub async fn generate_seed(&mut self) -> Result<GenerateSeedResult, Error> {
+        self.round_trip::<GenerateSeedResult>(Request::GenerateSeed)
             .await
     }
 
-    pub async fn proof_of_possession(&mut self, handle: usize) -> Result<Signature, Error> {
-        self.round_trip::<Signature>(Request::ProvePossession(handle))
+    /// Derives the key from the encrypted seed returned by `generate_seed` and the label.
+    /// The same seed and label always produce the same key
+    pub async fn generate_from_seed(
+        &mut self,
+        key_type: KeyType,
+        seed: &[u8],
+        label: &[u8],
+    ) -> Result<GenerateResult, Error> {
+        self.round_trip::<GenerateResult>(Request::GenerateFromSeed {
+            key_type,
+            seed: seed.into(),
+            label: label.into(),
+        })
+        .await
+    }
+
+    /// Generates the AES-256-GCM data key inside the signer and stores it in the session-local storage
+    pub async fn generate_data_key(&mut self) -> Result<GenerateDataKeyResult, Error> {
+        let res = self
+            .round_trip::<GenerateDataKeyResult>(Request::GenerateDataKey)
+            .await?;
+        self.record(Some(Request::ImportDataKey(res.encrypted_key.clone())))?;
+        Ok(res)
+    }
+
+    /// Imports the encrypted data key returned by `generate_data_key`
+    pub async fn import_data_key(&mut self, key_data: &[u8]) -> Result<ImportDataKeyResult, Error> {
+        let res = self
+            .round_trip::<ImportDataKeyResult>(Request::ImportDataKey(key_data.into()))
+            .await?;
+        self.record(None)?;
+        Ok(res)
+    }
+
+    /// Encrypts the data with the data key stored under the handle. `aad` is authenticated but not encrypted
+    pub async fn encrypt_data(
+        &mut self,
+        handle: KeyHandle,
+        plaintext: &[u8],
+        aad: &[u8],
+    ) -> Result<EncryptDataResult, Error> {
+        self.round_trip::<EncryptDataResult>(Request::EncryptData {
+            handle,
+            plaintext: plaintext.into(),
+            aad: aad.into(),
+        })
+        .await
+    }
+
+    pub async fn decrypt_data(
+        &mut self,
+        handle: KeyHandle,
+        ciphertext: &[u8],
+        aad: &[u8],
+    ) -> Result<DecryptDataResult, Error> {
+        self.round_trip::<DecryptDataResult>(Request::DecryptData {
+            handle,
+            ciphertext: ciphertext.into(),
+            aad: aad.into(),
+        })
+        .await
+    }
+
+    /// Decrypts the ECIES ciphertext addressed to the key stored under the handle.
+    /// Use `PublicKey::ecies_encrypt` to produce one
+    pub async fn ecies_decrypt(
+        &mut self,
+        handle: KeyHandle,
+        ciphertext: &[u8],
+    ) -> Result<EciesDecryptResult, Error> {
+        self.round_trip::<EciesDecryptResult>(Request::EciesDecrypt {
+            handle,
+            ciphertext: ciphertext.into(),
+        })
+        .await
+    }
+
+    /// Derives the key from the BIP39 mnemonic inside the signer and returns it encrypted
+    pub async fn import_mnemonic(
+        &mut self,
+        t: KeyType,
+        mnemonic: &str,
+        passphrase: &str,
+    ) -> Result<GenerateResult, Error> {
+        self.round_trip::<GenerateResult>(Request::ImportMnemonic {
+            key_type: t,
+            mnemonic: mnemonic.into(),
+            passphrase: passphrase.into(),
+        })
+        .await
+    }
+
+    /// Generates the key whose public key satisfies the constraint, optionally importing it
+    pub async fn generate_constrained(
+        &mut self,
+        t: KeyType,
+        constraint: &KeyConstraint,
+        import: bool,
+    ) -> Result<GenerateConstrainedResult, Error> {
+        let res = self
+            .round_trip::<GenerateConstrainedResult>(Request::GenerateConstrained {
+                key_type: t,
+                constraint: constraint.clone(),
+                import,
+            })
+            .await?;
+        if res.handle.is_some() {
+            self.record(Some(Request::Import(res.encrypted_private_key.clone())))?;
+        }
+        Ok(res)
+    }
+
+    pub async fn try_sign(
+        &mut self,
+        handle: KeyHandle,
+        msg: &[u8],
+        version: SigningVersion,
+        options: &SignOptions,
+    ) -> Result<Signature, Error> {
+        self.round_trip::<Signature>(Request::Sign {
+            handle: handle,
+            message: msg.into(),
+            version,
+            options: options.clone(),
+        })
+        .await
+    }
+
+    /// Sign all messages with the key stored under the handle in a single round trip
+    pub async fn try_sign_batch<M: AsRef<[u8]>>(
+        &mut self,
+        handle: KeyHandle,
+        msgs: &[M],
+        version: SigningVersion,
+        options: &SignOptions,
+    ) -> Result<Vec<Signature>, Error> {
+        self.round_trip::<Vec<Signature>>(Request::BatchSign {
+            handle,
+            msgs: msgs.iter().map(|m| m.as_ref().to_vec()).collect(),
+            version,
+            options: options.clone(),
+        })
+        .await
+    }
+
+    /// Start signing a message sent in chunks by `sign_stream_chunk`. `digest` overrides
+    /// the message digest as in `SignOptions`
+    pub async fn sign_stream_begin(
+        &mut self,
+        handle: KeyHandle,
+        version: SigningVersion,
+        digest: Option<DigestAlgorithm>,
+    ) -> Result<(), Error> {
+        self.round_trip::<()>(Request::SignStreamBegin {
+            handle,
+            version,
+            digest,
+        })
+        .await
+    }
+
+    pub async fn sign_stream_chunk(&mut self, chunk: &[u8]) -> Result<(), Error> {
+        self.round_trip::<()>(Request::SignStreamChunk(chunk.into()))
+            .await
+    }
+
+    /// Sign the message sent since `sign_stream_begin`
+    pub async fn sign_stream_finish(&mut self) -> Result<Signature, Error> {
+        self.round_trip::<Signature>(Request::SignStreamFinish)
+            .await
+    }
+
+    /// Sign the already computed 32 byte message digest with the key stored under the handle
+    pub async fn try_sign_prehashed(
+        &mut self,
+        handle: KeyHandle,
+        digest: &[u8],
+        version: SigningVersion,
+    ) -> Result<Signature, Error> {
+        self.round_trip::<Signature>(Request::SignDigest {
+            handle,
+            digest: digest.into(),
+            version,
+        })
+        .await
+    }
+
+    pub async fn try_sign_with(
+        &mut self,
+        key_data: &[u8],
+        msg: &[u8],
+        version: SigningVersion,
+        options: &SignOptions,
+    ) -> Result<Signature, Error> {
+        self.round_trip::<Signature>(Request::SignWith {
+            encrypted_private_key: key_data.into(),
+            message: msg.into(),
+            version,
+            options: options.clone(),
+        })
+        .await
+    }
+
+    pub async fn public_key(&mut self, handle: KeyHandle) -> Result<PublicKey, Error> {
+        if let Some(pk) = self
+            .public_keys
+            .as_mut()
+            .and_then(|cache| cache.handles.get(&handle))
+        {
+            return Ok(pk.clone());
+        }
+        let pk = self
+            .round_trip::<PublicKey>(Request::PublicKey(handle))
+            .await?;
+        if let Some(cache) = &mut self.public_keys {
+            cache.handles.insert(handle, pk.clone());
+        }
+        Ok(pk)
+    }
+
+    /// Returns the public key stored under the handle in the specified encoding
+    pub async fn export_public_key(
+        &mut self,
+        handle: KeyHandle,
+        format: PublicKeyFormat,
+    ) -> Result<ExportPublicKeyResult, Error> {
+        self.round_trip::<ExportPublicKeyResult>(Request::ExportPublicKey { handle, format })
+            .await
+    }
+
+    pub async fn public_key_from(&mut self, key_data: &[u8]) -> Result<PublicKey, Error> {
+        if let Some(pk) = self
+            .public_keys
+            .as_mut()
+            .and_then(|cache| cache.blobs.get(&key_data.to_vec()))
+        {
+            return Ok(pk.clone());
+        }
+        let pk = self
+            .round_trip::<PublicKey>(Request::PublicKeyFrom(key_data.into()))
+            .await?;
+        if let Some(cache) = &mut self.public_keys {
+            cache.blobs.insert(key_data.into(), pk.clone());
+        }
+        Ok(pk)
+    }
+
+    /// Verify the signature. Returns `false` if the signature doesn't match
+    pub async fn verify(
+        &mut self,
+        public_key: &PublicKey,
+        msg: &[u8],
+        signature: &Signature,
+        version: SigningVersion,
+    ) -> Result<bool, Error> {
+        self.round_trip::<bool>(Request::Verify {
+            public_key: public_key.clone(),
+            message: msg.into(),
+            signature: signature.clone(),
+            version,
+        })
+        .await
+    }
+
+    /// Verify the aggregate BLS signature of the message by all of the keys. Returns `false` if the signature doesn't match
+    pub async fn verify_aggregate(
+        &mut self,
+        public_keys: &[PublicKey],
+        msg: &[u8],
+        signature: &Signature,
+        version: SigningVersion,
+    ) -> Result<bool, Error> {
+        self.round_trip::<bool>(Request::VerifyAggregate {
+            public_keys: public_keys.into(),
+            message: msg.into(),
+            signature: signature.clone(),
+            version,
+        })
+        .await
+    }
+
+    /// Verify the Ed25519 signatures of the messages by the corresponding keys at once.
+    /// Returns `false` if any of the signatures doesn't match
+    pub async fn verify_batch(
+        &mut self,
+        public_keys: &[PublicKey],
+        messages: &[Vec<u8>],
+        signatures: &[Signature],
+    ) -> Result<bool, Error> {
+        self.round_trip::<bool>(Request::VerifyBatch {
+            public_keys: public_keys.into(),
+            messages: messages.into(),
+            signatures: signatures.into(),
+        })
+        .await
+    }
+
+    /// Starts the MuSig2 signing session by the key set. The session doesn't survive reconnection
+    pub async fn musig2_nonce(
+        &mut self,
+        handle: KeyHandle,
+        public_keys: &[PublicKey],
+        msg: &[u8],
+    ) -> Result<MuSig2NonceResult, Error> {
+        self.round_trip::<MuSig2NonceResult>(Request::MuSig2Nonce {
+            handle,
+            public_keys: public_keys.into(),
+            message: msg.into(),
+        })
+        .await
+    }
+
+    /// Returns the partial signature given the public nonces of all signers. The session can be used once
+    pub async fn musig2_sign(
+        &mut self,
+        session: usize,
+        pub_nonces: &[Vec<u8>],
+    ) -> Result<MuSig2SignResult, Error> {
+        self.round_trip::<MuSig2SignResult>(Request::MuSig2Sign {
+            session,
+            pub_nonces: pub_nonces.into(),
+        })
+        .await
+    }
+
+    /// Combines the partial signatures into the Schnorr signature
+    pub async fn musig2_aggregate(
+        &mut self,
+        public_keys: &[PublicKey],
+        msg: &[u8],
+        pub_nonces: &[Vec<u8>],
+        partial_signatures: &[Vec<u8>],
+    ) -> Result<MuSig2AggregateResult, Error> {
+        self.round_trip::<MuSig2AggregateResult>(Request::MuSig2Aggregate {
+            public_keys: public_keys.into(),
+            message: msg.into(),
+            pub_nonces: pub_nonces.into(),
+            partial_signatures: partial_signatures.into(),
+        })
+        .await
+    }
+
+    /// Imports the trusted public key. The handle can be passed to `verify_with`
+    pub async fn import_public_key(
+        &mut self,
+        public_key: &PublicKey,
+    ) -> Result<ImportResult, Error> {
+        let res = self
+            .round_trip::<ImportResult>(Request::ImportPublicKey(public_key.clone()))
+            .await?;
+        self.record(None)?;
+        Ok(res)
+    }
+
+    /// Lists the keys stored in the signer, e.g. to reconcile the handles after reconnecting
+    pub async fn list_keys(&mut self) -> Result<Vec<KeyInfo>, Error> {
+        self.round_trip::<Vec<KeyInfo>>(Request::ListKeys).await
+    }
+
+    /// Re-encrypts the blob with the configured backend key or with `key_id`, e.g. after
+    /// rotating the KMS key
+    pub async fn rewrap(
+        &mut self,
+        key_data: &[u8],
+        key_id: Option<&str>,
+    ) -> Result<RewrapResult, Error> {
+        self.round_trip::<RewrapResult>(Request::Rewrap {
+            key_data: key_data.into(),
+            key_id: key_id.map(String::from),
+        })
+        .await
+    }
+
+    /// Returns the per-key usage statistics, e.g. to find keys which are never used
+    pub async fn key_stats(&mut self) -> Result<Vec<KeyStats>, Error> {
+        self.round_trip::<Vec<KeyStats>>(Request::KeyStats).await
+    }
+
+    /// Attaches the label and metadata to the key, e.g. right after importing it
+    pub async fn set_metadata(
+        &mut self,
+        handle: KeyHandle,
+        metadata: KeyMetadata,
+    ) -> Result<(), Error> {
+        self.round_trip::<()>(Request::SetMetadata { handle, metadata })
+            .await?;
+        self.record(None)
+    }
+
+    /// Makes the key expire after `ttl`, e.g. for short-lived session keys. `None` removes the expiry.
+    /// The expiry is re-applied relative to the reconnection time if the session is replayed
+    pub async fn set_expiry(
+        &mut self,
+        handle: KeyHandle,
+        ttl: Option<Duration>,
+    ) -> Result<(), Error> {
+        self.round_trip::<()>(Request::SetExpiry {
+            handle,
+            ttl: ttl.map(|ttl| ttl.as_secs()),
+        })
+        .await?;
+        self.record(None)
+    }
+
+    /// Looks the key up by its label
+    pub async fn find_label(&mut self, label: &str) -> Result<Option<KeyInfo>, Error> {
+        self.round_trip::<Option<KeyInfo>>(Request::FindLabel(label.into()))
+            .await
+    }
+
+    /// Looks the key up by its public key, e.g. to recover the handle of a trusted key imported
+    /// by another connection sharing the keychain
+    pub async fn find_public_key(
+        &mut self,
+        public_key: &PublicKey,
+    ) -> Result<Option<KeyInfo>, Error> {
+        self.round_trip::<Option<KeyInfo>>(Request::FindPublicKey(public_key.clone()))
+            .await
+    }
+
+    /// Drops the key from the signer. The handle becomes invalid
+    pub async fn remove(&mut self, handle: KeyHandle) -> Result<(), Error> {
+        self.round_trip::<()>(Request::Remove(handle)).await?;
+        self.invalidate_public_key(handle);
+        self.record(None)
+    }
+
+    /// Verify the signature using the public key stored under the handle
+    pub async fn verify_with(
+        &mut self,
+        handle: KeyHandle,
+        msg: &[u8],
+        signature: &Signature,
+        version: SigningVersion,
+    ) -> Result<bool, Error> {
+        self.round_trip::<bool>(Request::VerifyWith {
+            handle,
+            message: msg.into(),
+            signature: signature.clone(),
+            version,
+        })
+        .await
+    }
+
+    /// Returns the CDDL description of the server's wire protocol
+    pub async fn describe(&mut self) -> Result<String, Error> {
+        self.round_trip::<String>(Request::Describe).await
+    }
+
+    /// Returns the server version and the insecure options it was started with. Available before initialization
+    pub async fn hello(&mut self) -> Result<HelloResult, Error> {
+        self.round_trip::<HelloResult>(Request::Hello).await
+    }
+
+    /// Returns the attestation document of the enclave binding `nonce` and `user_data`,
+    /// available before `Initialize`
+    pub async fn attest(&mut self, nonce: &[u8], user_data: &[u8]) -> Result<AttestResult, Error> {
+        self.round_trip::<AttestResult>(Request::Attest {
+            nonce: nonce.into(),
+            user_data: user_data.into(),
+        })
+        .await
+    }
+
+    /// Enables compression of the request and response payloads of at least `min_size` bytes
+    /// if the server supports any of the algorithms known to the client. Returns the selected
+    /// one. Not replayed after reconnection, the responses are uncompressed from then on
+    pub async fn negotiate_compression(
+        &mut self,
+        min_size: usize,
+    ) -> Result<Option<Compression>, Error> {
+        let res = self
+            .round_trip::<NegotiateCompressionResult>(Request::NegotiateCompression {
+                algorithms: codec::SUPPORTED_COMPRESSION.to_vec(),
+                min_size: min_size as u64,
+            })
+            .await?;
+        self.compression = res.algorithm.map(|alg| (alg, min_size));
+        Ok(res.algorithm)
+    }
+
+    /// Asks the server to push events, available before `Initialize`. They are passed to the
+    /// `with_events` callback while waiting for responses and returned by `next_event` otherwise.
+    /// The subscription isn't renewed after reconnection
+    pub async fn subscribe(&mut self) -> Result<(), Error> {
+        self.round_trip::<()>(Request::Subscribe).await
+    }
+
+    /// Waits for the next event pushed after `subscribe`
+    pub async fn next_event(&mut self) -> Result<Event, Error> {
+        loop {
+            read_message(&mut self.socket, &mut self.buf, None, self.max_frame_size).await?;
+            match Interim::try_from_cbor(&self.buf)? {
+                Interim::Event(event) => return Ok(event),
+                // left over from a request
+                Interim::Progress(_) | Interim::BackendState(_) => (),
+            }
+        }
+    }
+
+    /// Returns the server capabilities and the session state, available before `Initialize`
+    pub async fn get_info(&mut self) -> Result<InfoResult, Error> {
+        self.round_trip::<InfoResult>(Request::GetInfo).await
+    }
+
+    /// Checks the connection is alive without touching the keys. `payload` is echoed back
+    pub async fn ping(&mut self, payload: &[u8]) -> Result<PongResult, Error> {
+        self.round_trip::<PongResult>(Request::Ping {
+            payload: payload.into(),
+        })
+        .await
+    }
+
+    /// Makes the signer drop the keys of the session and close the connection. With the
+    /// server's shutdown `token` the whole signer is shut down
+    pub async fn shutdown(&mut self, token: Option<&str>) -> Result<(), Error> {
+        self.round_trip::<()>(Request::Shutdown {
+            token: token.map(String::from),
+        })
+        .await
+    }
+
+    pub async fn proof_of_possession(&mut self, handle: KeyHandle) -> Result<Signature, Error> {
+        self.round_trip::<Signature>(Request::ProvePossession(handle))
+            .await
+    }
+}
+
+/// Configures a client in one place and connects it. The client reconnects the same way
+/// as one created by `Client::with_reconnect`
+pub struct ClientBuilder<T, C> {
+    connect: ConnectFn<T>,
+    request_key: Option<RequestKey>,
+    heartbeat_timeout: Option<Duration>,
+    write_timeout: Option<Duration>,
+    request_timeout: Option<Duration>,
+    reconnect_policy: ReconnectPolicy,
+    max_frame_size: usize,
+    compression: Option<usize>,
+    interceptors: Vec<Box<dyn Interceptor>>,
+    _phantom: PhantomData<C>,
+}
+
+impl<T, C> ClientBuilder<T, C> {
+    /// Creates a builder of a client connecting and reconnecting using `connect`
+    pub fn new<F, Fut>(mut connect: F) -> Self
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = std::io::Result<T>> + Send + 'static,
+    {
+        Self {
+            connect: Box::new(move || Box::pin(connect())),
+            request_key: None,
+            heartbeat_timeout: None,
+            write_timeout: None,
+            request_timeout: None,
+            reconnect_policy: ReconnectPolicy::default(),
+            max_frame_size: usize::MAX,
+            compression: None,
+            interceptors: Vec::new(),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// See `Client::with_request_key`
+    pub fn with_request_key(mut self, key: &[u8]) -> Self {
+        self.request_key = Some(RequestKey(key.into()));
+        self
+    }
+
+    /// See `Client::with_heartbeat_timeout`
+    pub fn with_heartbeat_timeout(mut self, timeout: Duration) -> Self {
+        self.heartbeat_timeout = Some(timeout);
+        self
+    }
+
+    /// See `Client::with_write_timeout`
+    pub fn with_write_timeout(mut self, timeout: Duration) -> Self {
+        self.write_timeout = Some(timeout);
+        self
+    }
+
+    /// See `Client::with_request_timeout`
+    pub fn with_request_timeout(mut self, timeout: Duration) -> Self {
+        self.request_timeout = Some(timeout);
+        self
+    }
+
+    /// Applies to the initial connection as well as to reconnection
+    pub fn with_reconnect_policy(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect_policy = policy;
+        self
+    }
+
+    /// See `Client::with_max_frame_size`
+    pub fn with_max_frame_size(mut self, size: usize) -> Self {
+        self.max_frame_size = size;
+        self
+    }
+
+    /// See `Client::with_interceptor`
+    pub fn with_interceptor<I: Interceptor + 'static>(mut self, interceptor: I) -> Self {
+        self.interceptors.push(Box::new(interceptor));
+        self
+    }
+
+    /// Negotiates compression of the payloads of at least `min_size` bytes once connected,
+    /// see `Client::negotiate_compression`
+    pub fn with_compression(mut self, min_size: usize) -> Self {
+        self.compression = Some(min_size);
+        self
+    }
+}
+
+impl<T: Send + 'static, C> ClientBuilder<T, C> {
+    /// Creates a builder of a client connecting and reconnecting using `connector`
+    pub fn from_connector<K: Connector<Stream = T>>(connector: K) -> Self {
+        Self::new(move || connector.connect())
+    }
+}
+
+impl<C> ClientBuilder<vsock::asio::Stream, C> {
+    /// Creates a builder of a client connecting to the signer at `addr`
+    pub fn vsock(addr: vsock::SocketAddr) -> Self {
+        Self::from_connector(VsockConnector(addr))
+    }
+}
+
+impl<C> ClientBuilder<tokio::net::UnixStream, C> {
+    /// Creates a builder of a client connecting to the signer listening on `path`
+    pub fn unix(path: impl Into<std::path::PathBuf>) -> Self {
+        Self::from_connector(UnixConnector(path.into()))
+    }
+}
+
+impl<C> ClientBuilder<tokio::net::TcpStream, C> {
+    /// Creates a builder of a client connecting to the signer at `addr`
+    pub fn tcp(addr: std::net::SocketAddr) -> Self {
+        Self::from_connector(TcpConnector(addr))
+    }
+}
+
+impl<T, C> ClientBuilder<T, C>
+where
+    T: AsyncRead + AsyncWriteExt + Unpin,
+    C: Serialize + CredentialsSchema,
+{
+    /// Connects repeating the failed attempts according to the reconnection policy
+    pub async fn connect(mut self) -> Result<Client<T, C>, Error> {
+        let mut failed = 0;
+        let socket = loop {
+            match (self.connect)().await {
+                Ok(socket) => break socket,
+                Err(_) if failed + 1 < self.reconnect_policy.max_attempts => {
+                    failed += 1;
+                    tokio::time::sleep(self.reconnect_policy.delay(failed)).await;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        };
+
+        let mut client = Client {
+            connect: Some(self.connect),
+            request_key: self.request_key,
+            heartbeat_timeout: self.heartbeat_timeout,
+            write_timeout: self.write_timeout,
+            request_timeout: self.request_timeout,
+            reconnect_policy: self.reconnect_policy,
+            max_frame_size: self.max_frame_size,
+            interceptors: self.interceptors,
+            ..Client::new(socket)
+        };
+        if let Some(min_size) = self.compression {
+            client.negotiate_compression(min_size).await?;
+        }
+        Ok(client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Client, ClientBuilder, Compression, Error, Interceptor, ReconnectPolicy};
+    use crate::crypto::{
+        KeyConstraint, KeyType, PrivateKey, SharedKeychain, SignOptions, SigningVersion,
+    };
+    use crate::rpc::VersionedCredentials;
+    use crate::rpc::{
+        codec, encode_frame, server::Server, ErrorCode, Progress, Request, Result as RPCResult,
+        HEARTBEAT_FRAME,
+    };
+    use crate::tests::{DummyCredentials, Flaky, PassthroughFactory};
+    use crate::{BackendState, TryFromCBOR, TryIntoCBOR};
+    use std::sync::atomic::{AtomicU32, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use tokio::task::JoinHandle;
+
+    fn connect() -> (DuplexStream, JoinHandle<()>) {
+        let (client, server) = tokio::io::duplex(4096);
+        let task = tokio::spawn(async move {
+            let mut srv = Server::new(PassthroughFactory, rand_core::OsRng);
+            let _ = srv.serve_connection(server).await;
+        });
+        (client, task)
+    }
+
+    #[tokio::test]
+    async fn reconnect() {
+        let (sock, task) = connect();
+        let mut client =
+            Client::<_, DummyCredentials>::with_reconnect(sock, || async { Ok(connect().0) });
+        client.initialize(DummyCredentials {}).await.unwrap();
+        let res = client
+            .generate_and_import(KeyType::Secp256k1)
+            .await
+            .unwrap();
+
+        // drop the connection
+        task.abort();
+        let _ = task.await;
+
+        let sig = client
+            .try_sign(
+                res.handle,
+                b"text",
+                SigningVersion::Latest,
+                &SignOptions::default(),
+            )
+            .await
+            .unwrap();
+        assert!(client
+            .verify(&res.public_key, b"text", &sig, SigningVersion::Latest)
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn reconnect_sessions() {
+        let (sock, task) = connect();
+        let mut client =
+            Client::<_, DummyCredentials>::with_reconnect(sock, || async { Ok(connect().0) })
+                .with_request_key(b"request key");
+        client.initialize(DummyCredentials {}).await.unwrap();
+        let own = client.generate_and_import(KeyType::Ed25519).await.unwrap();
+        let mut session = client.session("tenant");
+        session.initialize(DummyCredentials {}).await.unwrap();
+        let tenant = session.generate_and_import(KeyType::Ed25519).await.unwrap();
+        drop(session);
+
+        task.abort();
+        let _ = task.await;
+
+        // both sessions are replayed on the new connection
+        assert_eq!(client.list_keys().await.unwrap().len(), 1);
+        let mut session = client.session("tenant");
+        for _ in 0..2 {
+            let keys = session.list_keys().await.unwrap();
+            assert_eq!(keys.len(), 1);
+            assert_eq!(keys[0].handle, tenant.handle);
+        }
+        let sig = session
+            .try_sign(
+                tenant.handle,
+                b"text",
+                SigningVersion::Latest,
+                &SignOptions::default(),
+            )
+            .await
+            .unwrap();
+        tenant.public_key.verify(b"text", &sig).unwrap();
+        drop(session);
+        let keys = client.list_keys().await.unwrap();
+        assert_eq!(keys[0].handle, own.handle);
+    }
+
+    #[tokio::test]
+    async fn reconnect_data_key() {
+        let (sock, task) = connect();
+        let mut client =
+            Client::<_, DummyCredentials>::with_reconnect(sock, || async { Ok(connect().0) });
+        client.initialize(DummyCredentials {}).await.unwrap();
+        // data keys share the handle space with signing keys
+        let data_key = client.generate_data_key().await.unwrap();
+        let imported = client
+            .import_data_key(&data_key.encrypted_key)
+            .await
+            .unwrap();
+        let key = client.generate_and_import(KeyType::Ed25519).await.unwrap();
+        let ct = client
+            .encrypt_data(imported.handle, b"text", b"")
+            .await
+            .unwrap();
+
+        task.abort();
+        let _ = task.await;
+
+        let sig = client
+            .try_sign(
+                key.handle,
+                b"text",
+                SigningVersion::Latest,
+                &SignOptions::default(),
+            )
+            .await
+            .unwrap();
+        key.public_key.verify(b"text", &sig).unwrap();
+        let res = client
+            .decrypt_data(data_key.handle, &ct.ciphertext, b"")
+            .await
+            .unwrap();
+        assert_eq!(res.plaintext, b"text");
+    }
+
+    #[tokio::test]
+    async fn reconnect_shared_keychain() {
+        let keychain = SharedKeychain::new();
+        let connect = |keychain: &SharedKeychain| {
+            let (client, server) = tokio::io::duplex(4096);
+            let keychain = keychain.clone();
+            let task = tokio::spawn(async move {
+                let mut srv = Server::new(PassthroughFactory, rand_core::OsRng)
+                    .with_shared_keychain(keychain);
+                let _ = srv.serve_connection(server).await;
+            });
+            (client, task)
+        };
+
+        let (sock, task) = connect(&keychain);
+        let k = keychain.clone();
+        let mut client = Client::<_, DummyCredentials>::with_reconnect(sock, move || {
+            let sock = connect(&k).0;
+            async move { Ok(sock) }
+        });
+        let key = PrivateKey::generate(KeyType::Ed25519, &mut rand_core::OsRng).unwrap();
+        let mut initial = client
+            .initialize_with(DummyCredentials {}, &[key.try_into_cbor().unwrap()])
+            .await
+            .unwrap();
+        let initial = initial.remove(0);
+        let generated = client.generate_and_import(KeyType::Ed25519).await.unwrap();
+        let removed = client.generate_and_import(KeyType::Ed25519).await.unwrap();
+        client.remove(removed.handle).await.unwrap();
+
+        task.abort();
+        let _ = task.await;
+
+        // the keys are neither duplicated nor removed again
+        for (handle, public_key) in [
+            (initial.handle, initial.public_key),
+            (generated.handle, generated.public_key),
+        ] {
+            let sig = client
+                .try_sign(
+                    handle,
+                    b"text",
+                    SigningVersion::Latest,
+                    &SignOptions::default(),
+                )
+                .await
+                .unwrap();
+            public_key.verify(b"text", &sig).unwrap();
+        }
+        assert_eq!(keychain.read().list().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn reconnect_backoff() {
+        let policy = ReconnectPolicy {
+            max_attempts: 3,
+            initial_delay: Duration::from_millis(4),
+            max_delay: Duration::from_millis(10),
+        };
+        for (failed, min, max) in [(1, 2, 4), (2, 4, 8), (3, 5, 10), (20, 5, 10)] {
+            let delay = policy.delay(failed);
+            assert!(delay >= Duration::from_millis(min) && delay <= Duration::from_millis(max));
+        }
+
+        let (sock, task) = connect();
+        let attempts = Arc::new(AtomicU32::new(0));
+        let a = attempts.clone();
+        let mut client = Client::<_, DummyCredentials>::with_reconnect(sock, move || {
+            // the signer is back on the last attempt
+            let n = a.fetch_add(1, Ordering::Relaxed);
+            async move {
+                if n < 2 {
+                    Err(std::io::ErrorKind::ConnectionRefused.into())
+                } else {
+                    Ok(connect().0)
+                }
+            }
+        })
+        .with_reconnect_policy(policy);
+        client.initialize(DummyCredentials {}).await.unwrap();
+        let res = client.generate_and_import(KeyType::Ed25519).await.unwrap();
+        task.abort();
+        let _ = task.await;
+        let pk = client.public_key(res.handle).await.unwrap();
+        assert_eq!(pk.fingerprint().unwrap()[..], res.fingerprint[..]);
+        assert_eq!(attempts.load(Ordering::Relaxed), 3);
+
+        // gone for good
+        let (sock, task) = connect();
+        let mut client = Client::<_, DummyCredentials>::with_reconnect(sock, || async {
+            Err(std::io::ErrorKind::ConnectionRefused.into())
+        })
+        .with_reconnect_policy(policy);
+        client.initialize(DummyCredentials {}).await.unwrap();
+        task.abort();
+        let _ = task.await;
+        match client.get_info().await {
+            Err(Error::IO(err)) => assert_eq!(err.kind(), std::io::ErrorKind::ConnectionRefused),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    // reads a single request and sends the heartbeats optionally followed by the response
+    async fn slow_peer(mut sock: DuplexStream, heartbeats: usize, respond: bool) {
+        let mut len_buf = [0; 4];
+        sock.read_exact(&mut len_buf).await.unwrap();
+        let mut buf = vec![0; u32::from_be_bytes(len_buf) as usize];
+        sock.read_exact(&mut buf).await.unwrap();
+        for _ in 0..heartbeats {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            sock.write_all(&HEARTBEAT_FRAME).await.unwrap();
+        }
+        if respond {
+            let res = RPCResult::Ok(String::from("schema"))
+                .try_into_cbor()
+                .unwrap();
+            sock.write_all(&u32::try_from(res.len()).unwrap().to_be_bytes())
+                .await
+                .unwrap();
+            sock.write_all(&res).await.unwrap();
+        }
+        std::future::pending::<()>().await;
+    }
+
+    #[tokio::test]
+    async fn heartbeat() {
+        let (sock, peer) = tokio::io::duplex(4096);
+        tokio::spawn(slow_peer(peer, 10, true));
+        let mut client = Client::<_, DummyCredentials>::new(sock)
+            .with_heartbeat_timeout(Duration::from_millis(100));
+        assert_eq!(client.describe().await.unwrap(), "schema");
+
+        let (sock, peer) = tokio::io::duplex(4096);
+        tokio::spawn(slow_peer(peer, 2, false));
+        let mut client = Client::<_, DummyCredentials>::new(sock)
+            .with_heartbeat_timeout(Duration::from_millis(100));
+        match client.describe().await {
+            Err(Error::IO(err)) => assert_eq!(err.kind(), std::io::ErrorKind::TimedOut),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[tokio::test]
+    async fn interceptors() {
+        #[derive(Clone, Default)]
+        struct Log(Arc<Mutex<Vec<String>>>);
+
+        impl Interceptor for Log {
+            fn on_request(&mut self, name: &'static str, _payload: &mut Vec<u8>) {
+                self.0.lock().unwrap().push(format!("> {}", name));
+            }
+
+            fn on_response(&mut self, name: &'static str, response: Result<&[u8], &Error>) {
+                let res = match response.map(RPCResult::<ciborium::Value>::try_from_cbor) {
+                    Ok(Ok(Ok(_))) => "ok",
+                    Ok(_) => "failed",
+                    Err(_) => "not received",
+                };
+                self.0.lock().unwrap().push(format!("< {} {}", name, res));
+            }
+        }
+
+        // replaces the payload of pings
+        struct Rewrite;
+
+        impl Interceptor for Rewrite {
+            fn on_request(&mut self, _name: &'static str, payload: &mut Vec<u8>) {
+                if let Ok(Request::<VersionedCredentials<DummyCredentials>>::Ping { .. }) =
+                    Request::try_from_cbor(payload)
+                {
+                    *payload = Request::<VersionedCredentials<DummyCredentials>>::Ping {
+                        payload: b"rewritten".to_vec(),
+                    }
+                    .try_into_cbor()
+                    .unwrap();
+                }
+            }
+        }
+
+        let (sock, task) = connect();
+        let log = Log::default();
+        let mut client = Client::<_, DummyCredentials>::new(sock)
+            .with_interceptor(log.clone())
+            .with_interceptor(Rewrite);
+        assert_eq!(client.ping(b"payload").await.unwrap().payload, b"rewritten");
+        assert!(client.generate(KeyType::Ed25519).await.is_err());
+        task.abort();
+        let _ = task.await;
+        assert!(client.get_info().await.is_err());
+
+        assert_eq!(
+            *log.0.lock().unwrap(),
+            [
+                "> Ping",
+                "< Ping ok",
+                "> Generate",
+                "< Generate failed",
+                "> GetInfo",
+                "< GetInfo not received"
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn socket_deadlines() {
+        // the peer never reads
+        let (sock, _peer) = tokio::io::duplex(64);
+        let mut client = Client::<_, DummyCredentials>::new(sock);
+        client.set_write_timeout(Some(Duration::from_millis(50)));
+        match client.ping(&[0; 1000]).await {
+            Err(Error::IO(err)) => assert_eq!(err.kind(), std::io::ErrorKind::TimedOut),
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        // the peer never responds
+        let (sock, peer) = tokio::io::duplex(4096);
+        tokio::spawn(slow_peer(peer, 0, false));
+        let mut client = Client::<_, DummyCredentials>::new(sock);
+        client.set_read_timeout(Some(Duration::from_millis(50)));
+        match client.describe().await {
+            Err(Error::IO(err)) => assert_eq!(err.kind(), std::io::ErrorKind::TimedOut),
+            res => panic!("unexpected result: {:?}", res),
+        }
+    }
+
+    #[tokio::test]
+    async fn large_response() {
+        // many times the stream buffer, so every frame arrives in parts
+        let (sock, mut peer) = tokio::io::duplex(64);
+        let entries: Vec<String> = (0..20000).map(|i| format!("entry {}", i)).collect();
+        let expected = entries.clone();
+        tokio::spawn(async move {
+            let mut buf = Vec::new();
+            codec::read_frame(&mut peer, &mut buf, usize::MAX)
+                .await
+                .unwrap();
+            encode_frame(&mut buf, &RPCResult::Ok(entries)).unwrap();
+            assert!(buf.len() > 128 << 10);
+            peer.write_all(&buf).await.unwrap();
+            std::future::pending::<()>().await;
+        });
+        let mut client = Client::<_, DummyCredentials>::new(sock);
+        let res = client
+            .round_trip::<Vec<String>>(Request::ListKeys)
+            .await
+            .unwrap();
+        assert_eq!(res, expected);
+    }
+
+    #[tokio::test]
+    async fn request_timeout() {
+        // heartbeats don't extend the deadline
+        let (sock, peer) = tokio::io::duplex(4096);
+        tokio::spawn(slow_peer(peer, 10, true));
+        let mut client = Client::<_, DummyCredentials>::new(sock)
+            .with_request_timeout(Duration::from_millis(50));
+        match client.describe().await {
+            Err(Error::Timeout(t)) => assert_eq!(t, Duration::from_millis(50)),
+            res => panic!("unexpected result: {:?}", res),
+        }
+        match client.describe().await {
+            Err(Error::IO(err)) => assert_eq!(err.kind(), std::io::ErrorKind::NotConnected),
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        let (sock, peer) = tokio::io::duplex(4096);
+        tokio::spawn(slow_peer(peer, 0, false));
+        let mut client =
+            Client::<_, DummyCredentials>::with_reconnect(sock, || async { Ok(connect().0) })
+                .with_request_timeout(Duration::from_millis(50));
+        assert!(matches!(client.describe().await, Err(Error::Timeout(_))));
+        client.set_request_timeout(None);
+        assert!(client.describe().await.unwrap().contains("Request"));
+    }
+
+    #[tokio::test]
+    async fn builder() {
+        let attempts = Arc::new(AtomicU32::new(0));
+        let a = attempts.clone();
+        let mut client = ClientBuilder::<_, DummyCredentials>::new(move || {
+            let n = a.fetch_add(1, Ordering::Relaxed);
+            async move {
+                if n == 0 {
+                    Err(std::io::ErrorKind::ConnectionRefused.into())
+                } else {
+                    Ok(connect().0)
+                }
+            }
+        })
+        .with_reconnect_policy(ReconnectPolicy {
+            max_attempts: 2,
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(1),
+        })
+        .with_request_timeout(Duration::from_secs(10))
+        .with_max_frame_size(1000)
+        .with_compression(100)
+        .connect()
+        .await
+        .unwrap();
+        assert_eq!(attempts.load(Ordering::Relaxed), 2);
+        assert_eq!(client.compression, Some((Compression::Zstd, 100)));
+
+        client.initialize(DummyCredentials {}).await.unwrap();
+        assert_eq!(client.ping(&[1; 100]).await.unwrap().payload, [1; 100]);
+        // compressed below the limit but not once decompressed
+        match client.ping(&[1; 2000]).await {
+            Err(Error::IO(err)) => assert_eq!(err.kind(), std::io::ErrorKind::InvalidData),
+            res => panic!("unexpected result: {:?}", res),
+        }
+
+        let res = ClientBuilder::<DuplexStream, DummyCredentials>::new(|| async {
+            Err(std::io::ErrorKind::ConnectionRefused.into())
+        })
+        .with_reconnect_policy(ReconnectPolicy {
+            max_attempts: 3,
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(1),
+        })
+        .connect()
+        .await;
+        assert!(matches!(res, Err(Error::IO(_))));
+    }
+
+    #[tokio::test]
+    async fn public_key_cache() {
+        let (sock, task) = connect();
+        let mut client = Client::<_, DummyCredentials>::new(sock).with_public_key_cache(16);
+        client.initialize(DummyCredentials {}).await.unwrap();
+        let a = client.generate_and_import(KeyType::Ed25519).await.unwrap();
+        let b = client.generate_and_import(KeyType::Ed25519).await.unwrap();
+        client.public_key(a.handle).await.unwrap();
+        client.public_key(b.handle).await.unwrap();
+        client
+            .public_key_from(&a.encrypted_private_key)
+            .await
+            .unwrap();
+
+        client.remove(b.handle).await.unwrap();
+        assert!(client.public_key(b.handle).await.is_err());
+
+        // served from the cache once the connection is gone
+        task.abort();
+        let _ = task.await;
+        let pk = client.public_key(a.handle).await.unwrap();
+        assert_eq!(pk.fingerprint().unwrap(), a.fingerprint.as_slice());
+        let pk = client
+            .public_key_from(&a.encrypted_private_key)
+            .await
+            .unwrap();
+        assert_eq!(pk.fingerprint().unwrap(), a.fingerprint.as_slice());
+
+        client.invalidate_public_key(a.handle);
+        assert!(matches!(
+            client.public_key(a.handle).await,
+            Err(Error::IO(_))
+        ));
+        client.clear_public_key_cache();
+        assert!(matches!(
+            client.public_key_from(&a.encrypted_private_key).await,
+            Err(Error::IO(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn error_chain() {
+        let (sock, task) = connect();
+        let mut client = Client::<_, DummyCredentials>::new(sock);
+        let err = client.generate(KeyType::Ed25519).await.unwrap_err();
+        assert_eq!(err.code(), Some(ErrorCode::Uninitialized));
+        let rpc = err.rpc().unwrap();
+        assert_eq!(
+            rpc.root_cause().message,
+            rpc.chain().last().unwrap().message
+        );
+        assert!(std::error::Error::source(&err).is_none());
+
+        task.abort();
+        let _ = task.await;
+        let err = client.ping(b"").await.unwrap_err();
+        assert!(err.rpc().is_none());
+        assert!(std::error::Error::source(&err)
+            .unwrap()
+            .is::<std::io::Error>());
+    }
+
+    #[tokio::test]
+    async fn backend_state() {
+        let (sock, server_sock) = tokio::io::duplex(4096);
+        let backend = Flaky::default();
+        let mut srv = Server::new(backend.clone(), rand_core::OsRng);
+        tokio::spawn(async move {
+            let _ = srv.serve_connection(server_sock).await;
+        });
+
+        let states = Arc::new(Mutex::new(Vec::new()));
+        let s = states.clone();
+        let mut client = Client::<_, DummyCredentials>::new(sock)
+            .with_backend_state(move |state| s.lock().unwrap().push(state));
+        client.initialize(DummyCredentials {}).await.unwrap();
+        let res = client.generate_and_import(KeyType::Ed25519).await.unwrap();
+
+        backend.0.store(true, Ordering::Relaxed);
+        let err = client.import(&res.encrypted_private_key).await.unwrap_err();
+        assert!(err.to_string().contains("encryption backend unavailable"));
+        client
+            .try_sign(
+                res.handle,
+                b"text",
+                SigningVersion::Latest,
+                &SignOptions::default(),
+            )
+            .await
+            .unwrap();
+
+        backend.0.store(false, Ordering::Relaxed);
+        client.import(&res.encrypted_private_key).await.unwrap();
+        assert_eq!(
+            *states.lock().unwrap(),
+            [BackendState::Unavailable, BackendState::Available]
+        );
+    }
+
+    #[tokio::test]
+    async fn progress() {
+        let (sock, _task) = connect();
+        let reports = Arc::new(Mutex::new(Vec::new()));
+        let r = reports.clone();
+        let mut client = Client::<_, DummyCredentials>::new(sock)
+            .with_progress(move |p| r.lock().unwrap().push(p));
+        client.initialize(DummyCredentials {}).await.unwrap();
+
+        let constraint = KeyConstraint::new("0000000000").with_max_attempts(3 * 4096);
+        let err = client
+            .generate_constrained(KeyType::Ed25519, &constraint, false)
             .await
+            .unwrap_err();
+        assert!(err.to_string().contains("after 12288 attempts"));
+        assert_eq!(
+            *reports.lock().unwrap(),
+            [
+                Progress {
+                    completed: 4096,
+                    total: 12288
+                },
+                Progress {
+                    completed: 8192,
+                    total: 12288
+                }
+            ]
+        );
     }
 }
@@ -0,0 +1,346 @@
+//! Length prefixed framing shared by the server, the client and the transports built on it
+//!
+//! Every frame is a 4 byte big endian payload length followed by the payload. A zero length
+//! frame is a heartbeat. Reads and writes go through `read_exact`/`write_all`, so frames split
+//! across any number of partial socket operations are reassembled transparently.
+//!
+//! Once negotiated, large payloads are replaced by `{ Zstd: bstr }` or `{ Deflate: bstr }` holding
+//! the Zstandard frame or the raw DEFLATE stream of the original payload.
+use crate::cddl::Cddl;
+use crate::serde_helper::bytes;
+use crate::{TryFromCBOR, TryIntoCBOR};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::io::{self, Read};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Zero length frame sent by the server while a request is still being processed.
+/// Never carries a response and is skipped by the client
+pub const HEARTBEAT_FRAME: [u8; 4] = [0; 4];
+
+/// Size of the big endian length prefix of every frame
+pub const FRAME_PREFIX_LEN: usize = 4;
+
+/// Clears `buf` and reserves the length prefix slot. The payload is then appended in place
+pub(crate) fn begin_frame(buf: &mut Vec<u8>) {
+    buf.clear();
+    buf.extend_from_slice(&[0; FRAME_PREFIX_LEN]);
+}
+
+/// Fills the length prefix reserved by `begin_frame`
+pub(crate) fn finish_frame(buf: &mut [u8]) {
+    let len = u32::try_from(buf.len() - FRAME_PREFIX_LEN).unwrap();
+    buf[..FRAME_PREFIX_LEN].copy_from_slice(&len.to_be_bytes());
+}
+
+/// Serializes `value` into `buf` as a complete length prefixed frame without an intermediate copy.
+/// The buffer is meant to be reused so its capacity is kept between frames
+pub fn encode_frame<T: Serialize>(
+    buf: &mut Vec<u8>,
+    value: &T,
+) -> std::result::Result<(), ciborium::ser::Error<std::io::Error>> {
+    begin_frame(buf);
+    value.try_into_writer(&mut *buf)?;
+    finish_frame(buf);
+    Ok(())
+}
+
+/// Frame compression algorithm
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, Cddl)]
+#[non_exhaustive]
+pub enum Compression {
+    Deflate,
+    /// Zstandard (RFC 8878), implemented by the pure Rust `ruzstd` crate
+    Zstd,
+    /// Algorithm unknown to this side
+    #[serde(other)]
+    Unknown,
+}
+
+/// Algorithms supported by this side in the order of preference
+pub const SUPPORTED_COMPRESSION: &[Compression] = &[Compression::Zstd, Compression::Deflate];
+
+const DEFLATE_LEVEL: u8 = 6;
+
+/// Start of the compressed payloads: a single entry map with the algorithm key
+const DEFLATE_PREFIX: &[u8] = b"\xa1\x67Deflate";
+const ZSTD_PREFIX: &[u8] = b"\xa1\x64Zstd";
+
+#[derive(Serialize, Deserialize)]
+enum Compressed {
+    Deflate(#[serde(with = "bytes")] Vec<u8>),
+    Zstd(#[serde(with = "bytes")] Vec<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecompressionError {
+    Invalid,
+    /// The decompressed payload is larger than the limit
+    TooLarge(usize),
+}
+
+impl std::fmt::Display for DecompressionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecompressionError::Invalid => f.write_str("invalid compressed frame"),
+            DecompressionError::TooLarge(max) => {
+                write!(f, "decompressed frame is larger than {} bytes", max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DecompressionError {}
+
+/// Compresses the payload of the complete frame in `buf` in place with `algorithm` if it's at
+/// least `min_size` bytes long and gets shorter. `Unknown` leaves the frame as it is
+pub fn compress_frame(
+    buf: &mut Vec<u8>,
+    algorithm: Compression,
+    min_size: usize,
+) -> std::result::Result<(), ciborium::ser::Error<std::io::Error>> {
+    let payload = &buf[FRAME_PREFIX_LEN..];
+    if payload.len() < min_size {
+        return Ok(());
+    }
+    let (compressed, prefix_len) = match algorithm {
+        Compression::Deflate => (
+            Compressed::Deflate(miniz_oxide::deflate::compress_to_vec(
+                payload,
+                DEFLATE_LEVEL,
+            )),
+            DEFLATE_PREFIX.len(),
+        ),
+        Compression::Zstd => (
+            Compressed::Zstd(ruzstd::encoding::compress_to_vec(
+                payload,
+                ruzstd::encoding::CompressionLevel::Fastest,
+            )),
+            ZSTD_PREFIX.len(),
+        ),
+        Compression::Unknown => return Ok(()),
+    };
+    let (Compressed::Deflate(data) | Compressed::Zstd(data)) = &compressed;
+    // map and byte string headers
+    if data.len() + prefix_len + 9 >= payload.len() {
+        return Ok(());
+    }
+    encode_frame(buf, &compressed)
+}
+
+/// Returns the decompressed payload of a compressed frame or `payload` itself otherwise
+pub fn decompress(payload: &[u8], max: usize) -> Result<Cow<'_, [u8]>, DecompressionError> {
+    if !payload.starts_with(DEFLATE_PREFIX) && !payload.starts_with(ZSTD_PREFIX) {
+        return Ok(Cow::Borrowed(payload));
+    }
+    match Compressed::try_from_cbor(payload).or(Err(DecompressionError::Invalid))? {
+        Compressed::Deflate(data) => miniz_oxide::inflate::decompress_to_vec_with_limit(&data, max)
+            .map(Cow::Owned)
+            .map_err(|err| match err.status {
+                miniz_oxide::inflate::TINFLStatus::HasMoreOutput => {
+                    DecompressionError::TooLarge(max)
+                }
+                _ => DecompressionError::Invalid,
+            }),
+        Compressed::Zstd(data) => {
+            let decoder = ruzstd::decoding::StreamingDecoder::new(&data[..])
+                .or(Err(DecompressionError::Invalid))?;
+            // one byte past the limit tells a too large payload apart
+            let mut out = Vec::new();
+            decoder
+                .take(max.saturating_add(1) as u64)
+                .read_to_end(&mut out)
+                .or(Err(DecompressionError::Invalid))?;
+            if out.len() > max {
+                return Err(DecompressionError::TooLarge(max));
+            }
+            Ok(Cow::Owned(out))
+        }
+    }
+}
+
+/// Reads the length prefix of the next frame
+pub async fn read_len<T: AsyncRead + Unpin>(rd: &mut T) -> io::Result<usize> {
+    let mut len = [0; FRAME_PREFIX_LEN];
+    rd.read_exact(&mut len).await?;
+    Ok(u32::from_be_bytes(len) as usize)
+}
+
+/// Reads the payload of `len` bytes into `buf` replacing its contents
+pub async fn read_payload<T: AsyncRead + Unpin>(
+    rd: &mut T,
+    len: usize,
+    buf: &mut Vec<u8>,
+) -> io::Result<()> {
+    buf.resize(len, 0);
+    rd.read_exact(buf).await?;
+    Ok(())
+}
+
+/// Discards the payload of `len` bytes without buffering it
+pub async fn skip_payload<T: AsyncRead + Unpin>(rd: &mut T, len: usize) -> io::Result<()> {
+    let skipped = tokio::io::copy(&mut rd.take(len as u64), &mut tokio::io::sink()).await?;
+    if skipped != len as u64 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+/// Reads a complete frame into `buf`, empty for a heartbeat. A payload longer than `max`
+/// fails with `io::ErrorKind::InvalidData` before being read
+pub async fn read_frame<T: AsyncRead + Unpin>(
+    rd: &mut T,
+    buf: &mut Vec<u8>,
+    max: usize,
+) -> io::Result<()> {
+    let len = read_len(rd).await?;
+    if len > max {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("frame too large: {} bytes, the limit is {}", len, max),
+        ));
+    }
+    read_payload(rd, len, buf).await
+}
+
+/// Writes `payload` as a single frame
+pub async fn write_frame<T: AsyncWrite + Unpin>(wr: &mut T, payload: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(payload.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "frame payload is too large"))?;
+    wr.write_all(&len.to_be_bytes()).await?;
+    wr.write_all(payload).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        compress_frame, decompress, encode_frame, read_frame, read_len, skip_payload, write_frame,
+        Compression, DecompressionError, DEFLATE_PREFIX, FRAME_PREFIX_LEN, HEARTBEAT_FRAME,
+        ZSTD_PREFIX,
+    };
+    use crate::TryFromCBOR;
+    use std::borrow::Cow;
+    use tokio::io::AsyncWriteExt;
+
+    #[test]
+    fn compression() {
+        let text = "text ".repeat(1000);
+        for (algorithm, prefix) in [
+            (Compression::Deflate, DEFLATE_PREFIX),
+            (Compression::Zstd, ZSTD_PREFIX),
+        ] {
+            let mut frame = Vec::new();
+            encode_frame(&mut frame, &text).unwrap();
+            let orig = frame.clone();
+
+            // below the threshold
+            compress_frame(&mut frame, algorithm, 10000).unwrap();
+            assert_eq!(frame, orig);
+            assert!(matches!(
+                decompress(&frame[FRAME_PREFIX_LEN..], usize::MAX),
+                Ok(Cow::Borrowed(_))
+            ));
+
+            compress_frame(&mut frame, algorithm, 100).unwrap();
+            assert!(frame.len() < orig.len() / 10);
+            assert!(frame[FRAME_PREFIX_LEN..].starts_with(prefix));
+            assert_eq!(
+                u32::from_be_bytes(frame[..4].try_into().unwrap()) as usize,
+                frame.len() - FRAME_PREFIX_LEN
+            );
+            let payload = decompress(&frame[FRAME_PREFIX_LEN..], usize::MAX).unwrap();
+            assert_eq!(payload, &orig[FRAME_PREFIX_LEN..]);
+            assert_eq!(String::try_from_cbor(&payload).unwrap(), text);
+            assert_eq!(
+                decompress(&frame[FRAME_PREFIX_LEN..], 1000),
+                Err(DecompressionError::TooLarge(1000))
+            );
+            assert_eq!(
+                decompress(&frame[FRAME_PREFIX_LEN..frame.len() - 1], usize::MAX),
+                Err(DecompressionError::Invalid)
+            );
+
+            // incompressible
+            let mut frame = Vec::new();
+            encode_frame(&mut frame, &serde_bytes_value(256)).unwrap();
+            let orig = frame.clone();
+            compress_frame(&mut frame, algorithm, 0).unwrap();
+            assert_eq!(frame, orig);
+        }
+
+        // malformed Zstandard frame
+        let mut frame = Vec::new();
+        encode_frame(
+            &mut frame,
+            &ciborium::Value::Map(vec![("Zstd".into(), ciborium::Value::Bytes(vec![0; 16]))]),
+        )
+        .unwrap();
+        assert_eq!(
+            decompress(&frame[FRAME_PREFIX_LEN..], usize::MAX),
+            Err(DecompressionError::Invalid)
+        );
+    }
+
+    fn serde_bytes_value(n: usize) -> ciborium::Value {
+        let mut state = 0x9e3779b97f4a7c15_u64;
+        ciborium::Value::Bytes(
+            (0..n)
+                .map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    state as u8
+                })
+                .collect(),
+        )
+    }
+
+    #[tokio::test]
+    async fn partial_io() {
+        // every read and write transfers a single byte
+        let (mut a, mut b) = tokio::io::duplex(1);
+        let payload: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        let mut encoded = Vec::new();
+        encode_frame(&mut encoded, &"text").unwrap();
+
+        let expected = payload.clone();
+        futures::join!(
+            async {
+                write_frame(&mut a, &payload).await.unwrap();
+                a.write_all(&HEARTBEAT_FRAME).await.unwrap();
+                a.write_all(&encoded).await.unwrap();
+                write_frame(&mut a, &[0; 100]).await.unwrap();
+                write_frame(&mut a, b"last").await.unwrap();
+                // truncated
+                a.write_all(&[0, 0, 0, 10, 1, 2]).await.unwrap();
+                drop(a);
+            },
+            async {
+                let mut buf = Vec::new();
+                read_frame(&mut b, &mut buf, 1000).await.unwrap();
+                assert_eq!(buf, expected);
+                read_frame(&mut b, &mut buf, 1000).await.unwrap();
+                assert!(buf.is_empty());
+                read_frame(&mut b, &mut buf, 1000).await.unwrap();
+                assert_eq!(String::try_from_cbor(&buf).unwrap(), "text");
+
+                let len = read_len(&mut b).await.unwrap();
+                assert_eq!(len, 100);
+                skip_payload(&mut b, len).await.unwrap();
+
+                let err = read_frame(&mut b, &mut buf, 3).await.unwrap_err();
+                assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+                // the payload is left unread
+                assert_eq!(
+                    read_len(&mut b).await.unwrap(),
+                    u32::from_be_bytes(*b"last") as usize
+                );
+
+                let len = read_len(&mut b).await.unwrap();
+                assert_eq!(len, 10);
+                let err = skip_payload(&mut b, len).await.unwrap_err();
+                assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+            }
+        );
+    }
+}
@@ -0,0 +1,105 @@
+//! Transports the client connects over
+//!
+//! The host code is written against `Connector` and runs unchanged against the enclave over
+//! vsock in production and against a local signer over a Unix domain socket or TCP otherwise,
+//! see `ClientBuilder::from_connector`.
+use std::future::Future;
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpStream, UnixStream};
+
+/// Opens connections to the signer. Called again to reconnect
+pub trait Connector: Send + 'static {
+    type Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static;
+
+    fn connect(&self) -> Pin<Box<dyn Future<Output = io::Result<Self::Stream>> + Send>>;
+}
+
+/// Connects to the enclave over vsock
+#[derive(Clone)]
+pub struct VsockConnector(pub vsock::SocketAddr);
+
+impl Connector for VsockConnector {
+    type Stream = vsock::asio::Stream;
+
+    fn connect(&self) -> Pin<Box<dyn Future<Output = io::Result<Self::Stream>> + Send>> {
+        let addr = self.0.clone();
+        Box::pin(async move { vsock::asio::Stream::connect(&addr).await })
+    }
+}
+
+/// Connects to a local signer listening on a Unix domain socket
+#[derive(Debug, Clone)]
+pub struct UnixConnector(pub PathBuf);
+
+impl Connector for UnixConnector {
+    type Stream = UnixStream;
+
+    fn connect(&self) -> Pin<Box<dyn Future<Output = io::Result<Self::Stream>> + Send>> {
+        let path = self.0.clone();
+        Box::pin(async move { UnixStream::connect(path).await })
+    }
+}
+
+/// Connects to a signer listening on a TCP socket
+#[derive(Debug, Clone)]
+pub struct TcpConnector(pub std::net::SocketAddr);
+
+impl Connector for TcpConnector {
+    type Stream = TcpStream;
+
+    fn connect(&self) -> Pin<Box<dyn Future<Output = io::Result<Self::Stream>> + Send>> {
+        Box::pin(TcpStream::connect(self.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Connector, TcpConnector, UnixConnector};
+    use crate::rpc::{client::ClientBuilder, server::Server};
+    use crate::tests::{DummyCredentials, PassthroughFactory};
+    use tokio::io::{AsyncRead, AsyncWrite};
+    use tokio::net::{TcpListener, UnixListener};
+
+    fn serve<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(sock: S) {
+        tokio::spawn(async move {
+            let mut srv = Server::new(PassthroughFactory, rand_core::OsRng);
+            let _ = srv.serve_connection(sock).await;
+        });
+    }
+
+    // the host code doesn't depend on the transport
+    async fn session<K: Connector>(connector: K) {
+        let mut client = ClientBuilder::<_, DummyCredentials>::from_connector(connector)
+            .connect()
+            .await
+            .unwrap();
+        client.initialize(DummyCredentials {}).await.unwrap();
+        assert_eq!(client.ping(b"payload").await.unwrap().payload, b"payload");
+    }
+
+    #[tokio::test]
+    async fn transports() {
+        let path = std::env::temp_dir().join(format!("signer-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        tokio::spawn(async move {
+            while let Ok((sock, _)) = listener.accept().await {
+                serve(sock);
+            }
+        });
+        session(UnixConnector(path.clone())).await;
+        std::fs::remove_file(&path).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            while let Ok((sock, _)) = listener.accept().await {
+                serve(sock);
+            }
+        });
+        session(TcpConnector(addr)).await;
+    }
+}
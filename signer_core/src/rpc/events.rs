@@ -0,0 +1,49 @@
+//! Notifications pushed by the server to the connections which sent `Subscribe`
+//!
+//! Events are published to an `EventBus` shared by the connections of the server, see
+//! `Server::with_event_bus`, and delivered as `Interim::Event` frames between responses.
+use crate::cddl::Cddl;
+use crate::rpc::ErrorCode;
+use crate::BackendState;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+/// Events buffered for a subscriber which doesn't keep up
+pub const DEFAULT_CAPACITY: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Cddl)]
+#[non_exhaustive]
+pub enum Event {
+    /// The number of keys in the keychain of a connection changed
+    KeychainChanged { keys: u64 },
+    /// Encryption backend availability observed by a connection changed
+    BackendState(BackendState),
+    /// A request was rejected by the allowlist or failed authentication
+    PolicyViolation { request: String, code: ErrorCode },
+    /// Events dropped because the subscriber didn't keep up
+    Lagged { missed: u64 },
+}
+
+#[derive(Debug, Clone)]
+pub struct EventBus(broadcast::Sender<Event>);
+
+impl EventBus {
+    pub fn new(capacity: usize) -> Self {
+        EventBus(broadcast::channel(capacity).0)
+    }
+
+    /// Delivers the event to the current subscribers, if any
+    pub fn publish(&self, event: Event) {
+        let _ = self.0.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.0.subscribe()
+    }
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
@@ -0,0 +1,305 @@
+//! gRPC transport of the signer protocol
+//!
+//! A connection is carried by a single bidirectional streaming call `signer.Signer/Session`,
+//! see `proto/signer.proto`. Every `Frame` message holds the payload of one protocol frame
+//! without the length prefix, an empty one being a heartbeat. Both sides bridge the call to an
+//! in-memory stream, so `Server` and `Client` run on top of it unchanged.
+use crate::rpc::{codec, server::DEFAULT_MAX_FRAME_SIZE};
+use futures::stream::{self, Stream, StreamExt};
+use prost::bytes::{Buf, BufMut};
+use prost::encoding::{self, DecodeContext, WireType};
+use std::convert::Infallible;
+use std::future::{self as std_future, Future};
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tonic::body::BoxBody;
+use tonic::codec::{ProstCodec, Streaming};
+use tonic::codegen::{http, Body, BoxFuture, BoxStream, Service, StdError};
+use tonic::server::NamedService;
+use tonic::transport::Channel;
+use tonic::Status;
+
+pub const SERVICE_NAME: &str = "signer.Signer";
+pub const SESSION_PATH: &str = "/signer.Signer/Session";
+
+/// Buffer size of the in-memory stream between the call and the protocol
+const DUPLEX_BUF_SIZE: usize = 64 << 10;
+
+/// `signer.Frame` message
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Frame {
+    pub cbor: Vec<u8>,
+}
+
+impl prost::Message for Frame {
+    fn encode_raw(&self, buf: &mut impl BufMut) {
+        if !self.cbor.is_empty() {
+            encoding::bytes::encode(1, &self.cbor, buf);
+        }
+    }
+
+    fn merge_field(
+        &mut self,
+        tag: u32,
+        wire_type: WireType,
+        buf: &mut impl Buf,
+        ctx: DecodeContext,
+    ) -> Result<(), prost::DecodeError> {
+        match tag {
+            1 => encoding::bytes::merge(wire_type, &mut self.cbor, buf, ctx),
+            _ => encoding::skip_field(wire_type, tag, buf, ctx),
+        }
+    }
+
+    fn encoded_len(&self) -> usize {
+        if self.cbor.is_empty() {
+            0
+        } else {
+            encoding::bytes::encoded_len(1, &self.cbor)
+        }
+    }
+
+    fn clear(&mut self) {
+        self.cbor.clear();
+    }
+}
+
+/// Reads length prefixed frames until the stream ends or a frame is longer than `max`
+fn frames_out<T>(rd: T, max: usize) -> impl Stream<Item = Frame> + Send + 'static
+where
+    T: AsyncRead + Unpin + Send + 'static,
+{
+    stream::unfold(rd, move |mut rd| async move {
+        let mut cbor = Vec::new();
+        codec::read_frame(&mut rd, &mut cbor, max).await.ok()?;
+        Some((Frame { cbor }, rd))
+    })
+}
+
+/// Writes the received messages as length prefixed frames and closes `wr` once the call ends
+async fn frames_in<T: AsyncWrite + Unpin>(mut incoming: Streaming<Frame>, mut wr: T) {
+    while let Ok(Some(frame)) = incoming.message().await {
+        if codec::write_frame(&mut wr, &frame.cbor).await.is_err() {
+            break;
+        }
+    }
+    let _ = wr.shutdown().await;
+}
+
+/// Serves a single session, normally by running `Server::serve_connection` on the stream
+pub trait SessionHandler: Send + Sync + 'static {
+    fn serve(&self, sock: DuplexStream) -> Pin<Box<dyn Future<Output = ()> + Send>>;
+}
+
+impl<F, Fut> SessionHandler for F
+where
+    F: Fn(DuplexStream) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    fn serve(&self, sock: DuplexStream) -> Pin<Box<dyn Future<Output = ()> + Send>> {
+        Box::pin(self(sock))
+    }
+}
+
+struct Session<H> {
+    handler: Arc<H>,
+    max_message_size: usize,
+}
+
+impl<H: SessionHandler> Service<tonic::Request<Streaming<Frame>>> for Session<H> {
+    type Response = tonic::Response<BoxStream<Frame>>;
+    type Error = Status;
+    type Future = std_future::Ready<Result<Self::Response, Status>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: tonic::Request<Streaming<Frame>>) -> Self::Future {
+        let (local, remote) = tokio::io::duplex(DUPLEX_BUF_SIZE);
+        let (rd, wr) = tokio::io::split(local);
+        tokio::spawn(self.handler.serve(remote));
+        tokio::spawn(frames_in(req.into_inner(), wr));
+        let frames = frames_out(rd, self.max_message_size).map(Ok);
+        std_future::ready(Ok(tonic::Response::new(Box::pin(frames))))
+    }
+}
+
+/// `signer.Signer` service to be added to `tonic::transport::Server`. Each `Session` call is
+/// passed to the handler as a new connection
+#[derive(Debug)]
+pub struct SignerService<H> {
+    handler: Arc<H>,
+    max_message_size: usize,
+}
+
+impl<H> Clone for SignerService<H> {
+    fn clone(&self) -> Self {
+        Self {
+            handler: self.handler.clone(),
+            max_message_size: self.max_message_size,
+        }
+    }
+}
+
+impl<H: SessionHandler> SignerService<H> {
+    pub fn new(handler: H) -> Self {
+        Self {
+            handler: Arc::new(handler),
+            max_message_size: DEFAULT_MAX_FRAME_SIZE,
+        }
+    }
+
+    /// Limits the size of the received and sent messages. Should match
+    /// `Server::with_max_frame_size` as larger messages are rejected by gRPC before reaching
+    /// the server. A larger response ends the session
+    pub fn with_max_message_size(mut self, size: usize) -> Self {
+        self.max_message_size = size;
+        self
+    }
+}
+
+impl<H, B> Service<http::Request<B>> for SignerService<H>
+where
+    H: SessionHandler,
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<BoxBody>;
+    type Error = Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        if req.uri().path() != SESSION_PATH {
+            return Box::pin(async { Ok(Status::unimplemented("unknown method").into_http()) });
+        }
+        let session = Session {
+            handler: self.handler.clone(),
+            max_message_size: self.max_message_size,
+        };
+        let mut grpc = tonic::server::Grpc::new(ProstCodec::<Frame, Frame>::default())
+            .max_decoding_message_size(self.max_message_size);
+        Box::pin(async move { Ok(grpc.streaming(session, req).await) })
+    }
+}
+
+impl<H> NamedService for SignerService<H> {
+    const NAME: &'static str = SERVICE_NAME;
+}
+
+/// Opens a session over the channel. The returned stream is used with `Client::new`.
+/// Messages are limited to `DEFAULT_MAX_FRAME_SIZE`
+pub async fn connect(channel: Channel) -> Result<DuplexStream, Status> {
+    connect_with_max_message_size(channel, DEFAULT_MAX_FRAME_SIZE).await
+}
+
+/// Opens a session limiting the size of the sent and received messages, see
+/// `Client::with_max_frame_size`. A larger response ends the session
+pub async fn connect_with_max_message_size(
+    channel: Channel,
+    size: usize,
+) -> Result<DuplexStream, Status> {
+    let mut grpc = tonic::client::Grpc::new(channel).max_decoding_message_size(size);
+    grpc.ready()
+        .await
+        .map_err(|e| Status::unavailable(e.to_string()))?;
+
+    let (local, remote) = tokio::io::duplex(DUPLEX_BUF_SIZE);
+    let (rd, wr) = tokio::io::split(local);
+    let res = grpc
+        .streaming(
+            tonic::Request::new(frames_out(rd, size)),
+            http::uri::PathAndQuery::from_static(SESSION_PATH),
+            ProstCodec::<Frame, Frame>::default(),
+        )
+        .await?;
+    tokio::spawn(frames_in(res.into_inner(), wr));
+    Ok(remote)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{connect, connect_with_max_message_size, Frame, SignerService};
+    use crate::crypto::{KeyType, SignOptions, SigningVersion};
+    use crate::rpc::{client::Client, server::Server, InsecureOptions};
+    use crate::tests::{DummyCredentials, PassthroughFactory};
+    use prost::Message;
+    use tokio::io::DuplexStream;
+    use tokio::net::TcpListener;
+    use tonic::transport::{server::TcpIncoming, Endpoint};
+
+    #[test]
+    fn frame_encoding() {
+        let frame = Frame {
+            cbor: b"payload".to_vec(),
+        };
+        let buf = frame.encode_to_vec();
+        assert_eq!(buf, b"\x0a\x07payload");
+        assert_eq!(Frame::decode(&buf[..]).unwrap(), frame);
+        // heartbeat
+        assert!(Frame::default().encode_to_vec().is_empty());
+        // unknown fields are skipped
+        assert_eq!(
+            Frame::decode(&b"\x10\x01\x0a\x07payload"[..]).unwrap(),
+            frame
+        );
+    }
+
+    #[tokio::test]
+    async fn grpc_session() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let incoming = TcpIncoming::from_listener(listener, true, None).unwrap();
+
+        let svc = SignerService::new(|sock: DuplexStream| async move {
+            let mut server = Server::new(PassthroughFactory, rand_core::OsRng)
+                .with_heartbeat_interval(std::time::Duration::from_millis(1))
+                .with_insecure_options(InsecureOptions {
+                    passthrough_backend: true,
+                    ..Default::default()
+                });
+            server.serve_connection(sock).await.unwrap();
+        });
+        tokio::spawn(
+            tonic::transport::Server::builder()
+                .add_service(svc)
+                .serve_with_incoming(incoming),
+        );
+
+        let channel = Endpoint::from_shared(format!("http://{}", addr))
+            .unwrap()
+            .connect()
+            .await
+            .unwrap();
+        let mut client: Client<DuplexStream, DummyCredentials> =
+            Client::new(connect(channel.clone()).await.unwrap());
+
+        let pong = client.ping(b"payload").await.unwrap();
+        assert_eq!(pong.payload, b"payload");
+
+        client.initialize(DummyCredentials {}).await.unwrap();
+        let res = client.generate(KeyType::Ed25519).await.unwrap();
+        let sig = client
+            .try_sign_with(
+                &res.encrypted_private_key,
+                b"text",
+                SigningVersion::Latest,
+                &SignOptions::default(),
+            )
+            .await
+            .unwrap();
+        res.public_key.verify(b"text", &sig).unwrap();
+
+        // the schema is larger than the limit
+        let mut client: Client<DuplexStream, DummyCredentials> =
+            Client::new(connect_with_max_message_size(channel, 1000).await.unwrap());
+        client.ping(b"payload").await.unwrap();
+        assert!(client.describe().await.is_err());
+    }
+}
@@ -0,0 +1,75 @@
+//! Key generation results remembered by the idempotency key
+//!
+//! A client which lost the connection while waiting for `Generate` or `GenerateAndImport` can't
+//! tell whether the key was generated. Wrapped into `Idempotent`, the retried request returns
+//! the key generated by the first one instead of minting another one.
+use crate::crypto::{KeyHandle, KeyType, PublicKey};
+use crate::lru::Lru;
+use crate::{GenerateAndImportResult, GenerateResult};
+use serde::Serialize;
+use tokio::sync::{Mutex, MutexGuard};
+
+pub const DEFAULT_CAPACITY: usize = 1024;
+
+/// Longest accepted idempotency key
+pub const MAX_KEY_LEN: usize = 64;
+
+#[derive(Debug, Clone)]
+pub(crate) struct Entry {
+    pub key_type: KeyType,
+    /// The key was generated by `GenerateAndImport`
+    pub import: bool,
+    pub encrypted_private_key: Vec<u8>,
+    pub public_key: PublicKey,
+    pub fingerprint: Vec<u8>,
+    /// Handle of the imported key, updated once it's imported again by a retry
+    pub handle: Option<KeyHandle>,
+}
+
+impl Entry {
+    pub fn result(&self) -> Generated {
+        match self.handle {
+            Some(handle) => Generated::GenerateAndImport(GenerateAndImportResult {
+                encrypted_private_key: self.encrypted_private_key.clone(),
+                public_key: self.public_key.clone(),
+                fingerprint: self.fingerprint.clone(),
+                handle,
+            }),
+            None => Generated::Generate(GenerateResult {
+                encrypted_private_key: self.encrypted_private_key.clone(),
+                public_key: self.public_key.clone(),
+                fingerprint: self.fingerprint.clone(),
+            }),
+        }
+    }
+}
+
+/// Response of the request wrapped into `Idempotent`
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub(crate) enum Generated {
+    Generate(GenerateResult),
+    GenerateAndImport(GenerateAndImportResult),
+}
+
+/// Results of the recent idempotent requests. To be useful it must be shared between the
+/// connections a retry may arrive on, see `Server::with_idempotency_cache`. Requests sharing
+/// the cache are processed one at a time, so a retry waits for the original request to complete
+#[derive(Debug)]
+pub struct IdempotencyCache(Mutex<Lru<Vec<u8>, Entry>>);
+
+impl IdempotencyCache {
+    pub fn new(capacity: usize) -> Self {
+        IdempotencyCache(Mutex::new(Lru::new(capacity)))
+    }
+
+    pub(crate) async fn lock(&self) -> MutexGuard<'_, Lru<Vec<u8>, Entry>> {
+        self.0.lock().await
+    }
+}
+
+impl Default for IdempotencyCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
@@ -0,0 +1,124 @@
+//! Keys of the client side application
+//!
+//! `Key` keeps the encrypted private key along with its public key and the handle it's
+//! imported under, so the application doesn't deal with either. Handles belong to the
+//! connection, a key must be used with the client which created it.
+use crate::crypto::{KeyHandle, KeyType, PublicKey, SignOptions, Signature, SigningVersion};
+use crate::rpc::client::{Client, Error};
+use crate::{CredentialsSchema, GenerateAndImportResult};
+use serde::Serialize;
+use tokio::io::{AsyncRead, AsyncWriteExt};
+
+#[derive(Debug, Clone)]
+pub struct Key {
+    encrypted_private_key: Vec<u8>,
+    public_key: PublicKey,
+    fingerprint: Vec<u8>,
+    handle: KeyHandle,
+}
+
+impl Key {
+    pub fn public_key(&self) -> &PublicKey {
+        &self.public_key
+    }
+
+    /// See `PublicKey::fingerprint`
+    pub fn fingerprint(&self) -> &[u8] {
+        &self.fingerprint
+    }
+
+    /// Encrypted private key to be stored by the application and imported again using
+    /// `Client::import_key`
+    pub fn encrypted_blob(&self) -> &[u8] {
+        &self.encrypted_private_key
+    }
+
+    /// Signs the message using the latest signing version and the default options
+    pub async fn sign<T, C>(
+        &self,
+        client: &mut Client<T, C>,
+        msg: &[u8],
+    ) -> Result<Signature, Error>
+    where
+        T: AsyncRead + AsyncWriteExt + Unpin,
+        C: Serialize + CredentialsSchema,
+    {
+        self.sign_with_options(client, msg, SigningVersion::Latest, &SignOptions::default())
+            .await
+    }
+
+    pub async fn sign_with_options<T, C>(
+        &self,
+        client: &mut Client<T, C>,
+        msg: &[u8],
+        version: SigningVersion,
+        options: &SignOptions,
+    ) -> Result<Signature, Error>
+    where
+        T: AsyncRead + AsyncWriteExt + Unpin,
+        C: Serialize + CredentialsSchema,
+    {
+        client.try_sign(self.handle, msg, version, options).await
+    }
+}
+
+impl From<GenerateAndImportResult> for Key {
+    fn from(value: GenerateAndImportResult) -> Self {
+        Key {
+            encrypted_private_key: value.encrypted_private_key,
+            public_key: value.public_key,
+            fingerprint: value.fingerprint,
+            handle: value.handle,
+        }
+    }
+}
+
+impl<T, C> Client<T, C>
+where
+    T: AsyncRead + AsyncWriteExt + Unpin,
+    C: Serialize + CredentialsSchema,
+{
+    /// Generates a new key and imports it
+    pub async fn generate_key(&mut self, t: KeyType) -> Result<Key, Error> {
+        Ok(self.generate_and_import(t).await?.into())
+    }
+
+    /// Imports the key previously returned by `Key::encrypted_blob`
+    pub async fn import_key(&mut self, encrypted_blob: &[u8]) -> Result<Key, Error> {
+        let res = self.import(encrypted_blob).await?;
+        Ok(Key {
+            encrypted_private_key: encrypted_blob.into(),
+            public_key: res.public_key,
+            fingerprint: res.fingerprint,
+            handle: res.handle,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::KeyType;
+    use crate::rpc::{client::Client, server::Server};
+    use crate::tests::{DummyCredentials, PassthroughFactory};
+
+    #[tokio::test]
+    async fn key() {
+        let (sock, server_sock) = tokio::io::duplex(4096);
+        tokio::spawn(async move {
+            let mut srv = Server::new(PassthroughFactory, rand_core::OsRng);
+            let _ = srv.serve_connection(server_sock).await;
+        });
+        let mut client = Client::<_, DummyCredentials>::new(sock);
+        client.initialize(DummyCredentials {}).await.unwrap();
+
+        let key = client.generate_key(KeyType::Secp256k1).await.unwrap();
+        assert_eq!(key.fingerprint(), key.public_key().fingerprint().unwrap());
+        let sig = key.sign(&mut client, b"text").await.unwrap();
+        key.public_key().verify(b"text", &sig).unwrap();
+
+        let imported = client.import_key(key.encrypted_blob()).await.unwrap();
+        assert_eq!(imported.fingerprint(), key.fingerprint());
+        let sig = imported.sign(&mut client, b"text").await.unwrap();
+        key.public_key().verify(b"text", &sig).unwrap();
+    }
+}
@@ -0,0 +1,259 @@
+//! Structural limits checked before a frame is decoded
+//!
+//! Frames come from the untrusted host. A few megabytes of CBOR can declare deeply nested
+//! containers exhausting the stack of the recursive decoder, or millions of tiny items each
+//! expanding into a heap allocation. The frame is walked without recursion or allocation
+//! proportional to its contents and rejected before decoding if it exceeds the limits.
+
+pub const DEFAULT_MAX_DEPTH: usize = 64;
+pub const DEFAULT_MAX_ITEMS: usize = 1 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeLimits {
+    /// Nesting depth of arrays, maps and indefinite length strings
+    pub max_depth: usize,
+    /// Number of data items including the nested ones
+    pub max_items: usize,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        DecodeLimits {
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_items: DEFAULT_MAX_ITEMS,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+    /// The frame ends in the middle of an item or a declared length exceeds the frame
+    Truncated,
+    Malformed,
+    TooDeep(usize),
+    TooManyItems(usize),
+    /// Bytes left after the top level item
+    TrailingData(usize),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Truncated => f.write_str("truncated CBOR item"),
+            Error::Malformed => f.write_str("malformed CBOR item"),
+            Error::TooDeep(max) => write!(f, "CBOR nesting is deeper than {}", max),
+            Error::TooManyItems(max) => write!(f, "more than {} CBOR items", max),
+            Error::TrailingData(len) => write!(f, "{} bytes after the CBOR item", len),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn remaining(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+
+    fn take(&mut self, n: usize) -> Result<&[u8], Error> {
+        if n > self.remaining() {
+            return Err(Error::Truncated);
+        }
+        let out = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(out)
+    }
+
+    /// Returns the major type and the argument, `None` for the indefinite length
+    fn header(&mut self) -> Result<(u8, Option<u64>), Error> {
+        let b = self.take(1)?[0];
+        let (major, info) = (b >> 5, b & 0x1f);
+        let arg = match info {
+            0..=23 => Some(info as u64),
+            24..=27 => {
+                let bytes = self.take(1 << (info - 24))?;
+                Some(bytes.iter().fold(0, |acc, &b| acc << 8 | b as u64))
+            }
+            31 => None,
+            _ => return Err(Error::Malformed),
+        };
+        Ok((major, arg))
+    }
+}
+
+/// Checks that `buf` holds exactly one well formed data item within `limits`
+pub fn check(buf: &[u8], limits: &DecodeLimits) -> Result<(), Error> {
+    let mut rd = Reader { buf, pos: 0 };
+    // items left in each open container, `None` for the indefinite length ones
+    let mut stack: Vec<Option<u64>> = vec![Some(1)];
+    let mut items = 0;
+
+    while let Some(top) = stack.last_mut() {
+        if *top == Some(0) {
+            stack.pop();
+            continue;
+        }
+        let (major, arg) = rd.header()?;
+        if arg.is_none() && major == 7 {
+            // break
+            if top.is_some() {
+                return Err(Error::Malformed);
+            }
+            stack.pop();
+            continue;
+        }
+        if let Some(n) = top {
+            *n -= 1;
+        }
+        items += 1;
+        if items > limits.max_items {
+            return Err(Error::TooManyItems(limits.max_items));
+        }
+
+        let remaining = rd.remaining() as u64;
+        let open = match (major, arg) {
+            (0 | 1, Some(_)) => None,
+            (2 | 3, Some(len)) => {
+                rd.take(usize::try_from(len).or(Err(Error::Truncated))?)?;
+                None
+            }
+            // every item takes at least one byte
+            (4, Some(n)) if n > remaining => return Err(Error::Truncated),
+            (5, Some(n)) if n > remaining / 2 => return Err(Error::Truncated),
+            (4, Some(n)) => Some(Some(n)),
+            (5, Some(n)) => Some(Some(n * 2)),
+            // indefinite length strings hold definite length chunks
+            (2..=5, None) => Some(None),
+            (6, Some(_)) => {
+                // the tagged item follows in the same container
+                if let Some(n) = stack.last_mut().unwrap() {
+                    *n += 1;
+                }
+                None
+            }
+            (7, Some(_)) => None,
+            _ => return Err(Error::Malformed),
+        };
+        if let Some(level) = open {
+            if stack.len() > limits.max_depth {
+                return Err(Error::TooDeep(limits.max_depth));
+            }
+            stack.push(level);
+        }
+    }
+
+    match rd.remaining() {
+        0 => Ok(()),
+        n => Err(Error::TrailingData(n)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check, DecodeLimits, Error};
+    use crate::crypto::{SignOptions, SigningVersion};
+    use crate::rpc::{Request, VersionedCredentials};
+    use crate::tests::DummyCredentials;
+    use crate::{TryFromCBOR, TryIntoCBOR};
+
+    type Req = Request<VersionedCredentials<DummyCredentials>>;
+
+    #[test]
+    fn limits() {
+        let limits = DecodeLimits::default();
+        let req = Req::Tagged {
+            id: 1,
+            request: Box::new(Req::Sign {
+                handle: 0.into(),
+                message: vec![0; 5000],
+                version: SigningVersion::Latest,
+                options: SignOptions::default(),
+            }),
+        }
+        .try_into_cbor()
+        .unwrap();
+        assert_eq!(check(&req, &limits), Ok(()));
+        // indefinite length
+        assert_eq!(check(b"\x9f\x5f\x41\x00\xff\x01\xff", &limits), Ok(()));
+
+        assert_eq!(check(&req[..req.len() - 1], &limits), Err(Error::Truncated));
+        assert_eq!(
+            check(&[&req[..], b"\x00"].concat(), &limits),
+            Err(Error::TrailingData(1))
+        );
+        // huge declared lengths
+        assert_eq!(
+            check(b"\x5b\xff\xff\xff\xff\xff\xff\xff\xff", &limits),
+            Err(Error::Truncated)
+        );
+        assert_eq!(
+            check(b"\x9b\x00\x00\x00\x01\x00\x00\x00\x00", &limits),
+            Err(Error::Truncated)
+        );
+        assert_eq!(check(b"\x1c", &limits), Err(Error::Malformed));
+        assert_eq!(check(b"\x81\xff", &limits), Err(Error::Malformed));
+
+        let deep = [vec![0x81; 1000], vec![0x00]].concat();
+        assert_eq!(check(&deep, &limits), Err(Error::TooDeep(64)));
+        let tags = [vec![0xc1; 1000], vec![0x00]].concat();
+        assert_eq!(check(&tags, &limits), Ok(()));
+
+        let mut many = vec![0x9a, 0x00, 0x20, 0x00, 0x00];
+        many.resize(many.len() + (2 << 20), 0x40);
+        assert_eq!(check(&many, &limits), Err(Error::TooManyItems(1 << 20)));
+    }
+
+    #[test]
+    fn mutated_frames() {
+        let limits = DecodeLimits::default();
+        let frames = [
+            Req::Ping {
+                payload: b"payload".to_vec(),
+            }
+            .try_into_cbor()
+            .unwrap(),
+            Req::Initialize(VersionedCredentials::new(DummyCredentials {}))
+                .try_into_cbor()
+                .unwrap(),
+            Req::BatchSign {
+                handle: 3.into(),
+                msgs: vec![b"a".to_vec(), b"bc".to_vec()],
+                version: SigningVersion::Latest,
+                options: SignOptions::default(),
+            }
+            .try_into_cbor()
+            .unwrap(),
+        ];
+        // xorshift, deterministic
+        let mut state = 0x2545f4914f6cdd1d_u64;
+        let mut next = move || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        for _ in 0..20000 {
+            let mut frame = frames[next() as usize % frames.len()].clone();
+            for _ in 0..1 + next() % 4 {
+                let pos = next() as usize % frame.len();
+                match next() % 3 {
+                    0 => frame[pos] = next() as u8,
+                    1 => frame.truncate(pos),
+                    _ => frame.insert(pos, next() as u8),
+                }
+                if frame.is_empty() {
+                    frame.push(0);
+                }
+            }
+            // must not panic whether accepted or not
+            if check(&frame, &limits).is_ok() {
+                let _ = Req::try_from_cbor(&frame);
+            }
+        }
+    }
+}
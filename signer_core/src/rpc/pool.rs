@@ -0,0 +1,152 @@
+//! Pool of client connections shared by concurrent callers
+//!
+//! A connection processes one request at a time, so callers sharing a single `Client` are
+//! serialized. `ClientPool` keeps several connections and leases an idle one to every caller.
+//! Key handles belong to a connection: the keys are either passed encrypted with every request
+//! (`try_sign_with`) or imported by every connection in the same order, e.g. using
+//! `initialize_with`, so that the handles match.
+use crate::rpc::client::{Client, Error};
+use std::future::Future;
+use std::ops::{Deref, DerefMut};
+use std::sync::Mutex;
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+pub struct ClientPool<T, C> {
+    idle: Mutex<Vec<Client<T, C>>>,
+    available: Semaphore,
+    size: usize,
+}
+
+impl<T, C> ClientPool<T, C> {
+    /// Creates a pool of the connected and initialized clients
+    pub fn new(clients: Vec<Client<T, C>>) -> Self {
+        Self {
+            size: clients.len(),
+            available: Semaphore::new(clients.len()),
+            idle: Mutex::new(clients),
+        }
+    }
+
+    /// Creates a pool of `size` clients returned by `connect`, which normally connects and
+    /// initializes a client
+    pub async fn connect<F, Fut>(size: usize, mut connect: F) -> Result<Self, Error>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<Client<T, C>, Error>>,
+    {
+        let mut clients = Vec::with_capacity(size);
+        for _ in 0..size {
+            clients.push(connect().await?);
+        }
+        Ok(Self::new(clients))
+    }
+
+    /// Number of connections
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Waits for an idle connection. It returns to the pool once the lease is dropped
+    pub async fn lease(&self) -> Lease<'_, T, C> {
+        // the semaphore is never closed
+        let permit = self.available.acquire().await.unwrap();
+        self.take(permit)
+    }
+
+    /// Returns an idle connection if any
+    pub fn try_lease(&self) -> Option<Lease<'_, T, C>> {
+        let permit = self.available.try_acquire().ok()?;
+        Some(self.take(permit))
+    }
+
+    fn take<'a>(&'a self, permit: SemaphorePermit<'a>) -> Lease<'a, T, C> {
+        let client = self.idle.lock().unwrap().pop().unwrap();
+        Lease {
+            client: Some(client),
+            pool: self,
+            _permit: permit,
+        }
+    }
+}
+
+/// Exclusive use of a pooled connection
+pub struct Lease<'a, T, C> {
+    client: Option<Client<T, C>>,
+    pool: &'a ClientPool<T, C>,
+    // released after the client is back
+    _permit: SemaphorePermit<'a>,
+}
+
+impl<T, C> Deref for Lease<'_, T, C> {
+    type Target = Client<T, C>;
+
+    fn deref(&self) -> &Self::Target {
+        self.client.as_ref().unwrap()
+    }
+}
+
+impl<T, C> DerefMut for Lease<'_, T, C> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.client.as_mut().unwrap()
+    }
+}
+
+impl<T, C> Drop for Lease<'_, T, C> {
+    fn drop(&mut self) {
+        if let Some(client) = self.client.take() {
+            self.pool.idle.lock().unwrap().push(client);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ClientPool;
+    use crate::crypto::{KeyType, SignOptions, SigningVersion};
+    use crate::rpc::{client::Client, server::Server};
+    use crate::tests::{DummyCredentials, PassthroughFactory};
+
+    #[tokio::test]
+    async fn pool() {
+        let pool = ClientPool::connect(2, || async {
+            let (sock, server_sock) = tokio::io::duplex(4096);
+            tokio::spawn(async move {
+                let mut srv = Server::new(PassthroughFactory, rand_core::OsRng);
+                let _ = srv.serve_connection(server_sock).await;
+            });
+            let mut client = Client::<_, DummyCredentials>::new(sock);
+            client.initialize(DummyCredentials {}).await?;
+            Ok(client)
+        })
+        .await
+        .unwrap();
+        assert_eq!(pool.size(), 2);
+
+        let key = pool.lease().await.generate(KeyType::Ed25519).await.unwrap();
+        let sign = |msg: &'static [u8]| {
+            let pool = &pool;
+            let key = &key;
+            async move {
+                let mut client = pool.lease().await;
+                let sig = client
+                    .try_sign_with(
+                        &key.encrypted_private_key,
+                        msg,
+                        SigningVersion::Latest,
+                        &SignOptions::default(),
+                    )
+                    .await
+                    .unwrap();
+                key.public_key.verify(msg, &sig).unwrap();
+            }
+        };
+        futures::join!(sign(b"a"), sign(b"b"), sign(b"c"), sign(b"d"));
+
+        let a = pool.try_lease().unwrap();
+        let b = pool.try_lease().unwrap();
+        assert!(pool.try_lease().is_none());
+        drop(a);
+        let _a = pool.try_lease().unwrap();
+        drop(b);
+    }
+}
@@ -1,30 +1,234 @@
-use crate::rpc::{Error as RPCError, Request, Result as RPCResult};
+use crate::attestation::{self, AttestationSource};
+use crate::blob::{self, BlobCodec};
+use crate::cddl::Cddl;
+use crate::crypto::{
+    self, DigestAlgorithm, Hasher, KeyHandle, KeyType, KeychainNamespaces, SharedKeychain,
+    SigningVersion, VerifyCache,
+};
+use crate::rpc::{
+    self, codec,
+    events::{Event, EventBus},
+    idempotency::{self, IdempotencyCache},
+    limits::{self, DecodeLimits},
+    AttestResult, Compression, Error as RPCError, ErrorCode, HelloResult, ImportResult, InfoResult,
+    InsecureOptions, Interim, NegotiateCompressionResult, PongResult, Progress, Request,
+    Result as RPCResult, VersionedCredentials,
+};
 use crate::{
-    EncryptedSigner, EncryptionBackend, EncryptionBackendFactory, Error as SignerError,
-    TryFromCBOR, TryIntoCBOR,
+    BackendState, CredentialsSchema, EncryptedSigner, EncryptionBackend, EncryptionBackendFactory,
+    Error as SignerError, MuSig2AggregateResult, TryFromCBOR, TryIntoCBOR,
 };
-use rand_core::CryptoRngCore;
-use serde::de::DeserializeOwned;
+use futures::future::{AbortHandle, Abortable, Aborted, FutureExt};
+use futures::stream::{FuturesUnordered, StreamExt};
+use hmac::Mac;
+use rand_core::{CryptoRng, CryptoRngCore, RngCore};
+use serde::de::{DeserializeOwned, IgnoredAny};
+use std::collections::HashMap;
 use std::io;
-use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+use subtle::ConstantTimeEq;
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{broadcast, mpsc};
+use zeroize::Zeroizing;
+
+/// Version of the connection state machine reported in state errors.
+/// Bump it whenever the set of requests allowed in some state changes
+pub const STATE_MACHINE_VERSION: u32 = 13;
+
+/// Default limit of the request frame size
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 16 << 20;
+
+/// Default limit of tagged requests processed concurrently on a single connection
+pub const DEFAULT_MAX_CONCURRENT_REQUESTS: usize = 16;
+
+/// Longest accepted trace ID of `Traced` requests
+pub const MAX_TRACE_ID_LEN: usize = 128;
+
+/// Longest accepted session name of `Session` requests
+pub const MAX_SESSION_NAME_LEN: usize = 128;
+
+/// Limit of named sessions opened on a single connection
+pub const MAX_SESSIONS: usize = 64;
+
+/// Requests accepted before `Initialize`
+pub const UNINITIALIZED_REQUESTS: &[&str] = &[
+    "Initialize",
+    "Describe",
+    "Verify",
+    "Hello",
+    "VerifyAggregate",
+    "InitializeWith",
+    "MuSig2Aggregate",
+    "VerifyBatch",
+    "Ping",
+    "GetInfo",
+    "Attest",
+    "NegotiateCompression",
+    "Subscribe",
+];
+
+/// Comma separated names of the only requests the server handles, set at build time with the
+/// `SIGNER_ALLOWED_REQUESTS` environment variable. All requests are handled if empty or not set.
+/// Being compiled in, the allowlist is covered by the enclave image measurement
+pub const COMPILED_ALLOWLIST: Option<&str> = option_env!("SIGNER_ALLOWED_REQUESTS");
+
+fn parse_allowlist(src: &str) -> Option<Vec<String>> {
+    let names: Vec<String> = src
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect();
+    (!names.is_empty()).then_some(names)
+}
+
+/// Binds the request key of the session to its keychain. Once set, every connection using the
+/// keychain must pass the same key on initialization and authenticate its requests
+fn bind_request_key(
+    keychain: &SharedKeychain,
+    key: Option<rpc::RequestKey>,
+) -> Result<(), StateError> {
+    let mut keychain = keychain.write();
+    match (keychain.request_key(), key) {
+        (Some(expected), Some(key)) if bool::from(expected.ct_eq(&key.0)) => Ok(()),
+        (Some(_), _) => Err(StateError::Unauthenticated),
+        (None, Some(key)) => {
+            keychain.set_request_key(&key.0);
+            Ok(())
+        }
+        (None, None) => Ok(()),
+    }
+}
 
 #[derive(Debug)]
 pub enum StateError {
-    Uninitialized,
+    Uninitialized {
+        request: &'static str,
+        allowed: &'static [&'static str],
+    },
     Initialized,
+    Disabled(&'static str),
+    NestedTagged,
+    /// `Traced` is wrapped into another envelope other than `Authenticated`
+    MisplacedTraced,
+    InvalidTraceId,
+    NoSignStream,
+    /// `Reinitialize` token is missing or doesn't match
+    Unauthorized,
+    /// `Shutdown` token doesn't match
+    InvalidShutdownToken,
+    /// The request isn't wrapped into `Authenticated` or its MAC or sequence number is invalid
+    Unauthenticated,
+    NoRequestKey,
+    /// The request can't be wrapped into `Idempotent`
+    NotIdempotent(&'static str),
+    InvalidIdempotencyKey,
+    /// The idempotency key was used with another request
+    IdempotencyKeyReused,
+    Cancelled,
+    /// The tagged request exceeds the concurrency limit of the connection
+    TooManyInFlight(usize),
+    /// `Session` is wrapped into another envelope
+    MisplacedSession,
+    InvalidSessionName,
+    /// The connection has the maximum number of named sessions
+    TooManySessions(usize),
 }
 
 impl std::fmt::Display for StateError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            StateError::Uninitialized => f.write_str("uninitialized"),
+            StateError::Uninitialized { request, allowed } => write!(
+                f,
+                "uninitialized: {} is not allowed before Initialize, allowed requests are {} (state machine version {}). Was the session re-initialized after reconnecting?",
+                request,
+                allowed.join(", "),
+                STATE_MACHINE_VERSION
+            ),
             StateError::Initialized => f.write_str("already initialized"),
+            StateError::Disabled(request) => write!(f, "{} is disabled", request),
+            StateError::NestedTagged => f.write_str("Tagged requests can't be nested"),
+            StateError::MisplacedTraced => f.write_str("Traced must wrap the whole request"),
+            StateError::InvalidTraceId => write!(
+                f,
+                "trace ID must be 1 to {} printable ASCII characters",
+                MAX_TRACE_ID_LEN
+            ),
+            StateError::NoSignStream => f.write_str("no SignStreamBegin in progress"),
+            StateError::Unauthorized => f.write_str("invalid Reinitialize token"),
+            StateError::InvalidShutdownToken => f.write_str("invalid Shutdown token"),
+            StateError::Unauthenticated => f.write_str("request authentication failed"),
+            StateError::NoRequestKey => f.write_str("no request key was provisioned by Initialize"),
+            StateError::NotIdempotent(request) => {
+                write!(f, "{} can't be wrapped into Idempotent", request)
+            }
+            StateError::InvalidIdempotencyKey => write!(
+                f,
+                "idempotency key must be 1 to {} bytes long",
+                idempotency::MAX_KEY_LEN
+            ),
+            StateError::IdempotencyKeyReused => {
+                f.write_str("idempotency key was used with another request")
+            }
+            StateError::Cancelled => f.write_str("cancelled"),
+            StateError::TooManyInFlight(limit) => {
+                write!(f, "too many requests in flight, the limit is {}", limit)
+            }
+            StateError::MisplacedSession => f.write_str("Session must wrap the whole request"),
+            StateError::InvalidSessionName => write!(
+                f,
+                "session name must be 1 to {} printable ASCII characters",
+                MAX_SESSION_NAME_LEN
+            ),
+            StateError::TooManySessions(limit) => {
+                write!(f, "too many sessions, the limit is {}", limit)
+            }
         }
     }
 }
 
 impl std::error::Error for StateError {}
 
+/// The host sent credentials of a schema version newer than the signer supports
+#[derive(Debug)]
+pub struct CredentialsVersionError {
+    pub version: u32,
+    pub supported: u32,
+}
+
+impl std::fmt::Display for CredentialsVersionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "credentials version too new: {}, the signer supports up to {}",
+            self.version, self.supported
+        )
+    }
+}
+
+impl std::error::Error for CredentialsVersionError {}
+
+/// The request frame is larger than the server accepts. The frame is skipped without being
+/// buffered and the connection stays usable
+#[derive(Debug)]
+pub struct FrameTooLargeError {
+    pub size: usize,
+    pub max: usize,
+}
+
+impl std::fmt::Display for FrameTooLargeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "frame too large: {} bytes, the limit is {}",
+            self.size, self.max
+        )
+    }
+}
+
+impl std::error::Error for FrameTooLargeError {}
+
 #[derive(Debug)]
 pub enum Error {
     IO(std::io::Error),
@@ -62,11 +266,87 @@ impl std::fmt::Display for Error {
 
 impl std::error::Error for Error {}
 
+/// Sends `rpc::Interim::Progress` frames of the current request to the client
+#[derive(Debug, Clone, Default)]
+pub struct ProgressReporter(Option<mpsc::UnboundedSender<Progress>>);
+
+impl ProgressReporter {
+    fn channel() -> (Self, mpsc::UnboundedReceiver<Progress>) {
+        let (tx, rx) = mpsc::unbounded_channel();
+        (Self(Some(tx)), rx)
+    }
+
+    pub fn report(&self, completed: u64, total: u64) {
+        if let Some(tx) = &self.0 {
+            // the receiver lives until the response is sent
+            let _ = tx.send(Progress { completed, total });
+        }
+    }
+}
+
+async fn send_interim<T: AsyncWrite + Unpin>(
+    sock: &mut T,
+    w_buf: &mut Vec<u8>,
+    interim: &Interim,
+) -> Result<(), Error> {
+    rpc::encode_frame(w_buf, interim)?;
+    Ok(sock.write_all(w_buf).await?)
+}
+
+/// Message being signed by `SignStream*` requests
+#[derive(Debug)]
+struct SignStream {
+    handle: KeyHandle,
+    version: SigningVersion,
+    hasher: Hasher,
+}
+
+/// State of a session other than the one whose request is being processed. Swapped with the
+/// fields of the server when a request of the session arrives
+#[derive(Debug)]
+struct SessionState<S> {
+    signer: Option<Arc<S>>,
+    keychain: Option<SharedKeychain>,
+    last_seq: Option<u64>,
+    sign_stream: Option<SignStream>,
+}
+
 #[derive(Debug)]
 pub struct Server<F, S, R> {
     fact: F,
-    signer: Option<S>,
-    rng: R,
+    signer: Option<Arc<S>>,
+    rng: SharedRng<R>,
+    verify_cache: VerifyCache,
+    blob_codec: Arc<dyn BlobCodec>,
+    heartbeat_interval: Option<Duration>,
+    insecure: InsecureOptions,
+    allowlist: Option<Vec<String>>,
+    keychain: Option<SharedKeychain>,
+    key_cache_size: Option<usize>,
+    max_concurrent_requests: usize,
+    max_frame_size: usize,
+    decode_limits: DecodeLimits,
+    started: Instant,
+    shut_down: bool,
+    /// `Shutdown` carried the shutdown token
+    shutdown_authorized: bool,
+    sign_stream: Option<SignStream>,
+    reinitialize_token: Option<String>,
+    shutdown_token: Option<String>,
+    attestation: Option<Arc<dyn AttestationSource>>,
+    last_seq: Option<u64>,
+    /// Negotiated algorithm and the minimum size of the compressed response payloads
+    compression: Option<(Compression, usize)>,
+    idempotency: Arc<IdempotencyCache>,
+    events: EventBus,
+    subscribed: bool,
+    /// Trace ID of the request being processed
+    trace_id: Option<String>,
+    /// Name of the current session, `None` for the one of requests without `Session`
+    session: Option<String>,
+    /// Other sessions of the connection by name
+    sessions: HashMap<Option<String>, SessionState<S>>,
+    session_keychains: Option<KeychainNamespaces>,
 }
 
 impl<F, S, R> Server<F, S, R> {
@@ -74,156 +354,1329 @@ impl<F, S, R> Server<F, S, R> {
         Self {
             fact,
             signer: None,
-            rng,
+            rng: SharedRng(Arc::new(Mutex::new(rng))),
+            verify_cache: VerifyCache::default(),
+            blob_codec: Arc::new(blob::Raw),
+            heartbeat_interval: None,
+            insecure: InsecureOptions::default(),
+            allowlist: COMPILED_ALLOWLIST.and_then(parse_allowlist),
+            keychain: None,
+            key_cache_size: None,
+            max_concurrent_requests: DEFAULT_MAX_CONCURRENT_REQUESTS,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            decode_limits: DecodeLimits::default(),
+            started: Instant::now(),
+            shut_down: false,
+            shutdown_authorized: false,
+            sign_stream: None,
+            reinitialize_token: None,
+            shutdown_token: None,
+            attestation: None,
+            last_seq: None,
+            compression: None,
+            idempotency: Arc::default(),
+            events: EventBus::default(),
+            subscribed: false,
+            trace_id: None,
+            session: None,
+            sessions: HashMap::new(),
+            session_keychains: None,
         }
     }
+
+    /// Narrows the set of handled requests down to `names`. Requests outside of
+    /// `COMPILED_ALLOWLIST` stay rejected
+    pub fn with_allowed_requests(mut self, names: &[&str]) -> Self {
+        self.allowlist = Some(
+            names
+                .iter()
+                .filter(|name| {
+                    self.allowlist
+                        .as_ref()
+                        .is_none_or(|allowed| allowed.iter().any(|a| a == *name))
+                })
+                .map(|name| name.to_string())
+                .collect(),
+        );
+        self
+    }
+
+    /// Sends `rpc::HEARTBEAT_FRAME` every `interval` while a request is being processed
+    /// so the client can tell a slow operation from a dead peer
+    pub fn with_heartbeat_interval(mut self, interval: Duration) -> Self {
+        self.heartbeat_interval = Some(interval);
+        self
+    }
+
+    /// Sets the codec applied to encrypted key blobs returned to and received from the host
+    pub fn with_blob_codec(mut self, codec: Arc<dyn BlobCodec>) -> Self {
+        self.blob_codec = codec;
+        self
+    }
+
+    /// Sets the insecure options reported by `Hello`, none by default. `ImportUnencrypted`,
+    /// `ImportWrapped` and `ImportMnemonic`, `ExportWrapped` and `Rewrap` with `key_id` requests
+    /// are rejected unless `import_unencrypted`, `export_wrapped` and `rewrap_key_id` are set
+    /// respectively
+    pub fn with_insecure_options(mut self, options: InsecureOptions) -> Self {
+        self.insecure = options;
+        self
+    }
+
+    /// Makes the signer use the shared keychain instead of the connection-local one, so the keys
+    /// imported over one connection are available to all servers sharing it
+    pub fn with_shared_keychain(mut self, keychain: SharedKeychain) -> Self {
+        self.keychain = Some(keychain);
+        self
+    }
+
+    /// Gives each named session the keychain of its name in `namespaces`, shared with the
+    /// sessions of the same name on other connections. By default each named session has its own
+    /// keychain dropped with the connection
+    pub fn with_session_keychains(mut self, namespaces: KeychainNamespaces) -> Self {
+        self.session_keychains = Some(namespaces);
+        self
+    }
+
+    /// Caches up to `size` keys decrypted by `SignWith` and `PublicKeyFrom` requests,
+    /// see `EncryptedSigner::with_key_cache`
+    pub fn with_key_cache_size(mut self, size: usize) -> Self {
+        self.key_cache_size = Some(size);
+        self
+    }
+
+    /// Limits the number of tagged signing requests processed concurrently on a connection.
+    /// Further tagged requests are answered with `StateError::TooManyInFlight` until one of
+    /// them completes
+    pub fn with_max_concurrent_requests(mut self, n: usize) -> Self {
+        self.max_concurrent_requests = n.max(1);
+        self
+    }
+
+    /// Rejects request frames larger than `size` bytes with `FrameTooLargeError`
+    pub fn with_max_frame_size(mut self, size: usize) -> Self {
+        self.max_frame_size = size;
+        self
+    }
+
+    /// Rejects request frames exceeding the nesting depth or the number of items before decoding them
+    pub fn with_decode_limits(mut self, limits: DecodeLimits) -> Self {
+        self.decode_limits = limits;
+        self
+    }
+
+    /// Publishes the events of the connection to `bus` shared with other connections, so that
+    /// a subscriber sees them all. By default only the events of the connection are delivered
+    pub fn with_event_bus(mut self, bus: EventBus) -> Self {
+        self.events = bus;
+        self
+    }
+
+    /// Shares the results of `Idempotent` requests with other connections, normally all
+    /// connections of the same peer. By default they are only remembered within the connection
+    pub fn with_idempotency_cache(mut self, cache: Arc<IdempotencyCache>) -> Self {
+        self.idempotency = cache;
+        self
+    }
+
+    /// Sets the moment the uptime reported by `Ping` is counted from. The server creation
+    /// by default, i.e. the start of the connection
+    pub fn with_start_time(mut self, started: Instant) -> Self {
+        self.started = started;
+        self
+    }
+
+    /// Makes `Reinitialize` requests pass `token` to be accepted. Without a token all of them are refused
+    pub fn with_reinitialize_token(mut self, token: impl Into<String>) -> Self {
+        self.reinitialize_token = Some(token.into());
+        self
+    }
+
+    /// Makes `Shutdown` requests passing `token` shut down the whole signer, see
+    /// `is_shutdown_authorized`. Requests with a token are rejected if not set
+    pub fn with_shutdown_token(mut self, token: impl Into<String>) -> Self {
+        self.shutdown_token = Some(token.into());
+        self
+    }
+
+    /// Sets the source of the documents returned by `Attest`. The request is rejected if not set
+    pub fn with_attestation_source(mut self, source: Arc<dyn AttestationSource>) -> Self {
+        self.attestation = Some(source);
+        self
+    }
+
+    /// Replaces the default cache of signature verification results with the one of the given size
+    pub fn with_verify_cache_size(mut self, size: usize) -> Self {
+        self.verify_cache = VerifyCache::new(size);
+        self
+    }
 }
 
 impl<F, R> Server<F, EncryptedSigner<F::Output>, R>
 where
     F: EncryptionBackendFactory,
     F::Output: EncryptionBackend,
-    F::Credentials: DeserializeOwned,
+    F::Credentials: DeserializeOwned + Cddl + CredentialsSchema,
     R: CryptoRngCore,
     RPCError: From<<F::Output as EncryptionBackend>::Error>
         + From<SignerError<<F::Output as EncryptionBackend>::Error>>,
 {
-    pub async fn serve_connection<T: AsyncWrite + AsyncReadExt + Unpin>(
+    /// Serves requests until the peer closes the connection or sends `Shutdown`. Tagged signing
+    /// requests are processed concurrently and answered as soon as they complete. Any other
+    /// request waits for them and is processed alone
+    pub async fn serve_connection<T: AsyncRead + AsyncWrite>(
+        &mut self,
+        sock: T,
+    ) -> Result<(), Error> {
+        let (rd, mut wr) = tokio::io::split(sock);
+        // a single frame is read ahead
+        let (frames_tx, frames_rx) = mpsc::channel(1);
+        let reader = read_frames(rd, frames_tx, self.max_frame_size);
+        let dispatch = self.dispatch(&mut wr, frames_rx);
+        tokio::pin!(reader, dispatch);
+        tokio::select! {
+            res = &mut dispatch => res,
+            res = &mut reader => {
+                res?;
+                // the requests read so far are still answered
+                dispatch.await
+            }
+        }
+    }
+
+    /// Returns true once `Shutdown` was processed. The keys of the connection are dropped and
+    /// the connection is closed by then
+    pub fn is_shut_down(&self) -> bool {
+        self.shut_down
+    }
+
+    /// Returns true once `Shutdown` with the shutdown token was processed. All keys are dropped
+    /// by then, the caller may retire the signer
+    pub fn is_shutdown_authorized(&self) -> bool {
+        self.shutdown_authorized
+    }
+
+    async fn dispatch<W: AsyncWrite + Unpin>(
         &mut self,
-        mut sock: T,
+        wr: &mut W,
+        mut frames: mpsc::Receiver<Result<Vec<u8>, FrameTooLargeError>>,
     ) -> Result<(), Error> {
-        let mut buf = Vec::<u8>::new();
+        // progress and state frames, written while the response is still being built
         let mut w_buf = Vec::<u8>::new();
+        let mut backend_state = BackendState::Available;
+        let heartbeat_interval = self.heartbeat_interval;
+        let mut in_flight = FuturesUnordered::new();
+        // abort handles of the in-flight requests by tag
+        let mut cancel = HashMap::new();
+        // request waiting for the in-flight ones to complete
+        let mut pending = None;
+        let mut subscription = None;
         loop {
-            let mut len_buf: [u8; 4] = [0; 4];
-            if let Err(err) = sock.read_exact(&mut len_buf).await {
-                break if err.kind() == io::ErrorKind::UnexpectedEof {
-                    Ok(())
-                } else {
-                    Err(err.into())
+            if in_flight.is_empty() {
+                if let Some((req, trace_id, mut buf)) = pending.take() {
+                    self.process(wr, &mut w_buf, &mut backend_state, req, trace_id, &mut buf)
+                        .await?;
+                    if self.shut_down {
+                        // the requests sent after Shutdown are dropped
+                        return Ok(wr.shutdown().await?);
+                    }
+                    if self.subscribed && subscription.is_none() {
+                        subscription = Some(self.events.subscribe());
+                    }
+                }
+            }
+            tokio::select! {
+                biased;
+                Some((id, res)) = in_flight.next(), if !in_flight.is_empty() => {
+                    cancel.remove(&id);
+                    let mut frame = tagged_frame(id, res)?;
+                    self.compress(&mut frame)?;
+                    self.report_backend_state(wr, &mut w_buf, &mut backend_state).await?;
+                    wr.write_all(&frame).await?;
+                }
+                frame = frames.recv(), if pending.is_none() => {
+                    let (req, buf) = match frame {
+                        Some(Ok(buf)) => (
+                            self.decode(&buf)
+                                .and_then(|req| self.enter_session(req))
+                                .and_then(|req| self.authenticate(req)),
+                            buf,
+                        ),
+                        Some(Err(err)) => (Err(err.into()), Vec::new()),
+                        None => break,
+                    };
+                    let (req, trace_id) = untrace(req);
+                    match (req, &self.signer) {
+                        // answered right away so the client can tell overload from a stall
+                        (Ok(Request::Tagged { id, request }), Some(_))
+                            if request.is_concurrent()
+                                && self.is_allowed(request.name())
+                                && in_flight.len() >= self.max_concurrent_requests =>
+                        {
+                            let limit = self.max_concurrent_requests;
+                            let mut frame = tagged_error(id, StateError::TooManyInFlight(limit))?;
+                            self.compress(&mut frame)?;
+                            wr.write_all(&frame).await?;
+                        }
+                        (Ok(Request::Tagged { id, request }), Some(signer))
+                            if request.is_concurrent() && self.is_allowed(request.name()) =>
+                        {
+                            let (handle, reg) = AbortHandle::new_pair();
+                            cancel.insert(id, handle);
+                            let fut = handle_tagged(
+                                signer.clone(),
+                                id,
+                                *request,
+                                trace_id,
+                                self.rng.clone(),
+                            );
+                            in_flight.push(Abortable::new(fut, reg).map(move |res| (id, res)));
+                        }
+                        // answered right away, the cancelled request is answered once dropped
+                        (Ok(Request::Cancel { id }), Some(_)) if self.is_allowed("Cancel") => {
+                            let found = cancel.remove(&id).map(|h: AbortHandle| h.abort());
+                            rpc::encode_frame(&mut w_buf, &RPCResult::Ok(found.is_some()))?;
+                            wr.write_all(&w_buf).await?;
+                        }
+                        (req, _) => pending = Some((req, trace_id, buf)),
+                    }
+                }
+                _ = tokio::time::sleep(heartbeat_interval.unwrap_or_default()),
+                    if heartbeat_interval.is_some() && !in_flight.is_empty() => {
+                    wr.write_all(&rpc::HEARTBEAT_FRAME).await?
+                }
+                res = async { subscription.as_mut().unwrap().recv().await },
+                    if subscription.is_some() => {
+                    let event = match res {
+                        Ok(event) => event,
+                        Err(broadcast::error::RecvError::Lagged(missed)) => Event::Lagged { missed },
+                        Err(broadcast::error::RecvError::Closed) => {
+                            subscription = None;
+                            continue;
+                        }
+                    };
+                    send_interim(wr, &mut w_buf, &Interim::Event(event)).await?
+                }
+            }
+        }
+        // the peer is gone but may still read the responses
+        while let Some((id, res)) = in_flight.next().await {
+            let mut frame = tagged_frame(id, res)?;
+            self.compress(&mut frame)?;
+            wr.write_all(&frame).await?;
+        }
+        Ok(())
+    }
+
+    /// Processes the request alone, sending progress and heartbeat frames until the response
+    /// is written
+    async fn process<W: AsyncWrite + Unpin>(
+        &mut self,
+        wr: &mut W,
+        w_buf: &mut Vec<u8>,
+        backend_state: &mut BackendState,
+        req: Result<Request<VersionedCredentials<F::Credentials>>, RPCError>,
+        trace_id: Option<String>,
+        buf: &mut Vec<u8>,
+    ) -> Result<(), Error> {
+        let heartbeat_interval = self.heartbeat_interval;
+        let keys = self.key_count();
+        let (progress, mut progress_rx) = ProgressReporter::channel();
+        {
+            let fut = self.handle_message(req, trace_id, buf, progress);
+            tokio::pin!(fut);
+            loop {
+                tokio::select! {
+                    biased;
+                    Some(p) = progress_rx.recv() => {
+                        send_interim(wr, w_buf, &Interim::Progress(p)).await?
+                    }
+                    res = &mut fut => break res?,
+                    _ = tokio::time::sleep(heartbeat_interval.unwrap_or_default()),
+                        if heartbeat_interval.is_some() => {
+                        wr.write_all(&rpc::HEARTBEAT_FRAME).await?
+                    }
+                }
+            }
+        }
+        // progress reported right before completion
+        while let Ok(p) = progress_rx.try_recv() {
+            send_interim(wr, w_buf, &Interim::Progress(p)).await?;
+        }
+        self.report_backend_state(wr, w_buf, backend_state).await?;
+        if let Some(keys) = self.key_count().filter(|&n| Some(n) != keys) {
+            self.events.publish(Event::KeychainChanged { keys });
+        }
+        rpc::finish_frame(buf);
+        self.compress(buf)?;
+        Ok(wr.write_all(buf).await?)
+    }
+
+    /// Compresses the complete response frame if compression was negotiated
+    fn compress(&self, frame: &mut Vec<u8>) -> Result<(), Error> {
+        if let Some((algorithm, min_size)) = self.compression {
+            codec::compress_frame(frame, algorithm, min_size)?;
+        }
+        Ok(())
+    }
+
+    async fn report_backend_state<W: AsyncWrite + Unpin>(
+        &self,
+        wr: &mut W,
+        w_buf: &mut Vec<u8>,
+        backend_state: &mut BackendState,
+    ) -> Result<(), Error> {
+        if let Some(state) = self.signer.as_deref().map(EncryptedSigner::backend_state) {
+            if state != *backend_state {
+                println!("encryption backend state changed: {:?}", state);
+                *backend_state = state;
+                self.events.publish(Event::BackendState(state));
+                send_interim(wr, w_buf, &Interim::BackendState(state)).await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn decode(
+        &self,
+        buf: &[u8],
+    ) -> Result<Request<VersionedCredentials<F::Credentials>>, RPCError> {
+        // compressed requests are accepted whether negotiated or not
+        let buf = codec::decompress(buf, self.max_frame_size)?;
+        limits::check(&buf, &self.decode_limits)?;
+        Ok(Request::try_from_cbor(&buf)?)
+    }
+
+    /// Unwraps `Session` requests making the named session the current one. Requests without
+    /// the envelope go to the session of the connection
+    fn enter_session(
+        &mut self,
+        req: Request<VersionedCredentials<F::Credentials>>,
+    ) -> Result<Request<VersionedCredentials<F::Credentials>>, RPCError> {
+        let (name, req) = match req {
+            Request::Session { name, request } => {
+                if name.is_empty()
+                    || name.len() > MAX_SESSION_NAME_LEN
+                    || !name.bytes().all(|c| c.is_ascii_graphic())
+                {
+                    return Err(StateError::InvalidSessionName.into());
+                }
+                (Some(name), *request)
+            }
+            req => (None, req),
+        };
+        if name == self.session {
+            return Ok(req);
+        }
+        let state = match self.sessions.remove(&name) {
+            Some(state) => state,
+            None if self.sessions.keys().flatten().count()
+                + usize::from(self.session.is_some())
+                >= MAX_SESSIONS =>
+            {
+                return Err(StateError::TooManySessions(MAX_SESSIONS).into())
+            }
+            None => SessionState {
+                signer: None,
+                keychain: self
+                    .session_keychains
+                    .as_ref()
+                    .zip(name.as_deref())
+                    .map(|(namespaces, name)| namespaces.get(name)),
+                last_seq: None,
+                sign_stream: None,
+            },
+        };
+        let current = SessionState {
+            signer: std::mem::replace(&mut self.signer, state.signer),
+            keychain: std::mem::replace(&mut self.keychain, state.keychain),
+            last_seq: std::mem::replace(&mut self.last_seq, state.last_seq),
+            sign_stream: std::mem::replace(&mut self.sign_stream, state.sign_stream),
+        };
+        self.sessions
+            .insert(std::mem::replace(&mut self.session, name), current);
+        Ok(req)
+    }
+
+    /// Unwraps `Authenticated` requests once the keychain of the session has a request key
+    fn authenticate(
+        &mut self,
+        req: Request<VersionedCredentials<F::Credentials>>,
+    ) -> Result<Request<VersionedCredentials<F::Credentials>>, RPCError> {
+        // set by any connection sharing the keychain
+        let key = self.signer.as_ref().and_then(|signer| {
+            signer
+                .keychain()
+                .read()
+                .request_key()
+                .map(|key| Zeroizing::new(key.to_vec()))
+        });
+        let Some(key) = key else {
+            return match req {
+                Request::Authenticated { .. } => Err(StateError::NoRequestKey.into()),
+                req => Ok(req),
+            };
+        };
+        let Request::Authenticated { seq, request, mac } = req else {
+            self.report_violation(req.name(), ErrorCode::Unauthorized);
+            return Err(StateError::Unauthenticated.into());
+        };
+        if self.last_seq.is_some_and(|last| seq <= last)
+            || rpc::request_mac(&key, seq, &request)
+                .verify_slice(&mac)
+                .is_err()
+        {
+            self.report_violation("Authenticated", ErrorCode::Unauthorized);
+            return Err(StateError::Unauthenticated.into());
+        }
+        self.last_seq = Some(seq);
+        match self.decode(&request)? {
+            Request::Authenticated { .. } => Err(StateError::Unauthenticated.into()),
+            req => Ok(req),
+        }
+    }
+
+    fn report_violation(&self, request: &str, code: ErrorCode) {
+        self.events.publish(Event::PolicyViolation {
+            request: request.into(),
+            code,
+        });
+    }
+
+    fn key_count(&self) -> Option<u64> {
+        self.signer.as_ref().map(|s| s.key_count() as u64)
+    }
+
+    fn is_allowed(&self, name: &str) -> bool {
+        self.allowlist
+            .as_ref()
+            .is_none_or(|allowed| allowed.iter().any(|a| a == name))
+    }
+
+    /// Generates the key unless the idempotency key was already used. The key imported by
+    /// `GenerateAndImport` is imported again if it's not in the keychain any more, e.g. after
+    /// reconnecting
+    async fn idempotent(
+        &mut self,
+        signer: &EncryptedSigner<F::Output>,
+        key: Vec<u8>,
+        req: Request<VersionedCredentials<F::Credentials>>,
+    ) -> RPCResult<idempotency::Generated> {
+        let (key_type, import) = match req {
+            Request::Generate(t) => (t, false),
+            Request::GenerateAndImport(t) => (t, true),
+            req => return Err(StateError::NotIdempotent(req.name()).into()),
+        };
+        if key.is_empty() || key.len() > idempotency::MAX_KEY_LEN {
+            return Err(StateError::InvalidIdempotencyKey.into());
+        }
+
+        let cache = self.idempotency.clone();
+        let mut cache = cache.lock().await;
+        if let Some(mut entry) = cache.get(&key).cloned() {
+            if (entry.key_type, entry.import) != (key_type, import) {
+                return Err(StateError::IdempotencyKeyReused.into());
+            }
+            if let Some(handle) = entry.handle {
+                let present = match signer.public_key(handle).await {
+                    Ok(pk) => pk
+                        .fingerprint()
+                        .is_ok_and(|f| f[..] == entry.fingerprint[..]),
+                    Err(_) => false,
                 };
+                if !present {
+                    entry.handle = Some(signer.import(&entry.encrypted_private_key).await?.handle);
+                    cache.insert(key, entry.clone());
+                }
+            }
+            return Ok(entry.result());
+        }
+
+        let entry = if import {
+            let res = signer.generate_and_import(key_type, &mut self.rng).await?;
+            idempotency::Entry {
+                key_type,
+                import,
+                encrypted_private_key: res.encrypted_private_key,
+                public_key: res.public_key,
+                fingerprint: res.fingerprint,
+                handle: Some(res.handle),
+            }
+        } else {
+            let res = signer.generate(key_type, &mut self.rng).await?;
+            idempotency::Entry {
+                key_type,
+                import,
+                encrypted_private_key: res.encrypted_private_key,
+                public_key: res.public_key,
+                fingerprint: res.fingerprint,
+                handle: None,
+            }
+        };
+        cache.insert(key, entry.clone());
+        Ok(entry.result())
+    }
+
+    /// Creates the signer and imports all keys. Nothing is kept if any of the steps fails
+    async fn try_initialize(
+        &self,
+        mut cred: VersionedCredentials<F::Credentials>,
+        keys: &[Vec<u8>],
+    ) -> Result<(EncryptedSigner<F::Output>, Vec<ImportResult>), RPCError> {
+        let request_key = cred.request_key.take();
+        let signer = self.new_signer(cred, self.keychain.clone()).await?;
+        // before the keys are imported into a shared keychain
+        if let Err(err) = bind_request_key(signer.keychain(), request_key) {
+            self.report_violation("Initialize", ErrorCode::Unauthorized);
+            return Err(err.into());
+        }
+        let mut res = Vec::with_capacity(keys.len());
+        for key_data in keys {
+            res.push(signer.import(key_data).await?);
+        }
+        Ok((signer, res))
+    }
+
+    async fn new_signer(
+        &self,
+        cred: VersionedCredentials<F::Credentials>,
+        keychain: Option<SharedKeychain>,
+    ) -> Result<EncryptedSigner<F::Output>, RPCError> {
+        if cred.version > F::Credentials::VERSION {
+            return Err(CredentialsVersionError {
+                version: cred.version,
+                supported: F::Credentials::VERSION,
             }
-            let len = u32::from_be_bytes(len_buf);
-            buf.resize(len as usize, 0);
-            sock.read_exact(&mut buf).await?;
+            .into());
+        }
+        if !cred.unknown.is_empty() {
+            let names: Vec<&str> = cred.unknown.keys().map(String::as_str).collect();
+            self.log(format_args!(
+                "ignoring unknown credentials fields: {}",
+                names.join(", ")
+            ));
+        }
+        let enc = self.fact.try_new(cred.credentials).await?;
+        let mut signer = EncryptedSigner::new(enc).with_codec(self.blob_codec.clone());
+        if let Some(keychain) = keychain {
+            signer = signer.with_keychain(keychain);
+        }
+        if let Some(size) = self.key_cache_size {
+            signer = signer.with_key_cache(size);
+        }
+        Ok(signer)
+    }
 
-            self.handle_message(&mut buf).await?;
-            let len = u32::try_from(buf.len()).unwrap().to_be_bytes();
-            w_buf.clear();
-            w_buf.extend_from_slice(&len);
-            w_buf.extend_from_slice(&buf);
+    /// Fails closed: `Reinitialize` is refused unless the server has a token
+    fn check_reinitialize_token(&self, token: Option<&str>) -> bool {
+        match (&self.reinitialize_token, token) {
+            (Some(expected), Some(token)) => expected.as_bytes().ct_eq(token.as_bytes()).into(),
+            _ => false,
+        }
+    }
 
-            sock.write_all(&w_buf).await?;
+    /// Prints the line prefixed with the trace ID of the request being processed, if any
+    fn log(&self, args: std::fmt::Arguments<'_>) {
+        match &self.trace_id {
+            Some(trace_id) => println!("trace {}: {}", trace_id, args),
+            None => println!("{}", args),
         }
     }
 
-    async fn handle_message(&mut self, buf: &mut Vec<u8>) -> Result<(), Error> {
-        let req = Request::<F::Credentials>::try_from_cbor(buf);
-        buf.clear();
+    async fn handle_message(
+        &mut self,
+        req: Result<Request<VersionedCredentials<F::Credentials>>, RPCError>,
+        trace_id: Option<String>,
+        buf: &mut Vec<u8>,
+        progress: ProgressReporter,
+    ) -> Result<(), Error> {
+        rpc::begin_frame(buf);
+        let req = match req {
+            Ok(Request::Tagged { id, request }) => {
+                rpc::begin_tagged_frame(buf, id)?;
+                match *request {
+                    Request::Tagged { .. } => Err(StateError::NestedTagged.into()),
+                    request => Ok(request),
+                }
+            }
+            req => req,
+        };
+        let start = buf.len();
+        let name = req.as_ref().map_or("request", Request::name);
+        self.trace_id = trace_id;
+        let res = self.handle_request(req, buf, progress).await;
+        if let Some(trace_id) = self.trace_id.take() {
+            attach_trace(buf, start, &trace_id, name)?;
+        }
+        res
+    }
 
+    async fn handle_request(
+        &mut self,
+        req: Result<Request<VersionedCredentials<F::Credentials>>, RPCError>,
+        buf: &mut Vec<u8>,
+        progress: ProgressReporter,
+    ) -> Result<(), Error> {
         let req = match req {
             Ok(req) => req,
             Err(err) => {
                 // return deserialization error to the client
-                println!("invalid request: {}", err);
-                return RPCResult::<()>::Err(err.into())
+                self.log(format_args!("invalid request: {}", err));
+                return RPCResult::<()>::Err(err)
                     .try_into_writer(buf)
                     .map_err(Into::into)
                     .and(Ok(()));
             }
         };
 
-        match (req, &mut self.signer) {
-            (Request::Initialize(cred), None) => match self.fact.try_new(cred).await {
-                Ok(enc) => {
-                    self.signer = Some(enc.into());
+        // the allowlist applies to the wrapped request
+        let name = match &req {
+            Request::Idempotent { request, .. } => request.name(),
+            req => req.name(),
+        };
+        if !self.is_allowed(name) {
+            self.report_violation(name, ErrorCode::Disabled);
+            return RPCResult::<()>::Err(StateError::Disabled(name).into())
+                .try_into_writer(buf)
+                .map_err(Into::into)
+                .and(Ok(()));
+        }
+
+        match (req, &self.signer) {
+            (Request::Initialize(cred), None) => match self.try_initialize(cred, &[]).await {
+                Ok((signer, _)) => {
+                    self.signer = Some(Arc::new(signer));
                     RPCResult::<()>::Ok(())
                 }
-                Err(err) => RPCResult::<()>::Err(err.into()),
+                Err(err) => RPCResult::<()>::Err(err),
             }
             .try_into_writer(buf)
             .and(Ok(())),
 
-            (Request::Initialize(_), Some(_)) => {
+            (Request::InitializeWith { credentials, keys }, None) => {
+                match self.try_initialize(credentials, &keys).await {
+                    Ok((signer, res)) => {
+                        self.signer = Some(Arc::new(signer));
+                        RPCResult::Ok(res)
+                    }
+                    Err(err) => RPCResult::Err(err),
+                }
+                .try_into_writer(buf)
+                .and(Ok(()))
+            }
+
+            (Request::Initialize(_) | Request::InitializeWith { .. }, Some(_)) => {
                 RPCResult::<()>::Err(StateError::Initialized.into())
                     .try_into_writer(buf)
                     .and(Ok(()))
             }
 
-            (_, None) => RPCResult::<()>::Err(StateError::Uninitialized.into())
+            (
+                Request::Reinitialize {
+                    mut credentials,
+                    token,
+                },
+                Some(signer),
+            ) => {
+                if !self.check_reinitialize_token(token.as_deref()) {
+                    self.report_violation("Reinitialize", ErrorCode::Unauthorized);
+                    return RPCResult::<()>::Err(StateError::Unauthorized.into())
+                        .try_into_writer(buf)
+                        .map_err(Into::into)
+                        .and(Ok(()));
+                }
+                // the keys stay, only the backend is replaced
+                let request_key = credentials.request_key.take();
+                let keychain = signer.keychain().clone();
+                match self.new_signer(credentials, Some(keychain)).await {
+                    Ok(signer) => {
+                        // replaces the key of all connections sharing the keychain
+                        if let Some(key) = request_key {
+                            signer.keychain().write().set_request_key(&key.0);
+                        }
+                        self.signer = Some(Arc::new(signer));
+                        RPCResult::<()>::Ok(())
+                    }
+                    Err(err) => RPCResult::<()>::Err(err),
+                }
                 .try_into_writer(buf)
-                .and(Ok(())),
+                .and(Ok(()))
+            }
 
-            (Request::Import(key_data), Some(signer)) => signer
-                .import(&key_data)
-                .await
-                .map_err(RPCError::from)
+            (Request::Describe, _) => RPCResult::Ok(rpc::describe::<F::Credentials>())
                 .try_into_writer(buf)
                 .and(Ok(())),
 
-            (Request::ImportUnencrypted(key), Some(signer)) => signer
-                .import_unencrypted(key)
-                .await
-                .map_err(RPCError::from)
-                .try_into_writer(buf)
-                .and(Ok(())),
+            (Request::Hello, _) => RPCResult::Ok(HelloResult {
+                version: env!("CARGO_PKG_VERSION").into(),
+                state_machine_version: STATE_MACHINE_VERSION,
+                insecure: self.insecure,
+                allowed_requests: self.allowlist.clone(),
+                shared_keychain: self.keychain.is_some(),
+            })
+            .try_into_writer(buf)
+            .and(Ok(())),
 
-            (Request::Generate(t), Some(signer)) => signer
-                .generate(t, &mut self.rng)
-                .await
-                .map_err(RPCError::from)
-                .try_into_writer(buf)
-                .and(Ok(())),
+            (Request::Shutdown { token }, Some(_)) => {
+                let authorized = match (&self.shutdown_token, &token) {
+                    (Some(expected), Some(token)) => {
+                        expected.as_bytes().ct_eq(token.as_bytes()).into()
+                    }
+                    _ => false,
+                };
+                if token.is_some() && !authorized {
+                    self.report_violation("Shutdown", ErrorCode::Unauthorized);
+                    return RPCResult::<()>::Err(StateError::InvalidShutdownToken.into())
+                        .try_into_writer(buf)
+                        .map_err(Into::into)
+                        .and(Ok(()));
+                }
+                let signer = self.signer.take().unwrap();
+                // the shared keys are used by other connections
+                if authorized || self.keychain.is_none() {
+                    signer.clear();
+                }
+                self.shut_down = true;
+                self.shutdown_authorized = authorized;
+                RPCResult::<()>::Ok(()).try_into_writer(buf).and(Ok(()))
+            }
 
-            (Request::GenerateAndImport(t), Some(signer)) => signer
-                .generate_and_import(t, &mut self.rng)
-                .await
-                .map_err(RPCError::from)
-                .try_into_writer(buf)
-                .and(Ok(())),
+            (Request::GetInfo, signer) => RPCResult::Ok(InfoResult {
+                version: env!("CARGO_PKG_VERSION").into(),
+                state_machine_version: STATE_MACHINE_VERSION,
+                key_types: KeyType::ALL.into(),
+                initialized: signer.is_some(),
+                keys: signer.as_ref().map_or(0, |s| s.key_count() as u64),
+            })
+            .try_into_writer(buf)
+            .and(Ok(())),
+
+            (Request::Attest { nonce, user_data }, _) => match &self.attestation {
+                Some(source) => source
+                    .attest(&nonce, &user_data)
+                    .map(|document| AttestResult { document })
+                    .map_err(|err| RPCError::from(attestation::Error(err))),
+                None => Err(RPCError::from(StateError::Disabled("Attest"))),
+            }
+            .try_into_writer(buf)
+            .and(Ok(())),
 
             (
-                Request::Sign {
-                    handle,
-                    message: msg,
-                    version,
+                Request::NegotiateCompression {
+                    algorithms,
+                    min_size,
                 },
-                Some(signer),
-            ) => signer
-                .try_sign(handle, &msg, version)
-                .map_err(RPCError::from)
-                .try_into_writer(buf)
-                .and(Ok(())),
+                _,
+            ) => {
+                let algorithm = algorithms
+                    .into_iter()
+                    .find(|alg| codec::SUPPORTED_COMPRESSION.contains(alg));
+                self.compression =
+                    algorithm.map(|alg| (alg, usize::try_from(min_size).unwrap_or(usize::MAX)));
+                RPCResult::Ok(NegotiateCompressionResult { algorithm })
+                    .try_into_writer(buf)
+                    .and(Ok(()))
+            }
+
+            (Request::Subscribe, _) => {
+                self.subscribed = true;
+                RPCResult::Ok(()).try_into_writer(buf).and(Ok(()))
+            }
+
+            (Request::Ping { payload }, _) => RPCResult::Ok(PongResult {
+                payload,
+                uptime: self.started.elapsed().as_secs(),
+            })
+            .try_into_writer(buf)
+            .and(Ok(())),
 
             (
-                Request::SignWith {
-                    encrypted_private_key: key_data,
-                    message: msg,
+                Request::Verify {
+                    public_key,
+                    message,
+                    signature,
                     version,
                 },
-                Some(signer),
-            ) => signer
-                .try_sign_with(&key_data, &msg, version)
-                .await
+                _,
+            ) => self
+                .verify_cache
+                .verify(&public_key, &message, &signature, version)
                 .map_err(RPCError::from)
                 .try_into_writer(buf)
                 .and(Ok(())),
 
-            (Request::PublicKey(handle), Some(signer)) => signer
-                .public_key(handle)
+            (
+                Request::VerifyAggregate {
+                    public_keys,
+                    message,
+                    signature,
+                    version,
+                },
+                _,
+            ) => crypto::verify_aggregate(&public_keys, &message, &signature, version)
                 .map_err(RPCError::from)
                 .try_into_writer(buf)
                 .and(Ok(())),
 
-            (Request::PublicKeyFrom(key_data), Some(signer)) => signer
-                .public_key_from(&key_data)
-                .await
+            (
+                Request::VerifyBatch {
+                    public_keys,
+                    messages,
+                    signatures,
+                },
+                _,
+            ) => crypto::verify_batch(&public_keys, &messages, &signatures)
                 .map_err(RPCError::from)
                 .try_into_writer(buf)
                 .and(Ok(())),
 
-            (Request::ProvePossession(handle), Some(signer)) => signer
+            (
+                Request::MuSig2Aggregate {
+                    public_keys,
+                    message,
+                    pub_nonces,
+                    partial_signatures,
+                },
+                _,
+            ) => {
+                MuSig2AggregateResult::new(&public_keys, &message, &pub_nonces, &partial_signatures)
+                    .map_err(RPCError::from)
+                    .try_into_writer(buf)
+                    .and(Ok(()))
+            }
+
+            // refused whether or not the session is initialized
+            (Request::Session { .. }, _) => {
+                RPCResult::<()>::Err(StateError::MisplacedSession.into())
+                    .try_into_writer(buf)
+                    .and(Ok(()))
+            }
+
+            (req, None) => RPCResult::<()>::Err(
+                StateError::Uninitialized {
+                    request: req.name(),
+                    allowed: UNINITIALIZED_REQUESTS,
+                }
+                .into(),
+            )
+            .try_into_writer(buf)
+            .and(Ok(())),
+
+            (Request::Import(key_data), Some(signer)) => signer
+                .import(&key_data)
+                .await
+                .map_err(RPCError::from)
+                .try_into_writer(buf)
+                .and(Ok(())),
+
+            (Request::ImportUnencrypted(_), Some(_)) if !self.insecure.import_unencrypted => {
+                RPCResult::<()>::Err(StateError::Disabled("ImportUnencrypted").into())
+                    .try_into_writer(buf)
+                    .and(Ok(()))
+            }
+
+            // the sender isn't authenticated, anyone can wrap a key to the public key
+            (Request::ImportWrapped { .. }, Some(_)) if !self.insecure.import_unencrypted => {
+                RPCResult::<()>::Err(StateError::Disabled("ImportWrapped").into())
+                    .try_into_writer(buf)
+                    .and(Ok(()))
+            }
+
+            // the mnemonic is key material supplied by the host
+            (Request::ImportMnemonic { .. }, Some(_)) if !self.insecure.import_unencrypted => {
+                RPCResult::<()>::Err(StateError::Disabled("ImportMnemonic").into())
+                    .try_into_writer(buf)
+                    .and(Ok(()))
+            }
+
+            (Request::ExportWrapped { .. }, Some(_)) if !self.insecure.export_wrapped => {
+                RPCResult::<()>::Err(StateError::Disabled("ExportWrapped").into())
+                    .try_into_writer(buf)
+                    .and(Ok(()))
+            }
+
+            (
+                Request::Rewrap {
+                    key_id: Some(_), ..
+                },
+                Some(_),
+            ) if !self.insecure.rewrap_key_id => {
+                RPCResult::<()>::Err(StateError::Disabled("Rewrap with key_id").into())
+                    .try_into_writer(buf)
+                    .and(Ok(()))
+            }
+
+            (Request::Rewrap { key_data, key_id }, Some(signer)) => signer
+                .rewrap(&key_data, key_id.as_deref())
+                .await
+                .map_err(RPCError::from)
+                .try_into_writer(buf)
+                .and(Ok(())),
+
+            (Request::ExportWrapped { handle, recipient }, Some(signer)) => signer
+                .export_wrapped(handle, &recipient, &mut self.rng)
+                .await
+                .map_err(RPCError::from)
+                .try_into_writer(buf)
+                .and(Ok(())),
+
+            (
+                Request::ImportWrapped {
+                    handle,
+                    wrapped_key,
+                },
+                Some(signer),
+            ) => signer
+                .import_wrapped(handle, &wrapped_key)
+                .await
+                .map_err(RPCError::from)
+                .try_into_writer(buf)
+                .and(Ok(())),
+
+            (Request::ImportUnencrypted(key), Some(signer)) => {
+                let res = match key.into_private_key() {
+                    Ok(pk) => signer.import_unencrypted(pk).await,
+                    Err(err) => Err(err.into()),
+                };
+                res.map_err(RPCError::from).try_into_writer(buf).and(Ok(()))
+            }
+
+            (Request::Generate(t), Some(signer)) => signer
+                .generate(t, &mut self.rng)
+                .await
+                .map_err(RPCError::from)
+                .try_into_writer(buf)
+                .and(Ok(())),
+
+            (Request::GenerateAndImport(t), Some(signer)) => signer
+                .generate_and_import(t, &mut self.rng)
+                .await
+                .map_err(RPCError::from)
+                .try_into_writer(buf)
+                .and(Ok(())),
+
+            (
+                Request::GenerateConstrained {
+                    key_type,
+                    constraint,
+                    import,
+                },
+                Some(signer),
+            ) => signer
+                .generate_constrained(key_type, &constraint, import, &mut self.rng, |c, t| {
+                    progress.report(c, t)
+                })
+                .await
+                .map_err(RPCError::from)
+                .try_into_writer(buf)
+                .and(Ok(())),
+
+            (
+                Request::ExportShares {
+                    handle,
+                    threshold,
+                    shares,
+                },
+                Some(signer),
+            ) => signer
+                .export_shares(handle, threshold, shares, &mut self.rng)
+                .await
+                .map_err(RPCError::from)
+                .try_into_writer(buf)
+                .and(Ok(())),
+
+            (Request::ImportShares(shares), Some(signer)) => signer
+                .import_shares(&shares)
+                .await
+                .map_err(RPCError::from)
+                .try_into_writer(buf)
+                .and(Ok(())),
+
+            (
+                Request::MuSig2Nonce {
+                    handle,
+                    public_keys,
+                    message,
+                },
+                Some(signer),
+            ) => signer
+                .musig2_nonce(handle, &public_keys, &message, &mut self.rng)
+                .await
+                .map_err(RPCError::from)
+                .try_into_writer(buf)
+                .and(Ok(())),
+
+            (
+                Request::MuSig2Sign {
+                    session,
+                    pub_nonces,
+                },
+                Some(signer),
+            ) => signer
+                .musig2_sign(session, &pub_nonces)
+                .map_err(RPCError::from)
+                .try_into_writer(buf)
+                .and(Ok(())),
+
+            (Request::ImportDeferred(key_data), Some(signer)) => {
+                RPCResult::Ok(signer.import_deferred(&key_data))
+                    .try_into_writer(buf)
+                    .and(Ok(()))
+            }
+
+            (Request::ImportPublicKey(public_key), Some(signer)) => signer
+                .import_public_key(public_key)
+                .map_err(RPCError::from)
+                .try_into_writer(buf)
+                .and(Ok(())),
+
+            (
+                Request::VerifyWith {
+                    handle,
+                    message,
+                    signature,
+                    version,
+                },
+                Some(signer),
+            ) => signer
+                .public_key(handle)
+                .await
+                .map_err(RPCError::from)
+                .and_then(|public_key| {
+                    self.verify_cache
+                        .verify(&public_key, &message, &signature, version)
+                        .map_err(RPCError::from)
+                })
+                .try_into_writer(buf)
+                .and(Ok(())),
+
+            (Request::DeriveChild { handle, path }, Some(signer)) => signer
+                .derive_child(handle, &path)
+                .await
+                .map_err(RPCError::from)
+                .try_into_writer(buf)
+                .and(Ok(())),
+
+            (Request::TweakKey { handle, tweak }, Some(signer)) => signer
+                .tweak_key(handle, &tweak)
+                .await
+                .map_err(RPCError::from)
+                .try_into_writer(buf)
+                .and(Ok(())),
+
+            (Request::ListKeys, Some(signer)) => RPCResult::Ok(signer.list_keys())
+                .try_into_writer(buf)
+                .and(Ok(())),
+
+            (Request::SetExpiry { handle, ttl }, Some(signer)) => signer
+                .set_expiry(handle, ttl)
+                .map_err(RPCError::from)
+                .try_into_writer(buf)
+                .and(Ok(())),
+
+            (Request::KeyStats, Some(signer)) => RPCResult::Ok(signer.key_stats())
+                .try_into_writer(buf)
+                .and(Ok(())),
+
+            (Request::SetMetadata { handle, metadata }, Some(signer)) => signer
+                .set_metadata(handle, metadata)
+                .map_err(RPCError::from)
+                .try_into_writer(buf)
+                .and(Ok(())),
+
+            (Request::FindLabel(label), Some(signer)) => RPCResult::Ok(signer.find_label(&label))
+                .try_into_writer(buf)
+                .and(Ok(())),
+
+            (Request::FindPublicKey(public_key), Some(signer)) => {
+                RPCResult::Ok(signer.find_public_key(&public_key))
+                    .try_into_writer(buf)
+                    .and(Ok(()))
+            }
+
+            (Request::Remove(handle), Some(signer)) => signer
+                .remove(handle)
+                .map_err(RPCError::from)
+                .try_into_writer(buf)
+                .and(Ok(())),
+
+            (Request::GenerateSeed, Some(signer)) => signer
+                .generate_seed(&mut self.rng)
+                .await
+                .map_err(RPCError::from)
+                .try_into_writer(buf)
+                .and(Ok(())),
+
+            (
+                Request::GenerateFromSeed {
+                    key_type,
+                    seed,
+                    label,
+                },
+                Some(signer),
+            ) => signer
+                .generate_from_seed(key_type, &seed, &label)
+                .await
+                .map_err(RPCError::from)
+                .try_into_writer(buf)
+                .and(Ok(())),
+
+            (Request::GenerateDataKey, Some(signer)) => signer
+                .generate_data_key(&mut self.rng)
+                .await
+                .map_err(RPCError::from)
+                .try_into_writer(buf)
+                .and(Ok(())),
+
+            (Request::ImportDataKey(key_data), Some(signer)) => signer
+                .import_data_key(&key_data)
+                .await
+                .map_err(RPCError::from)
+                .try_into_writer(buf)
+                .and(Ok(())),
+
+            (
+                Request::EncryptData {
+                    handle,
+                    plaintext,
+                    aad,
+                },
+                Some(signer),
+            ) => signer
+                .encrypt_data(handle, &plaintext, &aad, &mut self.rng)
+                .map_err(RPCError::from)
+                .try_into_writer(buf)
+                .and(Ok(())),
+
+            (
+                Request::DecryptData {
+                    handle,
+                    ciphertext,
+                    aad,
+                },
+                Some(signer),
+            ) => signer
+                .decrypt_data(handle, &ciphertext, &aad)
+                .map_err(RPCError::from)
+                .try_into_writer(buf)
+                .and(Ok(())),
+
+            (Request::EciesDecrypt { handle, ciphertext }, Some(signer)) => signer
+                .ecies_decrypt(handle, &ciphertext)
+                .await
+                .map_err(RPCError::from)
+                .try_into_writer(buf)
+                .and(Ok(())),
+
+            (
+                Request::ImportMnemonic {
+                    key_type,
+                    mnemonic,
+                    passphrase,
+                },
+                Some(signer),
+            ) => signer
+                .import_mnemonic(key_type, &mnemonic, &passphrase)
+                .await
+                .map_err(RPCError::from)
+                .try_into_writer(buf)
+                .and(Ok(())),
+
+            (Request::ExportPublicKey { handle, format }, Some(signer)) => signer
+                .export_public_key(handle, format)
+                .await
+                .map_err(RPCError::from)
+                .try_into_writer(buf)
+                .and(Ok(())),
+
+            (
+                req @ (Request::Sign { .. }
+                | Request::SignWith { .. }
+                | Request::SignDigest { .. }
+                | Request::BatchSign { .. }
+                | Request::PublicKey(_)
+                | Request::PublicKeyFrom(_)),
+                Some(signer),
+            ) => handle_concurrent(signer, req, &mut self.rng, buf).await,
+
+            (Request::Idempotent { key, request }, Some(signer)) => {
+                let signer = signer.clone();
+                self.idempotent(&signer, key, *request)
+                    .await
+                    .try_into_writer(buf)
+                    .and(Ok(()))
+            }
+
+            // nothing is in flight by now
+            (Request::Cancel { .. }, Some(_)) => {
+                RPCResult::Ok(false).try_into_writer(buf).and(Ok(()))
+            }
+
+            (Request::Tagged { .. }, Some(_)) => {
+                RPCResult::<()>::Err(StateError::NestedTagged.into())
+                    .try_into_writer(buf)
+                    .and(Ok(()))
+            }
+
+            // the envelope must be the outermost one
+            (Request::Authenticated { .. }, _) => {
+                RPCResult::<()>::Err(StateError::Unauthenticated.into())
+                    .try_into_writer(buf)
+                    .and(Ok(()))
+            }
+
+            (Request::Traced { .. }, _) => RPCResult::<()>::Err(StateError::MisplacedTraced.into())
+                .try_into_writer(buf)
+                .and(Ok(())),
+
+            (
+                Request::SignStreamBegin {
+                    handle,
+                    version,
+                    digest,
+                },
+                Some(signer),
+            ) => match signer.public_key(handle).await {
+                Ok(pk) if pk.key_type() == KeyType::Bls => {
+                    RPCResult::<()>::Err(crypto::Error::PrehashUnsupported.into())
+                }
+                Ok(_) => {
+                    // replaces the unfinished one if any
+                    self.sign_stream = Some(SignStream {
+                        handle,
+                        version,
+                        hasher: digest.unwrap_or(DigestAlgorithm::Blake2b256).hasher(),
+                    });
+                    RPCResult::Ok(())
+                }
+                Err(err) => RPCResult::Err(err.into()),
+            }
+            .try_into_writer(buf)
+            .and(Ok(())),
+
+            (Request::SignStreamChunk(chunk), Some(_)) => match &mut self.sign_stream {
+                Some(stream) => {
+                    stream.hasher.update(&chunk);
+                    RPCResult::<()>::Ok(())
+                }
+                None => RPCResult::Err(StateError::NoSignStream.into()),
+            }
+            .try_into_writer(buf)
+            .and(Ok(())),
+
+            (Request::SignStreamFinish, Some(signer)) => match self.sign_stream.take() {
+                Some(stream) => signer
+                    .try_sign_hashed(stream.handle, stream.hasher, stream.version)
+                    .await
+                    .map_err(RPCError::from),
+                None => Err(StateError::NoSignStream.into()),
+            }
+            .try_into_writer(buf)
+            .and(Ok(())),
+
+            (Request::ProvePossession(handle), Some(signer)) => signer
                 .try_prove(handle)
+                .await
                 .map_err(RPCError::from)
                 .try_into_writer(buf)
                 .and(Ok(())),
@@ -231,3 +1684,226 @@ where
         .map_err(Into::into)
     }
 }
+
+async fn read_frames<T: AsyncRead + Unpin>(
+    mut sock: T,
+    frames: mpsc::Sender<Result<Vec<u8>, FrameTooLargeError>>,
+    max_frame_size: usize,
+) -> Result<(), Error> {
+    loop {
+        let size = match codec::read_len(&mut sock).await {
+            Ok(size) => size,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break Ok(()),
+            Err(err) => break Err(err.into()),
+        };
+        let frame = if size > max_frame_size {
+            codec::skip_payload(&mut sock, size).await?;
+            Err(FrameTooLargeError {
+                size,
+                max: max_frame_size,
+            })
+        } else {
+            let mut buf = Vec::new();
+            codec::read_payload(&mut sock, size, &mut buf).await?;
+            Ok(buf)
+        };
+        if frames.send(frame).await.is_err() {
+            break Ok(());
+        }
+    }
+}
+
+/// Returns the response frame of the tagged request, the `Cancelled` error if it was aborted
+fn tagged_frame(id: u64, res: Result<Result<Vec<u8>, Error>, Aborted>) -> Result<Vec<u8>, Error> {
+    match res {
+        Ok(res) => res,
+        Err(Aborted) => tagged_error(id, StateError::Cancelled),
+    }
+}
+
+fn tagged_error(id: u64, err: StateError) -> Result<Vec<u8>, Error> {
+    let mut buf = Vec::new();
+    rpc::begin_tagged_frame(&mut buf, id)?;
+    RPCResult::<()>::Err(err.into()).try_into_writer(&mut buf)?;
+    rpc::finish_frame(&mut buf);
+    Ok(buf)
+}
+
+/// RNG of the connection shared by the concurrently processed requests, so that they never
+/// draw the same values, even from a deterministic generator
+#[derive(Debug)]
+struct SharedRng<R>(Arc<Mutex<R>>);
+
+impl<R> SharedRng<R> {
+    fn lock(&self) -> std::sync::MutexGuard<'_, R> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<R> Clone for SharedRng<R> {
+    fn clone(&self) -> Self {
+        SharedRng(self.0.clone())
+    }
+}
+
+impl<R: RngCore> RngCore for SharedRng<R> {
+    fn next_u32(&mut self) -> u32 {
+        self.lock().next_u32()
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.lock().next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.lock().fill_bytes(dest)
+    }
+
+    fn try_fill_bytes(&mut self, dest: &mut [u8]) -> Result<(), rand_core::Error> {
+        self.lock().try_fill_bytes(dest)
+    }
+}
+
+impl<R: CryptoRng> CryptoRng for SharedRng<R> {}
+
+/// Processes the tagged request and returns the complete response frame
+async fn handle_tagged<E, C, R>(
+    signer: Arc<EncryptedSigner<E>>,
+    id: u64,
+    req: Request<C>,
+    trace_id: Option<String>,
+    mut rng: R,
+) -> Result<Vec<u8>, Error>
+where
+    E: EncryptionBackend,
+    R: CryptoRngCore,
+    RPCError: From<SignerError<E::Error>>,
+{
+    let mut buf = Vec::new();
+    rpc::begin_tagged_frame(&mut buf, id)?;
+    let start = buf.len();
+    let name = req.name();
+    handle_concurrent(&signer, req, &mut rng, &mut buf).await?;
+    if let Some(trace_id) = trace_id {
+        attach_trace(&mut buf, start, &trace_id, name)?;
+    }
+    rpc::finish_frame(&mut buf);
+    Ok(buf)
+}
+
+/// Unwraps `Traced` returning the request and its trace ID
+fn untrace<C>(req: Result<Request<C>, RPCError>) -> (Result<Request<C>, RPCError>, Option<String>) {
+    match req {
+        Ok(Request::Traced { trace_id, request }) => {
+            if trace_id.is_empty()
+                || trace_id.len() > MAX_TRACE_ID_LEN
+                || !trace_id.bytes().all(|c| c.is_ascii_graphic())
+            {
+                return (Err(StateError::InvalidTraceId.into()), None);
+            }
+            (Ok(*request), Some(trace_id))
+        }
+        req => (req, None),
+    }
+}
+
+/// Start of an error response: a single entry map with the `Err` key
+const ERR_PREFIX: &[u8] = b"\xa1\x63Err";
+
+/// Logs the error response written to `buf` from `start` on and sets its trace ID.
+/// Successful responses are left as is
+fn attach_trace(
+    buf: &mut Vec<u8>,
+    start: usize,
+    trace_id: &str,
+    request: &str,
+) -> Result<(), Error> {
+    if !buf[start..].starts_with(ERR_PREFIX) {
+        return Ok(());
+    }
+    if let Err(mut err) = RPCResult::<IgnoredAny>::try_from_cbor(&buf[start..])? {
+        println!("trace {}: {} failed: {}", trace_id, request, err);
+        err.trace_id = Some(trace_id.into());
+        buf.truncate(start);
+        RPCResult::<()>::Err(err).try_into_writer(&mut *buf)?;
+    }
+    Ok(())
+}
+
+/// Handles requests for which `Request::is_concurrent` is true
+async fn handle_concurrent<E, C, R>(
+    signer: &EncryptedSigner<E>,
+    req: Request<C>,
+    rng: &mut R,
+    buf: &mut Vec<u8>,
+) -> Result<(), ciborium::ser::Error<io::Error>>
+where
+    E: EncryptionBackend,
+    R: CryptoRngCore,
+    RPCError: From<SignerError<E::Error>>,
+{
+    match req {
+        Request::Sign {
+            handle,
+            message: msg,
+            version,
+            options,
+        } => signer
+            .try_sign(handle, &msg, version, &options, rng)
+            .await
+            .map_err(RPCError::from)
+            .try_into_writer(buf)
+            .and(Ok(())),
+
+        Request::BatchSign {
+            handle,
+            msgs,
+            version,
+            options,
+        } => signer
+            .try_sign_batch(handle, &msgs, version, &options, rng)
+            .await
+            .map_err(RPCError::from)
+            .try_into_writer(buf)
+            .and(Ok(())),
+
+        Request::SignDigest {
+            handle,
+            digest,
+            version,
+        } => signer
+            .try_sign_prehashed(handle, &digest, version)
+            .await
+            .map_err(RPCError::from)
+            .try_into_writer(buf)
+            .and(Ok(())),
+
+        Request::SignWith {
+            encrypted_private_key: key_data,
+            message: msg,
+            version,
+            options,
+        } => signer
+            .try_sign_with(&key_data, &msg, version, &options, rng)
+            .await
+            .map_err(RPCError::from)
+            .try_into_writer(buf)
+            .and(Ok(())),
+
+        Request::PublicKey(handle) => signer
+            .public_key(handle)
+            .await
+            .map_err(RPCError::from)
+            .try_into_writer(buf)
+            .and(Ok(())),
+
+        Request::PublicKeyFrom(key_data) => signer
+            .public_key_from(&key_data)
+            .await
+            .map_err(RPCError::from)
+            .try_into_writer(buf)
+            .and(Ok(())),
+
+        req => unreachable!("{} isn't a concurrent request", req.name()),
+    }
+}
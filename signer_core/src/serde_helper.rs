@@ -2,6 +2,7 @@ use serde::de::{self, SeqAccess, Visitor};
 use std::marker::PhantomData;
 
 pub mod bytes;
+pub mod bytes_seq;
 
 pub struct ByteArrayVisitor<const T: usize> {
     _p: PhantomData<[u8; T]>,
@@ -54,11 +55,20 @@ impl<'a, const T: usize> Visitor<'a> for ByteArrayVisitor<T> {
     }
 }
 
-pub struct BytesVisitor(usize);
+pub struct BytesVisitor(usize, Option<usize>);
 
 impl BytesVisitor {
     pub fn new(sz: usize) -> Self {
-        BytesVisitor(sz)
+        BytesVisitor(sz, None)
+    }
+
+    /// Accepts byte arrays of either of two sizes
+    pub fn either(a: usize, b: usize) -> Self {
+        BytesVisitor(a, Some(b))
+    }
+
+    fn accepts(&self, sz: usize) -> bool {
+        sz == self.0 || Some(sz) == self.1
     }
 }
 
@@ -66,14 +76,17 @@ impl<'a> Visitor<'a> for BytesVisitor {
     type Value = Vec<u8>;
 
     fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
-        write!(formatter, "a byte array of size {}", self.0)
+        match self.1 {
+            Some(sz) => write!(formatter, "a byte array of size {} or {}", self.0, sz),
+            None => write!(formatter, "a byte array of size {}", self.0),
+        }
     }
 
     fn visit_bytes<E>(self, v: &[u8]) -> Result<Self::Value, E>
     where
         E: serde::de::Error,
     {
-        if v.len() == self.0 {
+        if self.accepts(v.len()) {
             Ok(v.into())
         } else {
             Err(de::Error::invalid_length(v.len(), &self))
@@ -93,7 +106,7 @@ impl<'a> Visitor<'a> for BytesVisitor {
             values.push(v);
         }
 
-        if values.len() != self.0 {
+        if !self.accepts(values.len()) {
             Err(de::Error::invalid_length(values.len(), &self))
         } else {
             Ok(values)
@@ -0,0 +1,32 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+struct Bytes<'a>(&'a [u8]);
+
+impl Serialize for Bytes<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_bytes(self.0)
+    }
+}
+
+#[derive(Deserialize)]
+struct ByteBuf(#[serde(with = "super::bytes")] Vec<u8>);
+
+pub fn serialize<S>(value: &[Vec<u8>], serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.collect_seq(value.iter().map(|v| Bytes(v)))
+}
+
+pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<Vec<u8>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(Vec::<ByteBuf>::deserialize(deserializer)?
+        .into_iter()
+        .map(|b| b.0)
+        .collect())
+}
@@ -0,0 +1,115 @@
+//! Runs the RPC server over the `AF_VSOCK` loopback (`VMADDR_CID_LOCAL`) to exercise the real vsock
+//! code paths. Enabled with the `vsock-tests` feature, requires the `vsock_loopback` kernel module.
+use signer_core::crypto::{KeyType, PublicKey, SignOptions, Signature, SigningVersion};
+use signer_core::rpc::{client::Client, server::Server};
+use signer_core::{EncryptedSigner, EncryptionBackend, EncryptionBackendFactory};
+use std::convert::Infallible;
+use vsock::asio::{Listener, Stream};
+use vsock::{SocketAddr, VMADDR_CID_ANY, VMADDR_CID_LOCAL, VMADDR_PORT_ANY};
+
+struct Passthrough;
+
+impl EncryptionBackend for Passthrough {
+    type Error = Infallible;
+
+    async fn encrypt(&self, src: &[u8]) -> Result<Vec<u8>, Self::Error> {
+        Ok(Vec::from(src))
+    }
+
+    async fn decrypt(&self, src: &[u8]) -> Result<Vec<u8>, Self::Error> {
+        Ok(Vec::from(src))
+    }
+}
+
+struct PassthroughFactory;
+
+impl EncryptionBackendFactory for PassthroughFactory {
+    type Output = Passthrough;
+    type Credentials = ();
+
+    async fn try_new(&self, _cred: ()) -> Result<Self::Output, Infallible> {
+        Ok(Passthrough)
+    }
+}
+
+type TestServer = Server<PassthroughFactory, EncryptedSigner<Passthrough>, rand_core::OsRng>;
+
+// binds the listener serving each connection in a separate task, `None` if vsock loopback
+// isn't available
+async fn serve() -> Option<SocketAddr> {
+    let listener = match Listener::bind(&SocketAddr::new(VMADDR_CID_ANY, VMADDR_PORT_ANY)) {
+        Ok(listener) => listener,
+        Err(err) => {
+            eprintln!("vsock is not available, skipping: {}", err);
+            return None;
+        }
+    };
+    let addr = SocketAddr::new(VMADDR_CID_LOCAL, listener.local_addr().unwrap().port());
+    // binding succeeds without the loopback transport, connecting doesn't
+    let probe = match Stream::connect(&addr).await {
+        Ok(probe) => probe,
+        Err(err) => {
+            eprintln!("vsock loopback is not available, skipping: {}", err);
+            return None;
+        }
+    };
+    tokio::spawn(async move {
+        listener.accept().await.unwrap();
+        drop(probe);
+        loop {
+            let (conn, _) = listener.accept().await.unwrap();
+            tokio::spawn(async move {
+                let mut srv = TestServer::new(PassthroughFactory, rand_core::OsRng);
+                srv.serve_connection(conn).await.unwrap();
+            });
+        }
+    });
+    Some(addr)
+}
+
+async fn session(addr: &SocketAddr, msg: &[u8]) {
+    let sock = Stream::connect(addr).await.unwrap();
+    let mut client: Client<Stream, ()> = Client::new(sock);
+    client.initialize(()).await.unwrap();
+    let res = client
+        .generate_and_import(KeyType::Secp256k1)
+        .await
+        .unwrap();
+    let sig = client
+        .try_sign(
+            res.handle,
+            msg,
+            SigningVersion::Latest,
+            &SignOptions::default(),
+        )
+        .await
+        .unwrap();
+    assert!(matches!(sig, Signature::Secp256k1(_)));
+    assert!(matches!(res.public_key, PublicKey::Secp256k1(_)));
+    assert!(client
+        .verify(&res.public_key, msg, &sig, SigningVersion::Latest)
+        .await
+        .unwrap());
+}
+
+#[tokio::test]
+async fn large_message() {
+    let Some(addr) = serve().await else { return };
+    // much larger than the socket buffer so both sides see partial reads and writes
+    let msg = vec![0x5a; 4 << 20];
+    session(&addr, &msg).await;
+}
+
+#[tokio::test]
+async fn concurrent_connections() {
+    let Some(addr) = serve().await else { return };
+    let sessions: Vec<_> = (0..16)
+        .map(|i| {
+            let addr = addr.clone();
+            tokio::spawn(async move { session(&addr, format!("message {}", i).as_bytes()).await })
+        })
+        .collect();
+    for s in sessions {
+        s.await.unwrap();
+    }
+}
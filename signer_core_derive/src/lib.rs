@@ -0,0 +1,168 @@
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, parse_quote, Attribute, Data, DeriveInput, Fields, LitStr, Result};
+
+/// Derives `signer_core::cddl::Cddl` following serde's default (externally tagged) data model.
+///
+/// Recognized field and variant attributes:
+/// * `#[serde(with = "bytes")]` maps the field to `bstr`
+/// * `#[serde(rename = "...")]` renames the field or variant
+/// * `#[serde(skip)]` omits the field or variant
+/// * `#[cddl(type = "...")]` overrides the field type expression
+#[proc_macro_derive(Cddl, attributes(cddl))]
+pub fn derive_cddl(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(input) {
+        Ok(ts) => ts.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
+
+#[derive(Default)]
+struct Attrs {
+    bytes: bool,
+    skip: bool,
+    optional: bool,
+    rename: Option<String>,
+    ty: Option<String>,
+}
+
+fn parse_attrs(attrs: &[Attribute]) -> Result<Attrs> {
+    let mut res = Attrs::default();
+    for attr in attrs {
+        if attr.path().is_ident("serde") {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("with") {
+                    let s: LitStr = meta.value()?.parse()?;
+                    res.bytes = s.value().rsplit("::").next() == Some("bytes");
+                } else if meta.path.is_ident("rename") {
+                    let s: LitStr = meta.value()?.parse()?;
+                    res.rename = Some(s.value());
+                } else if meta.path.is_ident("skip") {
+                    res.skip = true;
+                } else if meta.path.is_ident("default") || meta.path.is_ident("skip_serializing_if")
+                {
+                    res.optional = true;
+                    if meta.input.peek(syn::Token![=]) {
+                        let _: LitStr = meta.value()?.parse()?;
+                    }
+                } else if meta.input.peek(syn::Token![=]) {
+                    // ignore the rest
+                    let _: syn::Expr = meta.value()?.parse()?;
+                }
+                Ok(())
+            })?;
+        } else if attr.path().is_ident("cddl") {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("type") {
+                    let s: LitStr = meta.value()?.parse()?;
+                    res.ty = Some(s.value());
+                    Ok(())
+                } else {
+                    Err(meta.error("unsupported cddl attribute"))
+                }
+            })?;
+        }
+    }
+    Ok(res)
+}
+
+fn field_type(ty: &syn::Type, attrs: &Attrs) -> TokenStream2 {
+    if let Some(t) = &attrs.ty {
+        quote! { ::std::string::String::from(#t) }
+    } else if attrs.bytes {
+        quote! { ::std::string::String::from("bstr") }
+    } else {
+        quote! { <#ty as ::signer_core::cddl::Cddl>::cddl_type(schema) }
+    }
+}
+
+// Produces an expression evaluating to the CDDL group/array body of the given fields
+fn fields_expr(fields: &Fields) -> Result<TokenStream2> {
+    match fields {
+        Fields::Named(named) => {
+            let mut entries = Vec::new();
+            for f in &named.named {
+                let attrs = parse_attrs(&f.attrs)?;
+                if attrs.skip {
+                    continue;
+                }
+                let name = attrs
+                    .rename
+                    .clone()
+                    .unwrap_or_else(|| f.ident.as_ref().unwrap().to_string());
+                let prefix = if attrs.optional { "? " } else { "" };
+                let ty = field_type(&f.ty, &attrs);
+                entries.push(quote! { format!("{}{}: {}", #prefix, #name, #ty) });
+            }
+            if entries.is_empty() {
+                return Ok(quote! { ::std::string::String::from("{ }") });
+            }
+            Ok(quote! { format!("{{ {} }}", [#(#entries),*].join(", ")) })
+        }
+        Fields::Unnamed(unnamed) if unnamed.unnamed.len() == 1 => {
+            let f = &unnamed.unnamed[0];
+            Ok(field_type(&f.ty, &parse_attrs(&f.attrs)?))
+        }
+        Fields::Unnamed(unnamed) => {
+            let mut entries = Vec::new();
+            for f in &unnamed.unnamed {
+                entries.push(field_type(&f.ty, &parse_attrs(&f.attrs)?));
+            }
+            Ok(quote! { format!("[{}]", [#(#entries),*].join(", ")) })
+        }
+        Fields::Unit => Ok(quote! { ::std::string::String::from("null") }),
+    }
+}
+
+fn expand(mut input: DeriveInput) -> Result<TokenStream2> {
+    let ident = &input.ident;
+    let rule = parse_attrs(&input.attrs)?
+        .rename
+        .unwrap_or_else(|| ident.to_string());
+
+    let body = match &input.data {
+        Data::Struct(data) => fields_expr(&data.fields)?,
+        Data::Enum(data) => {
+            let mut alts = Vec::new();
+            for v in &data.variants {
+                let attrs = parse_attrs(&v.attrs)?;
+                if attrs.skip {
+                    continue;
+                }
+                let name = attrs.rename.unwrap_or_else(|| v.ident.to_string());
+                alts.push(match &v.fields {
+                    Fields::Unit => quote! { format!("\"{}\"", #name) },
+                    fields => {
+                        let f = fields_expr(fields)?;
+                        quote! { format!("{{ {}: {} }}", #name, #f) }
+                    }
+                });
+            }
+            quote! { [#(#alts),*].join(" / ") }
+        }
+        Data::Union(_) => {
+            return Err(syn::Error::new_spanned(
+                ident,
+                "Cddl can't be derived for unions",
+            ))
+        }
+    };
+
+    for param in input.generics.type_params_mut() {
+        param.bounds.push(parse_quote!(::signer_core::cddl::Cddl));
+    }
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::signer_core::cddl::Cddl for #ident #ty_generics #where_clause {
+            fn cddl_type(schema: &mut ::signer_core::cddl::Schema) -> ::std::string::String {
+                schema.define(#rule, |schema| {
+                    let _ = &schema;
+                    #body
+                })
+            }
+        }
+    })
+}